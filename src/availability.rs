@@ -0,0 +1,227 @@
+//! Tracking of which parts of the stream are available to the reader.
+//!
+//! By default, every byte is available as soon as it's been written to the storage layer, which is
+//! tracked using a [RangeSet]. Sources that deliver data in fixed-size pieces, such as
+//! peer-to-peer protocols, can use [PieceMap] so that data only becomes available once the whole
+//! piece containing it has been written. Custom implementations of [AvailabilityMap] can be
+//! supplied with [Settings::availability_map](crate::Settings::availability_map).
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::num::NonZeroU64;
+//! use std::result::Result;
+//!
+//! use stream_download::availability::{AvailabilityMap, AvailabilityMapFactory, PieceMap};
+//! use stream_download::storage::temp::TempStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let piece_size = NonZeroU64::new(16 * 1024).unwrap();
+//!     let factory = AvailabilityMapFactory::new(move |content_length| match content_length {
+//!         Some(length) => Box::new(PieceMap::new(piece_size, length)) as Box<dyn AvailabilityMap>,
+//!         None => AvailabilityMapFactory::default().create(None),
+//!     });
+//!     let reader = StreamDownload::new_http(
+//!         "https://some-cool-url.com/some-file.mp3".parse()?,
+//!         TempStorageProvider::default(),
+//!         Settings::default().availability_map(Some(factory)),
+//!     )
+//!     .await?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::fmt;
+use std::num::NonZeroU64;
+use std::ops::Range;
+use std::sync::Arc;
+
+use rangemap::RangeSet;
+
+/// Keeps track of which parts of the stream have been written to storage and which of those can
+/// be read.
+///
+/// The downloader reports every range of bytes it writes using [AvailabilityMap::insert]. Readers
+/// only see the ranges returned by [AvailabilityMap::get], which may lag behind the written data
+/// if the implementation waits for larger units to complete.
+pub trait AvailabilityMap: fmt::Debug + Send + Sync {
+    /// Marks the bytes in `range` as written. `range` is never empty.
+    fn insert(&mut self, range: Range<u64>);
+
+    /// Returns the contiguous range of available data that contains `position`, if there is one.
+    fn get(&self, position: u64) -> Option<Range<u64>>;
+
+    /// Returns the parts of `range` that haven't been written yet, in order.
+    fn gaps(&self, range: Range<u64>) -> Box<dyn Iterator<Item = Range<u64>> + '_>;
+
+    /// Returns every contiguous range of available data, in order.
+    fn ranges(&self) -> Box<dyn Iterator<Item = Range<u64>> + '_>;
+
+    /// Returns the position that a download needs to start from in order to make `position`
+    /// available.
+    /// The default implementation returns `position` unchanged.
+    fn fetch_start(&self, position: u64) -> u64 {
+        position
+    }
+
+    /// Returns whether the byte at `position` is available.
+    fn contains(&self, position: u64) -> bool {
+        self.get(position).is_some()
+    }
+}
+
+impl AvailabilityMap for RangeSet<u64> {
+    fn insert(&mut self, range: Range<u64>) {
+        RangeSet::insert(self, range);
+    }
+
+    fn get(&self, position: u64) -> Option<Range<u64>> {
+        RangeSet::get(self, &position).cloned()
+    }
+
+    fn gaps(&self, range: Range<u64>) -> Box<dyn Iterator<Item = Range<u64>> + '_> {
+        Box::new(RangeSet::gaps(self, &range).collect::<Vec<_>>().into_iter())
+    }
+
+    fn ranges(&self) -> Box<dyn Iterator<Item = Range<u64>> + '_> {
+        Box::new(self.iter().cloned())
+    }
+
+    fn contains(&self, position: u64) -> bool {
+        RangeSet::contains(self, &position)
+    }
+}
+
+/// [AvailabilityMap] that divides the stream into pieces of a fixed size and only makes a piece
+/// available once all of it has been written.
+///
+/// Downloads that need to fill part of a piece start from the first missing byte in that piece,
+/// so seeking into the middle of a piece downloads the whole piece. Any data past the supplied
+/// length is made available as soon as it's written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceMap {
+    piece_size: u64,
+    length: u64,
+    written: RangeSet<u64>,
+    complete: RangeSet<u64>,
+}
+
+impl PieceMap {
+    /// Creates a new [PieceMap] for a stream of `length` bytes divided into pieces of
+    /// `piece_size` bytes. The last piece may be shorter.
+    pub fn new(piece_size: NonZeroU64, length: u64) -> Self {
+        Self {
+            piece_size: piece_size.get(),
+            length,
+            written: RangeSet::new(),
+            complete: RangeSet::new(),
+        }
+    }
+
+    /// The size of each piece in bytes.
+    pub fn piece_size(&self) -> u64 {
+        self.piece_size
+    }
+
+    /// Returns whether the piece at `index` has been completely written.
+    pub fn is_piece_complete(&self, index: u64) -> bool {
+        self.piece_range(index)
+            .is_some_and(|piece| self.complete.contains(&piece.start))
+    }
+
+    fn piece_range(&self, index: u64) -> Option<Range<u64>> {
+        let start = index.checked_mul(self.piece_size)?;
+        (start < self.length).then(|| start..start.saturating_add(self.piece_size).min(self.length))
+    }
+}
+
+impl AvailabilityMap for PieceMap {
+    fn insert(&mut self, range: Range<u64>) {
+        self.written.insert(range.clone());
+        if range.end > self.length {
+            self.complete
+                .insert(range.start.max(self.length)..range.end);
+        }
+        let first = range.start / self.piece_size;
+        let last = (range.end - 1) / self.piece_size;
+        for index in first..=last {
+            let Some(piece) = self.piece_range(index) else {
+                break;
+            };
+            if self.written.gaps(&piece).next().is_none() {
+                self.complete.insert(piece);
+            }
+        }
+    }
+
+    fn get(&self, position: u64) -> Option<Range<u64>> {
+        self.complete.get(&position).cloned()
+    }
+
+    fn gaps(&self, range: Range<u64>) -> Box<dyn Iterator<Item = Range<u64>> + '_> {
+        Box::new(self.written.gaps(&range).collect::<Vec<_>>().into_iter())
+    }
+
+    fn ranges(&self) -> Box<dyn Iterator<Item = Range<u64>> + '_> {
+        Box::new(self.complete.iter().cloned())
+    }
+
+    fn fetch_start(&self, position: u64) -> u64 {
+        if position >= self.length {
+            return position;
+        }
+        let piece_start = position - position % self.piece_size;
+        self.written
+            .gaps(&(piece_start..position))
+            .next()
+            .map_or(position, |gap| gap.start)
+    }
+}
+
+/// Creates the [AvailabilityMap] used by each download. The function receives the content length
+/// of the stream, if it's known.
+///
+/// The default factory creates an empty [RangeSet].
+#[derive(Clone)]
+pub struct AvailabilityMapFactory(
+    Arc<dyn Fn(Option<u64>) -> Box<dyn AvailabilityMap> + Send + Sync>,
+);
+
+impl AvailabilityMapFactory {
+    /// Creates a new [AvailabilityMapFactory] from a function.
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: Fn(Option<u64>) -> Box<dyn AvailabilityMap> + Send + Sync + 'static,
+    {
+        Self(Arc::new(factory))
+    }
+
+    /// Creates a new [AvailabilityMap] for a stream with the given content length.
+    pub fn create(&self, content_length: Option<u64>) -> Box<dyn AvailabilityMap> {
+        (self.0)(content_length)
+    }
+}
+
+impl Default for AvailabilityMapFactory {
+    fn default() -> Self {
+        Self::new(|_| Box::<RangeSet<u64>>::default())
+    }
+}
+
+impl fmt::Debug for AvailabilityMapFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AvailabilityMapFactory")
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for AvailabilityMapFactory {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for AvailabilityMapFactory {}
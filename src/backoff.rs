@@ -0,0 +1,169 @@
+//! Strategies that control how the download task retries after the stream returns an error.
+//!
+//! By default, errors from the stream aren't retried. Setting a [BackoffStrategy] with
+//! [Settings::backoff](crate::Settings::backoff) makes the download task wait for the delay
+//! returned by the strategy and restart the stream from the current position with a range
+//! request. The attempt counter is reset once the stream delivers data again, and the download
+//! stops with the last error once the strategy returns `None`.
+//!
+//! [Exponential], [Fixed], and [Fibonacci] are provided, and any of them can be wrapped in
+//! [Jitter] so that many clients retrying against the same server don't reconnect in lockstep.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Determines how long to wait before each retry.
+/// Set a custom strategy with [Settings::backoff](crate::Settings::backoff).
+pub trait BackoffStrategy: fmt::Debug + Send + Sync {
+    /// Returns how long to wait before the given retry attempt, starting at 1, or `None` to stop
+    /// retrying.
+    fn next_delay(&self, attempt: u32) -> Option<Duration>;
+}
+
+/// [BackoffStrategy] that doubles the delay after each attempt, up to a maximum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exponential {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_retries: u32,
+}
+
+impl Exponential {
+    /// Creates a new [Exponential] strategy that waits `initial_delay` before the first retry and
+    /// stops after `max_retries` attempts.
+    pub fn new(initial_delay: Duration, max_delay: Duration, max_retries: u32) -> Self {
+        Self {
+            initial_delay,
+            max_delay,
+            max_retries,
+        }
+    }
+}
+
+impl BackoffStrategy for Exponential {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt > self.max_retries {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt - 1);
+        Some(
+            self.initial_delay
+                .checked_mul(factor)
+                .map_or(self.max_delay, |delay| delay.min(self.max_delay)),
+        )
+    }
+}
+
+/// [BackoffStrategy] that waits the same amount of time before each attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixed {
+    delay: Duration,
+    max_retries: u32,
+}
+
+impl Fixed {
+    /// Creates a new [Fixed] strategy that waits `delay` before each retry and stops after
+    /// `max_retries` attempts.
+    pub fn new(delay: Duration, max_retries: u32) -> Self {
+        Self { delay, max_retries }
+    }
+}
+
+impl BackoffStrategy for Fixed {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        (attempt > 0 && attempt <= self.max_retries).then_some(self.delay)
+    }
+}
+
+/// [BackoffStrategy] that grows the delay along the Fibonacci sequence, up to a maximum.
+/// This backs off more gradually than [Exponential].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fibonacci {
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_retries: u32,
+}
+
+impl Fibonacci {
+    /// Creates a new [Fibonacci] strategy that waits `initial_delay` before the first two retries
+    /// and stops after `max_retries` attempts.
+    pub fn new(initial_delay: Duration, max_delay: Duration, max_retries: u32) -> Self {
+        Self {
+            initial_delay,
+            max_delay,
+            max_retries,
+        }
+    }
+}
+
+impl BackoffStrategy for Fibonacci {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt > self.max_retries {
+            return None;
+        }
+        let (mut current, mut next) = (1u32, 1u32);
+        for _ in 1..attempt {
+            (current, next) = (next, current.saturating_add(next));
+        }
+        Some(
+            self.initial_delay
+                .checked_mul(current)
+                .map_or(self.max_delay, |delay| delay.min(self.max_delay)),
+        )
+    }
+}
+
+/// [BackoffStrategy] that randomizes the delays returned by another strategy.
+/// Each delay is scaled by a random factor between 0.5 and 1, so retries are spread out while
+/// still waiting at least half of the original delay.
+#[derive(Debug)]
+pub struct Jitter<B> {
+    inner: B,
+    state: AtomicU64,
+}
+
+impl<B: BackoffStrategy> Jitter<B> {
+    /// Creates a new [Jitter] strategy that randomizes the delays returned by `inner`.
+    pub fn new(inner: B) -> Self {
+        // Only used to seed the generator, so it doesn't need to be cryptographically secure
+        let seed = RandomState::new().build_hasher().finish();
+        Self {
+            inner,
+            state: AtomicU64::new(seed | 1),
+        }
+    }
+
+    fn next_random(&self) -> u64 {
+        // xorshift64
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+        x
+    }
+}
+
+impl<B: BackoffStrategy> BackoffStrategy for Jitter<B> {
+    fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        let delay = self.inner.next_delay(attempt)?;
+        let factor = 0.5 + (self.next_random() as f64 / u64::MAX as f64) / 2.0;
+        Some(delay.mul_f64(factor))
+    }
+}
+
+// Wrapper that allows the strategy to be stored in the settings
+#[derive(Debug, Clone)]
+pub(crate) struct SharedBackoff(pub(crate) Arc<dyn BackoffStrategy>);
+
+impl PartialEq for SharedBackoff {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedBackoff {}
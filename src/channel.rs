@@ -0,0 +1,94 @@
+//! A [SourceStream] implementation for data that's produced in the same process.
+//!
+//! Each [Bytes] sent through the channel is written to the buffer as it arrives, and the stream
+//! finishes once every sender has been dropped. The length isn't known ahead of time and the
+//! producer can't be asked to send earlier data again, so the reader can only seek within the
+//! data that has already been received.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::result::Result;
+//!
+//! use bytes::Bytes;
+//! use stream_download::channel::ChannelStream;
+//! use stream_download::storage::memory::MemoryStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//! use tokio::sync::mpsc;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let (tx, rx) = mpsc::channel(32);
+//!     let reader = StreamDownload::new::<ChannelStream>(
+//!         rx,
+//!         MemoryStorageProvider::default(),
+//!         Settings::default(),
+//!     )
+//!     .await?;
+//!
+//!     tx.send(Bytes::from_static(b"some encoded data")).await?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::task::{self, Poll};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use crate::source::SourceStream;
+
+/// A [SourceStream] that receives its data from a [mpsc::Receiver].
+#[derive(Debug)]
+pub struct ChannelStream {
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl ChannelStream {
+    /// Creates a new [ChannelStream] that reads from `rx`.
+    pub fn new(rx: mpsc::Receiver<Bytes>) -> Self {
+        Self { rx }
+    }
+}
+
+impl Stream for ChannelStream {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|bytes| bytes.map(Ok))
+    }
+}
+
+#[async_trait]
+impl SourceStream for ChannelStream {
+    type Url = mpsc::Receiver<Bytes>;
+    type StreamError = io::Error;
+
+    async fn create(rx: Self::Url) -> io::Result<Self> {
+        Ok(Self::new(rx))
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        None
+    }
+
+    async fn seek_range(&mut self, _start: u64, _end: Option<u64>) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "data that was already received from the channel can't be requested again",
+        ))
+    }
+
+    fn supports_seek(&self) -> bool {
+        false
+    }
+
+    fn supports_restart(&self) -> bool {
+        false
+    }
+}
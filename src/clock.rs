@@ -0,0 +1,116 @@
+//! Source of time used by the download task.
+//!
+//! By default, [TokioClock] is used, which relies on [tokio::time]. Enabling the `test-util`
+//! feature provides a `TestClock` that only moves forward when it's advanced manually, so timing
+//! behavior such as [Settings::seek_debounce](crate::Settings::seek_debounce) can be tested
+//! without real delays.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+#[cfg(feature = "test-util")]
+use parking_lot::Mutex;
+#[cfg(feature = "test-util")]
+use tokio::sync::Notify;
+
+/// Provides the current time and timers to the download task.
+/// Set a custom clock with [Settings::clock](crate::Settings::clock).
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once `duration` has elapsed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// [Clock] that uses [tokio::time].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// [Clock] that only moves forward when [TestClock::advance] is called.
+/// Cloning the clock returns a handle to the same time source.
+#[cfg(feature = "test-util")]
+#[derive(Debug, Clone)]
+pub struct TestClock {
+    inner: Arc<TestClockInner>,
+}
+
+#[cfg(feature = "test-util")]
+#[derive(Debug)]
+struct TestClockInner {
+    now: Mutex<Instant>,
+    notify: Notify,
+}
+
+#[cfg(feature = "test-util")]
+impl TestClock {
+    /// Creates a new [TestClock] starting at the current time.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(TestClockInner {
+                now: Mutex::new(Instant::now()),
+                notify: Notify::new(),
+            }),
+        }
+    }
+
+    /// Moves the clock forward by `duration` and completes any sleeps that have elapsed.
+    pub fn advance(&self, duration: Duration) {
+        *self.inner.now.lock() += duration;
+        self.inner.notify.notify_waiters();
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Default for TestClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        *self.inner.now.lock()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let inner = self.inner.clone();
+        let deadline = self.now() + duration;
+        Box::pin(async move {
+            loop {
+                // Register for notifications before checking the time so an advance in between
+                // isn't missed
+                let notified = inner.notify.notified();
+                if *inner.now.lock() >= deadline {
+                    return;
+                }
+                notified.await;
+            }
+        })
+    }
+}
+
+// Wrapper that allows the clock to be stored in the settings
+#[derive(Debug, Clone)]
+pub(crate) struct SharedClock(pub(crate) Arc<dyn Clock>);
+
+impl PartialEq for SharedClock {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedClock {}
@@ -0,0 +1,168 @@
+//! A shared limit on the number of concurrent connections to each host.
+//!
+//! Each [StreamDownload](crate::StreamDownload) created with the same [ConnectionLimit] (see
+//! [Settings::connection_limit](crate::Settings::connection_limit)) waits for a free connection to
+//! its host before it starts reading from the stream, and keeps it until the download task
+//! finishes. This prevents downloads that are started together, such as when prefetching a
+//! playlist, from getting the client throttled by a server that limits connections per client.
+//!
+//! The host is taken from the URL in the stream's [SourceInfo](crate::source::SourceInfo), so
+//! streams that don't report a URL aren't limited. Streams are created before the download task
+//! starts, so the initial response is already open while waiting. If the stream supports
+//! restarting, the response is closed while waiting and the request is sent again once a
+//! connection is available.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::result::Result;
+//!
+//! use stream_download::connection_limit::ConnectionLimit;
+//! use stream_download::storage::temp::TempStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     // Shared between all downloads
+//!     let limit = ConnectionLimit::new(2);
+//!     let reader = StreamDownload::new_http(
+//!         "https://some-cool-url.com/some-file.mp3".parse()?,
+//!         TempStorageProvider::default(),
+//!         Settings::default().connection_limit(Some(limit.clone())),
+//!     )
+//!     .await?;
+//!     println!("{:?}", limit.in_flight());
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+/// Limit on the number of concurrent connections to each host for all downloads that share it.
+/// Cloning the limit returns a handle to the same shared limit.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    max_per_host: usize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl ConnectionLimit {
+    /// Creates a new [ConnectionLimit] that allows up to `max_per_host` connections to each host.
+    /// Values below 1 are treated as 1.
+    pub fn new(max_per_host: usize) -> Self {
+        Self {
+            max_per_host: max_per_host.max(1),
+            hosts: Default::default(),
+        }
+    }
+
+    /// The maximum number of concurrent connections to each host.
+    pub fn max_per_host(&self) -> usize {
+        self.max_per_host
+    }
+
+    /// The number of connections currently in use for each host.
+    /// Hosts without any connections in use aren't included.
+    pub fn in_flight(&self) -> HashMap<String, usize> {
+        self.hosts
+            .lock()
+            .iter()
+            .map(|(host, semaphore)| {
+                (
+                    host.clone(),
+                    self.max_per_host - semaphore.available_permits(),
+                )
+            })
+            .filter(|(_, in_flight)| *in_flight > 0)
+            .collect()
+    }
+
+    fn semaphore(&self, host: &str) -> Arc<Semaphore> {
+        self.hosts
+            .lock()
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_host)))
+            .clone()
+    }
+
+    pub(crate) fn try_acquire(&self, host: &str) -> Option<ConnectionPermit> {
+        let permit = self.semaphore(host).try_acquire_owned().ok()?;
+        Some(self.permit(host, permit))
+    }
+
+    pub(crate) async fn acquire(&self, host: &str) -> ConnectionPermit {
+        let permit = self
+            .semaphore(host)
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        self.permit(host, permit)
+    }
+
+    fn permit(&self, host: &str, permit: OwnedSemaphorePermit) -> ConnectionPermit {
+        debug!(host, "acquired connection");
+        ConnectionPermit {
+            limit: self.clone(),
+            host: host.to_string(),
+            permit: Some(permit),
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionLimit")
+            .field("max_per_host", &self.max_per_host)
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
+
+impl PartialEq for ConnectionLimit {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.hosts, &other.hosts)
+    }
+}
+
+impl Eq for ConnectionLimit {}
+
+// Connection to a host that's released once this is dropped
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit {
+    limit: ConnectionLimit,
+    host: String,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.permit.take();
+        debug!(host = self.host, "released connection");
+        let mut hosts = self.limit.hosts.lock();
+        // Remove hosts that are no longer in use so the map doesn't grow indefinitely. Any
+        // downloads waiting for the host hold a reference to the semaphore.
+        if hosts
+            .get(&self.host)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+        {
+            hosts.remove(&self.host);
+        }
+    }
+}
+
+// Extracts the host and port from a URL
+pub(crate) fn host(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    (!host.is_empty()).then_some(host)
+}
@@ -0,0 +1,147 @@
+//! A [SourceStream] implementation for
+//! [data URLs](https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/Data_URLs).
+//!
+//! The payload is decoded up front and kept in memory, so the content length is always known and
+//! seeking doesn't require any additional work. This is mostly useful for embedding small assets
+//! and for testing without a server.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::result::Result;
+//!
+//! use stream_download::data_url::DataUrlStream;
+//! use stream_download::storage::memory::MemoryStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let reader = StreamDownload::new::<DataUrlStream>(
+//!         "data:text/plain;base64,SGVsbG8sIFdvcmxkIQ==".to_string(),
+//!         MemoryStorageProvider::default(),
+//!         Settings::default(),
+//!     )
+//!     .await?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::task::{self, Poll};
+
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use futures::Stream;
+use percent_encoding::percent_decode_str;
+use tracing::{debug, instrument};
+
+use crate::source::{SourceInfo, SourceStream};
+
+/// A [SourceStream] that serves the decoded contents of a data URL.
+#[derive(Debug)]
+pub struct DataUrlStream {
+    data: Bytes,
+    media_type: String,
+    position: usize,
+    end: usize,
+}
+
+impl DataUrlStream {
+    /// Creates a new [DataUrlStream] by decoding the supplied data URL.
+    /// Both base64 and percent-encoded payloads are supported.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned());
+
+        let url = url
+            .strip_prefix("data:")
+            .ok_or_else(|| invalid("data URL must start with 'data:'"))?;
+        let (metadata, payload) = url
+            .split_once(',')
+            .ok_or_else(|| invalid("data URL is missing the ',' separator"))?;
+        let (media_type, is_base64) = match metadata.strip_suffix(";base64") {
+            Some(media_type) => (media_type, true),
+            None => (metadata, false),
+        };
+
+        let payload: Vec<u8> = percent_decode_str(payload).collect();
+        let data = if is_base64 {
+            // Whitespace isn't part of the base64 alphabet, but it's allowed in data URLs
+            let payload: Vec<u8> = payload
+                .into_iter()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            base64::engine::general_purpose::STANDARD
+                .decode(payload)
+                .map_err(|e| invalid(&format!("invalid base64 payload: {e}")))?
+        } else {
+            payload
+        };
+        debug!(media_type, content_length = data.len(), "decoded data URL");
+
+        Ok(Self {
+            end: data.len(),
+            data: data.into(),
+            // Per RFC 2397, the media type defaults to text/plain if it's omitted
+            media_type: if media_type.is_empty() {
+                "text/plain;charset=US-ASCII".to_owned()
+            } else {
+                media_type.to_owned()
+            },
+            position: 0,
+        })
+    }
+
+    /// The media type of the payload, including any parameters.
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+}
+
+impl Stream for DataUrlStream {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if self.position >= self.end {
+            return Poll::Ready(None);
+        }
+        let chunk = self.data.slice(self.position..self.end);
+        self.position = self.end;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[async_trait]
+impl SourceStream for DataUrlStream {
+    type Url = String;
+    type StreamError = io::Error;
+
+    async fn create(url: Self::Url) -> io::Result<Self> {
+        Self::new(&url)
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.data.len() as u64)
+    }
+
+    #[instrument(skip(self))]
+    async fn seek_range(&mut self, start: u64, end: Option<u64>) -> io::Result<()> {
+        let len = self.data.len();
+        self.position = (start as usize).min(len);
+        self.end = end.map(|end| (end as usize).min(len)).unwrap_or(len);
+        Ok(())
+    }
+
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            content_type: Some(self.media_type.clone()),
+            content_length: self.content_length(),
+            ..Default::default()
+        }
+    }
+}
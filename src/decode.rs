@@ -0,0 +1,61 @@
+//! Decoding for streams whose data is compressed with a content encoding, such as HTTP responses
+//! with a `Content-Encoding` header. See [Decompression::Manual](crate::Decompression::Manual).
+
+use std::io::{self, Write};
+use std::mem;
+
+use bytes::Bytes;
+use flate2::write::{GzDecoder, ZlibDecoder};
+
+// Decodes the data of a stream as it arrives. Each call to decode returns all of the data that
+// can be decoded so far, so nothing is held back until the end of the stream.
+#[derive(Debug)]
+pub(crate) enum ContentDecoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+}
+
+impl ContentDecoder {
+    // Returns None if the encoding isn't supported
+    pub(crate) fn new(content_encoding: &str) -> Option<Self> {
+        let content_encoding = content_encoding.trim();
+        if content_encoding.eq_ignore_ascii_case("gzip")
+            || content_encoding.eq_ignore_ascii_case("x-gzip")
+        {
+            Some(Self::Gzip(GzDecoder::new(Vec::new())))
+        } else if content_encoding.eq_ignore_ascii_case("deflate") {
+            // HTTP's deflate encoding is a zlib stream rather than raw DEFLATE data
+            Some(Self::Deflate(ZlibDecoder::new(Vec::new())))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn decode(&mut self, bytes: &[u8]) -> io::Result<Bytes> {
+        match self {
+            Self::Gzip(decoder) => {
+                decoder.write_all(bytes).map_err(decode_error)?;
+                decoder.flush().map_err(decode_error)?;
+                Ok(mem::take(decoder.get_mut()).into())
+            }
+            Self::Deflate(decoder) => {
+                decoder.write_all(bytes).map_err(decode_error)?;
+                decoder.flush().map_err(decode_error)?;
+                Ok(mem::take(decoder.get_mut()).into())
+            }
+        }
+    }
+
+    // Returns any remaining decoded data. For gzip, this also fails if the stream ended before
+    // the trailer or the checksum doesn't match.
+    pub(crate) fn finish(self) -> io::Result<Bytes> {
+        match self {
+            Self::Gzip(decoder) => decoder.finish().map_err(decode_error).map(Into::into),
+            Self::Deflate(decoder) => decoder.finish().map_err(decode_error).map(Into::into),
+        }
+    }
+}
+
+fn decode_error(error: io::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
@@ -0,0 +1,152 @@
+//! Chunk transforms that decrypt AES-128 encrypted content as it's downloaded.
+//!
+//! [Aes128Ctr] can decrypt any part of the stream on its own since the counter for each block is
+//! derived from its position, so seeking works as usual. [Aes128Cbc] needs the previous block to
+//! decrypt the next one, so it can only be used for downloads that run from start to finish.
+//!
+//! Both can be converted into a [ChunkTransform] and passed to
+//! [Settings::chunk_transform](crate::Settings::chunk_transform).
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::io::Read;
+//! use std::result::Result;
+//!
+//! use stream_download::decrypt::Aes128Ctr;
+//! use stream_download::storage::temp::TempStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let key = [0; 16];
+//!     let iv = [0; 16];
+//!     let mut reader = StreamDownload::new_http(
+//!         "https://some-cool-url.com/some-file.mp3".parse()?,
+//!         TempStorageProvider::default(),
+//!         Settings::default().chunk_transform(Some(Aes128Ctr::new(key, iv).into())),
+//!     )
+//!     .await?;
+//!
+//!     let mut buf = Vec::new();
+//!     reader.read_to_end(&mut buf)?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::{fmt, io};
+
+use aes::cipher::{BlockDecryptMut, KeyIvInit, StreamCipher, StreamCipherSeek};
+use aes::{Aes128, Block};
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::ChunkTransform;
+
+const BLOCK_SIZE: usize = 16;
+
+/// AES-128 in counter mode with a 128-bit big-endian counter.
+#[derive(Clone)]
+pub struct Aes128Ctr {
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+impl Aes128Ctr {
+    /// Creates a new [Aes128Ctr] from the key and the initial counter block.
+    pub fn new(key: [u8; 16], iv: [u8; 16]) -> Self {
+        Self { key, iv }
+    }
+
+    /// Decrypts `buf` in place, where `position` is the offset of its first byte in the stream.
+    /// Counter mode is symmetric, so this can be used to encrypt data as well.
+    pub fn apply_keystream(&self, position: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut cipher = ctr::Ctr128BE::<Aes128>::new(&self.key.into(), &self.iv.into());
+        cipher
+            .try_seek(position)
+            .and_then(|_| cipher.try_apply_keystream(buf))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+    }
+}
+
+impl fmt::Debug for Aes128Ctr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Aes128Ctr").finish_non_exhaustive()
+    }
+}
+
+impl From<Aes128Ctr> for ChunkTransform {
+    fn from(cipher: Aes128Ctr) -> Self {
+        ChunkTransform::new(move |position, chunk| {
+            let mut buf = chunk.to_vec();
+            cipher.apply_keystream(position, &mut buf)?;
+            Ok(buf.into())
+        })
+    }
+}
+
+/// AES-128 in cipher block chaining mode.
+///
+/// Each block can only be decrypted once the previous one is known, so chunks have to arrive in
+/// order starting from the beginning of the stream. Seeking to data that hasn't been downloaded
+/// fails with an [io::ErrorKind::Unsupported] error, either from the seek itself or from the
+/// download. Incomplete blocks are held back until the rest of the block arrives, which makes the
+/// content length unknown. Padding at the end of the stream is left in place.
+#[derive(Clone)]
+pub struct Aes128Cbc {
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+impl Aes128Cbc {
+    /// Creates a new [Aes128Cbc] from the key and the initialization vector.
+    pub fn new(key: [u8; 16], iv: [u8; 16]) -> Self {
+        Self { key, iv }
+    }
+}
+
+impl fmt::Debug for Aes128Cbc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Aes128Cbc").finish_non_exhaustive()
+    }
+}
+
+impl From<Aes128Cbc> for ChunkTransform {
+    fn from(cipher: Aes128Cbc) -> Self {
+        let state = Mutex::new(CbcState {
+            decryptor: cbc::Decryptor::new(&cipher.key.into(), &cipher.iv.into()),
+            pending: Vec::new(),
+            next_position: 0,
+        });
+        ChunkTransform::new(move |position, chunk| state.lock().decrypt(position, &chunk))
+    }
+}
+
+struct CbcState {
+    decryptor: cbc::Decryptor<Aes128>,
+    // Data that doesn't fill a whole block yet
+    pending: Vec<u8>,
+    // Position in storage that the next decrypted block will be written to
+    next_position: u64,
+}
+
+impl CbcState {
+    fn decrypt(&mut self, position: u64, chunk: &[u8]) -> io::Result<Bytes> {
+        if position != self.next_position {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "CBC decryption requires the stream to be downloaded in order",
+            ));
+        }
+        self.pending.extend_from_slice(chunk);
+        let len = self.pending.len() - self.pending.len() % BLOCK_SIZE;
+        let mut buf: Vec<_> = self.pending.drain(..len).collect();
+        for block in buf.chunks_exact_mut(BLOCK_SIZE) {
+            self.decryptor
+                .decrypt_block_mut(Block::from_mut_slice(block));
+        }
+        self.next_position += buf.len() as u64;
+        Ok(buf.into())
+    }
+}
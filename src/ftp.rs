@@ -0,0 +1,309 @@
+//! A [SourceStream] implementation for files served over FTP.
+//!
+//! URLs take the form of `ftp://[user[:password]@]host[:port]/path`. If no user is given, the
+//! stream logs in anonymously. The content length is retrieved with the `SIZE` command and seeking
+//! uses the `REST` command, so servers that support both behave much like HTTP servers that
+//! support range requests. Each seek opens a new connection since an FTP transfer can't be
+//! interrupted without closing it.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::result::Result;
+//!
+//! use stream_download::ftp::FtpStream;
+//! use stream_download::storage::memory::MemoryStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let reader = StreamDownload::new::<FtpStream>(
+//!         "ftp://ftp.example.com/music.mp3".to_string(),
+//!         MemoryStorageProvider::default(),
+//!         Settings::default(),
+//!     )
+//!     .await?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::pin::Pin;
+use std::task::{self, Poll};
+use std::{fmt, io};
+
+use async_ftp::types::FileType;
+use async_ftp::FtpError;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use percent_encoding::percent_decode_str;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, instrument, warn};
+
+use crate::source::{SourceInfo, SourceStream};
+
+const DEFAULT_PORT: u16 = 21;
+
+/// The parts of an FTP URL needed to connect to the server and retrieve a file.
+#[derive(Clone, PartialEq, Eq)]
+pub struct FtpUrl {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    path: String,
+}
+
+impl FtpUrl {
+    /// Parses a URL in the form of `ftp://[user[:password]@]host[:port]/path`.
+    /// Percent-encoded characters in the user, password and path are decoded.
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned());
+        let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().into_owned();
+
+        let url = url
+            .strip_prefix("ftp://")
+            .ok_or_else(|| invalid("FTP URL must start with 'ftp://'"))?;
+        let (authority, path) = url
+            .split_once('/')
+            .ok_or_else(|| invalid("FTP URL is missing the file path"))?;
+        if path.is_empty() {
+            return Err(invalid("FTP URL is missing the file path"));
+        }
+        let (credentials, address) = match authority.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, authority),
+        };
+        let (user, password) = match credentials {
+            Some(credentials) => match credentials.split_once(':') {
+                Some((user, password)) => (decode(user), decode(password)),
+                None => (decode(credentials), String::new()),
+            },
+            None => ("anonymous".to_owned(), "anonymous".to_owned()),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| invalid(&format!("invalid FTP port: {port}")))?,
+            ),
+            None => (address, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(invalid("FTP URL is missing the host"));
+        }
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            user,
+            password,
+            path: decode(path),
+        })
+    }
+
+    /// The host name of the server.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The port of the server.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The user used to log in.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// The path of the file on the server.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl fmt::Display for FtpUrl {
+    // The password is left out so the URL can be logged
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ftp://{}@{}:{}/{}",
+            self.user, self.host, self.port, self.path
+        )
+    }
+}
+
+impl fmt::Debug for FtpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FtpUrl")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+type DataStream = Box<dyn Stream<Item = io::Result<Bytes>> + Unpin + Send + Sync>;
+
+/// A [SourceStream] that retrieves a file from an FTP server.
+pub struct FtpStream {
+    url: FtpUrl,
+    // Kept open for as long as the transfer is running
+    control: Option<async_ftp::FtpStream>,
+    data: DataStream,
+    content_length: Option<u64>,
+    supports_seek: bool,
+}
+
+impl fmt::Debug for FtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FtpStream")
+            .field("url", &self.url)
+            .field("content_length", &self.content_length)
+            .field("supports_seek", &self.supports_seek)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FtpStream {
+    /// Connects to the server and starts retrieving the file.
+    #[instrument(skip(url), fields(url = url.to_string()))]
+    pub async fn new(url: FtpUrl) -> io::Result<Self> {
+        let mut control = connect(&url).await?;
+        let content_length = match control.size(&url.path).await {
+            Ok(Some(size)) => {
+                debug!(content_length = size, "received content length");
+                Some(size as u64)
+            }
+            Ok(None) => {
+                warn!("invalid response to SIZE command");
+                None
+            }
+            Err(e) => {
+                warn!("error requesting content length: {e}");
+                None
+            }
+        };
+        let data = retrieve(&mut control, &url.path, None).await?;
+
+        Ok(Self {
+            url,
+            control: Some(control),
+            data,
+            content_length,
+            supports_seek: true,
+        })
+    }
+
+    /// The URL of the file, without the password.
+    pub fn url(&self) -> &FtpUrl {
+        &self.url
+    }
+}
+
+async fn connect(url: &FtpUrl) -> io::Result<async_ftp::FtpStream> {
+    debug!("connecting to FTP server");
+    let mut control = async_ftp::FtpStream::connect((url.host.as_str(), url.port))
+        .await
+        .map_err(ftp_error)?;
+    control
+        .login(&url.user, &url.password)
+        .await
+        .map_err(ftp_error)?;
+    // Sizes and offsets are only meaningful in binary mode
+    control
+        .transfer_type(FileType::Binary)
+        .await
+        .map_err(ftp_error)?;
+    Ok(control)
+}
+
+async fn retrieve(
+    control: &mut async_ftp::FtpStream,
+    path: &str,
+    len: Option<u64>,
+) -> io::Result<DataStream> {
+    let reader = control.get(path).await.map_err(ftp_error)?;
+    Ok(match len {
+        Some(len) => Box::new(ReaderStream::new(reader.take(len))),
+        None => Box::new(ReaderStream::new(reader)),
+    })
+}
+
+fn ftp_error(e: FtpError) -> io::Error {
+    match e {
+        FtpError::ConnectionError(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+impl Stream for FtpStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.data).poll_next(cx)
+    }
+}
+
+#[async_trait]
+impl SourceStream for FtpStream {
+    type Url = String;
+    type StreamError = io::Error;
+
+    async fn create(url: Self::Url) -> io::Result<Self> {
+        Self::new(FtpUrl::parse(&url)?).await
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    #[instrument(skip(self))]
+    async fn seek_range(&mut self, start: u64, end: Option<u64>) -> io::Result<()> {
+        // The current transfer can't be stopped without closing the connection
+        self.close();
+        if Some(start) == self.content_length {
+            debug!(
+                "attempting to seek where start is the length of the file, returning empty stream"
+            );
+            return Ok(());
+        }
+        let mut control = connect(&self.url).await?;
+        let mut len = end.map(|end| end.saturating_sub(start));
+        if start > 0 && self.supports_seek {
+            if let Err(e) = control.restart_from(start).await {
+                warn!(
+                    "server rejected REST command, falling back to downloading the full file: {e}"
+                );
+                self.supports_seek = false;
+                len = None;
+            }
+        }
+        self.data = retrieve(&mut control, &self.url.path, len).await?;
+        self.control = Some(control);
+        debug!("done seeking");
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.data = Box::new(futures::stream::empty());
+        self.control = None;
+    }
+
+    fn supports_seek(&self) -> bool {
+        self.supports_seek
+    }
+
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            url: Some(self.url.to_string()),
+            supports_seek: self.supports_seek,
+            content_length: self.content_length,
+            ..Default::default()
+        }
+    }
+}
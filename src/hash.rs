@@ -0,0 +1,94 @@
+//! Incremental hashing of the downloaded data.
+//!
+//! A [PrefixHash] covers the contiguous section at the start of the stream. Each call to
+//! [StreamDownload::update_hash](crate::StreamDownload::update_hash) feeds it any data that was
+//! downloaded since the last call, so intermediate digests can be checked against per-offset
+//! checksums while the download is still in progress.
+//!
+//! This module doesn't depend on any hashing library. Implement [StreamHasher] for the hash
+//! function required by the protocol.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::collections::hash_map::DefaultHasher;
+//! use std::error::Error;
+//! use std::hash::Hasher;
+//! use std::result::Result;
+//!
+//! use stream_download::hash::{PrefixHash, StreamHasher};
+//! use stream_download::storage::temp::TempStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! struct Sip(DefaultHasher);
+//!
+//! impl StreamHasher for Sip {
+//!     fn update(&mut self, data: &[u8]) {
+//!         self.0.write(data);
+//!     }
+//!
+//!     fn digest(&self) -> Vec<u8> {
+//!         self.0.finish().to_be_bytes().to_vec()
+//!     }
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let mut reader = StreamDownload::new_http(
+//!         "https://some-cool-url.com/some-file.mp3".parse()?,
+//!         TempStorageProvider::default(),
+//!         Settings::default(),
+//!     )
+//!     .await?;
+//!
+//!     let mut hash = PrefixHash::new(Sip(DefaultHasher::new()));
+//!     let hashed_len = reader.update_hash(&mut hash)?;
+//!     println!(
+//!         "digest of the first {hashed_len} bytes: {:?}",
+//!         hash.digest()
+//!     );
+//!     Ok(())
+//! }
+//! ```
+
+/// Hash function used by a [PrefixHash].
+pub trait StreamHasher: Send + Sync {
+    /// Adds `data` to the hash.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of all the data added so far. This must not prevent more data from
+    /// being added afterwards.
+    fn digest(&self) -> Vec<u8>;
+}
+
+/// Running hash of the contiguous section at the start of the stream.
+#[derive(Debug, Clone)]
+pub struct PrefixHash<H> {
+    hasher: H,
+    hashed_len: u64,
+}
+
+impl<H: StreamHasher> PrefixHash<H> {
+    /// Creates a new [PrefixHash] that hasn't covered any data yet.
+    pub fn new(hasher: H) -> Self {
+        Self {
+            hasher,
+            hashed_len: 0,
+        }
+    }
+
+    /// The number of bytes from the start of the stream that the hash covers.
+    pub fn hashed_len(&self) -> u64 {
+        self.hashed_len
+    }
+
+    /// Returns the digest of the first [hashed_len](PrefixHash::hashed_len) bytes of the stream.
+    pub fn digest(&self) -> Vec<u8> {
+        self.hasher.digest()
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.hashed_len += data.len() as u64;
+    }
+}
@@ -29,10 +29,13 @@
 
 use std::error::Error;
 use std::fmt::Display;
-use std::io;
+use std::future::{self, Future};
+use std::ops::Range;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{self, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use std::{io, mem};
 
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -42,11 +45,14 @@ use mediatype::MediaTypeBuf;
 pub use reqwest;
 use tracing::{debug, instrument, warn};
 
-use crate::source::SourceStream;
+use crate::source::{SourceInfo, SourceStream};
 
+mod multipart;
 #[cfg(feature = "reqwest")]
 mod reqwest_client;
 
+use multipart::ByteRangesParser;
+
 /// Wrapper trait for an HTTP client that exposes only functionality necessary for retrieving the
 /// stream content. If the `reqwest` feature is enabled, this trait is implemented for
 /// [reqwest::Client](https://docs.rs/reqwest/latest/reqwest/struct.Client.html).
@@ -63,11 +69,27 @@ pub trait Client: Send + Sync + Unpin + 'static {
     type Response: ClientResponse<Error = Self::Error, Headers = Self::Headers>;
 
     /// The error type returned by HTTP requests.
-    type Error: Error + Send + Sync;
+    type Error: Error + Send + Sync + 'static;
 
     /// Creates a new instance of the client.
     fn create() -> Self;
 
+    /// Creates a new instance of the client configured with the supplied [ClientOptions].
+    /// The default implementation ignores the options and calls [Client::create].
+    fn create_with_options(_options: ClientOptions) -> Self
+    where
+        Self: Sized,
+    {
+        Self::create()
+    }
+
+    /// Opens a connection to the server hosting the URL ahead of time so that a later request can
+    /// reuse it, which reduces the time until the first byte arrives.
+    /// The default implementation does nothing.
+    async fn prewarm(&self, _url: &Self::Url) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Sends an HTTP GET request to the URL.
     async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error>;
 
@@ -79,6 +101,278 @@ pub trait Client: Send + Sync + Unpin + 'static {
         start: u64,
         end: Option<u64>,
     ) -> Result<Self::Response, Self::Error>;
+
+    /// Sends an HTTP GET request to the URL with additional request headers.
+    /// This is used for range requests when a custom [RangeHeaderFn] is set with
+    /// [HttpStream::range_header].
+    /// The default implementation ignores the headers and sends a normal GET request, which will
+    /// cause the stream to fall back to downloading the full resource.
+    async fn get_with_headers(
+        &self,
+        url: &Self::Url,
+        _headers: &[(String, String)],
+    ) -> Result<Self::Response, Self::Error> {
+        self.get(url).await
+    }
+
+    /// Sends an HTTP GET request to the URL using the `If-None-Match` and `If-Modified-Since`
+    /// headers from the supplied [CacheValidators].
+    /// The default implementation ignores the validators and sends a normal GET request.
+    async fn get_conditional(
+        &self,
+        url: &Self::Url,
+        _validators: &CacheValidators,
+    ) -> Result<Self::Response, Self::Error> {
+        self.get(url).await
+    }
+}
+
+/// A [Client] that can send POST requests, which is required by [HttpStream::new_post].
+#[async_trait]
+pub trait PostClient: Client {
+    /// Sends an HTTP POST request with the supplied body to the URL with additional request
+    /// headers. This is used for all requests made by streams created with
+    /// [HttpStream::new_post], including range requests.
+    async fn post_with_headers(
+        &self,
+        url: &Self::Url,
+        body: Bytes,
+        headers: &[(String, String)],
+    ) -> Result<Self::Response, Self::Error>;
+}
+
+// Sends the requests for streams created with HttpStream::new_post. This is stored with the body
+// since the rest of HttpStream only requires C to implement Client.
+type PostFn<C> =
+    for<'a> fn(&'a C, &'a <C as Client>::Url, Bytes, &'a [(String, String)]) -> PostFuture<'a, C>;
+
+type PostFuture<'a, C> = Pin<
+    Box<dyn Future<Output = Result<<C as Client>::Response, <C as Client>::Error>> + Send + 'a>,
+>;
+
+fn post_with_headers<'a, C: PostClient>(
+    client: &'a C,
+    url: &'a C::Url,
+    body: Bytes,
+    headers: &'a [(String, String)],
+) -> PostFuture<'a, C> {
+    client.post_with_headers(url, body, headers)
+}
+
+/// The `User-Agent` header sent by clients created by this crate unless another one is
+/// configured.
+pub const DEFAULT_USER_AGENT: &str = concat!("stream-download-rs/", env!("CARGO_PKG_VERSION"));
+
+/// The unit used for range requests unless another one is configured with
+/// [HttpStream::range_unit].
+pub const DEFAULT_RANGE_UNIT: &str = "bytes";
+
+/// Function that builds the request headers used to request the range `start..=end` from the
+/// server. An `end` of `None` requests everything from `start` to the end of the resource.
+pub type RangeHeaderFn = Arc<dyn Fn(u64, Option<u64>) -> Vec<(String, String)> + Send + Sync>;
+
+/// Options used to configure a [Client] created with [Client::create_with_options].
+/// Any option that isn't set keeps the client's default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    user_agent: Option<String>,
+    accept_encoding: Option<String>,
+    socket_options: SocketOptions,
+    buffer_options: BufferOptions,
+}
+
+impl ClientOptions {
+    /// Sets the `User-Agent` header sent with the initial request and every range request.
+    /// [DEFAULT_USER_AGENT] is used if this isn't set.
+    pub fn user_agent(self, user_agent: impl Into<String>) -> Self {
+        Self {
+            user_agent: Some(user_agent.into()),
+            ..self
+        }
+    }
+
+    /// Sets the `Accept-Encoding` header sent with every request. Clients created by this crate
+    /// send `identity` if this isn't set so the content length and seek positions stay exact.
+    /// Compressed responses aren't decoded by the reqwest client, so this is meant to be used
+    /// with [Decompression::Manual](crate::Decompression::Manual).
+    #[cfg(feature = "compression")]
+    pub fn accept_encoding(self, accept_encoding: impl Into<String>) -> Self {
+        Self {
+            accept_encoding: Some(accept_encoding.into()),
+            ..self
+        }
+    }
+
+    /// Sets the [SocketOptions] applied to the client's connections.
+    pub fn socket_options(self, socket_options: SocketOptions) -> Self {
+        Self {
+            socket_options,
+            ..self
+        }
+    }
+
+    /// Sets the [BufferOptions] applied to the client's connections.
+    pub fn buffer_options(self, buffer_options: BufferOptions) -> Self {
+        Self {
+            buffer_options,
+            ..self
+        }
+    }
+
+    /// Retrieves the configured user agent.
+    pub fn get_user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    /// Retrieves the configured `Accept-Encoding` header.
+    pub fn get_accept_encoding(&self) -> Option<&str> {
+        self.accept_encoding.as_deref()
+    }
+
+    /// Retrieves the configured [SocketOptions].
+    pub fn get_socket_options(&self) -> SocketOptions {
+        self.socket_options
+    }
+
+    /// Retrieves the configured [BufferOptions].
+    pub fn get_buffer_options(&self) -> BufferOptions {
+        self.buffer_options
+    }
+}
+
+/// Socket-level options applied to the connections made by a [Client].
+/// Any option that isn't set keeps the client's default.
+///
+/// These are best-effort hints. They're ignored by clients that don't support them, as well as on
+/// platforms that don't use TCP sockets such as WASM. The keepalive interval sets the idle time
+/// before the first probe is sent. The interval between subsequent probes and the number of
+/// retries are determined by the operating system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Enables or disables `TCP_NODELAY`. Disabling Nagle's algorithm reduces the latency of small
+    /// requests such as the range requests sent when seeking.
+    pub fn tcp_nodelay(self, enabled: bool) -> Self {
+        Self {
+            tcp_nodelay: Some(enabled),
+            ..self
+        }
+    }
+
+    /// Enables `SO_KEEPALIVE` with the supplied idle time.
+    pub fn tcp_keepalive(self, interval: Duration) -> Self {
+        Self {
+            tcp_keepalive: Some(interval),
+            ..self
+        }
+    }
+
+    /// Retrieves the configured `TCP_NODELAY` setting.
+    pub fn get_tcp_nodelay(&self) -> Option<bool> {
+        self.tcp_nodelay
+    }
+
+    /// Retrieves the configured keepalive interval.
+    pub fn get_tcp_keepalive(&self) -> Option<Duration> {
+        self.tcp_keepalive
+    }
+}
+
+/// Options that limit how much response data a [Client] buffers before it's read.
+/// Any option that isn't set keeps the client's default.
+///
+/// Smaller buffers mean less data from a previous request competes with the response to a seek,
+/// and data is handed to the stream in smaller pieces as soon as it arrives. This can reduce
+/// first-byte and seek latency at the cost of throughput.
+///
+/// These are best-effort hints. They're ignored by clients that don't support them. The reqwest
+/// client only applies them to HTTP/2 connections since it doesn't expose its HTTP/1 read buffer
+/// size, but HTTP/1 responses are already delivered as soon as each read completes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferOptions {
+    http2_stream_window_size: Option<u32>,
+    http2_connection_window_size: Option<u32>,
+    http2_max_frame_size: Option<u32>,
+}
+
+impl BufferOptions {
+    /// Sets the HTTP/2 flow control window for each response, which limits how much data the
+    /// server can send before the stream reads it.
+    pub fn http2_stream_window_size(self, size: u32) -> Self {
+        Self {
+            http2_stream_window_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Sets the HTTP/2 flow control window shared by all responses on a connection.
+    pub fn http2_connection_window_size(self, size: u32) -> Self {
+        Self {
+            http2_connection_window_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Sets the maximum HTTP/2 frame size. Smaller frames are delivered to the stream sooner.
+    /// HTTP/2 doesn't allow frames smaller than 16 KiB.
+    pub fn http2_max_frame_size(self, size: u32) -> Self {
+        Self {
+            http2_max_frame_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Retrieves the configured HTTP/2 stream window size.
+    pub fn get_http2_stream_window_size(&self) -> Option<u32> {
+        self.http2_stream_window_size
+    }
+
+    /// Retrieves the configured HTTP/2 connection window size.
+    pub fn get_http2_connection_window_size(&self) -> Option<u32> {
+        self.http2_connection_window_size
+    }
+
+    /// Retrieves the configured maximum HTTP/2 frame size.
+    pub fn get_http2_max_frame_size(&self) -> Option<u32> {
+        self.http2_max_frame_size
+    }
+}
+
+/// Caching validators used to check if a previously downloaded resource has changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheValidators {
+    /// The `ETag` response header.
+    pub etag: Option<String>,
+    /// The `Last-Modified` response header.
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    /// Retrieves the validators from a set of response headers.
+    pub fn from_headers<H: ResponseHeaders>(headers: &H) -> Self {
+        Self {
+            etag: headers.header("ETag").map(ToOwned::to_owned),
+            last_modified: headers.header("Last-Modified").map(ToOwned::to_owned),
+        }
+    }
+
+    /// Returns `true` if there are no validators available.
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// Result of a conditional request created with [HttpStream::new_conditional].
+#[derive(Debug)]
+pub enum Revalidation<S> {
+    /// The server responded with `304 Not Modified` so any cached content can be used as-is.
+    NotModified,
+    /// The resource has changed and needs to be downloaded again.
+    Modified(S),
 }
 
 /// Represents the content type HTTP response header
@@ -97,6 +391,9 @@ pub trait ResponseHeaders: Send + Sync + Unpin {
     fn header(&self, name: &str) -> Option<&str>;
 }
 
+/// Future returned by [ClientResponse::trailers] that resolves to the HTTP trailers.
+pub type TrailersFuture<H> = Pin<Box<dyn Future<Output = Option<H>> + Send + Sync>>;
+
 /// A wrapper trait for an HTTP response that exposes only functionality necessary for retrieving
 /// the stream content. If the `reqwest` feature is enabled,
 /// this trait is implemented for
@@ -106,7 +403,7 @@ pub trait ClientResponse: Send + Sync {
     /// Error type returned by the underlying response stream.
     type Error;
     /// Object containing HTTP response headers.
-    type Headers: ResponseHeaders;
+    type Headers: ResponseHeaders + 'static;
 
     /// The size of the remote resource in bytes.
     /// The result should be `None` if the stream is infinite or doesn't have a known length.
@@ -122,11 +419,29 @@ pub trait ClientResponse: Send + Sync {
     /// Checks if the response status is successful.
     fn is_success(&self) -> bool;
 
+    /// Checks if the response status is `304 Not Modified`.
+    /// The default implementation always returns `false`.
+    fn is_not_modified(&self) -> bool {
+        false
+    }
+
     /// Turns the response into an error if the response was not successful.
     fn status_error(self) -> Result<(), Self::Error>;
 
     /// Converts the response into a byte stream
     fn stream(self) -> Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Unpin + Send + Sync>;
+
+    /// Returns a future that resolves to the HTTP trailers sent after the response body, if any.
+    /// The future is only polled once the stream returned from
+    /// [stream](ClientResponse::stream) has finished, so implementations that support trailers
+    /// should share the trailer state between the stream and this future.
+    /// The default implementation always returns `None`. The `reqwest` client doesn't support
+    /// trailers since
+    /// [reqwest::Response](https://docs.rs/reqwest/latest/reqwest/struct.Response.html) doesn't
+    /// expose them.
+    fn trailers(&self) -> TrailersFuture<Self::Headers> {
+        Box::pin(future::ready(None))
+    }
 }
 
 /// An HTTP implementation of the [SourceStream] trait.
@@ -136,11 +451,28 @@ pub struct HttpStream<C: Client> {
     content_length: Option<u64>,
     content_type: Option<ContentType>,
     url: C::Url,
+    mirrors: Vec<C::Url>,
     headers: C::Headers,
+    supports_seek: bool,
+    pending_trailers: Option<TrailersFuture<C::Headers>>,
+    trailers: Option<C::Headers>,
+    received_length: u64,
+    expected_length: Option<u64>,
+    range_header: Option<RangeHeaderFn>,
+    range_unit: String,
+    initial_position: u64,
+    post: Option<(Bytes, PostFn<C>)>,
+    max_ranges: usize,
+    multipart: Option<ByteRangesParser>,
+    chunk_start: Option<u64>,
 }
 
 impl<C: Client> HttpStream<C> {
     /// Creates a new [HttpStream] from a [Client].
+    ///
+    /// If the response doesn't include the length of the resource but the server advertises
+    /// support for range requests, a request for the first byte is sent to read the length from
+    /// the `Content-Range` header instead.
     #[instrument(skip(client, url), fields(url = url.to_string()))]
     pub async fn new(client: C, url: <Self as SourceStream>::Url) -> io::Result<Self> {
         debug!("requesting stream content");
@@ -155,7 +487,189 @@ impl<C: Client> HttpStream<C> {
             "request finished"
         );
 
-        let content_length = if let Some(content_length) = response.content_length() {
+        Ok(Self::from_response(client, url, Vec::new(), response)
+            .probe_missing_length()
+            .await)
+    }
+
+    /// Creates a new [HttpStream] using a [Client] created with the supplied [ClientOptions].
+    /// See [Client::create_with_options].
+    pub async fn new_with_options(
+        url: <Self as SourceStream>::Url,
+        options: ClientOptions,
+    ) -> io::Result<Self> {
+        Self::new(C::create_with_options(options), url).await
+    }
+
+    /// Opens a connection for the URL using a [Client] created with [Client::create] so that a
+    /// stream created for the same server shortly afterwards receives its first byte sooner.
+    /// See [Client::prewarm].
+    ///
+    /// The connection can only be reused if clients share their connection pool, which is the case
+    /// for the `reqwest` client.
+    pub async fn prewarm(url: &<Self as SourceStream>::Url) -> io::Result<()> {
+        C::create()
+            .prewarm(url)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Creates a new [HttpStream] from a [Client] using a POST request with the supplied body.
+    ///
+    /// This is useful for APIs that don't serve content through GET requests. Range requests are
+    /// sent as POST requests with the same body and an added `Range` header, so seeking only
+    /// works if the server supports ranges for this kind of request. Otherwise, the stream falls
+    /// back to downloading the full resource.
+    #[instrument(skip(client, url, body), fields(url = url.to_string()))]
+    pub async fn new_post(
+        client: C,
+        url: <Self as SourceStream>::Url,
+        body: impl Into<Bytes>,
+    ) -> io::Result<Self>
+    where
+        C: PostClient,
+    {
+        debug!("requesting stream content using a POST request");
+        let request_start = Instant::now();
+
+        let body = body.into();
+        let response =
+            check_response::<C>(client.post_with_headers(&url, body.clone(), &[]).await)?;
+        debug!(
+            duration = format!("{:?}", request_start.elapsed()),
+            "request finished"
+        );
+
+        Ok(Self {
+            post: Some((body, post_with_headers::<C>)),
+            ..Self::from_response(client, url, Vec::new(), response)
+        })
+    }
+
+    /// Creates a new [HttpStream] from a [Client] using a conditional request.
+    ///
+    /// If the server indicates that the resource hasn't changed since the supplied
+    /// [CacheValidators] were retrieved, [Revalidation::NotModified] is returned and no content is
+    /// downloaded. Use [HttpStream::cache_validators] to get the validators for a stream.
+    #[instrument(skip(client, url, validators), fields(url = url.to_string()))]
+    pub async fn new_conditional(
+        client: C,
+        url: <Self as SourceStream>::Url,
+        validators: &CacheValidators,
+    ) -> io::Result<Revalidation<Self>> {
+        debug!(
+            validators = format!("{validators:?}"),
+            "requesting stream content with validators"
+        );
+        let request_start = Instant::now();
+
+        let response = client
+            .get_conditional(&url, validators)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        debug!(
+            duration = format!("{:?}", request_start.elapsed()),
+            "request finished"
+        );
+
+        if response.is_not_modified() {
+            debug!("resource not modified");
+            return Ok(Revalidation::NotModified);
+        }
+        Ok(Revalidation::Modified(
+            Self::from_response(client, url, Vec::new(), response)
+                .probe_missing_length()
+                .await,
+        ))
+    }
+
+    /// Retrieves the [SourceInfo] of the resource without downloading its content.
+    ///
+    /// This sends a range request for the first byte only, so the response also shows whether
+    /// the server supports range requests. If the server ignores the range and starts sending the
+    /// whole resource, the response is dropped after reading the headers.
+    #[instrument(skip(client, url), fields(url = url.to_string()))]
+    pub async fn probe(client: C, url: <Self as SourceStream>::Url) -> io::Result<SourceInfo> {
+        debug!("probing stream metadata");
+        let response = check_response::<C>(client.get_range(&url, 0, Some(0)).await)?;
+        let supports_range = response
+            .headers()
+            .header("Content-Range")
+            .and_then(|content_range| content_range_start(content_range, DEFAULT_RANGE_UNIT))
+            .is_some();
+        let stream = Self::from_response(client, url, Vec::new(), response);
+        let info = SourceInfo {
+            supports_seek: stream.supports_seek && supports_range,
+            ..stream.info()
+        };
+        debug!(
+            content_length = info.content_length,
+            supports_seek = info.supports_seek,
+            "received stream metadata"
+        );
+        Ok(info)
+    }
+
+    /// Creates a new [HttpStream] from a [Client] using a list of mirrors that serve the same
+    /// resource.
+    ///
+    /// Each URL is tried in order until one of them returns a successful response. If a range
+    /// request to the active URL fails later on, the other mirrors will be tried as well. Mirrors
+    /// that report a different content length or `ETag` than the original response are skipped.
+    /// Whichever URL succeeds is used for all subsequent requests.
+    #[instrument(skip(client, urls))]
+    pub async fn new_with_mirrors(
+        client: C,
+        urls: impl IntoIterator<Item = <Self as SourceStream>::Url>,
+    ) -> io::Result<Self> {
+        let mut urls = urls.into_iter();
+        let mut failed_urls = Vec::new();
+        let mut last_error = None;
+
+        while let Some(url) = urls.next() {
+            debug!(url = url.to_string(), "requesting stream content");
+            let request_start = Instant::now();
+
+            match check_response::<C>(client.get(&url).await) {
+                Ok(response) => {
+                    debug!(
+                        duration = format!("{:?}", request_start.elapsed()),
+                        "request finished"
+                    );
+                    // Keep the failed URLs around as a last resort in case the other mirrors
+                    // stop working too
+                    let mirrors = urls.chain(failed_urls).collect();
+                    return Ok(Self::from_response(client, url, mirrors, response)
+                        .probe_missing_length()
+                        .await);
+                }
+                Err(e) => {
+                    warn!(url = url.to_string(), "error requesting mirror: {e}");
+                    last_error = Some(e);
+                    failed_urls.push(url);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no URLs were provided")
+        }))
+    }
+
+    fn from_response(client: C, url: C::Url, mirrors: Vec<C::Url>, response: C::Response) -> Self {
+        let headers = response.headers();
+        // Partial responses only report the length of the returned range, so we need to check
+        // Content-Range for the size of the whole resource
+        let total_length = headers.header("Content-Range").and_then(|content_range| {
+            content_range_total_length(content_range, DEFAULT_RANGE_UNIT)
+        });
+        let encoded = is_encoded(&headers);
+        let content_length = if encoded {
+            // The length headers refer to the encoded body, so they won't match the number of
+            // bytes we receive if the client decodes it
+            warn!("response body is encoded, treating the stream as unknown length");
+            None
+        } else if let Some(content_length) = total_length.or_else(|| response.content_length()) {
             debug!(content_length, "received content length");
             Some(content_length)
         } else {
@@ -180,16 +694,227 @@ impl<C: Client> HttpStream<C> {
             None
         };
 
-        let headers = response.headers();
+        let initial_position = headers
+            .header("Content-Range")
+            .and_then(|content_range| content_range_start(content_range, DEFAULT_RANGE_UNIT))
+            .unwrap_or(0);
+        if initial_position > 0 {
+            debug!(initial_position, "response does not start at the beginning");
+        }
+        let response_length = response.content_length().filter(|_| !encoded);
+        let pending_trailers = response.trailers();
         let stream = response.stream();
-        Ok(Self {
+        Self {
             stream: Box::new(stream),
             client,
             content_length,
             content_type,
             headers,
             url,
-        })
+            mirrors,
+            // Range requests apply to the encoded representation, so they can't be used to seek
+            // within the decoded content
+            supports_seek: !encoded,
+            pending_trailers: Some(pending_trailers),
+            trailers: None,
+            received_length: 0,
+            expected_length: response_length,
+            range_header: None,
+            range_unit: DEFAULT_RANGE_UNIT.to_string(),
+            initial_position,
+            post: None,
+            max_ranges: 1,
+            multipart: None,
+            chunk_start: None,
+        }
+    }
+
+    async fn range_request(
+        &self,
+        url: &C::Url,
+        start: u64,
+        end: Option<u64>,
+    ) -> io::Result<C::Response> {
+        let request_start = Instant::now();
+        let response = match (&self.post, self.supports_seek, &self.range_header) {
+            (Some((body, post)), supports_seek, range_header) => {
+                let headers = match (supports_seek, range_header) {
+                    (true, Some(range_header)) => range_header(start, end),
+                    (true, None) => vec![("Range".to_string(), self.range_value(start, end))],
+                    (false, _) => Vec::new(),
+                };
+                debug!("sending HTTP POST request");
+                post(&self.client, url, body.clone(), &headers).await
+            }
+            (None, true, Some(range_header)) => {
+                debug!("sending HTTP range request with custom range headers");
+                self.client
+                    .get_with_headers(url, &range_header(start, end))
+                    .await
+            }
+            (None, true, None) if self.range_unit == DEFAULT_RANGE_UNIT => {
+                debug!("sending HTTP range request");
+                self.client.get_range(url, start, end).await
+            }
+            (None, true, None) => {
+                debug!(
+                    unit = self.range_unit,
+                    "sending HTTP range request with custom unit"
+                );
+                let headers = [("Range".to_string(), self.range_value(start, end))];
+                self.client.get_with_headers(url, &headers).await
+            }
+            (None, false, _) => {
+                debug!("range requests not supported, sending HTTP request for the full resource");
+                self.client.get(url).await
+            }
+        };
+        debug!(
+            duration = format!("{:?}", request_start.elapsed()),
+            "HTTP request finished"
+        );
+        check_response::<C>(response)
+    }
+
+    // Value of the Range header used to request `start..=end`
+    fn range_value(&self, start: u64, end: Option<u64>) -> String {
+        format!(
+            "{}={start}-{}",
+            self.range_unit,
+            end.map(|e| e.to_string()).unwrap_or_default()
+        )
+    }
+
+    async fn mirror_range_request(
+        &mut self,
+        start: u64,
+        end: Option<u64>,
+        error: io::Error,
+    ) -> io::Result<C::Response> {
+        let mut last_error = error;
+        for i in 0..self.mirrors.len() {
+            warn!(
+                url = self.mirrors[i].to_string(),
+                "range request failed, trying mirror: {last_error}"
+            );
+            match self.range_request(&self.mirrors[i], start, end).await {
+                Ok(response) if self.is_same_resource(&response) => {
+                    // This mirror becomes the primary URL for any future requests
+                    mem::swap(&mut self.url, &mut self.mirrors[i]);
+                    return Ok(response);
+                }
+                Ok(_) => {
+                    last_error = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "mirror content does not match the original resource",
+                    );
+                }
+                Err(e) => {
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn is_same_resource(&self, response: &C::Response) -> bool {
+        let headers = response.headers();
+        if let (Some(etag), Some(mirror_etag)) =
+            (self.headers.header("ETag"), headers.header("ETag"))
+        {
+            if etag != mirror_etag {
+                return false;
+            }
+        }
+        if let (Some(content_length), Some(total_length)) = (
+            self.content_length,
+            headers.header("Content-Range").and_then(|content_range| {
+                content_range_total_length(content_range, &self.range_unit)
+            }),
+        ) {
+            if content_length != total_length {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Overrides the headers used for range requests.
+    /// This is useful for servers that expect a non-standard range format instead of
+    /// `Range: bytes=<start>-<end>`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::error::Error;
+    /// use std::result::Result;
+    /// use std::sync::Arc;
+    ///
+    /// use stream_download::http::reqwest::Client;
+    /// use stream_download::http::HttpStream;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn Error>> {
+    ///     let stream = HttpStream::new(
+    ///         Client::new(),
+    ///         "https://some-cool-url.com/some-file.mp3".parse()?,
+    ///     )
+    ///     .await?
+    ///     .range_header(Arc::new(|start, end| {
+    ///         vec![(
+    ///             "x-range".to_string(),
+    ///             format!("{start}-{}", end.map(|e| e.to_string()).unwrap_or_default()),
+    ///         )]
+    ///     }));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn range_header(self, range_header: RangeHeaderFn) -> Self {
+        Self {
+            range_header: Some(range_header),
+            ..self
+        }
+    }
+
+    /// Sets the unit used in the `Range` header of range requests, which is also the unit
+    /// expected in the `Content-Range` header of the responses. This is only needed for APIs
+    /// that use a custom unit in place of `bytes`. Positions are still sent as offsets into the
+    /// stream. Range responses with a `Content-Range` in a different unit are rejected, since
+    /// their position can't be determined. The unit is ignored when using a custom
+    /// [range_header](Self::range_header).
+    /// The default value is [DEFAULT_RANGE_UNIT].
+    pub fn range_unit(self, range_unit: impl Into<String>) -> Self {
+        let range_unit = range_unit.into();
+        // The initial response may already be partial
+        let content_range = self.headers.header("Content-Range");
+        let initial_position = content_range
+            .and_then(|content_range| content_range_start(content_range, &range_unit))
+            .unwrap_or(0);
+        let content_length = if is_encoded(&self.headers) {
+            None
+        } else {
+            content_range
+                .and_then(|content_range| content_range_total_length(content_range, &range_unit))
+                .or(self.expected_length)
+        };
+        Self {
+            range_unit,
+            initial_position,
+            content_length,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of missing ranges that can be requested at once when filling in
+    /// parts of the stream that were skipped over.
+    /// Servers that support this respond with a `multipart/byteranges` body, which is split back
+    /// into its parts as it's received. If the server responds with anything else, the stream
+    /// falls back to requesting one range at a time.
+    /// Multiple ranges are never requested when using a custom [range_header](Self::range_header)
+    /// or [range_unit](Self::range_unit), or for streams created with [new_post](Self::new_post).
+    /// The default value is 1, which only requests one range at a time.
+    pub fn max_ranges_per_request(self, max_ranges: usize) -> Self {
+        Self { max_ranges, ..self }
     }
 
     /// The [ContentType] of the response stream.
@@ -207,13 +932,230 @@ impl<C: Client> HttpStream<C> {
     pub fn headers(&self) -> &C::Headers {
         &self.headers
     }
+
+    /// The [CacheValidators] from the response headers.
+    /// These can be stored alongside the downloaded content and passed to
+    /// [HttpStream::new_conditional] later to check if the content is still up to date.
+    pub fn cache_validators(&self) -> CacheValidators {
+        CacheValidators::from_headers(&self.headers)
+    }
+
+    /// Object containing the HTTP trailers sent after the most recent response body.
+    /// This is only available once the stream has finished and the server sent trailers, and only
+    /// for clients that support them, which doesn't include the `reqwest` client.
+    pub fn trailers(&self) -> Option<&C::Headers> {
+        self.trailers.as_ref()
+    }
+
+    // Some servers only report the length of the resource in response to range requests
+    async fn probe_missing_length(mut self) -> Self {
+        let supports_range = self.headers.header("Accept-Ranges").is_some_and(|units| {
+            units
+                .split(',')
+                .any(|unit| unit.trim().eq_ignore_ascii_case("bytes"))
+        });
+        if self.content_length.is_some() || !self.supports_seek || !supports_range {
+            return self;
+        }
+        debug!("content length missing, requesting it with a range request");
+        match check_response::<C>(self.client.get_range(&self.url, 0, Some(0)).await) {
+            Ok(response) => {
+                let headers = response.headers();
+                let content_range = headers.header("Content-Range");
+                if let Some(content_length) = content_range.and_then(|content_range| {
+                    content_range_total_length(content_range, DEFAULT_RANGE_UNIT)
+                }) {
+                    debug!(content_length, "received content length");
+                    self.content_length = Some(content_length);
+                } else {
+                    debug!("range response did not include the content length");
+                }
+            }
+            Err(e) => warn!("error requesting content length: {e}"),
+        }
+        self
+    }
+
+    // Checks whether the response contains a `Content-Range` in a different unit than the one that
+    // was requested
+    fn is_mismatched_range_response(&self, response: &C::Response) -> bool {
+        self.range_header.is_none()
+            && response
+                .headers()
+                .header("Content-Range")
+                .is_some_and(|content_range| {
+                    content_range_spec(content_range, &self.range_unit).is_none()
+                })
+    }
+
+    fn set_response(&mut self, response: C::Response) {
+        self.expected_length = response.content_length();
+        self.received_length = 0;
+        self.trailers = None;
+        self.pending_trailers = Some(response.trailers());
+        self.stream = Box::new(response.stream());
+    }
+
+    fn fall_back_to_single_range(&mut self, reason: &str) {
+        warn!("{reason}, falling back to single range requests");
+        self.max_ranges = 1;
+    }
+
+    fn poll_body(&mut self, cx: &mut task::Context<'_>) -> Poll<Option<Result<Bytes, C::Error>>> {
+        if self.pending_trailers.is_none() {
+            return Pin::new(&mut self.stream).poll_next(cx);
+        }
+        match Pin::new(&mut self.stream).poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) => {
+                self.received_length += bytes.len() as u64;
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            Poll::Ready(None) => {
+                // Trailers are sent after the body, so we can only check for them once the
+                // stream is finished
+                let Some(pending_trailers) = &mut self.pending_trailers else {
+                    return Poll::Ready(None);
+                };
+                let Poll::Ready(trailers) = pending_trailers.as_mut().poll(cx) else {
+                    return Poll::Pending;
+                };
+                self.pending_trailers = None;
+                self.trailers = trailers;
+                if let Some(mismatch) = self.trailer_mismatch() {
+                    warn!("{mismatch}");
+                }
+                Poll::Ready(None)
+            }
+            res => res,
+        }
+    }
+
+    // Describes how the content length from the trailers differs from the received data, if it
+    // does
+    fn trailer_mismatch(&self) -> Option<String> {
+        let trailer_length = self
+            .trailers
+            .as_ref()
+            .and_then(|trailers| trailers.header("Content-Length"))
+            .and_then(|length| length.parse::<u64>().ok())?;
+        if trailer_length != self.received_length {
+            return Some(format!(
+                "content length from trailers ({trailer_length}) does not match the received \
+                 length ({})",
+                self.received_length
+            ));
+        }
+        self.expected_length
+            .filter(|l| *l != trailer_length)
+            .map(|expected_length| {
+                format!(
+                    "content length from trailers ({trailer_length}) does not match the response \
+                     headers ({expected_length})"
+                )
+            })
+    }
+}
+
+fn check_response<C: Client>(response: Result<C::Response, C::Error>) -> io::Result<C::Response> {
+    let response = response.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if response.is_success() {
+        return Ok(response);
+    }
+    if let Err(e) = response.status_error() {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, e))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unknown error from HTTP request",
+        ))
+    }
+}
+
+// Checks if the body is compressed or otherwise transformed such that the length headers don't
+// reflect the length of the content
+fn is_encoded(headers: &impl ResponseHeaders) -> bool {
+    let content_encoding = headers
+        .header("Content-Encoding")
+        .is_some_and(|encoding| !encoding.trim().eq_ignore_ascii_case("identity"));
+    let transfer_encoding = headers.header("Transfer-Encoding").is_some_and(|encoding| {
+        encoding
+            .split(',')
+            .any(|coding| !coding.trim().eq_ignore_ascii_case("chunked"))
+    });
+    content_encoding || transfer_encoding
+}
+
+// Returns the part of a header in the form of `<unit> <start>-<end>/<total>` after the unit, or
+// `None` if the header uses a different unit
+fn content_range_spec<'a>(content_range: &'a str, unit: &str) -> Option<&'a str> {
+    let (range_unit, spec) = content_range.trim().split_once(char::is_whitespace)?;
+    range_unit.eq_ignore_ascii_case(unit).then_some(spec)
+}
+
+// Parses the start position from a header in the form of `<unit> <start>-<end>/<total>`
+fn content_range_start(content_range: &str, unit: &str) -> Option<u64> {
+    content_range_spec(content_range, unit)?
+        .split_once('-')?
+        .0
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Parses the total length from a header in the form of `<unit> <start>-<end>/<total>`
+fn content_range_total_length(content_range: &str, unit: &str) -> Option<u64> {
+    content_range_spec(content_range, unit)?
+        .rsplit_once('/')?
+        .1
+        .trim()
+        .parse()
+        .ok()
 }
 
 impl<C: Client> Stream for HttpStream<C> {
     type Item = Result<Bytes, C::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.stream).poll_next(cx)
+        let this = &mut *self;
+        loop {
+            let Some(multipart) = &mut this.multipart else {
+                return this.poll_body(cx);
+            };
+            match multipart.next_chunk() {
+                Ok(Some(chunk)) => {
+                    if chunk.start.is_some() {
+                        this.chunk_start = chunk.start;
+                    }
+                    return Poll::Ready(Some(Ok(chunk.data)));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // Any ranges that weren't received will be requested again
+                    this.fall_back_to_single_range(&format!(
+                        "error parsing multipart response: {e}"
+                    ));
+                    this.multipart = None;
+                    this.close();
+                    return Poll::Ready(None);
+                }
+            }
+            match this.poll_body(cx) {
+                Poll::Ready(Some(Ok(bytes))) => {
+                    if let Some(multipart) = &mut this.multipart {
+                        multipart.push(&bytes);
+                    }
+                }
+                Poll::Ready(None) => {
+                    if let Some(multipart) = this.multipart.take() {
+                        if !multipart.is_finished() {
+                            warn!("multipart response ended before the closing delimiter");
+                        }
+                    }
+                    return Poll::Ready(None);
+                }
+                res => return res,
+            }
+        }
     }
 }
 
@@ -238,31 +1180,140 @@ impl<C: Client> SourceStream for HttpStream<C> {
                  stream"
             );
             self.stream = Box::new(futures::stream::empty());
+            self.pending_trailers = None;
+            self.trailers = None;
+            self.multipart = None;
             return Ok(());
         }
-        debug!("sending HTTP range request");
-        let request_start = Instant::now();
+        let response = match self.range_request(&self.url, start, end).await {
+            Ok(response) => response,
+            Err(e) => self.mirror_range_request(start, end, e).await?,
+        };
+        if self.is_mismatched_range_response(&response) {
+            // The response is partial, but its position can't be interpreted
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected a Content-Range in {}, got {:?}",
+                    self.range_unit,
+                    response.headers().header("Content-Range")
+                ),
+            ));
+        }
+        if self.supports_seek && start > 0 && response.headers().header("Content-Range").is_none() {
+            // Servers that don't support range requests will respond with the full content
+            warn!("server ignored range request, falling back to downloading the full resource");
+            self.supports_seek = false;
+        }
+        self.multipart = None;
+        self.set_response(response);
+        debug!("done seeking");
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn seek_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<()> {
+        let Some(first) = ranges.first() else {
+            return Ok(());
+        };
+        let multiple_ranges = ranges.len() > 1
+            && self.max_ranges > 1
+            && self.supports_seek
+            && self.post.is_none()
+            && self.range_header.is_none()
+            && self.range_unit == DEFAULT_RANGE_UNIT;
+        if !multiple_ranges {
+            return self.seek_range(first.start, Some(first.end)).await;
+        }
+
+        let ranges = ranges[..ranges.len().min(self.max_ranges)]
+            .iter()
+            .map(|range| format!("{}-{}", range.start, range.end))
+            .collect::<Vec<_>>()
+            .join(",");
+        debug!(ranges, "sending HTTP multi-range request");
         let response = self
             .client
-            .get_range(&self.url, start, end)
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
-        debug!(
-            duration = format!("{:?}", request_start.elapsed()),
-            "HTTP request finished"
-        );
-        if !response.is_success() {
-            if let Err(e) = response.status_error() {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "unknown error from HTTP range request",
-                ));
+            .get_with_headers(
+                &self.url,
+                &[("Range".to_string(), format!("bytes={ranges}"))],
+            )
+            .await;
+        let response = match check_response::<C>(response) {
+            Ok(response) => response,
+            Err(e) => {
+                self.fall_back_to_single_range(&format!("multi-range request failed: {e}"));
+                return self.seek_range(first.start, Some(first.end)).await;
+            }
+        };
+
+        let boundary = response
+            .content_type()
+            .and_then(multipart::byteranges_boundary);
+        self.multipart = None;
+        self.chunk_start = None;
+        if let Some(boundary) = boundary {
+            debug!("received multipart response");
+            self.multipart = Some(ByteRangesParser::new(&boundary));
+        } else if let Some(start) = response
+            .headers()
+            .header("Content-Range")
+            .and_then(|content_range| content_range_start(content_range, &self.range_unit))
+        {
+            self.fall_back_to_single_range("server returned a single range");
+            self.chunk_start = Some(start);
+        } else {
+            self.fall_back_to_single_range("server ignored multi-range request");
+            if first.start > 0 {
+                warn!("falling back to downloading the full resource");
+                self.supports_seek = false;
             }
         }
-        self.stream = Box::new(response.stream());
-        debug!("done seeking");
+        self.set_response(response);
         Ok(())
     }
+
+    fn close(&mut self) {
+        debug!("closing response");
+        self.stream = Box::new(futures::stream::empty());
+        self.pending_trailers = None;
+        self.multipart = None;
+    }
+
+    fn supports_seek(&self) -> bool {
+        self.supports_seek
+    }
+
+    fn validate_trailers(&self) -> io::Result<()> {
+        match self.trailer_mismatch() {
+            Some(mismatch) => Err(io::Error::new(io::ErrorKind::InvalidData, mismatch)),
+            None => Ok(()),
+        }
+    }
+
+    fn max_ranges_per_request(&self) -> usize {
+        self.max_ranges
+    }
+
+    fn take_chunk_start(&mut self) -> Option<u64> {
+        self.chunk_start.take()
+    }
+
+    fn initial_position(&self) -> u64 {
+        self.initial_position
+    }
+
+    fn content_encoding(&self) -> Option<&str> {
+        self.header("Content-Encoding")
+            .filter(|encoding| !encoding.trim().eq_ignore_ascii_case("identity"))
+    }
+
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            url: Some(self.url.to_string()),
+            content_type: self.header("Content-Type").map(ToOwned::to_owned),
+            supports_seek: self.supports_seek,
+            content_length: self.content_length,
+        }
+    }
 }
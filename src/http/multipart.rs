@@ -0,0 +1,148 @@
+// Parsing for `multipart/byteranges` responses, which servers send when a single request asks for
+// more than one range. Each part starts with a delimiter line and its own headers, and the
+// Content-Range header of each part determines how many bytes of data follow.
+
+use std::io;
+use std::ops::Range;
+
+use bytes::{Buf, Bytes, BytesMut};
+use mediatype::{names, MediaTypeBuf, ReadParams};
+
+// Returns the boundary if the content type is `multipart/byteranges`
+pub(crate) fn byteranges_boundary(content_type: &str) -> Option<String> {
+    let media_type = content_type.parse::<MediaTypeBuf>().ok()?;
+    if media_type.ty() != names::MULTIPART || media_type.subty() != names::BYTERANGES {
+        return None;
+    }
+    media_type
+        .get_param(names::BOUNDARY)
+        .map(|boundary| boundary.unquoted_str().into_owned())
+}
+
+// A chunk of data from one of the parts
+pub(crate) struct PartChunk {
+    // Only set for the first chunk of each part
+    pub(crate) start: Option<u64>,
+    pub(crate) data: Bytes,
+}
+
+enum State {
+    Delimiter,
+    Body { start: Option<u64>, remaining: u64 },
+    Finished,
+}
+
+pub(crate) struct ByteRangesParser {
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+    state: State,
+}
+
+impl ByteRangesParser {
+    pub(crate) fn new(boundary: &str) -> Self {
+        Self {
+            delimiter: format!("--{boundary}").into_bytes(),
+            buf: BytesMut::new(),
+            state: State::Delimiter,
+        }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        if !matches!(self.state, State::Finished) {
+            self.buf.extend_from_slice(bytes);
+        }
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        matches!(self.state, State::Finished)
+    }
+
+    // Returns the next chunk of part data, or None if more data needs to be pushed first
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<PartChunk>> {
+        loop {
+            match &mut self.state {
+                State::Finished => return Ok(None),
+                State::Delimiter => {
+                    if !self.parse_part_headers()? {
+                        return Ok(None);
+                    }
+                }
+                State::Body { start, remaining } => {
+                    if self.buf.is_empty() {
+                        return Ok(None);
+                    }
+                    let len = (*remaining).min(self.buf.len() as u64) as usize;
+                    let chunk = PartChunk {
+                        start: start.take(),
+                        data: self.buf.split_to(len).freeze(),
+                    };
+                    *remaining -= len as u64;
+                    if *remaining == 0 {
+                        self.state = State::Delimiter;
+                    }
+                    return Ok(Some(chunk));
+                }
+            }
+        }
+    }
+
+    // Returns false if the headers haven't been fully received yet
+    fn parse_part_headers(&mut self) -> io::Result<bool> {
+        let Some(delimiter_start) = find(&self.buf, &self.delimiter) else {
+            return Ok(false);
+        };
+        let headers_start = delimiter_start + self.delimiter.len();
+        // The closing delimiter is followed by two dashes
+        let Some(suffix) = self.buf.get(headers_start..headers_start + 2) else {
+            return Ok(false);
+        };
+        if suffix == b"--" {
+            self.buf.clear();
+            self.state = State::Finished;
+            return Ok(true);
+        }
+        let Some(headers_len) = find(&self.buf[headers_start..], b"\r\n\r\n") else {
+            return Ok(false);
+        };
+
+        let headers = std::str::from_utf8(&self.buf[headers_start..headers_start + headers_len])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let range = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Range"))
+            .and_then(|(_, value)| content_range(value))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "multipart response part is missing a valid Content-Range header",
+                )
+            })?;
+        self.buf.advance(headers_start + headers_len + 4);
+        self.state = if range.is_empty() {
+            State::Delimiter
+        } else {
+            State::Body {
+                start: Some(range.start),
+                remaining: range.end - range.start,
+            }
+        };
+        Ok(true)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Parses a header in the form of `bytes <start>-<end>/<total>` into an exclusive range
+fn content_range(content_range: &str) -> Option<Range<u64>> {
+    let range = content_range.trim().strip_prefix("bytes")?.trim();
+    let (range, _) = range.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let end = end.trim().parse::<u64>().ok()?.checked_add(1)?;
+    (start <= end).then_some(start..end)
+}
@@ -8,7 +8,10 @@ use reqwest::header::{self, AsHeaderName, HeaderMap};
 use tap::TapFallible;
 use tracing::warn;
 
-use crate::http::{Client, ClientResponse, ResponseHeaders};
+use crate::http::{
+    CacheValidators, Client, ClientOptions, ClientResponse, PostClient, ResponseHeaders,
+    DEFAULT_USER_AGENT,
+};
 
 impl ResponseHeaders for HeaderMap {
     fn header(&self, name: &str) -> Option<&str> {
@@ -48,6 +51,10 @@ impl ClientResponse for reqwest::Response {
         self.status().is_success()
     }
 
+    fn is_not_modified(&self) -> bool {
+        self.status() == reqwest::StatusCode::NOT_MODIFIED
+    }
+
     fn status_error(self) -> Result<(), Self::Error> {
         self.error_for_status().map(|_| ())
     }
@@ -55,6 +62,33 @@ impl ClientResponse for reqwest::Response {
     fn stream(self) -> Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Unpin + Send + Sync> {
         Box::new(self.bytes_stream())
     }
+
+    // reqwest doesn't expose trailers, so the default implementation of `trailers` is used
+}
+
+const IDENTITY: &str = "identity";
+
+// Creates a builder for a client that asks for uncompressed responses by default so the content
+// length and seek positions stay exact
+fn default_builder(user_agent: &str, accept_encoding: &str) -> reqwest::ClientBuilder {
+    let accept_encoding = header::HeaderValue::from_str(accept_encoding).unwrap_or_else(|e| {
+        warn!("invalid accept encoding value: {e:?}");
+        header::HeaderValue::from_static(IDENTITY)
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT_ENCODING, accept_encoding);
+    reqwest::Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers)
+}
+
+fn build_client(builder: reqwest::ClientBuilder) -> reqwest::Client {
+    builder.build().unwrap_or_else(|e| {
+        // This only fails if the TLS backend can't be initialized, in which case the default
+        // client would panic anyway
+        warn!("error creating client: {e:?}");
+        reqwest::Client::new()
+    })
 }
 
 // per reqwest's docs, it's advisable to create a single client and reuse it
@@ -68,7 +102,42 @@ impl Client for reqwest::Client {
     type Headers = HeaderMap;
 
     fn create() -> Self {
-        CLIENT.get_or_init(reqwest::Client::new).clone()
+        CLIENT
+            .get_or_init(|| build_client(default_builder(DEFAULT_USER_AGENT, IDENTITY)))
+            .clone()
+    }
+
+    fn create_with_options(options: ClientOptions) -> Self {
+        if options == ClientOptions::default() {
+            return Self::create();
+        }
+        let mut builder = default_builder(
+            options.get_user_agent().unwrap_or(DEFAULT_USER_AGENT),
+            options.get_accept_encoding().unwrap_or(IDENTITY),
+        );
+
+        let socket_options = options.get_socket_options();
+        if let Some(nodelay) = socket_options.get_tcp_nodelay() {
+            builder = builder.tcp_nodelay(nodelay);
+        }
+        if let Some(keepalive) = socket_options.get_tcp_keepalive() {
+            builder = builder.tcp_keepalive(keepalive);
+        }
+
+        let buffer_options = options.get_buffer_options();
+        build_client(
+            builder
+                .http2_initial_stream_window_size(buffer_options.get_http2_stream_window_size())
+                .http2_initial_connection_window_size(
+                    buffer_options.get_http2_connection_window_size(),
+                )
+                .http2_max_frame_size(buffer_options.get_http2_max_frame_size()),
+        )
+    }
+
+    async fn prewarm(&self, url: &Self::Url) -> Result<(), Self::Error> {
+        // The connection is returned to the pool once the response is dropped
+        self.head(url.clone()).send().await.map(|_| ())
     }
 
     async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error> {
@@ -92,4 +161,52 @@ impl Client for reqwest::Client {
             .send()
             .await
     }
+
+    async fn get_with_headers(
+        &self,
+        url: &Self::Url,
+        headers: &[(String, String)],
+    ) -> Result<Self::Response, Self::Error> {
+        headers
+            .iter()
+            .fold(self.get(url.clone()), |request, (name, value)| {
+                request.header(name, value)
+            })
+            .send()
+            .await
+    }
+
+    async fn get_conditional(
+        &self,
+        url: &Self::Url,
+        validators: &CacheValidators,
+    ) -> Result<Self::Response, Self::Error> {
+        let mut request = self.get(url.clone());
+        if let Some(etag) = &validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+        request.send().await
+    }
+}
+
+#[async_trait]
+impl PostClient for reqwest::Client {
+    async fn post_with_headers(
+        &self,
+        url: &Self::Url,
+        body: Bytes,
+        headers: &[(String, String)],
+    ) -> Result<Self::Response, Self::Error> {
+        headers
+            .iter()
+            .fold(
+                self.post(url.clone()).body(body),
+                |request, (name, value)| request.header(name, value),
+            )
+            .send()
+            .await
+    }
 }
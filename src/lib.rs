@@ -5,30 +5,124 @@
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 #![doc = include_str!("../README.md")]
 
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
 use std::future::{self, Future};
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, BufRead, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use source::{Source, SourceHandle, SourceStream};
-use storage::{StorageProvider, StorageReader};
+use availability::{AvailabilityMap, AvailabilityMapFactory};
+use backoff::{BackoffStrategy, SharedBackoff};
+use bytes::{Buf, Bytes};
+use clock::{Clock, SharedClock, TokioClock};
+use connection_limit::ConnectionLimit;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use rangemap::RangeSet;
+use source::{Source, SourceHandle, SourceInfo, SourceStream};
+use spawner::{DownloadTask, Spawner};
+use storage::budget::DiskBudget;
+use storage::memory::MemoryStorageProvider;
+use storage::{LostStorageWriter, StorageProvider, StorageReader, StorageWriter};
 use tap::{Tap, TapFallible};
+use tokio::sync::watch;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, instrument, trace};
+use tracing::{debug, error, instrument, trace, warn};
 
+pub mod availability;
+pub mod backoff;
+pub mod channel;
+pub mod clock;
+pub mod connection_limit;
+#[cfg(feature = "data-url")]
+pub mod data_url;
+#[cfg(feature = "compression")]
+mod decode;
+#[cfg(feature = "aes")]
+pub mod decrypt;
+#[cfg(feature = "ftp")]
+pub mod ftp;
+#[cfg(feature = "hash")]
+pub mod hash;
 #[cfg(feature = "http")]
 pub mod http;
+#[cfg(feature = "local-server")]
+pub mod local_server;
+#[cfg(feature = "test-util")]
+pub mod replay;
+pub mod shared;
 pub mod source;
+pub mod spawner;
 pub mod storage;
 
 /// Settings to configure the stream behavior.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Settings {
     prefetch_bytes: u64,
+    flush_interval: u64,
+    fsync_on_flush: bool,
+    content_length_override: Option<u64>,
+    max_read_ahead: Option<u64>,
+    fill_gaps: bool,
+    seek_debounce: Duration,
+    range_coalesce_threshold: u64,
+    max_range_requests: Option<usize>,
+    content_length_exceeded: ContentLengthExceeded,
+    disk_budget: Option<DiskBudget>,
+    connection_limit: Option<ConnectionLimit>,
+    availability_map: Option<AvailabilityMapFactory>,
+    on_content_length: Option<ContentLengthCallback>,
+    on_first_byte: Option<FirstByteCallback>,
+    clock: Option<SharedClock>,
+    serialize_requests: bool,
+    total_timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    backoff: Option<SharedBackoff>,
+    spawner: Spawner,
+    check_resume_length: bool,
+    adaptive_prefetch: Option<AdaptivePrefetch>,
+    prefetch_seek: PrefetchSeek,
+    chunk_transform: Option<ChunkTransform>,
+    content_length_resolver: Option<ContentLengthResolver>,
+    validate_trailers: bool,
+    decompression: Decompression,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
             prefetch_bytes: 256 * 1024,
+            flush_interval: 0,
+            fsync_on_flush: false,
+            content_length_override: None,
+            max_read_ahead: None,
+            fill_gaps: true,
+            seek_debounce: Duration::ZERO,
+            range_coalesce_threshold: 0,
+            max_range_requests: None,
+            content_length_exceeded: ContentLengthExceeded::default(),
+            disk_budget: None,
+            connection_limit: None,
+            availability_map: None,
+            on_content_length: None,
+            on_first_byte: None,
+            clock: None,
+            serialize_requests: false,
+            total_timeout: None,
+            stall_timeout: None,
+            backoff: None,
+            spawner: Spawner::default(),
+            check_resume_length: true,
+            adaptive_prefetch: None,
+            prefetch_seek: PrefetchSeek::default(),
+            chunk_transform: None,
+            content_length_resolver: None,
+            validate_trailers: false,
+            decompression: Decompression::default(),
         }
     }
 }
@@ -37,73 +131,2012 @@ impl Settings {
     /// How many bytes to download from the stream before allowing read requests.
     /// This is used to create a buffer between the read position and the stream position
     /// and prevent stuttering.
+    /// Prefetch finishes once the first chunk that reaches this amount is received, so a value of
+    /// 1 allows reads as soon as the first non-empty chunk arrives.
     /// The default value is 256 kilobytes.
     pub fn prefetch_bytes(self, prefetch_bytes: u64) -> Self {
-        Self { prefetch_bytes }
+        Self {
+            prefetch_bytes,
+            ..self
+        }
+    }
+
+    /// How many bytes to write to the storage layer before flushing it.
+    /// Downloaded data is only made available to the reader once it's been flushed, so the
+    /// storage never claims to contain bytes that haven't been written out yet. Regardless of
+    /// this setting, the writer will always be flushed once the reader is waiting on the
+    /// downloaded data.
+    /// The default value is 0, which flushes after every chunk.
+    pub fn flush_interval(self, flush_interval: u64) -> Self {
+        Self {
+            flush_interval,
+            ..self
+        }
+    }
+
+    /// Whether to sync downloaded data to stable storage before making it available to the reader.
+    /// This calls [StorageReader::sync_data] each time the writer is flushed, which for temporary
+    /// files waits until the data has been written to disk. Data is never marked as downloaded
+    /// before it's been synced, so any data that's reported as downloaded survives a crash, such
+    /// as when resuming with [StreamDownload::from_stream_resumed]. This reduces throughput, so
+    /// consider increasing [flush_interval](Settings::flush_interval) as well.
+    /// The default value is `false`, which relies on the operating system to write the data out.
+    pub fn fsync_on_flush(self, fsync_on_flush: bool) -> Self {
+        Self {
+            fsync_on_flush,
+            ..self
+        }
+    }
+
+    /// Content length to use instead of the one reported by the stream.
+    /// This is useful when the server reports an incorrect content length or doesn't report it at
+    /// all, but the real size is known ahead of time. The override is used to calculate seek
+    /// positions relative to the end of the stream and to determine when the download is
+    /// complete.
+    /// The default value is `None`, which uses the content length reported by the stream.
+    pub fn content_length_override(self, content_length_override: Option<u64>) -> Self {
+        Self {
+            content_length_override,
+            ..self
+        }
+    }
+
+    /// The maximum number of bytes the download is allowed to get ahead of the reader.
+    /// Once the limit is reached, the download will pause until the reader catches up. This keeps
+    /// the amount of storage used by slow readers bounded. The stream isn't polled while the
+    /// download is paused, so data also stops accumulating in the HTTP client's buffers and the
+    /// connection applies backpressure to the server.
+    /// The limit does not apply during the prefetch phase or when the reader is waiting on data
+    /// that hasn't been downloaded yet.
+    /// The default value is `None`, which allows the download to proceed as fast as possible.
+    pub fn max_read_ahead(self, max_read_ahead: Option<u64>) -> Self {
+        Self {
+            max_read_ahead,
+            ..self
+        }
+    }
+
+    /// Whether to download any parts of the stream that were skipped over by seeking once the
+    /// stream reaches the end.
+    /// Disabling this allows sparse access to the stream, such as reading the metadata at the
+    /// start and end of a file without downloading everything in between. Combine this with
+    /// [max_read_ahead](Settings::max_read_ahead) to prevent the initial request from downloading
+    /// past the parts that are needed. Any missing data will still be downloaded once the reader
+    /// reaches it.
+    /// This only applies to streams with a known content length.
+    /// The default value is `true`.
+    pub fn fill_gaps(self, fill_gaps: bool) -> Self {
+        Self { fill_gaps, ..self }
+    }
+
+    /// How long to wait for further seek requests before restarting the download at a new
+    /// position. Only the last seek position received within this window is downloaded, which
+    /// avoids sending range requests that would be abandoned immediately when seeks arrive in
+    /// quick succession.
+    /// The default value is zero, which handles every seek immediately.
+    pub fn seek_debounce(self, seek_debounce: Duration) -> Self {
+        Self {
+            seek_debounce,
+            ..self
+        }
+    }
+
+    /// The maximum distance in bytes between two missing parts of the stream that will be
+    /// downloaded with a single request.
+    /// Any data in between is downloaded again instead of being skipped. Similarly, seeking
+    /// forward by less than this amount continues the current request instead of starting a new
+    /// one, while longer forward seeks and backward seeks to data that hasn't been downloaded
+    /// always start a new request. This trades some extra bandwidth for fewer round trips, which
+    /// helps when the reader jumps between many small sections of the stream over a high-latency
+    /// connection.
+    /// The default value is 0, which never combines requests.
+    pub fn range_coalesce_threshold(self, range_coalesce_threshold: u64) -> Self {
+        Self {
+            range_coalesce_threshold,
+            ..self
+        }
+    }
+
+    /// The maximum number of range requests that can be sent for a single stream.
+    /// This limits the cost of seek-heavy access patterns against servers that are rate-limited
+    /// or billed per request. Once the limit is reached, seeking to a position that hasn't been
+    /// downloaded returns a [TooManyRangeRequests] error and any parts of the stream that were
+    /// skipped over won't be downloaded.
+    /// The default value is `None`, which doesn't limit the number of requests.
+    pub fn max_range_requests(self, max_range_requests: Option<usize>) -> Self {
+        Self {
+            max_range_requests,
+            ..self
+        }
+    }
+
+    /// How to handle streams that send more data than their reported content length.
+    /// The default value is [ContentLengthExceeded::TreatAsUnknown].
+    pub fn content_length_exceeded(self, content_length_exceeded: ContentLengthExceeded) -> Self {
+        Self {
+            content_length_exceeded,
+            ..self
+        }
+    }
+
+    /// A [DiskBudget] shared with other downloads to limit the total amount of data they store.
+    /// Once the limit is reached, downloads that weren't read from recently are paused. See the
+    /// [budget](storage::budget) module for details.
+    /// The default value is `None`, which doesn't limit the storage used.
+    pub fn disk_budget(self, disk_budget: Option<DiskBudget>) -> Self {
+        Self {
+            disk_budget,
+            ..self
+        }
+    }
+
+    /// A [ConnectionLimit] shared with other downloads that limits the number of concurrent
+    /// connections to each host. See the [connection_limit] module for details.
+    /// The default value is `None`, which doesn't limit the number of connections.
+    pub fn connection_limit(self, connection_limit: Option<ConnectionLimit>) -> Self {
+        Self {
+            connection_limit,
+            ..self
+        }
+    }
+
+    /// An [AvailabilityMapFactory] that creates the structure used to track which parts of the
+    /// stream can be read. See the [availability] module for details.
+    /// The default value is `None`, which makes data available as soon as it's written.
+    pub fn availability_map(self, availability_map: Option<AvailabilityMapFactory>) -> Self {
+        Self {
+            availability_map,
+            ..self
+        }
+    }
+
+    /// A [ContentLengthCallback] that's called once the download task starts with the content
+    /// length of the stream, or `None` if it's unknown. If the content length wasn't known, the
+    /// callback is called again with the final size once the stream reaches the end. This allows
+    /// progress displays to be updated without polling
+    /// [content_length](MetricsHandle::content_length).
+    /// The default value is `None`.
+    pub fn on_content_length(self, on_content_length: Option<ContentLengthCallback>) -> Self {
+        Self {
+            on_content_length,
+            ..self
+        }
+    }
+
+    /// A [FirstByteCallback] that's called once the first chunk of data has been written to
+    /// storage and can be read. This can be used to measure the time to first byte or to signal
+    /// that playback can start, even if prefetching hasn't finished yet. The callback isn't called
+    /// if the stream ends or fails before any data is received.
+    /// The default value is `None`.
+    pub fn on_first_byte(self, on_first_byte: Option<FirstByteCallback>) -> Self {
+        Self {
+            on_first_byte,
+            ..self
+        }
+    }
+
+    /// The [Clock] used by the download task to measure time, such as when waiting for
+    /// [seek_debounce](Settings::seek_debounce) to elapse. This is mainly useful for tests, which
+    /// can use the `TestClock` provided by the `test-util` feature to control time manually.
+    /// The default value is [TokioClock].
+    pub fn clock<C: Clock + 'static>(self, clock: C) -> Self {
+        Self {
+            clock: Some(SharedClock(Arc::new(clock))),
+            ..self
+        }
+    }
+
+    /// Whether to close the current response before sending a range request when seeking.
+    /// Some HTTP/1.1 servers only handle one request per client at a time and stall if a new
+    /// request arrives while the previous response is still open. Servers that multiplex
+    /// requests over HTTP/2 don't need this and seek faster without it.
+    /// The default value is `false`, which keeps the previous response open until the new one is
+    /// ready.
+    pub fn serialize_requests(self, serialize_requests: bool) -> Self {
+        Self {
+            serialize_requests,
+            ..self
+        }
+    }
+
+    /// The maximum amount of time the download is allowed to take, regardless of whether it's
+    /// making progress.
+    /// Once the time is up, the download stops with a [DeadlineExceeded] error. Anything that was
+    /// downloaded before then can still be read, and reads past that point return the end of the
+    /// stream. This is useful in environments that limit the total execution time.
+    /// The default value is `None`, which doesn't limit the duration.
+    pub fn total_timeout(self, total_timeout: Option<Duration>) -> Self {
+        Self {
+            total_timeout,
+            ..self
+        }
+    }
+
+    /// How long the download can go without making progress while the reader is waiting before
+    /// the connection is considered stalled.
+    /// Some connections stop delivering data without closing or returning an error. Once this
+    /// happens, the current response is closed and the download is restarted from the current
+    /// position with a range request, the same as [StreamDownload::reconnect]. The number of
+    /// times this happened is available from [Stats::stall_recoveries].
+    /// The default value is `None`, which never restarts stalled downloads.
+    pub fn stall_timeout(self, stall_timeout: Option<Duration>) -> Self {
+        Self {
+            stall_timeout,
+            ..self
+        }
+    }
+
+    /// The [BackoffStrategy] used to retry after the stream returns an error. See the
+    /// [backoff] module for details.
+    /// By default, errors from the stream aren't retried.
+    pub fn backoff<B: BackoffStrategy + 'static>(self, backoff: B) -> Self {
+        Self {
+            backoff: Some(SharedBackoff(Arc::new(backoff))),
+            ..self
+        }
+    }
+
+    /// Where the download task runs. See the [spawner] module for details.
+    /// The default value is [Spawner::current], which uses [tokio::spawn].
+    pub fn spawner(self, spawner: Spawner) -> Self {
+        Self { spawner, ..self }
+    }
+
+    /// Whether to check [content_length_override](Settings::content_length_override) against the
+    /// length reported by the stream when resuming a download with
+    /// [StreamDownload::from_stream_resumed].
+    /// A stored length may be stale if the resource changed since it was recorded, and seeking
+    /// relative to the end of the stream or resuming at the wrong offset would then corrupt the
+    /// data. If the lengths don't match, an error with a kind of [io::ErrorKind::InvalidData] is
+    /// returned. Disable this to always trust the configured length, for example if the stream
+    /// doesn't report an accurate length.
+    /// This only compares the lengths, so it doesn't send any additional requests and doesn't
+    /// detect changes that keep the same length. To check whether the content itself changed, use
+    /// [HttpStream::new_conditional](crate::http::HttpStream::new_conditional) to create the
+    /// stream. The default value is `true`.
+    pub fn check_resume_length(self, check_resume_length: bool) -> Self {
+        Self {
+            check_resume_length,
+            ..self
+        }
+    }
+
+    /// Adjusts how much data is prefetched based on the download speed measured during prefetch.
+    /// With this enabled, [prefetch_bytes](Settings::prefetch_bytes) is the minimum amount of data
+    /// to prefetch, and prefetch continues until it covers the amount that can be downloaded
+    /// within the configured duration, so fast connections buffer more while slow ones allow reads
+    /// sooner. Prefetch is still disabled if [prefetch_bytes](Settings::prefetch_bytes) is set to
+    /// `0`. The measured speed is available from [Stats::prefetch_throughput].
+    /// The default value is [None].
+    pub fn adaptive_prefetch(self, adaptive_prefetch: Option<AdaptivePrefetch>) -> Self {
+        Self {
+            adaptive_prefetch,
+            ..self
+        }
+    }
+
+    /// How to handle seeks to data that hasn't been downloaded yet while prefetch is still in
+    /// progress.
+    /// The default value is [PrefetchSeek::ContinueWithinWindow].
+    pub fn prefetch_seek(self, prefetch_seek: PrefetchSeek) -> Self {
+        Self {
+            prefetch_seek,
+            ..self
+        }
+    }
+
+    /// A [ChunkTransform] that's applied to every chunk before it's written to storage, such as
+    /// to decrypt or validate the content. Errors returned from the transform stop the download.
+    /// If the transform changes the length of a chunk, positions in storage no longer match
+    /// positions in the source, so the content length is treated as unknown and seeking to data
+    /// that hasn't been downloaded is no longer possible.
+    /// The default value is `None`.
+    pub fn chunk_transform(self, chunk_transform: Option<ChunkTransform>) -> Self {
+        Self {
+            chunk_transform,
+            ..self
+        }
+    }
+
+    /// A [ContentLengthResolver] that's called with the [SourceInfo] of the stream to look up its
+    /// content length, such as from a separate metadata service when the streaming endpoint
+    /// doesn't report it. The resolved length is used the same way as
+    /// [content_length_override](Settings::content_length_override).
+    /// [content_length_override](Settings::content_length_override) takes precedence over the
+    /// resolver, which in turn takes precedence over the length reported by the stream. If the
+    /// resolver returns `None`, the length reported by the stream is used instead.
+    /// The default value is `None`.
+    pub fn content_length_resolver(
+        self,
+        content_length_resolver: Option<ContentLengthResolver>,
+    ) -> Self {
+        Self {
+            content_length_resolver,
+            ..self
+        }
+    }
+
+    /// Whether to fail the download if the metadata a stream receives after the end of a
+    /// response, such as the `Content-Length` in HTTP trailers, doesn't match the data that was
+    /// received. See [SourceStream::validate_trailers]. Mismatches are only logged when this is
+    /// disabled. The default value is `false`.
+    pub fn validate_trailers(self, validate_trailers: bool) -> Self {
+        Self {
+            validate_trailers,
+            ..self
+        }
+    }
+
+    /// How to handle streams with compressed content. See [Decompression] and
+    /// [SourceStream::content_encoding]. A content length set with
+    /// [content_length_override](Self::content_length_override) or
+    /// [content_length_resolver](Self::content_length_resolver) still applies to decoded content.
+    /// The default value is [Decompression::Raw].
+    pub fn decompression(self, decompression: Decompression) -> Self {
+        Self {
+            decompression,
+            ..self
+        }
+    }
+
+    /// Retrieves the configured prefetch bytes
+    pub fn get_prefetch_bytes(&self) -> u64 {
+        self.prefetch_bytes
+    }
+
+    /// Retrieves the configured flush interval
+    pub fn get_flush_interval(&self) -> u64 {
+        self.flush_interval
+    }
+
+    /// Retrieves whether data is synced to stable storage when flushing
+    pub fn get_fsync_on_flush(&self) -> bool {
+        self.fsync_on_flush
+    }
+
+    /// Retrieves the configured content length override
+    pub fn get_content_length_override(&self) -> Option<u64> {
+        self.content_length_override
+    }
+
+    /// Retrieves the configured maximum read-ahead
+    pub fn get_max_read_ahead(&self) -> Option<u64> {
+        self.max_read_ahead
+    }
+
+    /// Retrieves whether gap filling is enabled
+    pub fn get_fill_gaps(&self) -> bool {
+        self.fill_gaps
+    }
+
+    /// Retrieves the configured seek debounce duration
+    pub fn get_seek_debounce(&self) -> Duration {
+        self.seek_debounce
+    }
+
+    /// Retrieves the configured range coalesce threshold
+    pub fn get_range_coalesce_threshold(&self) -> u64 {
+        self.range_coalesce_threshold
+    }
+
+    /// Retrieves the configured maximum number of range requests
+    pub fn get_max_range_requests(&self) -> Option<usize> {
+        self.max_range_requests
+    }
+
+    /// Retrieves how streams that exceed their content length are handled
+    pub fn get_content_length_exceeded(&self) -> ContentLengthExceeded {
+        self.content_length_exceeded
+    }
+
+    /// Retrieves the configured disk budget
+    pub fn get_disk_budget(&self) -> Option<DiskBudget> {
+        self.disk_budget.clone()
+    }
+
+    /// Retrieves the configured connection limit
+    pub fn get_connection_limit(&self) -> Option<ConnectionLimit> {
+        self.connection_limit.clone()
+    }
+
+    /// Retrieves the configured availability map factory
+    pub fn get_availability_map(&self) -> Option<AvailabilityMapFactory> {
+        self.availability_map.clone()
+    }
+
+    /// Retrieves the configured content length callback
+    pub fn get_on_content_length(&self) -> Option<ContentLengthCallback> {
+        self.on_content_length.clone()
+    }
+
+    /// Retrieves the configured first byte callback
+    pub fn get_on_first_byte(&self) -> Option<FirstByteCallback> {
+        self.on_first_byte.clone()
+    }
+
+    /// Retrieves whether the current response is closed before seeking
+    pub fn get_serialize_requests(&self) -> bool {
+        self.serialize_requests
+    }
+
+    /// Retrieves the configured total timeout
+    pub fn get_total_timeout(&self) -> Option<Duration> {
+        self.total_timeout
+    }
+
+    /// Retrieves the configured stall timeout
+    pub fn get_stall_timeout(&self) -> Option<Duration> {
+        self.stall_timeout
+    }
+
+    /// Retrieves the configured backoff strategy
+    pub fn get_backoff(&self) -> Option<Arc<dyn BackoffStrategy>> {
+        self.backoff.as_ref().map(|backoff| backoff.0.clone())
+    }
+
+    /// Retrieves the configured adaptive prefetch settings
+    pub fn get_adaptive_prefetch(&self) -> Option<AdaptivePrefetch> {
+        self.adaptive_prefetch
+    }
+
+    /// Retrieves how seeks during prefetch are handled
+    pub fn get_prefetch_seek(&self) -> PrefetchSeek {
+        self.prefetch_seek
+    }
+
+    /// Retrieves the configured chunk transform
+    pub fn get_chunk_transform(&self) -> Option<ChunkTransform> {
+        self.chunk_transform.clone()
+    }
+
+    /// Retrieves the configured content length resolver
+    pub fn get_content_length_resolver(&self) -> Option<ContentLengthResolver> {
+        self.content_length_resolver.clone()
+    }
+
+    /// Retrieves whether trailers are validated at the end of each response
+    pub fn get_validate_trailers(&self) -> bool {
+        self.validate_trailers
+    }
+
+    /// Retrieves how compressed content is handled
+    pub fn get_decompression(&self) -> Decompression {
+        self.decompression
+    }
+
+    /// Retrieves whether the content length override is checked when resuming
+    pub fn get_check_resume_length(&self) -> bool {
+        self.check_resume_length
+    }
+
+    /// Retrieves the configured spawner
+    pub fn get_spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    /// Retrieves the configured clock
+    pub fn get_clock(&self) -> Arc<dyn Clock> {
+        match &self.clock {
+            Some(clock) => clock.0.clone(),
+            None => Arc::new(TokioClock),
+        }
+    }
+
+    pub(crate) fn create_availability_map(
+        &self,
+        content_length: Option<u64>,
+    ) -> Box<dyn AvailabilityMap> {
+        match &self.availability_map {
+            Some(factory) => factory.create(content_length),
+            None => Box::<RangeSet<u64>>::default(),
+        }
+    }
+
+    pub(crate) fn range_request_limit_reached(&self, range_requests: usize) -> bool {
+        self.max_range_requests
+            .is_some_and(|max_range_requests| range_requests >= max_range_requests)
+    }
+}
+
+/// Settings for growing the prefetch target on fast connections.
+/// See [Settings::adaptive_prefetch].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptivePrefetch {
+    max_bytes: u64,
+    buffer_duration: Duration,
+}
+
+impl AdaptivePrefetch {
+    /// Creates a new [AdaptivePrefetch] that prefetches as much data as can be downloaded within
+    /// `buffer_duration` at the measured speed, up to `max_bytes`.
+    pub fn new(max_bytes: u64, buffer_duration: Duration) -> Self {
+        Self {
+            max_bytes,
+            buffer_duration,
+        }
+    }
+
+    /// The maximum number of bytes to prefetch.
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// How much playback time the prefetched data should cover at the measured speed.
+    pub fn buffer_duration(&self) -> Duration {
+        self.buffer_duration
+    }
+
+    pub(crate) fn target(&self, throughput: Option<u64>, min_bytes: u64) -> u64 {
+        // The first chunk may arrive before any time has passed, in which case the connection is
+        // as fast as it gets
+        let target = throughput.map_or(u64::MAX, |throughput| {
+            (throughput as f64 * self.buffer_duration.as_secs_f64()) as u64
+        });
+        target.min(self.max_bytes).max(min_bytes)
+    }
+}
+
+/// Determines what happens when the reader seeks to data that hasn't been downloaded yet while
+/// prefetch is still in progress. See [Settings::prefetch_seek].
+/// When prefetch restarts, the in-flight prefetch request is closed before the new one is sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrefetchSeek {
+    /// If the seek position is within the range that's currently being prefetched, keep
+    /// prefetching and serve the read once prefetch finishes, since the data will arrive without
+    /// another request. Seeks outside of that range, or past data that's already available,
+    /// restart prefetch at the new position.
+    #[default]
+    ContinueWithinWindow,
+    /// Always restart prefetch at the new position, even if the current prefetch would reach it.
+    Restart,
+}
+
+/// Determines how streams with compressed content, such as HTTP responses with a
+/// `Content-Encoding`, are handled. See [Settings::decompression].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Decompression {
+    /// Store the data as it's received. Clients created by this crate send
+    /// `Accept-Encoding: identity`, so servers normally don't compress the response and the
+    /// content length and seek positions stay exact. If the content is compressed anyway, it's
+    /// stored compressed and the stream is treated as unknown length.
+    #[default]
+    Raw,
+    /// Decode content that's compressed with `gzip` or `deflate` before it's stored. Other
+    /// encodings are stored as they are received.
+    ///
+    /// Positions in the decoded data don't match positions in the compressed stream, so the
+    /// content length is treated as unknown and seeking is limited to data that's already
+    /// downloaded. Errors in the compressed data, including a truncated `gzip` stream, stop the
+    /// download. The reqwest client only asks for compressed content if it's created with
+    /// `http::ClientOptions::accept_encoding`.
+    #[cfg(feature = "compression")]
+    Manual,
+}
+
+/// Determines what happens when a stream sends more data than its reported content length.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContentLengthExceeded {
+    /// Keep downloading and treat the stream as if the content length was never reported.
+    /// Seeking relative to the end of the stream won't be possible afterwards and any parts of
+    /// the stream that were skipped over won't be filled in.
+    #[default]
+    TreatAsUnknown,
+    /// Stop the download with an [io::ErrorKind::InvalidData] error.
+    /// Any data that was received before the content length was exceeded can still be read.
+    Error,
+}
+
+/// Function called when the content length of a stream becomes known.
+/// See [Settings::on_content_length].
+#[derive(Clone)]
+pub struct ContentLengthCallback(Arc<dyn Fn(Option<u64>) + Send + Sync>);
+
+impl ContentLengthCallback {
+    /// Creates a new [ContentLengthCallback] from a function.
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(Option<u64>) + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+
+    pub(crate) fn call(&self, content_length: Option<u64>) {
+        (self.0)(content_length);
+    }
+}
+
+impl fmt::Debug for ContentLengthCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContentLengthCallback")
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for ContentLengthCallback {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ContentLengthCallback {}
+
+type FirstByteFn = dyn FnOnce() + Send;
+
+/// Function called when the first downloaded data becomes readable.
+/// The function is only called once, even if the settings are used for multiple downloads.
+/// See [Settings::on_first_byte].
+#[derive(Clone)]
+pub struct FirstByteCallback(Arc<Mutex<Option<Box<FirstByteFn>>>>);
+
+impl FirstByteCallback {
+    /// Creates a new [FirstByteCallback] from a function.
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        Self(Arc::new(Mutex::new(Some(Box::new(callback)))))
+    }
+
+    pub(crate) fn call(&self) {
+        let callback = self.0.lock().take();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+}
+
+impl fmt::Debug for FirstByteCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FirstByteCallback").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for FirstByteCallback {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for FirstByteCallback {}
+
+/// Function applied to each chunk before it's written to storage.
+/// It receives the position in storage that the chunk will be written to along with the chunk.
+/// See [Settings::chunk_transform].
+#[derive(Clone)]
+pub struct ChunkTransform(Arc<dyn Fn(u64, Bytes) -> io::Result<Bytes> + Send + Sync>);
+
+impl ChunkTransform {
+    /// Creates a new [ChunkTransform] from a function.
+    pub fn new<F>(transform: F) -> Self
+    where
+        F: Fn(u64, Bytes) -> io::Result<Bytes> + Send + Sync + 'static,
+    {
+        Self(Arc::new(transform))
+    }
+
+    pub(crate) fn call(&self, position: u64, chunk: Bytes) -> io::Result<Bytes> {
+        (self.0)(position, chunk)
+    }
+}
+
+impl fmt::Debug for ChunkTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkTransform").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for ChunkTransform {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ChunkTransform {}
+
+/// Asynchronous function used to look up the content length of a stream.
+/// See [Settings::content_length_resolver].
+#[derive(Clone)]
+pub struct ContentLengthResolver(Arc<ResolveFn>);
+
+type ResolveFn = dyn Fn(&SourceInfo) -> BoxFuture<'static, Option<u64>> + Send + Sync;
+
+impl ContentLengthResolver {
+    /// Creates a new [ContentLengthResolver] from a function that returns a future.
+    pub fn new<F, Fut>(resolver: F) -> Self
+    where
+        F: Fn(&SourceInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<u64>> + Send + 'static,
+    {
+        Self(Arc::new(move |info| Box::pin(resolver(info))))
+    }
+
+    pub(crate) async fn call(&self, info: &SourceInfo) -> Option<u64> {
+        (self.0)(info).await
+    }
+}
+
+impl fmt::Debug for ContentLengthResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContentLengthResolver")
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for ContentLengthResolver {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ContentLengthResolver {}
+
+/// Error returned when seeking would exceed the limit set by
+/// [Settings::max_range_requests].
+/// This is wrapped in an [io::Error] with a kind of [io::ErrorKind::Other].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyRangeRequests;
+
+impl fmt::Display for TooManyRangeRequests {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the maximum number of range requests has been reached")
+    }
+}
+
+impl Error for TooManyRangeRequests {}
+
+impl From<TooManyRangeRequests> for io::Error {
+    fn from(error: TooManyRangeRequests) -> Self {
+        io::Error::new(io::ErrorKind::Other, error)
+    }
+}
+
+/// Error returned by [StreamDownload::into_memory] when the resource is larger than the maximum
+/// download size.
+/// This is wrapped in an [io::Error] with a kind of [io::ErrorKind::Other].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxDownloadSizeExceeded;
+
+impl fmt::Display for MaxDownloadSizeExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the resource is larger than the maximum download size")
+    }
+}
+
+impl Error for MaxDownloadSizeExceeded {}
+
+impl From<MaxDownloadSizeExceeded> for io::Error {
+    fn from(error: MaxDownloadSizeExceeded) -> Self {
+        io::Error::new(io::ErrorKind::Other, error)
+    }
+}
+
+/// Error returned when the download takes longer than [Settings::total_timeout].
+/// This is wrapped in an [io::Error] with a kind of [io::ErrorKind::TimedOut].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the download did not finish before the total timeout")
+    }
+}
+
+impl Error for DeadlineExceeded {}
+
+impl From<DeadlineExceeded> for io::Error {
+    fn from(error: DeadlineExceeded) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, error)
+    }
+}
+
+/// Error returned when the storage backing the stream is no longer available, such as when a
+/// temporary file is removed while the download is still running. Any buffered data is lost, so
+/// the stream needs to be recreated to continue.
+/// This is wrapped in an [io::Error] with a kind of [io::ErrorKind::NotFound].
+/// [source](Error::source) returns the error reported by the storage layer.
+#[derive(Debug)]
+pub struct StorageLost(io::Error);
+
+impl StorageLost {
+    /// The error reported by the storage layer.
+    pub fn inner(&self) -> &io::Error {
+        &self.0
+    }
+}
+
+impl fmt::Display for StorageLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the storage for the stream is no longer available")
+    }
+}
+
+impl Error for StorageLost {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+impl From<StorageLost> for io::Error {
+    fn from(error: StorageLost) -> Self {
+        io::Error::new(io::ErrorKind::NotFound, error)
+    }
+}
+
+// Storage that's already been created can only report a missing file if it was removed while it
+// was still in use
+pub(crate) fn storage_error(error: io::Error) -> io::Error {
+    if error.kind() == io::ErrorKind::NotFound
+        && !error.get_ref().is_some_and(|e| e.is::<StorageLost>())
+    {
+        StorageLost(error).into()
+    } else {
+        error
+    }
+}
+
+/// Error that stopped the download task.
+/// Reads that can't continue because of the error and [StreamDownload::join] return this wrapped
+/// in an [io::Error] with the same kind as the original error. [source](Error::source) returns
+/// the underlying cause, such as the error from the HTTP client, so it can be downcast.
+#[derive(Debug, Clone)]
+pub struct DownloadError(Arc<io::Error>);
+
+impl DownloadError {
+    pub(crate) fn new(error: io::Error) -> Self {
+        Self(Arc::new(error))
+    }
+
+    /// The error returned by the download task.
+    pub fn inner(&self) -> &io::Error {
+        &self.0
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for DownloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        // io::Error reports the source of the error it wraps rather than the wrapped error itself,
+        // so return the wrapped error directly to keep it in the chain
+        self.0
+            .get_ref()
+            .map(|error| error as &(dyn Error + 'static))
+    }
+}
+
+impl From<DownloadError> for io::Error {
+    fn from(error: DownloadError) -> Self {
+        io::Error::new(error.0.kind(), error)
+    }
+}
+
+/// Iterator over the downloaded chunks of a [StreamDownload].
+/// See [StreamDownload::chunks].
+pub struct Chunks<'a, P: StorageProvider> {
+    reader: &'a mut StreamDownload<P>,
+}
+
+impl<P: StorageProvider> Iterator for Chunks<'_, P> {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_chunk().transpose()
+    }
+}
+
+/// Statistics collected while reading from a [StreamDownload].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    stall_count: u64,
+    total_stall_duration: Duration,
+    stall_recoveries: u64,
+    prefetch_throughput: Option<u64>,
+    bytes_received: u64,
+    bytes_on_disk: u64,
+}
+
+impl Stats {
+    /// How many times a read or seek had to wait for the requested data to be downloaded.
+    pub fn stall_count(&self) -> u64 {
+        self.stall_count
+    }
+
+    /// The total amount of time spent waiting for the requested data to be downloaded.
+    pub fn total_stall_duration(&self) -> Duration {
+        self.total_stall_duration
+    }
+
+    /// How many times the download was restarted because it stopped making progress. See
+    /// [Settings::stall_timeout].
+    pub fn stall_recoveries(&self) -> u64 {
+        self.stall_recoveries
+    }
+
+    /// The download speed in bytes per second measured during prefetch. This is only measured
+    /// if [Settings::adaptive_prefetch] is enabled.
+    pub fn prefetch_throughput(&self) -> Option<u64> {
+        self.prefetch_throughput
+    }
+
+    /// The number of bytes received from the stream.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// The number of bytes written to storage. This can differ from
+    /// [bytes_received](Stats::bytes_received) if a [ChunkTransform] changes the length of the
+    /// data.
+    pub fn bytes_on_disk(&self) -> u64 {
+        self.bytes_on_disk
+    }
+}
+
+/// Snapshot of the internal state of a [StreamDownload], useful for diagnosing downloads that
+/// appear to be stuck.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugState {
+    read_position: u64,
+    write_position: u64,
+    requested_position: Option<u64>,
+    content_length: Option<u64>,
+    downloaded: Vec<Range<u64>>,
+    download_complete: bool,
+    download_error: Option<String>,
+    reader_notifications: u64,
+}
+
+impl DebugState {
+    /// The position of the reader in the stream.
+    pub fn read_position(&self) -> u64 {
+        self.read_position
+    }
+
+    /// The position where the next downloaded chunk will be written.
+    pub fn write_position(&self) -> u64 {
+        self.write_position
+    }
+
+    /// The position the reader is currently waiting for, if any.
+    pub fn requested_position(&self) -> Option<u64> {
+        self.requested_position
+    }
+
+    /// The length of the stream, if known.
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    /// The ranges of the stream that have been downloaded and can be read without waiting.
+    pub fn downloaded(&self) -> &[Range<u64>] {
+        &self.downloaded
+    }
+
+    /// Whether the download task has finished.
+    pub fn download_complete(&self) -> bool {
+        self.download_complete
+    }
+
+    /// The error that caused the download task to stop, if any.
+    pub fn download_error(&self) -> Option<&str> {
+        self.download_error.as_deref()
+    }
+
+    /// How many times the download task woke up readers that were blocked waiting for data.
+    /// Updates that happen while no reader is blocked don't wake anything up.
+    pub fn reader_notifications(&self) -> u64 {
+        self.reader_notifications
+    }
+}
+
+/// Read-only view of a [StreamDownload] for monitoring, created by
+/// [StreamDownload::metrics_handle].
+///
+/// The handle is cheap to clone and can be passed to other threads. It can't seek or request data,
+/// and it doesn't keep the download running once the [StreamDownload] is dropped. Reading from it
+/// only holds internal locks long enough to copy the state.
+///
+/// Data is always flushed to storage before it's reported as
+/// [downloaded](MetricsHandle::downloaded), and any reader waiting on it is woken up before
+/// [wait_for_position](MetricsHandle::wait_for_position) resolves. Any data the handle reports
+/// can be read from the [StreamDownload] without waiting.
+///
+/// The handle refers to the source that was active when it was created, so a new one should be
+/// created after calling [switch_source](StreamDownload::switch_source) or
+/// [switch_source_retain_buffer](StreamDownload::switch_source_retain_buffer).
+#[derive(Debug, Clone)]
+pub struct MetricsHandle {
+    handle: SourceHandle,
+}
+
+impl MetricsHandle {
+    /// The position of the reader in the stream.
+    pub fn read_position(&self) -> u64 {
+        self.handle.read_position()
+    }
+
+    /// The position where the next downloaded chunk will be written.
+    /// Data before this position may not have been flushed yet, so use
+    /// [downloaded](MetricsHandle::downloaded) to check what can be read.
+    pub fn write_position(&self) -> u64 {
+        self.handle.write_position()
+    }
+
+    /// The length of the stream, if known.
+    pub fn content_length(&self) -> Option<u64> {
+        self.handle.content_length()
+    }
+
+    /// The ranges of the stream that have been downloaded and can be read without waiting.
+    pub fn downloaded(&self) -> Vec<Range<u64>> {
+        self.handle.downloaded().ranges().collect()
+    }
+
+    /// Whether the reader can seek to parts of the stream that haven't been downloaded yet, such as
+    /// to decide whether to offer a seek bar. This is based on what the source reported through
+    /// [SourceStream::supports_seek] and [SourceStream::supports_restart], so it doesn't send any
+    /// requests. It can change to `false` if a range request is ignored by the server or a
+    /// [ChunkTransform] changes the length of the data. Seeking within data that was already
+    /// downloaded is always possible.
+    pub fn can_seek(&self) -> bool {
+        self.handle.can_seek()
+    }
+
+    /// Whether the initial prefetch has finished.
+    pub fn prefetch_complete(&self) -> bool {
+        self.handle.prefetch_complete()
+    }
+
+    /// Whether the download task has finished.
+    pub fn download_complete(&self) -> bool {
+        self.handle.download_complete()
+    }
+
+    /// The error that caused the download task to stop, if any.
+    pub fn download_error(&self) -> Option<String> {
+        self.handle.download_error().map(|e| e.to_string())
+    }
+
+    /// Waits until the byte at `position` has been downloaded.
+    ///
+    /// This doesn't request any data, so it only resolves once the download reaches the position
+    /// on its own. If the download finishes without reaching it, an error with a kind of
+    /// [io::ErrorKind::UnexpectedEof] is returned, or the download error if the task failed.
+    pub async fn wait_for_position(&self, position: u64) -> io::Result<()> {
+        self.handle.wait_for_position(position).await
+    }
+
+    /// The current download speed in bytes per second, averaged over the last few seconds.
+    /// Returns `None` until enough data has been received to measure it or once the download has
+    /// finished.
+    pub fn download_speed(&self) -> Option<f64> {
+        self.handle.download_speed()
+    }
+
+    /// The estimated time until the download finishes, based on the remaining data and the
+    /// current [download_speed](MetricsHandle::download_speed).
+    /// Returns `None` if the content length or the download speed isn't known.
+    pub fn eta(&self) -> Option<Duration> {
+        self.handle.eta()
+    }
+
+    /// Returns a snapshot of the [Stats] collected so far.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            stall_count: self.handle.stall_count(),
+            total_stall_duration: self.handle.stall_duration(),
+            stall_recoveries: self.handle.stall_recoveries(),
+            prefetch_throughput: self.handle.prefetch_throughput(),
+            bytes_received: self.handle.bytes_received(),
+            bytes_on_disk: self.handle.bytes_on_disk(),
+        }
+    }
+
+    /// Returns a [DebugState] snapshot of the internal download state.
+    pub fn debug_state(&self) -> DebugState {
+        DebugState {
+            read_position: self.handle.read_position(),
+            write_position: self.handle.write_position(),
+            requested_position: self.handle.requested_position(),
+            content_length: self.handle.content_length(),
+            downloaded: self.downloaded(),
+            download_complete: self.handle.download_complete(),
+            download_error: self.handle.download_error().map(|e| e.to_string()),
+            reader_notifications: self.handle.reader_notifications(),
+        }
+    }
+}
+
+/// Represents content streamed from a remote source.
+/// This struct implements [read](https://doc.rust-lang.org/stable/std/io/trait.Read.html)
+/// and [seek](https://doc.rust-lang.org/stable/std/io/trait.Seek.html)
+/// so it can be used as a generic source for libraries and applications that operate on these
+/// traits. On creation, an async task is spawned that will immediately start to download the remote
+/// content. Use [Settings::spawner] to control where the task runs.
+///
+/// Any read attempts that request part of the stream that hasn't been downloaded yet will block
+/// until the requested portion is reached. Any seek attempts that meet the same criteria will
+/// result in additional request to restart the stream download from the seek point.
+/// If the server responds to one of these requests with the full content instead, the download
+/// will restart from the beginning and any further seeks to positions that haven't been downloaded
+/// will return an error.
+///
+/// Seeking before reading anything is supported as well. This is useful for formats that store
+/// their metadata at the end of the file, such as zip archives. Seeking near the end of the stream
+/// only requests the tail, and the rest of the stream is downloaded afterwards unless
+/// [Settings::fill_gaps] is disabled, in which case it's only downloaded once it's read.
+///
+/// Construction only waits for the connection to be established, so any errors from the initial
+/// request are returned from the constructor. Prefetching continues in the background and the
+/// first read will block until it finishes. See [StreamDownload::prefetch_complete].
+///
+/// The content length is resolved before the constructor returns, so seeking relative to the end
+/// of the stream never waits on the download, even before the first read. If the stream doesn't
+/// report a content length and neither [Settings::content_length_override] nor
+/// [Settings::content_length_resolver] provide one, these seeks return an error with a kind of
+/// [io::ErrorKind::Unsupported].
+///
+/// [StreamDownload] also implements [BufRead], so line-based parsers can use it without wrapping
+/// it in a [BufReader](io::BufReader). [fill_buf](BufRead::fill_buf) blocks until some data is
+/// available at the current position and returns up to 8 KiB of it without waiting for more. It
+/// returns an empty slice at the end of the stream.
+///
+/// Reads and seeks share a single cursor, so use a
+/// [SharedStreamDownload](shared::SharedStreamDownload) to read from multiple threads.
+///
+/// If the stream download hasn't completed when this struct is dropped, the task will be cancelled.
+#[derive(Debug)]
+pub struct StreamDownload<P: StorageProvider> {
+    output_reader: P::Reader,
+    handle: SourceHandle,
+    download_task_cancellation_token: CancellationToken,
+    download_task: DownloadTask,
+    settings: Settings,
+    // Data returned from fill_buf that hasn't been consumed yet, starting at read_buf_start
+    read_buf: Bytes,
+    read_buf_start: u64,
+}
+
+impl<P: StorageProvider> StreamDownload<P> {
+    #[cfg(feature = "reqwest")]
+    /// Creates a new [StreamDownload] that accesses an HTTP resource at the given URL.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::error::Error;
+    /// use std::io::Read;
+    /// use std::result::Result;
+    ///
+    /// use stream_download::storage::temp::TempStorageProvider;
+    /// use stream_download::{Settings, StreamDownload};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn Error>> {
+    ///     let mut reader = StreamDownload::new_http(
+    ///         "https://some-cool-url.com/some-file.mp3".parse()?,
+    ///         TempStorageProvider::default(),
+    ///         Settings::default(),
+    ///     )
+    ///     .await?;
+    ///
+    ///     let mut buf = Vec::new();
+    ///     reader.read_to_end(&mut buf)?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn new_http(
+        url: ::reqwest::Url,
+        storage_provider: P,
+        settings: Settings,
+    ) -> io::Result<Self> {
+        Self::new::<http::HttpStream<::reqwest::Client>>(url, storage_provider, settings).await
+    }
+
+    /// Creates a new [StreamDownload] that accesses a remote resource at the given URL.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::error::Error;
+    /// use std::io::Read;
+    /// use std::result::Result;
+    ///
+    /// use reqwest::Client;
+    /// use stream_download::http::HttpStream;
+    /// use stream_download::storage::temp::TempStorageProvider;
+    /// use stream_download::{Settings, StreamDownload};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn Error>> {
+    ///     let mut reader = StreamDownload::new::<HttpStream<Client>>(
+    ///         "https://some-cool-url.com/some-file.mp3".parse()?,
+    ///         TempStorageProvider::default(),
+    ///         Settings::default(),
+    ///     )
+    ///     .await?;
+    ///
+    ///     let mut buf = Vec::new();
+    ///     reader.read_to_end(&mut buf)?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn new<S: SourceStream>(
+        url: S::Url,
+        storage_provider: P,
+        settings: Settings,
+    ) -> io::Result<Self> {
+        Self::from_make_stream(move || S::create(url), storage_provider, settings).await
+    }
+
+    /// Creates a new [StreamDownload] from a [SourceStream].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::error::Error;
+    /// use std::io::Read;
+    /// use std::result::Result;
+    ///
+    /// use reqwest::Client;
+    /// use stream_download::http::HttpStream;
+    /// use stream_download::storage::temp::TempStorageProvider;
+    /// use stream_download::{Settings, StreamDownload};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn Error>> {
+    ///     let stream = HttpStream::new(
+    ///         Client::new(),
+    ///         "https://some-cool-url.com/some-file.mp3".parse()?,
+    ///     )
+    ///     .await?;
+    ///
+    ///     let mut reader = StreamDownload::from_stream(
+    ///         stream,
+    ///         TempStorageProvider::default(),
+    ///         Settings::default(),
+    ///     )
+    ///     .await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn from_stream<S: SourceStream>(
+        stream: S,
+        storage_provider: P,
+        settings: Settings,
+    ) -> Result<Self, io::Error> {
+        Self::from_make_stream(
+            move || future::ready(Ok(stream)),
+            storage_provider,
+            settings,
+        )
+        .await
+    }
+
+    /// Creates a new [StreamDownload] from a [SourceStream] for a resource whose first
+    /// `resume_from` bytes were already downloaded, for example by a previous run of the
+    /// application.
+    ///
+    /// The first `resume_from` bytes are copied from `existing` into the storage and marked as
+    /// downloaded, and the download continues from `resume_from` using a range request. The reader
+    /// starts at the beginning of the stream, so the existing data can be read without waiting.
+    /// The existing data must be identical to the start of the resource.
+    ///
+    /// An error is returned if `resume_from` is past the content length of the stream or if
+    /// `existing` contains fewer than `resume_from` bytes. If
+    /// [content_length_override](Settings::content_length_override) is set, it's checked against
+    /// the length reported by the stream unless [Settings::check_resume_length] is disabled.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::error::Error;
+    /// use std::fs::File;
+    /// use std::result::Result;
+    ///
+    /// use reqwest::Client;
+    /// use stream_download::http::HttpStream;
+    /// use stream_download::storage::temp::TempStorageProvider;
+    /// use stream_download::{Settings, StreamDownload};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), Box<dyn Error>> {
+    ///     let existing = File::open("partial-download.mp3")?;
+    ///     let resume_from = existing.metadata()?.len();
+    ///     let stream = HttpStream::new(
+    ///         Client::new(),
+    ///         "https://some-cool-url.com/some-file.mp3".parse()?,
+    ///     )
+    ///     .await?;
+    ///
+    ///     let reader = StreamDownload::from_stream_resumed(
+    ///         stream,
+    ///         TempStorageProvider::default(),
+    ///         Settings::default(),
+    ///         resume_from,
+    ///         existing,
+    ///     )
+    ///     .await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn from_stream_resumed<S: SourceStream>(
+        stream: S,
+        storage_provider: P,
+        settings: Settings,
+        resume_from: u64,
+        existing: impl Read,
+    ) -> io::Result<Self> {
+        if let (true, Some(content_length_override), Some(reported_length)) = (
+            settings.check_resume_length,
+            settings.content_length_override,
+            stream.content_length(),
+        ) {
+            if content_length_override != reported_length {
+                warn!(
+                    content_length_override,
+                    reported_length, "content length changed since it was stored"
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "content length of the stream does not match the configured length",
+                ));
+            }
+        }
+        let content_length = stream_content_length(&stream, &settings).await;
+        if content_length.is_some_and(|content_length| resume_from > content_length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "resume position is past the end of the stream",
+            ));
+        }
+
+        let storage = storage_provider.create_reader(content_length)?;
+        let mut writer = storage.writer()?;
+        let copied = io::copy(&mut existing.take(resume_from), &mut writer)
+            .wrap_err("error copying existing data")?;
+        if copied < resume_from {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "existing data is shorter than the resume position",
+            ));
+        }
+        writer.flush()?;
+        if settings.fsync_on_flush {
+            P::Reader::sync_data(&mut writer)?;
+        }
+        writer.seek(SeekFrom::Start(0))?;
+
+        let mut downloaded = settings.create_availability_map(content_length);
+        if resume_from > 0 {
+            downloaded.insert(0..resume_from);
+        }
+        let (handle, cancellation_token, download_task) = spawn_download(
+            stream,
+            LostStorageWriter::new::<P::Reader>(writer),
+            content_length,
+            downloaded,
+            None,
+            settings.clone(),
+        );
+        debug!(resume_from, "resuming download");
+        if resume_from > 0 {
+            handle.seek(resume_from);
+        }
+
+        Ok(Self {
+            output_reader: storage,
+            handle,
+            download_task_cancellation_token: cancellation_token,
+            download_task,
+            settings,
+            read_buf: Bytes::new(),
+            read_buf_start: 0,
+        })
+    }
+
+    /// Cancels the background task that's downloading the stream content.
+    /// This has no effect if the download is already completed.
+    ///
+    /// Any data that was downloaded before the cancellation can still be read. Reads that reach
+    /// the end of the downloaded data will return EOF instead of waiting for more data, including
+    /// any reads that are currently blocked.
+    pub fn cancel_download(&self) {
+        self.download_task_cancellation_token.cancel();
+    }
+
+    /// Waits for the background download task to finish and returns its result.
+    ///
+    /// This doesn't stop the download, so call [cancel_download](StreamDownload::cancel_download)
+    /// first to finish early. Errors encountered while downloading are returned as-is. If the
+    /// task panicked, the returned error wraps the task's
+    /// [JoinError](https://docs.rs/tokio/latest/tokio/task/struct.JoinError.html) instead. If the
+    /// task is driven manually using [Spawner::manual] and it's dropped before finishing, an error
+    /// is returned as well.
+    pub async fn join(mut self) -> io::Result<()> {
+        self.download_task.wait().await
+    }
+
+    /// Replaces the remote resource and restarts the download from the new URL.
+    ///
+    /// This is intended for switching between different versions of the same content, such as
+    /// quality variants of an audio stream. The current download is cancelled and any buffered
+    /// data is discarded, so a new storage provider is required. The read position is preserved,
+    /// so the next read will continue from the same offset in the new resource. Use
+    /// [switch_source_retain_buffer](StreamDownload::switch_source_retain_buffer) if the new
+    /// resource is identical to the current one.
+    ///
+    /// Since this requires a mutable reference, it can't run while a read is in progress. If an
+    /// error occurs while connecting to the new resource, the current download is left untouched.
+    pub async fn switch_source<S: SourceStream>(
+        &mut self,
+        url: S::Url,
+        storage_provider: P,
+    ) -> io::Result<()> {
+        let position = self.output_reader.stream_position()?;
+        let stream = S::create(url).await.wrap_err("error creating stream")?;
+        let content_length = stream_content_length(&stream, &self.settings).await;
+        let storage = storage_provider.create_reader(content_length)?;
+        let (handle, cancellation_token, download_task) = spawn_download(
+            stream,
+            LostStorageWriter::new::<P::Reader>(storage.writer()?),
+            content_length,
+            self.settings.create_availability_map(content_length),
+            Some(self.handle.source_info_sender()),
+            self.settings.clone(),
+        );
+        debug!(position, "switched source, discarding buffered data");
+
+        self.cancel_download();
+        self.output_reader = storage;
+        self.read_buf.clear();
+        self.handle = handle;
+        self.download_task_cancellation_token = cancellation_token;
+        self.download_task = download_task;
+        if position > 0 {
+            self.seek(SeekFrom::Start(position))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the remote resource and restarts the download from the new URL while keeping any
+    /// data that's already been downloaded.
+    ///
+    /// The new resource must be identical to the current one, for example if the same file is
+    /// available from a different server or the URL contains an access token that expired. The
+    /// download continues from the first position after the reader that hasn't been downloaded
+    /// yet. An error is returned if the content length of the new resource doesn't match.
+    ///
+    /// Since this requires a mutable reference, it can't run while a read is in progress. If an
+    /// error occurs while connecting to the new resource, the current download is left untouched.
+    pub async fn switch_source_retain_buffer<S: SourceStream>(
+        &mut self,
+        url: S::Url,
+    ) -> io::Result<()> {
+        let position = self.output_reader.stream_position()?;
+        let stream = S::create(url).await.wrap_err("error creating stream")?;
+        let content_length = stream_content_length(&stream, &self.settings).await;
+        if content_length != self.handle.content_length() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "content length of the new source does not match the current source",
+            ));
+        }
+
+        // Storage writers may share a cursor with each other, so the previous download needs to
+        // finish before another one can write to the same storage
+        self.cancel_download();
+        self.download_task.wait().await.ok();
+        let mut writer = self.output_reader.writer()?;
+        writer.seek(SeekFrom::Start(0))?;
+        let downloaded = self
+            .handle
+            .take_downloaded(self.settings.create_availability_map(content_length));
+        let resume_position = downloaded.get(position).map_or(position, |range| range.end);
+        let (handle, cancellation_token, download_task) = spawn_download(
+            stream,
+            LostStorageWriter::new::<P::Reader>(writer),
+            content_length,
+            downloaded,
+            Some(self.handle.source_info_sender()),
+            self.settings.clone(),
+        );
+        debug!(
+            position,
+            resume_position, "switched source, keeping buffered data"
+        );
+
+        handle.set_read_position(position);
+        if resume_position > 0 {
+            handle.seek(resume_position);
+        }
+        self.handle = handle;
+        self.download_task_cancellation_token = cancellation_token;
+        self.download_task = download_task;
+        Ok(())
+    }
+
+    /// Pauses the download without closing the connection.
+    ///
+    /// Any data that's already been downloaded can still be read while paused. If a read needs
+    /// data that hasn't been downloaded yet, the download continues until the requested data is
+    /// available and then pauses again. Use [resume](StreamDownload::resume) to continue
+    /// downloading in the background.
+    pub fn pause(&self) {
+        self.handle.set_paused(true);
+    }
+
+    /// Resumes a download that was paused with [pause](StreamDownload::pause).
+    pub fn resume(&self) {
+        self.handle.set_paused(false);
+    }
+
+    /// Closes the current connection and continues the download from the same position with a
+    /// new request.
+    ///
+    /// This is useful when the network changes, such as when switching from WiFi to cellular,
+    /// since existing connections may stall without returning an error. Buffered data and the read
+    /// position are kept. This counts towards [Settings::max_range_requests], and it has no effect
+    /// once the download has finished or if the stream doesn't
+    /// [support restarting](SourceStream::supports_restart). If the download task stopped because
+    /// of an error, use
+    /// [switch_source_retain_buffer](StreamDownload::switch_source_retain_buffer) to restart it
+    /// instead.
+    pub fn reconnect(&self) {
+        self.handle.reconnect();
+    }
+
+    /// Changes the [prefetch_bytes](Settings::prefetch_bytes) setting after the download has
+    /// started.
+    ///
+    /// This only affects prefetch that's still in progress, including prefetch that's restarted
+    /// by seeking, and downloads started later with [switch_source](StreamDownload::switch_source).
+    /// Lowering the value below the amount that's already been prefetched completes prefetch
+    /// once the next chunk is received. Data that's already been downloaded is kept.
+    pub fn set_prefetch_bytes(&mut self, prefetch_bytes: u64) {
+        self.settings.prefetch_bytes = prefetch_bytes;
+        self.handle.set_prefetch_bytes(prefetch_bytes);
+    }
+
+    /// Returns whether the initial prefetch has finished and the start of the stream can be read
+    /// without blocking.
+    ///
+    /// The constructors return as soon as the connection is established and the content length is
+    /// known, so this can be used to tell when the stream is actually ready to read.
+    pub fn prefetch_complete(&self) -> bool {
+        self.handle.prefetch_complete()
+    }
+
+    /// Copies the downloaded data to a file at the given path and returns the number of bytes
+    /// written.
+    ///
+    /// Only the contiguous section at the start of the stream is copied, so any data that was
+    /// downloaded after seeking past a missing section is skipped. The download continues in the
+    /// background and the read position is left unchanged.
+    ///
+    /// When using [BoundedStorageProvider](storage::bounded::BoundedStorageProvider), the start
+    /// of the stream may have already been overwritten, so the snapshot won't be accurate.
+    pub fn snapshot_to(&mut self, path: impl AsRef<Path>) -> io::Result<u64> {
+        let length = self.handle.downloaded().get(0).map_or(0, |range| range.end);
+        let mut file = File::create(path).wrap_err("error creating snapshot file")?;
+        let position = self.output_reader.stream_position()?;
+        self.output_reader.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut (&mut self.output_reader).take(length), &mut file);
+        // Restore the position even if copying failed so the next read isn't affected
+        self.output_reader.seek(SeekFrom::Start(position))?;
+        let copied = copied.wrap_err("error writing snapshot file")?;
+        debug!(copied, "wrote snapshot");
+        Ok(copied)
+    }
+
+    /// Stops the download and writes the data downloaded so far to a file at the given path,
+    /// returning the number of bytes written.
+    ///
+    /// Unlike [snapshot_to](StreamDownload::snapshot_to), this waits for the download task to
+    /// finish so that any data it has received is flushed to storage and included in the file.
+    /// Like [snapshot_to](StreamDownload::snapshot_to), only the contiguous section at the start
+    /// of the stream is written. The file and the returned length can be passed to
+    /// [from_stream_resumed](StreamDownload::from_stream_resumed) to continue the download later.
+    pub async fn finalize(mut self, path: impl AsRef<Path>) -> io::Result<u64> {
+        self.cancel_download();
+        // The download task flushes its writer before exiting
+        self.download_task.wait().await.ok();
+        let copied = self.snapshot_to(path)?;
+        debug!(copied, "finalized download");
+        Ok(copied)
+    }
+
+    /// Adds any data downloaded at the start of the stream since the last update to `hash` and
+    /// returns the number of bytes it now covers.
+    ///
+    /// Only the contiguous section at the start of the stream is hashed, so data downloaded after
+    /// seeking past a missing section is included once the gap is filled. This never waits for
+    /// the download and the read position is left unchanged.
+    ///
+    /// When using [BoundedStorageProvider](storage::bounded::BoundedStorageProvider), the start
+    /// of the stream may have already been overwritten, so the hash won't be accurate.
+    #[cfg(feature = "hash")]
+    pub fn update_hash<H: hash::StreamHasher>(
+        &mut self,
+        hash: &mut hash::PrefixHash<H>,
+    ) -> io::Result<u64> {
+        let start = hash.hashed_len();
+        let end = self.available_at(0);
+        if end <= start {
+            return Ok(start);
+        }
+
+        let position = self.output_reader.stream_position()?;
+        self.output_reader.seek(SeekFrom::Start(start))?;
+        let mut remaining = end - start;
+        let mut buf = vec![0; FILL_BUF_SIZE];
+        let result = loop {
+            if remaining == 0 {
+                break Ok(());
+            }
+            let len = buf.len().min(remaining as usize);
+            match self.output_reader.read(&mut buf[..len]) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read_len) => {
+                    hash.update(&buf[..read_len]);
+                    remaining -= read_len as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        // Restore the position even if reading failed so the next read isn't affected
+        self.output_reader.seek(SeekFrom::Start(position))?;
+        result.wrap_err("error reading data to hash")?;
+        trace!(start, end, "updated hash");
+        Ok(end)
+    }
+
+    /// Returns an iterator over the data from the current position onwards, yielding each
+    /// contiguous section as soon as it's available.
+    ///
+    /// Each item contains everything that's been downloaded past the previous one, up to 64 KiB,
+    /// so chunk sizes depend on how quickly the data arrives. The iterator blocks until more data
+    /// is written and ends once the end of the stream is reached. If the download fails before
+    /// that, the error is returned as the final item.
+    pub fn chunks(&mut self) -> Chunks<'_, P> {
+        Chunks { reader: self }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let position = self.output_reader.stream_position()?;
+        if self.at_end(position) {
+            return Ok(None);
+        }
+
+        let mut len = self.available_at(position);
+        if len == 0 {
+            self.handle.request_position(position + 1);
+            self.handle.wait_for_requested_position();
+            len = self.available_at(position);
+        }
+        if len == 0 {
+            // The download stopped before reaching this position
+            return match self.handle.download_error() {
+                Some(error) => Err(error.into()),
+                None => Ok(None),
+            };
+        }
+
+        let len = len.min(MAX_CHUNK_SIZE as u64);
+        let mut buf = vec![0; len as usize];
+        self.output_reader
+            .read_exact(&mut buf)
+            .map_err(storage_error)?;
+        self.handle.set_read_position(position + len);
+        trace!(position, chunk_size = len, "returning chunk");
+        Ok(Some(buf.into()))
+    }
+
+    /// Returns whether reading at `offset` would need to wait for the downloader.
+    ///
+    /// This only checks the current state, so it never blocks or sends any requests. Reads at or
+    /// past the end of the stream and reads after the download has stopped never wait. If the
+    /// content length is unknown, any offset that hasn't been downloaded yet would wait.
+    pub fn would_block_at(&self, offset: u64) -> bool {
+        !self.at_end(offset) && !self.handle.download_complete() && self.available_at(offset) == 0
+    }
+
+    /// Reads the data that's available at the current position without blocking past the
+    /// deadline.
+    ///
+    /// Any data that's already downloaded is returned immediately. Otherwise, this waits until
+    /// some data arrives or the deadline passes, in which case an error with a kind of
+    /// [io::ErrorKind::WouldBlock] is returned and the position is left unchanged. This is useful
+    /// for realtime consumers such as audio callbacks that can't afford to block for long.
+    /// Like [Read::read], this returns `0` once the end of the stream is reached.
+    pub fn try_read(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        let position = self.output_reader.stream_position()?;
+        if buf.is_empty() || self.at_end(position) {
+            return Ok(0);
+        }
+
+        if self.would_block_at(position) {
+            self.handle.request_position(position + 1);
+            if !self.handle.wait_for_requested_position_until(deadline) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no data was available before the deadline",
+                ));
+            }
+        }
+
+        // This is 0 if the download stopped before reaching the position
+        let len = buf.len().min(self.available_at(position) as usize);
+        self.output_reader
+            .read(&mut buf[..len])
+            .map_err(storage_error)
+            .tap_ok(|l| self.handle.set_read_position(position + *l as u64))
+            .tap(|l| trace!(read_length = format!("{l:?}"), "returning read"))
+    }
+
+    /// Blocks until everything from the current position up to `upto` has been downloaded,
+    /// without moving the reader.
+    ///
+    /// The download is directed to the missing data the same way it would be for a read, so this
+    /// can be used to make sure a prefix is available before handing the reader to another
+    /// component. Positions past the end of the stream are clamped to the content length. If the
+    /// download stops before reaching `upto`, the download error is returned, or an error with a
+    /// kind of [io::ErrorKind::UnexpectedEof] if it finished without one.
+    pub fn ensure_buffered(&self, upto: u64) -> io::Result<()> {
+        let position = self.handle.read_position();
+        let upto = self
+            .handle
+            .content_length()
+            .map_or(upto, |content_length| upto.min(content_length));
+        if upto <= position || self.available_at(position) >= upto - position {
+            return Ok(());
+        }
+
+        self.handle.request_position(upto);
+        debug!(requested_position = upto, "waiting for buffered position");
+        self.handle.wait_for_requested_position();
+        if self.available_at(position) >= upto - position {
+            return Ok(());
+        }
+        Err(match self.handle.download_error() {
+            Some(error) => error.into(),
+            None => io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the download finished before reaching the requested position",
+            ),
+        })
+    }
+
+    /// Reads the data immediately before the current position into the start of `buf` and moves
+    /// the reader back to the first byte that was read.
+    ///
+    /// Seeking to the end and calling this repeatedly visits the stream in windows of
+    /// `buf.len()` bytes from the end toward the start, which suits formats that are parsed
+    /// backward from a footer. Each window is requested the same way a seek followed by a read
+    /// would be. Returns `0` once the start of the stream is reached. The content length must be
+    /// known, otherwise an error with a kind of [io::ErrorKind::Unsupported] is returned.
+    pub fn read_backward(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(content_length) = self.handle.content_length() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot read backward when content length is unknown",
+            ));
+        };
+        let end = self.output_reader.stream_position()?.min(content_length);
+        let len = (buf.len() as u64).min(end);
+        let start = end - len;
+        let buf = &mut buf[..len as usize];
+        self.seek(SeekFrom::Start(start))?;
+        if let Err(e) = self.read_exact(buf) {
+            return Err(match self.handle.download_error() {
+                Some(error) if e.kind() == io::ErrorKind::UnexpectedEof => error.into(),
+                _ => e,
+            });
+        }
+        self.seek(SeekFrom::Start(start))?;
+        Ok(buf.len())
+    }
+
+    fn at_end(&self, position: u64) -> bool {
+        self.handle
+            .content_length()
+            .is_some_and(|content_length| position >= content_length)
+    }
+
+    // Returns how many bytes can be read from the given position without waiting
+    fn available_at(&self, position: u64) -> u64 {
+        let available = self
+            .handle
+            .downloaded()
+            .get(position)
+            .map_or(0, |range| range.end - position);
+        self.handle
+            .content_length()
+            .map_or(available, |content_length| {
+                available.min(content_length.saturating_sub(position))
+            })
     }
 
-    /// Retrieves the configured prefetch bytes
-    pub fn get_prefetch_bytes(&self) -> u64 {
-        self.prefetch_bytes
+    // Waits until the data at `stream_position` is available and returns how many of the `len`
+    // requested bytes can be read. Reads are limited to the content length, and to the data that
+    // was downloaded if the download stopped early.
+    fn wait_for_read(&mut self, stream_position: u64, len: usize) -> io::Result<usize> {
+        let mut requested_position = stream_position + len as u64;
+        if let Some(content_length) = self.handle.content_length() {
+            if stream_position >= content_length {
+                trace!(
+                    current_position = stream_position,
+                    content_length,
+                    "reached the end of the stream"
+                );
+                return Ok(0);
+            }
+            // Don't wait on data past the end of the stream since it will never arrive. The
+            // storage may also contain more data than the stream length if the stream sent more
+            // than it reported, so this makes sure we don't read past the end.
+            requested_position = requested_position.min(content_length);
+        }
+        let len = (requested_position - stream_position) as usize;
+        trace!(
+            current_position = stream_position,
+            requested_position = requested_position
+        );
+
+        if let Some(closest_set) = self.handle.downloaded().get(stream_position) {
+            trace!(
+                downloaded_range = format!("{closest_set:?}"),
+                "current position already downloaded"
+            );
+            if closest_set.end >= requested_position {
+                trace!("requested position already downloaded");
+                return Ok(len);
+            }
+            debug!("requested position not yet downloaded");
+        } else {
+            debug!("stream position not yet downloaded");
+        }
+
+        self.handle.request_position(requested_position);
+        debug!(
+            requested_position = requested_position,
+            "waiting for requested position"
+        );
+        self.handle.wait_for_requested_position();
+        debug!(
+            current_position = stream_position,
+            requested_position = requested_position,
+            output_stream_position = self.output_reader.stream_position()?,
+            "reached requested position"
+        );
+
+        if self.handle.download_complete() {
+            // The download may have been cancelled before reaching the requested position, so
+            // we can only return the data that was actually downloaded
+            let available_len = self
+                .handle
+                .downloaded()
+                .get(stream_position)
+                .map(|range| range.end - stream_position)
+                .unwrap_or(0);
+            debug!(available_len, "download complete");
+            return Ok(len.min(available_len as usize));
+        }
+        Ok(len)
     }
-}
 
-/// Represents content streamed from a remote source.
-/// This struct implements [read](https://doc.rust-lang.org/stable/std/io/trait.Read.html)
-/// and [seek](https://doc.rust-lang.org/stable/std/io/trait.Seek.html)
-/// so it can be used as a generic source for libraries and applications that operate on these
-/// traits. On creation, an async task is spawned that will immediately start to download the remote
-/// content.
-///
-/// Any read attempts that request part of the stream that hasn't been downloaded yet will block
-/// until the requested portion is reached. Any seek attempts that meet the same criteria will
-/// result in additional request to restart the stream download from the seek point.
-///
-/// If the stream download hasn't completed when this struct is dropped, the task will be cancelled.
-#[derive(Debug)]
-pub struct StreamDownload<P: StorageProvider> {
-    output_reader: P::Reader,
-    handle: SourceHandle,
-    download_task_cancellation_token: CancellationToken,
-}
+    /// Returns the [SourceInfo] of the source that's currently being downloaded.
+    pub fn source_info(&self) -> SourceInfo {
+        self.handle.source_info()
+    }
 
-impl<P: StorageProvider> StreamDownload<P> {
-    #[cfg(feature = "reqwest")]
-    /// Creates a new [StreamDownload] that accesses an HTTP resource at the given URL.
-    ///
-    /// # Example
-    ///
-    /// ```no_run
-    /// use std::error::Error;
-    /// use std::io::Read;
-    /// use std::result::Result;
+    /// Returns a receiver that's notified with the new [SourceInfo] whenever the source changes.
     ///
-    /// use stream_download::storage::temp::TempStorageProvider;
-    /// use stream_download::{Settings, StreamDownload};
-    ///
-    /// #[tokio::main]
-    /// async fn main() -> Result<(), Box<dyn Error>> {
-    ///     let mut reader = StreamDownload::new_http(
-    ///         "https://some-cool-url.com/some-file.mp3".parse()?,
-    ///         TempStorageProvider::default(),
-    ///         Settings::default(),
-    ///     )
-    ///     .await?;
+    /// This happens when the source is replaced using
+    /// [switch_source](StreamDownload::switch_source) or
+    /// [switch_source_retain_buffer](StreamDownload::switch_source_retain_buffer), or when the
+    /// stream moves to a different source while seeking, such as an
+    /// [HttpStream](crate::http::HttpStream) falling back to one of its mirrors.
+    pub fn source_changes(&self) -> watch::Receiver<SourceInfo> {
+        self.handle.source_info_sender().subscribe()
+    }
+
+    /// Returns a snapshot of the [Stats] collected so far.
+    pub fn stats(&self) -> Stats {
+        self.metrics_handle().stats()
+    }
+
+    /// The current download speed in bytes per second. See [MetricsHandle::download_speed].
+    pub fn download_speed(&self) -> Option<f64> {
+        self.handle.download_speed()
+    }
+
+    /// The estimated time until the download finishes. See [MetricsHandle::eta].
+    pub fn eta(&self) -> Option<Duration> {
+        self.handle.eta()
+    }
+
+    /// Whether the reader can seek to parts of the stream that haven't been downloaded yet. See
+    /// [MetricsHandle::can_seek].
+    pub fn can_seek(&self) -> bool {
+        self.handle.can_seek()
+    }
+
+    /// Returns a [DebugState] snapshot of the internal download state.
+    /// This only holds internal locks long enough to copy the state, so it's safe to call
+    /// periodically from a separate thread without affecting the download.
+    pub fn debug_state(&self) -> DebugState {
+        self.metrics_handle().debug_state()
+    }
+
+    /// Serves the stream over HTTP on localhost and returns the URL it can be requested from.
     ///
-    ///     let mut buf = Vec::new();
-    ///     reader.read_to_end(&mut buf)?;
-    ///     Ok(())
-    /// }
-    /// ```
-    pub async fn new_http(
-        url: ::reqwest::Url,
+    /// This is useful for players that can only consume URLs. Byte range requests are supported
+    /// so the player can seek. The server keeps running until the returned
+    /// [ServerHandle](local_server::ServerHandle) is dropped or shut down. See the
+    /// [local_server] module for details.
+    #[cfg(feature = "local-server")]
+    pub async fn serve_local(
+        self,
+    ) -> io::Result<(local_server::LocalUrl, local_server::ServerHandle)>
+    where
+        P: 'static,
+    {
+        local_server::serve(self).await
+    }
+
+    /// Returns a [MetricsHandle] that can be used to monitor the download from another thread.
+    pub fn metrics_handle(&self) -> MetricsHandle {
+        MetricsHandle {
+            handle: self.handle.clone(),
+        }
+    }
+
+    async fn from_make_stream<S, F, Fut>(
+        make_stream: F,
         storage_provider: P,
         settings: Settings,
-    ) -> io::Result<Self> {
-        Self::new::<http::HttpStream<::reqwest::Client>>(url, storage_provider, settings).await
+    ) -> Result<Self, io::Error>
+    where
+        S: SourceStream,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<S>> + Send,
+    {
+        let stream = make_stream().await.wrap_err("error creating stream")?;
+        let content_length = stream_content_length(&stream, &settings).await;
+        let storage = storage_provider.create_reader(content_length)?;
+        let (handle, cancellation_token, download_task) = spawn_download(
+            stream,
+            LostStorageWriter::new::<P::Reader>(storage.writer()?),
+            content_length,
+            settings.create_availability_map(content_length),
+            None,
+            settings.clone(),
+        );
+
+        Ok(Self {
+            output_reader: storage,
+            handle,
+            download_task_cancellation_token: cancellation_token,
+            download_task,
+            settings,
+            read_buf: Bytes::new(),
+            read_buf_start: 0,
+        })
     }
+}
 
-    /// Creates a new [StreamDownload] that accesses a remote resource at the given URL.
+impl StreamDownload<MemoryStorageProvider> {
+    /// Downloads the entire resource at the given URL into memory and returns it as a seekable
+    /// [Cursor](io::Cursor).
+    ///
+    /// This is the simplest way to get random access to a small resource without the overhead of
+    /// a temporary file. The download works the same way as [new](StreamDownload::new), but this
+    /// only returns once it's finished. If the resource is larger than `max_download_size` bytes,
+    /// the download is cancelled and an error wrapping [MaxDownloadSizeExceeded] is returned.
     ///
     /// # Example
     ///
@@ -114,116 +2147,282 @@ impl<P: StorageProvider> StreamDownload<P> {
     ///
     /// use reqwest::Client;
     /// use stream_download::http::HttpStream;
-    /// use stream_download::storage::temp::TempStorageProvider;
     /// use stream_download::{Settings, StreamDownload};
     ///
     /// #[tokio::main]
     /// async fn main() -> Result<(), Box<dyn Error>> {
-    ///     let mut reader = StreamDownload::new::<HttpStream<Client>>(
+    ///     let mut cursor = StreamDownload::into_memory::<HttpStream<Client>>(
     ///         "https://some-cool-url.com/some-file.mp3".parse()?,
-    ///         TempStorageProvider::default(),
     ///         Settings::default(),
+    ///         10 * 1024 * 1024,
     ///     )
     ///     .await?;
     ///
     ///     let mut buf = Vec::new();
-    ///     reader.read_to_end(&mut buf)?;
+    ///     cursor.read_to_end(&mut buf)?;
     ///     Ok(())
     /// }
     /// ```
-    pub async fn new<S: SourceStream>(
+    pub async fn into_memory<S: SourceStream>(
         url: S::Url,
-        storage_provider: P,
         settings: Settings,
-    ) -> io::Result<Self> {
-        Self::from_make_stream(move || S::create(url), storage_provider, settings).await
+        max_download_size: u64,
+    ) -> io::Result<io::Cursor<Vec<u8>>> {
+        let stream = S::create(url).await.wrap_err("error creating stream")?;
+        // Check the length before the storage is allocated
+        if stream_content_length(&stream, &settings)
+            .await
+            .is_some_and(|length| length > max_download_size)
+        {
+            return Err(MaxDownloadSizeExceeded.into());
+        }
+        let mut reader =
+            Self::from_stream(stream, MemoryStorageProvider::default(), settings).await?;
+        // The byte after the limit only arrives if the resource is too large
+        match reader.handle.wait_for_position(max_download_size).await {
+            Ok(()) => return Err(MaxDownloadSizeExceeded.into()),
+            Err(e)
+                if e.kind() == io::ErrorKind::UnexpectedEof
+                    && reader.handle.download_error().is_none() => {}
+            Err(e) => return Err(e),
+        }
+        // The download is finished, so reading doesn't need to wait
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        Ok(io::Cursor::new(buf))
     }
 
-    /// Creates a new [StreamDownload] from a [SourceStream].
+    /// Downloads the entire stream into an [AsyncWrite](tokio::io::AsyncWrite) without storing
+    /// it, returning the number of bytes written.
+    ///
+    /// Each chunk is written as soon as it arrives, and the writer is flushed once the stream
+    /// ends. If the stream fails and a [backoff](Settings::backoff) strategy is configured, the
+    /// stream is restarted from the last written position after the backoff delay. The
+    /// [on_content_length](Settings::on_content_length) and
+    /// [on_first_byte](Settings::on_first_byte) callbacks are called the same way as for a
+    /// regular download. Other settings are ignored since nothing is stored.
+    ///
+    /// Errors from the writer, or from the stream once no retries remain, stop the download and
+    /// are returned. If the stream ends before reaching its content length, an error with a kind
+    /// of [io::ErrorKind::UnexpectedEof] is returned. Dropping the future stops the download after
+    /// the last completed write, but the writer won't be flushed in that case.
+    pub async fn download_to_async<S, W>(
+        mut stream: S,
+        writer: &mut W,
+        settings: Settings,
+    ) -> io::Result<u64>
+    where
+        S: SourceStream,
+        W: tokio::io::AsyncWrite + Unpin + ?Sized,
+    {
+        use futures::StreamExt;
+        use tokio::io::AsyncWriteExt;
+
+        let content_length = stream.content_length();
+        if let Some(on_content_length) = &settings.on_content_length {
+            on_content_length.call(content_length);
+        }
+        let mut written = 0;
+        let mut retry_attempt = 0;
+        loop {
+            let chunk = match stream.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => {
+                    error!("Error fetching chunk from stream: {e:?}");
+                    let error = source::stream_error(e);
+                    Self::retry_forward(&mut stream, &settings, &mut retry_attempt, written, error)
+                        .await?;
+                    continue;
+                }
+                None => break,
+            };
+            retry_attempt = 0;
+            writer
+                .write_all(&chunk)
+                .await
+                .wrap_err("error writing to destination")?;
+            if written == 0 && !chunk.is_empty() {
+                if let Some(on_first_byte) = &settings.on_first_byte {
+                    on_first_byte.call();
+                }
+            }
+            written += chunk.len() as u64;
+            trace!(chunk_size = chunk.len(), written, "forwarded chunk");
+        }
+        writer
+            .flush()
+            .await
+            .wrap_err("error flushing destination")?;
+
+        match content_length {
+            Some(content_length) if written < content_length => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream ended before reaching the content length",
+                ));
+            }
+            Some(_) => {}
+            None => {
+                if let Some(on_content_length) = &settings.on_content_length {
+                    on_content_length.call(Some(written));
+                }
+            }
+        }
+        debug!(written, "finished forwarding stream");
+        Ok(written)
+    }
+
+    // Waits for the backoff delay and restarts the stream at the given position, returning the
+    // last error once the backoff strategy stops retrying
+    async fn retry_forward<S: SourceStream>(
+        stream: &mut S,
+        settings: &Settings,
+        attempt: &mut u32,
+        position: u64,
+        mut error: io::Error,
+    ) -> io::Result<()> {
+        let Some(backoff) = settings.get_backoff() else {
+            return Err(error);
+        };
+        if !stream.supports_restart() {
+            debug!("stream can't be restarted, not retrying");
+            return Err(error);
+        }
+        loop {
+            *attempt += 1;
+            let Some(delay) = backoff.next_delay(*attempt) else {
+                warn!(attempts = *attempt - 1, "no retries remaining");
+                return Err(error);
+            };
+            debug!(attempt, delay = ?delay, "retrying after error");
+            settings.get_clock().sleep(delay).await;
+            // The current response may be stuck on a connection that's no longer usable
+            stream.close();
+            match stream.seek_range(position, None).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("error reconnecting: {e}");
+                    error = e;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "reqwest")]
+    /// Retrieves the [SourceInfo] of the HTTP resource at the given URL without downloading its
+    /// content.
+    ///
+    /// No download task or storage is created, so this is a cheap way to decide whether and how
+    /// to stream a resource based on its length, type, and whether it can be seeked. See
+    /// [HttpStream::probe](http::HttpStream::probe) to use a custom [Client](http::Client).
     ///
     /// # Example
     ///
     /// ```no_run
     /// use std::error::Error;
-    /// use std::io::Read;
     /// use std::result::Result;
     ///
-    /// use reqwest::Client;
-    /// use stream_download::http::HttpStream;
-    /// use stream_download::storage::temp::TempStorageProvider;
-    /// use stream_download::{Settings, StreamDownload};
+    /// use stream_download::StreamDownload;
     ///
     /// #[tokio::main]
     /// async fn main() -> Result<(), Box<dyn Error>> {
-    ///     let stream = HttpStream::new(
-    ///         Client::new(),
-    ///         "https://some-cool-url.com/some-file.mp3".parse()?,
-    ///     )
-    ///     .await?;
-    ///
-    ///     let mut reader = StreamDownload::from_stream(
-    ///         stream,
-    ///         TempStorageProvider::default(),
-    ///         Settings::default(),
-    ///     )
-    ///     .await?;
+    ///     let info = StreamDownload::probe("https://some-cool-url.com/some-file.mp3".parse()?).await?;
+    ///     println!("{:?} {:?}", info.content_length, info.content_type);
     ///     Ok(())
     /// }
     /// ```
-    pub async fn from_stream<S: SourceStream>(
-        stream: S,
-        storage_provider: P,
-        settings: Settings,
-    ) -> Result<Self, io::Error> {
-        Self::from_make_stream(
-            move || future::ready(Ok(stream)),
-            storage_provider,
-            settings,
-        )
-        .await
+    pub async fn probe(url: ::reqwest::Url) -> io::Result<SourceInfo> {
+        http::HttpStream::probe(<::reqwest::Client as http::Client>::create(), url).await
     }
+}
 
-    /// Cancels the background task that's downloading the stream content.
-    /// This has no effect if the download is already completed.
-    pub fn cancel_download(&self) {
-        self.download_task_cancellation_token.cancel();
+async fn stream_content_length<S: SourceStream>(stream: &S, settings: &Settings) -> Option<u64> {
+    if let Some(content_length) = settings.content_length_override {
+        debug!(content_length, "using content length override");
+        return Some(content_length);
     }
+    if let Some(resolver) = &settings.content_length_resolver {
+        if let Some(content_length) = resolver.call(&stream.info()).await {
+            debug!(content_length, "using resolved content length");
+            return Some(content_length);
+        }
+        debug!("content length resolver returned no length");
+    }
+    #[cfg(feature = "compression")]
+    if content_decoder(stream, settings).is_some() {
+        debug!("content is decoded before it's stored, treating the stream as unknown length");
+        return None;
+    }
+    stream.content_length()
+}
 
-    async fn from_make_stream<S, F, Fut>(
-        make_stream: F,
-        storage_provider: P,
-        settings: Settings,
-    ) -> Result<Self, io::Error>
-    where
-        S: SourceStream,
-        F: FnOnce() -> Fut + Send + 'static,
-        Fut: Future<Output = io::Result<S>> + Send,
-    {
-        let stream = make_stream().await.wrap_err("error creating stream")?;
-        let content_length = stream.content_length();
-        let storage = storage_provider.create_reader(content_length)?;
-        let source = Source::new(storage.writer()?, content_length, settings);
-        let handle = source.source_handle();
-        let cancellation_token = CancellationToken::new();
-        let cancellation_token_ = cancellation_token.clone();
-
-        tokio::spawn(async move {
-            source
-                .download(stream, cancellation_token_)
-                .await
-                .tap_err(|e| error!("Error downloading stream: {e}"))?;
-            debug!("download task finished");
-            Ok::<_, io::Error>(())
-        });
-
-        Ok(Self {
-            output_reader: storage,
-            handle,
-            download_task_cancellation_token: cancellation_token,
-        })
+#[cfg(feature = "compression")]
+fn content_decoder<S: SourceStream>(
+    stream: &S,
+    settings: &Settings,
+) -> Option<decode::ContentDecoder> {
+    match settings.decompression {
+        Decompression::Raw => None,
+        Decompression::Manual => stream
+            .content_encoding()
+            .and_then(decode::ContentDecoder::new),
     }
 }
 
+fn spawn_download<S: SourceStream, W: StorageWriter>(
+    stream: S,
+    writer: LostStorageWriter<W>,
+    content_length: Option<u64>,
+    downloaded: Box<dyn AvailabilityMap>,
+    source_info: Option<Arc<watch::Sender<SourceInfo>>>,
+    settings: Settings,
+) -> (SourceHandle, CancellationToken, DownloadTask) {
+    // Replacement downloads reuse the existing channel so subscribers are notified of the switch
+    let source_info = match source_info {
+        Some(source_info) => {
+            source_info.send_replace(stream.info());
+            source_info
+        }
+        None => Arc::new(watch::channel(stream.info()).0),
+    };
+    let spawner = settings.spawner.clone();
+    let seekable = stream.supports_restart();
+    #[cfg(feature = "compression")]
+    let decoder = content_decoder(&stream, &settings);
+    let source = Source::new(
+        writer,
+        content_length,
+        downloaded,
+        source_info,
+        seekable,
+        settings,
+    );
+    #[cfg(feature = "compression")]
+    let source = match decoder {
+        Some(decoder) => source.decode_with(decoder),
+        None => source,
+    };
+    let handle = source.source_handle();
+    let cancellation_token = CancellationToken::new();
+    let cancellation_token_ = cancellation_token.clone();
+    let handle_ = handle.clone();
+
+    let download_task = spawner.spawn(async move {
+        let result = source
+            .download(stream, cancellation_token_)
+            .await
+            .map_err(|e| {
+                error!("Error downloading stream: {e}");
+                handle_.set_download_error(e)
+            });
+        handle_.set_task_finished();
+        result?;
+        debug!("download task finished");
+        Ok::<_, io::Error>(())
+    });
+    (handle, cancellation_token, download_task)
+}
+
 impl<P: StorageProvider> Drop for StreamDownload<P> {
     fn drop(&mut self) {
         self.cancel_download();
@@ -235,47 +2434,84 @@ impl<P: StorageProvider> Read for StreamDownload<P> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         trace!(buffer_length = buf.len(), "read requested");
         let stream_position = self.output_reader.stream_position()?;
-        let requested_position = stream_position + buf.len() as u64;
+        let read_len = self.wait_for_read(stream_position, buf.len())?;
+        if read_len == 0 {
+            return Ok(0);
+        }
+        self.output_reader
+            .read(&mut buf[..read_len])
+            .map_err(storage_error)
+            .tap_ok(|l| self.handle.set_read_position(stream_position + *l as u64))
+            .tap(|l| debug!(read_length = format!("{l:?}"), "returning read"))
+    }
+
+    #[instrument(skip_all)]
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let total_len = bufs.iter().map(|buf| buf.len()).sum();
         trace!(
-            current_position = stream_position,
-            requested_position = requested_position
+            buffer_count = bufs.len(),
+            buffer_length = total_len,
+            "vectored read requested"
         );
-
-        if let Some(closest_set) = self.handle.downloaded().get(&stream_position) {
-            trace!(
-                downloaded_range = format!("{closest_set:?}"),
-                "current position already downloaded"
-            );
-            if closest_set.end >= requested_position {
-                return self.output_reader.read(buf).tap(|l| {
-                    trace!(
-                        read_length = format!("{l:?}"),
-                        "requested position already downloaded, returning read"
-                    )
-                });
-            } else {
-                debug!("requested position not yet downloaded");
+        let stream_position = self.output_reader.stream_position()?;
+        // Wait for the whole span so all of the buffers can be filled with a single read
+        let mut remaining = self.wait_for_read(stream_position, total_len)?;
+        if remaining == 0 {
+            return Ok(0);
+        }
+        let mut available = Vec::with_capacity(bufs.len());
+        for buf in bufs.iter_mut() {
+            if remaining == 0 {
+                break;
             }
-        } else {
-            debug!("stream position not yet downloaded");
+            let len = buf.len().min(remaining);
+            available.push(IoSliceMut::new(&mut buf[..len]));
+            remaining -= len;
         }
+        self.output_reader
+            .read_vectored(&mut available)
+            .map_err(storage_error)
+            .tap_ok(|l| self.handle.set_read_position(stream_position + *l as u64))
+            .tap(|l| debug!(read_length = format!("{l:?}"), "returning vectored read"))
+    }
+}
 
-        self.handle.request_position(requested_position);
-        debug!(
-            requested_position = requested_position,
-            "waiting for requested position"
-        );
-        self.handle.wait_for_requested_position();
-        debug!(
-            current_position = stream_position,
-            requested_position = requested_position,
-            output_stream_position = self.output_reader.stream_position()?,
-            "reached requested position"
-        );
+impl<P: StorageProvider> BufRead for StreamDownload<P> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let position = self.output_reader.stream_position()?;
+        // The buffer is discarded whenever the position changes without going through consume
+        if self.read_buf.is_empty() || self.read_buf_start != position {
+            // Only the first byte is waited on so anything that's already available is returned
+            // without waiting for a full buffer
+            let len = match self.wait_for_read(position, 1)? {
+                0 => 0,
+                _ => self.available_at(position).min(FILL_BUF_SIZE as u64) as usize,
+            };
+            let mut buf = vec![0; len];
+            self.output_reader
+                .read_exact(&mut buf)
+                .map_err(storage_error)?;
+            // The position only moves forward once the data is consumed
+            self.output_reader.seek(SeekFrom::Start(position))?;
+            trace!(position, buffered = len, "filled read buffer");
+            self.read_buf = buf.into();
+            self.read_buf_start = position;
+        }
+        Ok(&self.read_buf)
+    }
 
-        self.output_reader
-            .read(buf)
-            .tap(|l| debug!(read_length = format!("{l:?}"), "returning read"))
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.read_buf.len());
+        if amt == 0 {
+            return;
+        }
+        self.read_buf.advance(amt);
+        self.read_buf_start += amt as u64;
+        // The consumed data has already been downloaded, so this never waits
+        match self.output_reader.seek(SeekFrom::Current(amt as i64)) {
+            Ok(position) => self.handle.set_read_position(position),
+            Err(e) => warn!("error moving the read position after consuming data: {e}"),
+        }
     }
 }
 
@@ -290,7 +2526,9 @@ impl<P: StorageProvider> Seek for StreamDownload<P> {
             SeekFrom::End(pos) => {
                 debug!(seek_position = pos, "seeking from end");
                 if let Some(length) = self.handle.content_length() {
-                    (length as i64 - pos) as u64
+                    length
+                        .checked_add_signed(pos)
+                        .ok_or_else(invalid_seek_position)?
                 } else {
                     return Err(io::Error::new(
                         io::ErrorKind::Unsupported,
@@ -300,22 +2538,48 @@ impl<P: StorageProvider> Seek for StreamDownload<P> {
             }
             SeekFrom::Current(pos) => {
                 debug!(seek_position = pos, "seeking from current position");
-                (self.output_reader.stream_position()? as i64 + pos) as u64
+                self.output_reader
+                    .stream_position()?
+                    .checked_add_signed(pos)
+                    .ok_or_else(invalid_seek_position)?
             }
         };
 
         debug!(absolute_seek_pos, "absolute seek position");
-        if let Some(closest_set) = self.handle.downloaded().get(&absolute_seek_pos) {
-            debug!(
-                downloaded_range = format!("{closest_set:?}"),
-                "seek position already downloaded"
-            );
+        let past_end = self
+            .handle
+            .content_length()
+            .is_some_and(|length| absolute_seek_pos >= length);
+        if past_end || self.handle.is_buffered(absolute_seek_pos) {
+            // The data is either already available locally or the position is at the end of the
+            // stream where reads always return EOF, so we only need to move the read cursor.
+            // Don't notify the downloader here since no network activity is required.
+            debug!(past_end, "seek position doesn't need to be downloaded");
             return self
                 .output_reader
                 .seek(SeekFrom::Start(absolute_seek_pos))
+                .tap_ok(|p| self.handle.set_read_position(*p))
                 .tap(|p| debug!(position = format!("{p:?}"), "returning seek position"));
         }
 
+        if !self.handle.seekable() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot seek to a position that hasn't been downloaded because the source does \
+                 not support seeking",
+            ));
+        }
+
+        if self
+            .settings
+            .range_request_limit_reached(self.handle.range_requests())
+        {
+            warn!("range request limit reached, rejecting seek");
+            return Err(TooManyRangeRequests.into());
+        }
+
+        // The downloader uses the read position to check when the requested data is available
+        self.handle.set_read_position(absolute_seek_pos);
         self.handle.request_position(absolute_seek_pos);
         self.handle.seek(absolute_seek_pos);
         debug!(
@@ -327,10 +2591,24 @@ impl<P: StorageProvider> Seek for StreamDownload<P> {
 
         self.output_reader
             .seek(SeekFrom::Start(absolute_seek_pos))
+            .tap_ok(|p| self.handle.set_read_position(*p))
             .tap(|p| debug!(position = format!("{p:?}"), "returning seek position"))
     }
 }
 
+// Matches the default capacity of BufReader
+const FILL_BUF_SIZE: usize = 8 * 1024;
+
+// Keeps chunks from a finished download from copying the whole stream at once
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+fn invalid_seek_position() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+    )
+}
+
 pub(crate) trait WrapIoResult {
     fn wrap_err(self, msg: &str) -> Self;
 }
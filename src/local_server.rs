@@ -0,0 +1,373 @@
+//! Serves a [StreamDownload] over HTTP on localhost.
+//!
+//! This is useful for media frameworks that can only consume URLs. The server supports single
+//! byte range requests, so clients can seek within the stream. Requested ranges are read from the
+//! [StreamDownload], so reads of data that hasn't been downloaded yet wait for the download and
+//! seeks outside of the downloaded data start a new request just like they would for any other
+//! reader.
+//!
+//! Range requests are only supported once the content length is known. Until then, the entire
+//! stream is returned instead.
+//!
+//! Once the length is known, responses include a strong `ETag` that's unique to the server, so
+//! clients can resume with `If-Range`. Range requests with an `If-Range` validator that doesn't
+//! match it, including any date, receive the entire stream.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::result::Result;
+//!
+//! use stream_download::storage::temp::TempStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let reader = StreamDownload::new_http(
+//!         "https://some-cool-url.com/some-file.mp3".parse()?,
+//!         TempStorageProvider::default(),
+//!         Settings::default(),
+//!     )
+//!     .await?;
+//!
+//!     let (url, server) = reader.serve_local().await?;
+//!     println!("serving the stream at {url}");
+//!     // Pass the URL to the player here
+//!     server.shutdown().await?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+
+use bytes::Bytes;
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+use crate::shared::SharedStreamDownload;
+use crate::storage::StorageProvider;
+use crate::StreamDownload;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// URL that a [StreamDownload] is being served at.
+/// Created by [StreamDownload::serve_local].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalUrl {
+    addr: SocketAddr,
+}
+
+impl LocalUrl {
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl fmt::Display for LocalUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}/", self.addr)
+    }
+}
+
+/// Handle to a server started by [StreamDownload::serve_local].
+///
+/// The server stops accepting connections once this is dropped. The [StreamDownload] is dropped
+/// once all open connections have closed.
+#[derive(Debug)]
+pub struct ServerHandle {
+    cancellation_token: CancellationToken,
+    task: JoinHandle<hyper::Result<()>>,
+}
+
+impl ServerHandle {
+    /// Stops the server and waits for any open connections to finish.
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.cancellation_token.cancel();
+        match (&mut self.task).await {
+            Ok(result) => result.map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+    }
+}
+
+pub(crate) async fn serve<P>(reader: StreamDownload<P>) -> io::Result<(LocalUrl, ServerHandle)>
+where
+    P: StorageProvider + 'static,
+{
+    // The standard listener stays in non-blocking mode, which hyper requires
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await?
+        .into_std()?;
+    let addr = listener.local_addr()?;
+
+    let content_type = reader.source_info().content_type;
+    let reader = SharedStreamDownload::new(reader);
+    // Identifies this server's representation of the stream in entity tags
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos());
+    let resource = Arc::new(Resource {
+        id,
+        content_type,
+        reader,
+    });
+    let make_service = make_service_fn(move |_| {
+        let resource = resource.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let resource = resource.clone();
+                async move { Ok::<_, Infallible>(respond(resource, request)) }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .serve(make_service);
+
+    let cancellation_token = CancellationToken::new();
+    let task = tokio::spawn(server.with_graceful_shutdown({
+        let cancellation_token = cancellation_token.clone();
+        async move { cancellation_token.cancelled().await }
+    }));
+    debug!(%addr, "serving stream");
+
+    Ok((
+        LocalUrl { addr },
+        ServerHandle {
+            cancellation_token,
+            task,
+        },
+    ))
+}
+
+struct Resource<P: StorageProvider> {
+    id: u128,
+    content_type: Option<String>,
+    reader: SharedStreamDownload<P>,
+}
+
+impl<P: StorageProvider> Resource<P> {
+    fn etag(&self, content_length: u64) -> String {
+        format!("\"{:x}-{content_length:x}\"", self.id)
+    }
+}
+
+enum RequestedRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+fn respond<P>(resource: Arc<Resource<P>>, request: Request<Body>) -> Response<Body>
+where
+    P: StorageProvider + 'static,
+{
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return with_status(Response::new(Body::empty()), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let content_length = resource.reader.metrics_handle().content_length();
+    let etag = content_length.map(|content_length| resource.etag(content_length));
+    let range = match (content_length, &etag) {
+        (Some(content_length), Some(etag)) if if_range_matches(&request, etag) => request
+            .headers()
+            .get(header::RANGE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(RequestedRange::Full, |value| {
+                requested_range(value, content_length)
+            }),
+        // Ranges can't be resolved without the length
+        _ => RequestedRange::Full,
+    };
+    debug!(
+        method = %request.method(),
+        range = ?request.headers().get(header::RANGE),
+        "received request"
+    );
+
+    let (status, start, end) = match range {
+        RequestedRange::Full => (StatusCode::OK, 0, content_length),
+        RequestedRange::Partial(range) => {
+            (StatusCode::PARTIAL_CONTENT, range.start, Some(range.end))
+        }
+        RequestedRange::Unsatisfiable => {
+            let mut response = with_status(
+                Response::new(Body::empty()),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            );
+            if let Some(content_length) = content_length {
+                insert_header(
+                    &mut response,
+                    header::CONTENT_RANGE,
+                    &format!("bytes */{content_length}"),
+                );
+            }
+            return response;
+        }
+    };
+
+    let body = if request.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        stream_body(resource.clone(), start, end)
+    };
+    let mut response = with_status(Response::new(body), status);
+    if let Some(etag) = &etag {
+        insert_header(&mut response, header::ETAG, etag);
+    }
+    if let Some(content_length) = content_length {
+        insert_header(&mut response, header::ACCEPT_RANGES, "bytes");
+        if status == StatusCode::PARTIAL_CONTENT {
+            let end = end.unwrap_or(content_length);
+            insert_header(
+                &mut response,
+                header::CONTENT_RANGE,
+                &format!("bytes {start}-{}/{content_length}", end - 1),
+            );
+        }
+    }
+    if let Some(end) = end {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+    }
+    if let Some(content_type) = &resource.content_type {
+        insert_header(&mut response, header::CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+fn with_status(mut response: Response<Body>, status: StatusCode) -> Response<Body> {
+    *response.status_mut() = status;
+    response
+}
+
+fn insert_header(response: &mut Response<Body>, name: header::HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        response.headers_mut().insert(name, value);
+    }
+}
+
+// Ranges are only served if there's no If-Range header or it contains the current entity tag.
+// Dates never match since there's no modification time to compare against, and weak entity tags
+// aren't allowed in If-Range.
+fn if_range_matches(request: &Request<Body>, etag: &str) -> bool {
+    request
+        .headers()
+        .get(header::IF_RANGE)
+        .map_or(true, |value| value.as_bytes() == etag.as_bytes())
+}
+
+// Only single ranges are supported. Anything else is ignored and the whole stream is returned,
+// which is allowed by RFC 9110.
+fn requested_range(value: &str, content_length: u64) -> RequestedRange {
+    let Some((start, end)) = value
+        .trim()
+        .strip_prefix("bytes=")
+        .and_then(|range| range.split_once('-'))
+    else {
+        return RequestedRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let parse = |value: &str| value.parse::<u64>().ok();
+
+    let range = match (start.is_empty(), end.is_empty()) {
+        // Suffix range containing the last N bytes
+        (true, false) => match parse(end) {
+            Some(0) => return RequestedRange::Unsatisfiable,
+            Some(suffix) => content_length.saturating_sub(suffix)..content_length,
+            None => return RequestedRange::Full,
+        },
+        (false, true) => match parse(start) {
+            Some(start) => start..content_length,
+            None => return RequestedRange::Full,
+        },
+        (false, false) => match (parse(start), parse(end)) {
+            (Some(start), Some(end)) if start <= end => start..(end + 1).min(content_length),
+            _ => return RequestedRange::Full,
+        },
+        (true, true) => return RequestedRange::Full,
+    };
+
+    if range.start >= content_length {
+        RequestedRange::Unsatisfiable
+    } else {
+        RequestedRange::Partial(range)
+    }
+}
+
+fn stream_body<P>(resource: Arc<Resource<P>>, start: u64, end: Option<u64>) -> Body
+where
+    P: StorageProvider + 'static,
+{
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut position = start;
+        loop {
+            let len = end.map_or(CHUNK_SIZE, |end| {
+                CHUNK_SIZE.min((end - position).try_into().unwrap_or(usize::MAX))
+            });
+            if len == 0 {
+                return;
+            }
+            let resource = resource.clone();
+            let chunk =
+                tokio::task::spawn_blocking(move || read_chunk(&resource.reader, position, len))
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                    .and_then(|chunk| chunk);
+
+            match chunk {
+                Ok(chunk) if chunk.is_empty() => {
+                    if end.is_some_and(|end| position < end) {
+                        error!(position, "stream ended before the requested range");
+                        sender.abort();
+                    }
+                    return;
+                }
+                Ok(chunk) => {
+                    position += chunk.len() as u64;
+                    if sender.send_data(chunk).await.is_err() {
+                        debug!("client disconnected");
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("Error reading from stream: {e:?}");
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+    body
+}
+
+fn read_chunk<P: StorageProvider>(
+    reader: &SharedStreamDownload<P>,
+    position: u64,
+    len: usize,
+) -> io::Result<Bytes> {
+    let mut buf = vec![0; len];
+    let read_len = reader.read_at(position, &mut buf)?;
+    buf.truncate(read_len);
+    Ok(buf.into())
+}
@@ -0,0 +1,291 @@
+//! Harness that replays a sequence of seek and read operations against a [StreamDownload] and
+//! checks the results against the reference data.
+//!
+//! The stream is served from memory by a [MockStream], so the only source of nondeterminism is
+//! the timing between the reader and the download task. This makes it easy to write property
+//! tests or fuzz targets over arbitrary operation sequences, which is where bugs in the
+//! coordination between the read position and the download position tend to show up.
+//!
+//! # Example
+//!
+//! ```
+//! use std::io::SeekFrom;
+//!
+//! use stream_download::replay::{replay, Op};
+//!
+//! let reference = (0..=255).cycle().take(64 * 1024).collect::<Vec<u8>>();
+//! replay(
+//!     &[
+//!         Op::Read(1024),
+//!         Op::Seek(SeekFrom::Start(50_000)),
+//!         Op::Read(4096),
+//!         Op::Seek(SeekFrom::Current(-10_000)),
+//!         Op::Read(100_000),
+//!     ],
+//!     &reference,
+//! );
+//! ```
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use tokio::sync::oneshot;
+
+use crate::source::SourceStream;
+use crate::storage::memory::MemoryStorageProvider;
+use crate::storage::StorageProvider;
+use crate::{Settings, StreamDownload};
+
+/// An operation performed on the [StreamDownload] during a replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// A single call to [read](Read::read) with a buffer of the given length.
+    Read(usize),
+    /// A call to [seek](Seek::seek).
+    Seek(SeekFrom),
+}
+
+/// Describes the data served by a [MockStream].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockSource {
+    data: Bytes,
+    chunk_size: usize,
+}
+
+impl MockSource {
+    /// Creates a new [MockSource] that serves `data` in chunks of 4 KiB.
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Self {
+            data: data.into(),
+            chunk_size: 4096,
+        }
+    }
+
+    /// Sets the size of the chunks returned by the stream.
+    pub fn chunk_size(self, chunk_size: NonZeroUsize) -> Self {
+        Self {
+            chunk_size: chunk_size.get(),
+            ..self
+        }
+    }
+}
+
+/// [SourceStream] that serves data from memory and supports seeking.
+#[derive(Debug)]
+pub struct MockStream {
+    source: MockSource,
+    position: usize,
+    end: usize,
+}
+
+impl Stream for MockStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.position >= self.end {
+            return Poll::Ready(None);
+        }
+        let start = self.position;
+        let end = (start + self.source.chunk_size).min(self.end);
+        self.position = end;
+        Poll::Ready(Some(Ok(self.source.data.slice(start..end))))
+    }
+}
+
+#[async_trait]
+impl SourceStream for MockStream {
+    type Url = MockSource;
+    type StreamError = io::Error;
+
+    async fn create(source: Self::Url) -> io::Result<Self> {
+        let end = source.data.len();
+        Ok(Self {
+            source,
+            position: 0,
+            end,
+        })
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.source.data.len() as u64)
+    }
+
+    async fn seek_range(&mut self, start: u64, end: Option<u64>) -> io::Result<()> {
+        let len = self.source.data.len();
+        self.position = (start as usize).min(len);
+        // The end of the range is inclusive
+        self.end = end.map_or(len, |end| (end as usize).saturating_add(1).min(len));
+        Ok(())
+    }
+}
+
+/// Replays operations against a [StreamDownload] created from a [MockStream].
+/// See [replay] for a shortcut that uses the default configuration.
+#[derive(Debug, Clone)]
+pub struct Replay<P: StorageProvider> {
+    source: MockSource,
+    storage: P,
+    settings: Settings,
+}
+
+impl Replay<MemoryStorageProvider> {
+    /// Creates a new [Replay] that serves `reference` from a [MockStream] and stores it in memory.
+    pub fn new(reference: impl Into<Bytes>) -> Self {
+        Self {
+            source: MockSource::new(reference),
+            storage: MemoryStorageProvider::default(),
+            settings: Settings::default(),
+        }
+    }
+}
+
+impl<P: StorageProvider + 'static> Replay<P> {
+    /// Sets the storage used by the [StreamDownload].
+    pub fn storage<Q: StorageProvider>(self, storage: Q) -> Replay<Q> {
+        Replay {
+            source: self.source,
+            storage,
+            settings: self.settings,
+        }
+    }
+
+    /// Sets the [Settings] used by the [StreamDownload].
+    pub fn settings(self, settings: Settings) -> Self {
+        Self { settings, ..self }
+    }
+
+    /// Sets the size of the chunks returned by the [MockStream].
+    pub fn chunk_size(self, chunk_size: NonZeroUsize) -> Self {
+        Self {
+            source: self.source.chunk_size(chunk_size),
+            ..self
+        }
+    }
+
+    /// Creates a new [StreamDownload], applies `ops` to it in order, and checks each result
+    /// against the reference data.
+    ///
+    /// The download task runs on its own runtime in a background thread, so this must not be
+    /// called from within an async context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any operation returns a different result than reading from the reference data
+    /// would, or if the [StreamDownload] can't be created.
+    pub fn run(&self, ops: &[Op]) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("failed to create runtime");
+        let mut reader = runtime
+            .block_on(StreamDownload::new::<MockStream>(
+                self.source.clone(),
+                self.storage.clone(),
+                self.settings.clone(),
+            ))
+            .expect("failed to create stream");
+
+        // Keep the download task running while the operations block this thread. The runtime
+        // also stops if an operation panics since the sender is dropped.
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let driver = thread::spawn(move || {
+            runtime.block_on(async move {
+                stop_rx.await.ok();
+            });
+        });
+
+        let reference = &self.source.data;
+        let mut position = 0u64;
+        for (i, op) in ops.iter().enumerate() {
+            match *op {
+                Op::Read(len) => {
+                    let mut buf = vec![0; len];
+                    let read_len = reader
+                        .read(&mut buf)
+                        .unwrap_or_else(|e| panic!("op {i} ({op:?}) failed: {e}"));
+                    let available = (reference.len() as u64).saturating_sub(position) as usize;
+                    assert!(
+                        read_len <= len.min(available),
+                        "op {i} ({op:?}) at position {position} read {read_len} bytes, but only \
+                         {} were expected",
+                        len.min(available)
+                    );
+                    assert!(
+                        read_len > 0 || len == 0 || available == 0,
+                        "op {i} ({op:?}) at position {position} returned EOF before the end of \
+                         the stream"
+                    );
+                    let start = position.min(reference.len() as u64) as usize;
+                    assert!(
+                        buf[..read_len] == reference[start..start + read_len],
+                        "op {i} ({op:?}) at position {position} returned data that doesn't match \
+                         the reference"
+                    );
+                    position += read_len as u64;
+                }
+                Op::Seek(seek_from) => {
+                    let expected = expected_position(position, reference.len() as u64, seek_from);
+                    match (reader.seek(seek_from), expected) {
+                        (Ok(new_position), Some(expected)) => {
+                            assert_eq!(
+                                expected, new_position,
+                                "op {i} ({op:?}) from position {position} moved to the wrong \
+                                 position"
+                            );
+                            position = new_position;
+                        }
+                        (Err(e), None) => {
+                            assert_eq!(
+                                io::ErrorKind::InvalidInput,
+                                e.kind(),
+                                "op {i} ({op:?}) from position {position} failed with the wrong \
+                                 error"
+                            );
+                        }
+                        (result, expected) => {
+                            panic!(
+                                "op {i} ({op:?}) from position {position} returned {result:?}, \
+                                 but {expected:?} was expected"
+                            );
+                        }
+                    }
+                }
+            }
+            assert_eq!(
+                position,
+                reader
+                    .stream_position()
+                    .unwrap_or_else(|e| panic!("op {i} ({op:?}) failed: {e}")),
+                "op {i} ({op:?}) left the reader at the wrong position"
+            );
+        }
+
+        drop(reader);
+        stop_tx.send(()).ok();
+        driver.join().expect("download task panicked");
+    }
+}
+
+fn expected_position(position: u64, len: u64, seek_from: SeekFrom) -> Option<u64> {
+    match seek_from {
+        SeekFrom::Start(pos) => Some(pos),
+        SeekFrom::End(pos) => len.checked_add_signed(pos),
+        SeekFrom::Current(pos) => position.checked_add_signed(pos),
+    }
+}
+
+/// Replays `ops` against a [StreamDownload] that downloads `reference` from a [MockStream] into
+/// memory with the default [Settings]. Use [Replay] to change the configuration.
+///
+/// # Panics
+///
+/// Panics if any operation returns a different result than reading from `reference` would.
+pub fn replay(ops: &[Op], reference: &[u8]) {
+    Replay::new(reference.to_vec()).run(ops);
+}
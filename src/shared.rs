@@ -0,0 +1,122 @@
+//! Positional reads from multiple threads.
+//!
+//! [StreamDownload] has a single read cursor, so interleaving [Read](std::io::Read) and
+//! [Seek](std::io::Seek) calls from different threads would cause them to move each other's
+//! position. It requires `&mut self` for reads, so it can't be shared without some form of
+//! locking. [SharedStreamDownload] wraps it in a mutex and combines each seek with the read that
+//! follows it, so reads from different threads can't interfere with each other.
+//!
+//! Reads still wait for the data to be downloaded while holding the lock, so a read of data
+//! that hasn't been downloaded yet blocks other readers until it finishes.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::result::Result;
+//!
+//! use stream_download::shared::SharedStreamDownload;
+//! use stream_download::storage::temp::TempStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     let reader = StreamDownload::new_http(
+//!         "https://some-cool-url.com/some-file.mp3".parse()?,
+//!         TempStorageProvider::default(),
+//!         Settings::default(),
+//!     )
+//!     .await?;
+//!     let reader = SharedStreamDownload::new(reader);
+//!
+//!     let handles: Vec<_> = [0, 4096]
+//!         .into_iter()
+//!         .map(|position| {
+//!             let reader = reader.clone();
+//!             tokio::task::spawn_blocking(move || {
+//!                 let mut buf = [0; 1024];
+//!                 reader.read_exact_at(position, &mut buf).map(|_| buf)
+//!             })
+//!         })
+//!         .collect();
+//!     for handle in handles {
+//!         handle.await??;
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::storage::StorageProvider;
+use crate::{MetricsHandle, StreamDownload};
+
+/// A [StreamDownload] that can be cloned and read from multiple threads.
+/// See the [module-level documentation](self) for details.
+pub struct SharedStreamDownload<P: StorageProvider> {
+    reader: Arc<Mutex<StreamDownload<P>>>,
+    // Kept outside of the lock so it doesn't wait on blocking reads
+    metrics: MetricsHandle,
+}
+
+impl<P: StorageProvider> Clone for SharedStreamDownload<P> {
+    fn clone(&self) -> Self {
+        Self {
+            reader: self.reader.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+// The storage reader isn't required to implement Debug
+impl<P: StorageProvider> fmt::Debug for SharedStreamDownload<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedStreamDownload")
+            .field("metrics", &self.metrics)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P: StorageProvider> SharedStreamDownload<P> {
+    /// Creates a new [SharedStreamDownload] from `reader`.
+    pub fn new(reader: StreamDownload<P>) -> Self {
+        Self {
+            metrics: reader.metrics_handle(),
+            reader: Arc::new(Mutex::new(reader)),
+        }
+    }
+
+    /// Reads from `position` into `buf` and returns the number of bytes read. This behaves like
+    /// a seek followed by a [read](Read::read), but no other reads can happen in between.
+    pub fn read_at(&self, position: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock();
+        reader.seek(SeekFrom::Start(position))?;
+        reader.read(buf)
+    }
+
+    /// Reads exactly enough bytes from `position` to fill `buf`. This behaves like a seek
+    /// followed by [read_exact](Read::read_exact), but no other reads can happen in between.
+    pub fn read_exact_at(&self, position: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut reader = self.reader.lock();
+        reader.seek(SeekFrom::Start(position))?;
+        reader.read_exact(buf)
+    }
+
+    /// Returns a [MetricsHandle] that can be used to monitor the download without waiting on
+    /// any reads.
+    pub fn metrics_handle(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    /// Returns the inner [StreamDownload] if this is the only remaining handle to it.
+    pub fn try_into_inner(self) -> Result<StreamDownload<P>, Self> {
+        let metrics = self.metrics;
+        Arc::try_unwrap(self.reader)
+            .map(Mutex::into_inner)
+            .map_err(|reader| Self { reader, metrics })
+    }
+}
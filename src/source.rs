@@ -1,23 +1,37 @@
 //! Provides the [SourceStream] trait which abstracts over the transport used to
 //! stream remote content.
+use std::collections::VecDeque;
 use std::error::Error;
-use std::io::{self, SeekFrom};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::ops::Range;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use std::{future, mem};
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use futures::future::BoxFuture;
 use futures::{Stream, StreamExt};
-use parking_lot::{Condvar, Mutex, RwLock, RwLockReadGuard};
-use rangemap::RangeSet;
-use tokio::sync::mpsc;
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use tokio::sync::futures::Notified;
+use tokio::sync::{mpsc, watch, Notify};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, instrument, trace};
+use tracing::{debug, error, instrument, trace, warn};
 
-use crate::storage::StorageWriter;
-use crate::Settings;
+use crate::availability::AvailabilityMap;
+use crate::clock::Clock;
+use crate::connection_limit::{self, ConnectionPermit};
+#[cfg(feature = "compression")]
+use crate::decode::ContentDecoder;
+use crate::storage::budget::BudgetRegistration;
+use crate::storage::{LostStorageWriter, StorageWriter};
+use crate::{ContentLengthExceeded, DeadlineExceeded, DownloadError, PrefetchSeek, Settings};
+
+// The download speed is averaged over this window
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+const MAX_SPEED_SAMPLES: usize = 64;
 
 /// Represents a remote resource that can be streamed over the network. Streaming
 /// over http is implemented via the [HttpStream](crate::http::HttpStream)
@@ -33,7 +47,7 @@ pub trait SourceStream:
     type Url: Send;
 
     /// Error type thrown by the underlying stream.
-    type StreamError: Error + Send;
+    type StreamError: Error + Send + Sync + 'static;
 
     /// Creates an instance of the stream.
     async fn create(url: Self::Url) -> io::Result<Self>;
@@ -46,6 +60,124 @@ pub trait SourceStream:
     /// requested range has not been downloaded, so this method should jump to the
     /// requested position in the stream as quickly as possible.
     async fn seek_range(&mut self, start: u64, end: Option<u64>) -> io::Result<()>;
+
+    /// Closes the response that's currently being streamed. This is called before
+    /// [seek_range](SourceStream::seek_range) if [Settings::serialize_requests] is enabled so that
+    /// the previous request is finished before the next one is sent. It's also always called when
+    /// a seek restarts prefetch so the abandoned prefetch request doesn't keep using bandwidth.
+    /// The default implementation does nothing.
+    fn close(&mut self) {}
+
+    /// Returns whether the stream is able to jump to arbitrary positions. If this returns `false`
+    /// after a call to [seek_range](SourceStream::seek_range), the stream is assumed to have
+    /// restarted from the beginning of the resource instead.
+    fn supports_seek(&self) -> bool {
+        true
+    }
+
+    /// Returns whether [seek_range](SourceStream::seek_range) can be used to send a new request.
+    /// If this returns `false`, the stream can only be read once from start to finish, so the
+    /// reader can only seek within data that was already downloaded.
+    /// The default implementation returns `true`.
+    fn supports_restart(&self) -> bool {
+        true
+    }
+
+    /// Requests several ranges of the resource at once. This is only called with more than one
+    /// range if [max_ranges_per_request](SourceStream::max_ranges_per_request) returns a value
+    /// greater than 1. The data for each range should be returned in order, and
+    /// [take_chunk_start](SourceStream::take_chunk_start) should report where each range begins.
+    /// Any ranges that aren't returned are requested again later.
+    /// The default implementation only requests the first range.
+    async fn seek_ranges(&mut self, ranges: &[Range<u64>]) -> io::Result<()> {
+        match ranges.first() {
+            Some(range) => self.seek_range(range.start, Some(range.end)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the maximum number of ranges that can be requested at once with
+    /// [seek_ranges](SourceStream::seek_ranges).
+    /// The default implementation returns 1.
+    fn max_ranges_per_request(&self) -> usize {
+        1
+    }
+
+    /// Returns the position in the resource of the chunk that was most recently returned if it
+    /// doesn't directly follow the previous chunk, such as at the start of each range requested
+    /// with [seek_ranges](SourceStream::seek_ranges).
+    /// The default implementation returns `None`.
+    fn take_chunk_start(&mut self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the position in the resource that the stream starts at. This is normally `0`, but
+    /// it may differ if the initial request only returned part of the resource.
+    fn initial_position(&self) -> u64 {
+        0
+    }
+
+    /// Returns the encoding that the data returned by the stream is compressed with, such as the
+    /// `Content-Encoding` of an HTTP response. This is used to decode the data when
+    /// [Settings::decompression](crate::Settings::decompression) is set to `Manual`.
+    /// The default implementation returns `None`.
+    fn content_encoding(&self) -> Option<&str> {
+        None
+    }
+
+    /// Checks the metadata received after the end of the current response, such as HTTP
+    /// trailers, against the data that was received. This is called each time the stream ends if
+    /// [Settings::validate_trailers](crate::Settings::validate_trailers) is enabled, and an error
+    /// stops the download.
+    /// The default implementation returns `Ok(())`.
+    fn validate_trailers(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns a [SourceInfo] describing the stream. This is captured when the download starts
+    /// and again after each call to [seek_range](SourceStream::seek_range), and can be retrieved
+    /// later with [StreamDownload::source_info](crate::StreamDownload::source_info).
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            supports_seek: self.supports_seek(),
+            content_length: self.content_length(),
+            ..Default::default()
+        }
+    }
+}
+
+// Errors that are already I/O errors are returned as-is so their kind isn't lost
+pub(crate) fn stream_error<E: Error + Send + Sync + 'static>(error: E) -> io::Error {
+    let error: Box<dyn Error + Send + Sync> = Box::new(error);
+    match error.downcast::<io::Error>() {
+        Ok(error) => *error,
+        Err(error) => io::Error::new(io::ErrorKind::Other, error),
+    }
+}
+
+/// Metadata about a [SourceStream] that's available after the stream has been handed off to the
+/// download task.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceInfo {
+    /// The URL that the content is being downloaded from, if applicable.
+    pub url: Option<String>,
+    /// The media type of the content, if known.
+    pub content_type: Option<String>,
+    /// Whether the stream supported seeking when the download started.
+    pub supports_seek: bool,
+    /// The content length reported by the stream, if known.
+    pub content_length: Option<u64>,
+}
+
+impl Default for SourceInfo {
+    fn default() -> Self {
+        Self {
+            url: None,
+            content_type: None,
+            supports_seek: true,
+            content_length: None,
+        }
+    }
 }
 
 #[derive(PartialEq, Eq)]
@@ -55,55 +187,306 @@ enum PrefetchResult {
     EndOfFile,
 }
 
+async fn budget_changed(changed: Pin<&mut Option<Notified<'_>>>) {
+    match changed.as_pin_mut() {
+        Some(changed) => changed.await,
+        None => future::pending().await,
+    }
+}
+
 enum DownloadFinishResult {
     Complete,
     ChunkMissing,
+    GapsRemaining,
+}
+
+// State shared between the download task and the reader
+#[derive(Debug)]
+struct SharedState {
+    downloaded: RwLock<Box<dyn AvailabilityMap>>,
+    requested_position: AtomicI64,
+    position_reached: (Mutex<Waiter>, Condvar),
+    // This can change if the stream turns out to be longer than reported
+    content_length: RwLock<Option<u64>>,
+    // Shared with any downloads that replace this one so subscribers keep receiving updates
+    source_info: Arc<watch::Sender<SourceInfo>>,
+    seekable: AtomicBool,
+    seek_tx: mpsc::Sender<u64>,
+    read_position: AtomicU64,
+    write_position: AtomicU64,
+    reader_notify: Notify,
+    reconnect_notify: Notify,
+    prefetch_complete: AtomicBool,
+    // Can be changed while the download is running
+    prefetch_bytes: AtomicU64,
+    paused: AtomicBool,
+    stall_count: AtomicU64,
+    stall_duration_nanos: AtomicU64,
+    stall_recoveries: AtomicU64,
+    // Bytes per second, or 0 if it hasn't been measured
+    prefetch_throughput: AtomicU64,
+    // Bytes received from the stream, which can differ from the bytes written to storage
+    bytes_received: AtomicU64,
+    bytes_on_disk: AtomicU64,
+    range_requests: AtomicUsize,
+    // Recent (time, total bytes received) samples used to measure the download speed
+    speed_samples: Mutex<VecDeque<(Instant, u64)>>,
+    clock: Arc<dyn Clock>,
+    download_error: Mutex<Option<DownloadError>>,
+    budget: Option<BudgetRegistration>,
+    // Notified whenever more data is downloaded. Set to true once the download task exits.
+    progress: watch::Sender<bool>,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct SourceHandle {
-    downloaded: Arc<RwLock<RangeSet<u64>>>,
-    requested_position: Arc<AtomicI64>,
-    position_reached: Arc<(Mutex<Waiter>, Condvar)>,
-    content_length: Option<u64>,
-    seek_tx: mpsc::Sender<u64>,
+    shared: Arc<SharedState>,
 }
 
 impl SourceHandle {
-    pub fn downloaded(&self) -> RwLockReadGuard<rangemap::RangeSet<u64>> {
-        self.downloaded.read()
+    pub fn downloaded(&self) -> RwLockReadGuard<Box<dyn AvailabilityMap>> {
+        self.shared.downloaded.read()
+    }
+
+    /// Replaces the downloaded ranges and returns the previous ones.
+    pub fn take_downloaded(
+        &self,
+        replacement: Box<dyn AvailabilityMap>,
+    ) -> Box<dyn AvailabilityMap> {
+        mem::replace(&mut *self.shared.downloaded.write(), replacement)
+    }
+
+    /// Returns whether the reader can move to `position` without any network activity.
+    pub fn is_buffered(&self, position: u64) -> bool {
+        self.shared.downloaded.read().contains(position)
     }
 
     pub fn request_position(&self, position: u64) {
-        self.requested_position
+        self.shared
+            .requested_position
             .store(position as i64, Ordering::SeqCst);
+        // The downloader may be paused due to the read-ahead limit
+        self.shared.reader_notify.notify_one();
+    }
+
+    pub fn read_position(&self) -> u64 {
+        self.shared.read_position.load(Ordering::SeqCst)
+    }
+
+    pub fn write_position(&self) -> u64 {
+        self.shared.write_position.load(Ordering::SeqCst)
+    }
+
+    pub fn requested_position(&self) -> Option<u64> {
+        let position = self.shared.requested_position.load(Ordering::SeqCst);
+        (position > -1).then_some(position as u64)
+    }
+
+    pub fn set_read_position(&self, position: u64) {
+        self.shared.read_position.store(position, Ordering::SeqCst);
+        if let Some(budget) = &self.shared.budget {
+            budget.mark_read();
+        }
+        self.shared.reader_notify.notify_one();
+    }
+
+    /// Waits for the requested position like
+    /// [wait_for_requested_position](Self::wait_for_requested_position), but gives up once the
+    /// deadline passes. Returns `false` if the deadline passed, in which case the request is
+    /// withdrawn.
+    pub fn wait_for_requested_position_until(&self, deadline: Instant) -> bool {
+        let (mutex, cvar) = &self.shared.position_reached;
+        let mut waiter = mutex.lock();
+        Waiter::wait_until(&mut waiter, cvar, deadline);
+        if waiter.is_blocked() {
+            if self.shared.requested_position.swap(-1, Ordering::SeqCst) > -1 {
+                debug!("deadline passed before reaching the requested position");
+                return false;
+            }
+            // The downloader reached the position just as the deadline passed, so it's about to
+            // notify us
+            Waiter::wait(&mut waiter, cvar);
+        }
+        if !waiter.stream_done {
+            waiter.position_reached = false;
+        }
+        true
     }
 
     pub fn wait_for_requested_position(&self) {
-        let (mutex, cvar) = &*self.position_reached;
+        let (mutex, cvar) = &self.shared.position_reached;
         let mut waiter = mutex.lock();
         if !waiter.stream_done {
             let wait_start = Instant::now();
+            // Only count this as a stall if we actually need to wait for the downloader
+            let stalled = !waiter.position_reached;
             debug!("waiting for requested position");
-            cvar.wait_while(&mut waiter, |waiter| {
-                !waiter.stream_done && !waiter.position_reached
-            });
+            Waiter::wait(&mut waiter, cvar);
             if !waiter.stream_done {
                 waiter.position_reached = false;
             }
-            debug!(
-                elapsed = format!("{:?}", wait_start.elapsed()),
-                "position reached"
-            );
+            let elapsed = wait_start.elapsed();
+            if stalled {
+                self.shared.stall_count.fetch_add(1, Ordering::Relaxed);
+                self.shared
+                    .stall_duration_nanos
+                    .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+            }
+            debug!(elapsed = format!("{elapsed:?}"), "position reached");
         }
     }
 
+    pub fn stall_count(&self) -> u64 {
+        self.shared.stall_count.load(Ordering::Relaxed)
+    }
+
+    pub fn stall_duration(&self) -> Duration {
+        Duration::from_nanos(self.shared.stall_duration_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn prefetch_throughput(&self) -> Option<u64> {
+        let throughput = self.shared.prefetch_throughput.load(Ordering::Relaxed);
+        (throughput > 0).then_some(throughput)
+    }
+
+    pub fn stall_recoveries(&self) -> u64 {
+        self.shared.stall_recoveries.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.shared.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_on_disk(&self) -> u64 {
+        self.shared.bytes_on_disk.load(Ordering::Relaxed)
+    }
+
+    pub fn download_speed(&self) -> Option<f64> {
+        if self.download_complete() {
+            return None;
+        }
+        let samples = self.shared.speed_samples.lock();
+        let (&(start, first), &(_, last)) = (samples.front()?, samples.back()?);
+        // Measuring up to the current time makes the speed drop while the download is stalled
+        let elapsed = self.shared.clock.now().saturating_duration_since(start);
+        if samples.len() < 2 || elapsed.is_zero() {
+            return None;
+        }
+        Some((last - first) as f64 / elapsed.as_secs_f64())
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let content_length = self.content_length()?;
+        let remaining: u64 = self
+            .downloaded()
+            .gaps(0..content_length)
+            .map(|gap| gap.end - gap.start)
+            .sum();
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        let speed = self.download_speed().filter(|speed| *speed > 0.0)?;
+        Some(Duration::from_secs_f64(remaining as f64 / speed))
+    }
+
     pub fn seek(&self, position: u64) {
-        self.seek_tx.try_send(position).ok();
+        self.shared.seek_tx.try_send(position).ok();
     }
 
     pub fn content_length(&self) -> Option<u64> {
-        self.content_length
+        *self.shared.content_length.read()
+    }
+
+    pub fn source_info(&self) -> SourceInfo {
+        self.shared.source_info.borrow().clone()
+    }
+
+    pub fn source_info_sender(&self) -> Arc<watch::Sender<SourceInfo>> {
+        self.shared.source_info.clone()
+    }
+
+    pub fn range_requests(&self) -> usize {
+        self.shared.range_requests.load(Ordering::SeqCst)
+    }
+
+    pub fn seekable(&self) -> bool {
+        self.shared.seekable.load(Ordering::SeqCst)
+    }
+
+    pub fn can_seek(&self) -> bool {
+        self.seekable() && self.shared.source_info.borrow().supports_seek
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.shared.paused.store(paused, Ordering::SeqCst);
+        self.shared.reader_notify.notify_one();
+    }
+
+    pub fn reconnect(&self) {
+        self.shared.reconnect_notify.notify_one();
+    }
+
+    pub fn set_prefetch_bytes(&self, prefetch_bytes: u64) {
+        self.shared
+            .prefetch_bytes
+            .store(prefetch_bytes, Ordering::SeqCst);
+    }
+
+    pub fn prefetch_complete(&self) -> bool {
+        self.shared.prefetch_complete.load(Ordering::SeqCst)
+    }
+
+    pub fn download_complete(&self) -> bool {
+        self.shared.position_reached.0.lock().stream_done
+    }
+
+    pub fn reader_notifications(&self) -> u64 {
+        self.shared.position_reached.0.lock().notifications
+    }
+
+    /// Records the error that stopped the download and returns it wrapped in a [DownloadError].
+    pub fn set_download_error(&self, error: io::Error) -> io::Error {
+        // The error may have already been recorded before it was returned from the task
+        if error.get_ref().is_some_and(|e| e.is::<DownloadError>()) {
+            return error;
+        }
+        let error = DownloadError::new(error);
+        *self.shared.download_error.lock() = Some(error.clone());
+        error.into()
+    }
+
+    pub fn download_error(&self) -> Option<DownloadError> {
+        self.shared.download_error.lock().clone()
+    }
+
+    /// Marks the download task as finished. Any error must be set before this is called.
+    pub fn set_task_finished(&self) {
+        // The task may have stopped because of an error without completing the download, so make
+        // sure the reader doesn't wait on data that will never arrive
+        self.shared
+            .notify_waiters(|waiter| waiter.stream_done = true);
+        self.shared.progress.send_replace(true);
+    }
+
+    pub async fn wait_for_position(&self, position: u64) -> io::Result<()> {
+        // Subscribe before checking so no updates are missed in between
+        let mut progress = self.shared.progress.subscribe();
+        loop {
+            if self.is_buffered(position) {
+                return Ok(());
+            }
+            if self.download_complete() || *progress.borrow_and_update() {
+                return Err(match self.download_error() {
+                    Some(e) => e.into(),
+                    None => io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the download finished without reaching the position",
+                    ),
+                });
+            }
+            // The sender lives as long as the shared state, so this can't fail
+            progress.changed().await.ok();
+        }
     }
 }
 
@@ -111,34 +494,129 @@ impl SourceHandle {
 struct Waiter {
     position_reached: bool,
     stream_done: bool,
+    // Number of threads blocked on the condvar
+    waiting: usize,
+    // Number of times the condvar was notified
+    notifications: u64,
+}
+
+impl Waiter {
+    fn is_blocked(&self) -> bool {
+        !self.stream_done && !self.position_reached
+    }
+
+    fn wait(waiter: &mut MutexGuard<Waiter>, cvar: &Condvar) {
+        waiter.waiting += 1;
+        cvar.wait_while(waiter, |waiter| waiter.is_blocked());
+        waiter.waiting -= 1;
+    }
+
+    fn wait_until(waiter: &mut MutexGuard<Waiter>, cvar: &Condvar, deadline: Instant) {
+        waiter.waiting += 1;
+        cvar.wait_while_until(waiter, |waiter| waiter.is_blocked(), deadline);
+        waiter.waiting -= 1;
+    }
+}
+
+impl SharedState {
+    // Updates the waiter state and wakes up any readers that are blocked on it. Readers check the
+    // state before blocking, so the condvar is only notified if one is actually waiting.
+    fn notify_waiters(&self, update: impl FnOnce(&mut Waiter)) {
+        let (mutex, cvar) = &self.position_reached;
+        let mut waiter = mutex.lock();
+        update(&mut waiter);
+        if waiter.waiting > 0 {
+            waiter.notifications += 1;
+            cvar.notify_all();
+        }
+    }
 }
 
 pub(crate) struct Source<W: StorageWriter> {
-    writer: W,
-    downloaded: Arc<RwLock<RangeSet<u64>>>,
-    requested_position: Arc<AtomicI64>,
-    position_reached: Arc<(Mutex<Waiter>, Condvar)>,
-    content_length: Option<u64>,
-    seek_tx: mpsc::Sender<u64>,
+    writer: LostStorageWriter<W>,
+    shared: Arc<SharedState>,
     seek_rx: mpsc::Receiver<u64>,
+    unflushed_start: Option<u64>,
+    prefetch_start: u64,
+    prefetch_start_time: Instant,
+    range_end: Option<u64>,
+    // Set once the chunk transform changes the length of a chunk, after which positions in
+    // storage no longer match positions in the source
+    length_transformed: bool,
+    // Number of retries since the stream last delivered data
+    retry_attempt: u32,
+    #[cfg(feature = "compression")]
+    decoder: Option<ContentDecoder>,
     settings: Settings,
 }
 
 impl<H: StorageWriter> Source<H> {
-    pub(crate) fn new(writer: H, content_length: Option<u64>, settings: Settings) -> Self {
+    pub(crate) fn new(
+        writer: LostStorageWriter<H>,
+        content_length: Option<u64>,
+        downloaded: Box<dyn AvailabilityMap>,
+        source_info: Arc<watch::Sender<SourceInfo>>,
+        seekable: bool,
+        settings: Settings,
+    ) -> Self {
         let (seek_tx, seek_rx) = mpsc::channel(32);
         Self {
             writer,
-            downloaded: Default::default(),
-            requested_position: Arc::new(AtomicI64::new(-1)),
-            position_reached: Default::default(),
-            seek_tx,
+            shared: Arc::new(SharedState {
+                downloaded: RwLock::new(downloaded),
+                requested_position: AtomicI64::new(-1),
+                position_reached: Default::default(),
+                content_length: RwLock::new(content_length),
+                source_info,
+                seekable: AtomicBool::new(seekable),
+                seek_tx,
+                read_position: Default::default(),
+                write_position: Default::default(),
+                reader_notify: Default::default(),
+                reconnect_notify: Default::default(),
+                // Don't start prefetch if it's set to 0
+                prefetch_complete: AtomicBool::new(settings.prefetch_bytes == 0),
+                prefetch_bytes: AtomicU64::new(settings.prefetch_bytes),
+                paused: Default::default(),
+                stall_count: Default::default(),
+                stall_duration_nanos: Default::default(),
+                stall_recoveries: Default::default(),
+                prefetch_throughput: Default::default(),
+                bytes_received: Default::default(),
+                bytes_on_disk: Default::default(),
+                range_requests: Default::default(),
+                speed_samples: Default::default(),
+                clock: settings.get_clock(),
+                download_error: Default::default(),
+                budget: settings
+                    .disk_budget
+                    .as_ref()
+                    .map(|budget| budget.register(settings.get_clock())),
+                progress: watch::channel(false).0,
+            }),
             seek_rx,
-            content_length,
+            unflushed_start: None,
+            prefetch_start: 0,
+            prefetch_start_time: Instant::now(),
+            range_end: None,
+            length_transformed: false,
+            retry_attempt: 0,
+            #[cfg(feature = "compression")]
+            decoder: None,
             settings,
         }
     }
 
+    // Decodes the data before it's stored. Positions in storage won't match positions in the
+    // source, so the stream can't be restarted.
+    #[cfg(feature = "compression")]
+    pub(crate) fn decode_with(mut self, decoder: ContentDecoder) -> Self {
+        self.decoder = Some(decoder);
+        self.length_transformed = true;
+        self.shared.seekable.store(false, Ordering::SeqCst);
+        self
+    }
+
     #[instrument(skip_all)]
     pub(crate) async fn download<S: SourceStream>(
         mut self,
@@ -148,43 +626,115 @@ impl<H: StorageWriter> Source<H> {
         debug!("starting file download");
 
         let download_start = Instant::now();
+        self.prefetch_start_time = self.settings.get_clock().now();
+        self.record_speed_sample(0);
 
-        // Don't start prefetch if it's set to 0
-        let mut prefetch_complete = self.settings.prefetch_bytes == 0;
+        let initial_position = stream.initial_position();
+        if initial_position > 0 {
+            debug!(
+                initial_position,
+                "stream does not start at the beginning of the resource"
+            );
+            self.writer.seek(SeekFrom::Start(initial_position))?;
+            self.shared
+                .write_position
+                .store(initial_position, Ordering::SeqCst);
+            self.prefetch_start = initial_position;
+        }
+        self.report_content_length(*self.shared.content_length.read());
+
+        // Released when the download task finishes
+        let _connection = self
+            .acquire_connection(&mut stream, &cancellation_token)
+            .await?;
+        if cancellation_token.is_cancelled() {
+            debug!("received cancellation request while waiting for a connection");
+            return Ok(());
+        }
+
+        let mut deadline = match self.settings.total_timeout {
+            Some(total_timeout) => self.settings.get_clock().sleep(total_timeout),
+            None => Box::pin(future::pending()),
+        };
+        let mut stall_check = self.stall_check();
+        let mut last_write_position = self.shared.write_position.load(Ordering::SeqCst);
+        let mut prefetch_complete = self.shared.prefetch_complete.load(Ordering::SeqCst);
+        // Set when the stream has finished but some parts haven't been downloaded because gap
+        // filling is disabled. Missing parts are only downloaded once the reader needs them.
+        let mut waiting_for_reader = false;
+        let shared = self.shared.clone();
         loop {
+            // Listen for budget updates before checking the budget, otherwise an update that
+            // happens before the listener is first polled would be missed
+            let budget_update = shared.budget.as_ref().map(BudgetRegistration::changed);
+            tokio::pin!(budget_update);
+            if let Some(budget_update) = budget_update.as_mut().as_pin_mut() {
+                budget_update.enable();
+            }
+            // The read-ahead limit doesn't apply during prefetch since the reader is waiting for
+            // prefetch to finish
+            let paused = self.paused_by_user()
+                || (prefetch_complete && (self.read_ahead_exceeded()? || self.budget_exceeded()));
             tokio::select! {
-                bytes = stream.next() => {
+                bytes = stream.next(), if !paused && !waiting_for_reader => {
                     let bytes = match bytes {
                         Some(Err(e)) => {
                             error!("Error fetching chunk from stream: {e:?}");
+                            if self.settings.get_backoff().is_some() {
+                                if !prefetch_complete {
+                                    debug!("retrying during prefetch, ending prefetch early");
+                                    self.end_prefetch()?;
+                                    prefetch_complete = true;
+                                }
+                                self.retry(&mut stream, stream_error(e), &cancellation_token)
+                                    .await?;
+                            }
                             continue;
                         },
                         Some(Ok(bytes)) => {
                             trace!(chunk_size=bytes.len());
+                            self.retry_attempt = 0;
+                            self.shared
+                                .bytes_received
+                                .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                            self.record_speed_sample(bytes.len() as u64);
                             Some(bytes)
                         },
-                        None => None,
+                        None => {
+                            if self.settings.validate_trailers {
+                                stream.validate_trailers()?;
+                            }
+                            self.finish_decoding()?;
+                            None
+                        },
                     };
 
                     if prefetch_complete {
                         if let Some(bytes) = bytes {
+                            if let Some(chunk_start) = stream.take_chunk_start() {
+                                self.move_writer(chunk_start)?;
+                            }
                             self.handle_response_chunk(bytes)?;
                         } else {
                             debug!(
                                 download_duration = format!("{:?}", download_start.elapsed()),
                                 "stream finished downloading"
                             );
-                            match self.download_finish(&mut stream, self.content_length).await? {
+                            match self.download_finish(&mut stream).await? {
                                 DownloadFinishResult::ChunkMissing => {
                                     continue;
                                 },
                                 DownloadFinishResult::Complete => {
                                     return Ok(());
                                 },
+                                DownloadFinishResult::GapsRemaining => {
+                                    debug!("waiting for the reader to request missing data");
+                                    waiting_for_reader = !self.download_requested_gap(&mut stream).await?;
+                                },
                             }
                         }
                     } else {
-                        match self.prefetch(bytes).await? {
+                        match self.prefetch(&mut stream, bytes).await? {
                             PrefetchResult::Continue => { },
                             PrefetchResult::Complete => {
                                 debug!(
@@ -207,44 +757,194 @@ impl<H: StorageWriter> Source<H> {
                 pos = self.seek_rx.recv() => {
                     if let Some(pos) = pos {
                         debug!(position = pos, "received seek position");
-                        if self.should_seek(pos)? {
+                        let pos = self.debounce_seek(pos).await;
+                        let pos = self.shared.downloaded.read().fetch_start(pos);
+                        self.flush()?;
+                        // The stream is only still active if it hasn't reached the end yet
+                        let stream_active = prefetch_complete && !waiting_for_reader;
+                        if self.should_seek(pos, stream_active)? {
                             debug!("seek position not yet downloaded");
-                            if !prefetch_complete {
-                                debug!("seeking during prefetch, ending prefetch early");
-                                prefetch_complete = true;
+                            if self.range_request_limit_reached() {
+                                // The reader checks the limit before seeking, so this can only
+                                // happen if a gap was filled in the meantime
+                                warn!("range request limit reached, stopping download");
+                                self.complete_download();
+                                return Ok(());
                             }
-
-                            self.seek(&mut stream, pos, None).await?;
+                            if prefetch_complete {
+                                self.seek(&mut stream, pos, None).await?;
+                            } else if self.within_prefetch_window(pos)? {
+                                debug!("seek position is within the prefetch window, continuing prefetch");
+                            } else {
+                                debug!("seeking during prefetch, restarting prefetch");
+                                self.restart_prefetch(&mut stream, pos).await?;
+                            }
+                            waiting_for_reader = false;
                         }
                     }
                 },
+                _ = self.shared.reader_notify.notified(), if paused || waiting_for_reader => {
+                    trace!("reader position updated");
+                    if waiting_for_reader {
+                        waiting_for_reader = !self.download_requested_gap(&mut stream).await?;
+                    }
+                },
+                _ = budget_changed(budget_update.as_mut()), if paused => {
+                    trace!("disk budget updated");
+                },
+                _ = self.shared.reconnect_notify.notified() => {
+                    // Nothing to do if the stream already finished
+                    if !waiting_for_reader {
+                        if !prefetch_complete {
+                            debug!("reconnecting during prefetch, ending prefetch early");
+                            self.end_prefetch()?;
+                            prefetch_complete = true;
+                        }
+                        self.reconnect(&mut stream).await?;
+                    }
+                },
+                _ = &mut stall_check => {
+                    let write_position = self.shared.write_position.load(Ordering::SeqCst);
+                    let reader_waiting =
+                        self.shared.requested_position.load(Ordering::SeqCst) > -1;
+                    // Only a stream that's expected to be delivering data can be stalled
+                    if write_position == last_write_position
+                        && reader_waiting
+                        && !paused
+                        && !waiting_for_reader
+                    {
+                        warn!(write_position, "download stalled, reconnecting");
+                        if !prefetch_complete {
+                            self.end_prefetch()?;
+                            prefetch_complete = true;
+                        }
+                        if self.reconnect(&mut stream).await? {
+                            self.shared.stall_recoveries.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    last_write_position = self.shared.write_position.load(Ordering::SeqCst);
+                    stall_check = self.stall_check();
+                },
                 _ = cancellation_token.cancelled() => {
                     debug!("received cancellation request, stopping download task");
-                    self.writer.flush()?;
+                    if !prefetch_complete {
+                        self.end_prefetch()?;
+                    }
+                    self.flush()?;
                     self.complete_download();
                     return Ok(());
                 }
+                _ = &mut deadline => {
+                    warn!("total timeout exceeded, stopping download task");
+                    if !prefetch_complete {
+                        self.end_prefetch()?;
+                    }
+                    self.flush()?;
+                    // The error needs to be visible by the time the reader is notified
+                    let error = self
+                        .source_handle()
+                        .set_download_error(DeadlineExceeded.into());
+                    self.complete_download();
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    fn stall_check(&self) -> BoxFuture<'static, ()> {
+        match self.settings.get_stall_timeout() {
+            Some(stall_timeout) => self.settings.get_clock().sleep(stall_timeout),
+            None => Box::pin(future::pending()),
+        }
+    }
+
+    // Waits for the backoff delay and restarts the stream from the current position, returning
+    // the last error once the backoff strategy stops retrying
+    async fn retry<S: SourceStream>(
+        &mut self,
+        stream: &mut S,
+        mut error: io::Error,
+        cancellation_token: &CancellationToken,
+    ) -> io::Result<()> {
+        let Some(backoff) = self.settings.get_backoff() else {
+            return Ok(());
+        };
+        loop {
+            self.retry_attempt += 1;
+            let Some(delay) = backoff.next_delay(self.retry_attempt) else {
+                warn!(attempts = self.retry_attempt - 1, "no retries remaining");
+                return Err(error);
+            };
+            debug!(attempt = self.retry_attempt, delay = ?delay, "retrying after error");
+            tokio::select! {
+                _ = self.settings.get_clock().sleep(delay) => {},
+                // The main loop handles the cancellation
+                _ = cancellation_token.cancelled() => return Ok(()),
+            }
+            match self.reconnect(stream).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    warn!("error reconnecting: {e}");
+                    error = e;
+                }
             }
         }
     }
 
-    async fn prefetch(&mut self, bytes: Option<Bytes>) -> io::Result<PrefetchResult> {
+    async fn debounce_seek(&mut self, mut pos: u64) -> u64 {
+        let debounce = self.settings.seek_debounce;
+        if debounce.is_zero() {
+            return pos;
+        }
+        // Only the most recent seek matters, so skip over any that arrive within the window
+        let clock = self.settings.get_clock();
+        loop {
+            let next_pos = tokio::select! {
+                next_pos = self.seek_rx.recv() => next_pos,
+                _ = clock.sleep(debounce) => None,
+            };
+            let Some(next_pos) = next_pos else {
+                return pos;
+            };
+            debug!(
+                previous_position = pos,
+                position = next_pos,
+                "received another seek position, discarding the previous one"
+            );
+            pos = next_pos;
+        }
+    }
+
+    async fn prefetch<S: SourceStream>(
+        &mut self,
+        stream: &mut S,
+        bytes: Option<Bytes>,
+    ) -> io::Result<PrefetchResult> {
         if let Some(bytes) = bytes {
-            self.writer.write_all(&bytes)?;
+            let written = self.write_chunk(bytes)?;
             self.writer.flush()?;
             let stream_position = self.writer.stream_position()?;
+            self.shared
+                .write_position
+                .store(stream_position, Ordering::SeqCst);
+            self.check_content_length(stream_position - written, stream_position)?;
+            let prefetched = stream_position - self.prefetch_start;
+            let prefetch_target = self.prefetch_target(prefetched);
             trace!(
                 stream_position = stream_position,
-                prefetch_target = self.settings.prefetch_bytes,
+                prefetch_target,
                 progress = format!(
                     "{:.2}%",
-                    (stream_position as f32 / self.settings.prefetch_bytes as f32) * 100.0
+                    (prefetched as f32 / prefetch_target as f32) * 100.0
                 ),
                 "prefetch"
             );
 
-            if stream_position >= self.settings.prefetch_bytes {
-                self.downloaded.write().insert(0..stream_position);
+            if prefetched >= prefetch_target {
+                self.add_downloaded(self.prefetch_start..stream_position)?;
+                // Anything woken up by the data should also see that prefetch is complete
+                self.shared.prefetch_complete.store(true, Ordering::SeqCst);
+                self.notify_downloaded(stream_position);
                 Ok(PrefetchResult::Complete)
             } else {
                 Ok(PrefetchResult::Continue)
@@ -252,40 +952,185 @@ impl<H: StorageWriter> Source<H> {
         } else {
             debug!("file shorter than prefetch length, download finished");
             self.writer.flush()?;
-            self.downloaded
-                .write()
-                .insert(0..self.writer.stream_position()?);
+            let stream_position = self.writer.stream_position()?;
+            if stream_position > self.prefetch_start {
+                // The reader isn't woken up until we know whether the start of the resource
+                // still needs to be downloaded, otherwise it could request it a second time
+                self.add_downloaded(self.prefetch_start..stream_position)?;
+                self.shared.progress.send_modify(|_| {});
+            }
+            self.shared.prefetch_complete.store(true, Ordering::SeqCst);
+            let gap = self
+                .shared
+                .downloaded
+                .read()
+                .gaps(0..self.prefetch_start)
+                .next();
+            if let Some(gap) = gap {
+                // The beginning of the resource was never requested, so it still needs to be
+                // downloaded before we can finish
+                debug!("downloading the start of the resource");
+                self.seek(stream, gap.start, Some(self.prefetch_start))
+                    .await?;
+                return Ok(PrefetchResult::Complete);
+            }
+            self.report_final_length()?;
             self.complete_download();
             Ok(PrefetchResult::EndOfFile)
         }
     }
 
+    fn prefetch_target(&self, prefetched: u64) -> u64 {
+        let prefetch_bytes = self.shared.prefetch_bytes.load(Ordering::SeqCst);
+        let Some(adaptive_prefetch) = &self.settings.adaptive_prefetch else {
+            return prefetch_bytes;
+        };
+        let elapsed = self.settings.get_clock().now() - self.prefetch_start_time;
+        let throughput = if elapsed.is_zero() {
+            None
+        } else {
+            let throughput = (prefetched as f64 / elapsed.as_secs_f64()) as u64;
+            self.shared
+                .prefetch_throughput
+                .store(throughput, Ordering::Relaxed);
+            Some(throughput)
+        };
+        let target = adaptive_prefetch.target(throughput, prefetch_bytes);
+        trace!(throughput, target, "adjusted prefetch target");
+        target
+    }
+
+    fn within_prefetch_window(&mut self, pos: u64) -> io::Result<bool> {
+        if self.settings.prefetch_seek == PrefetchSeek::Restart || pos < self.prefetch_start {
+            return Ok(false);
+        }
+        let write_position = self.writer.stream_position()?;
+        if pos - self.prefetch_start >= self.prefetch_target(write_position - self.prefetch_start) {
+            return Ok(false);
+        }
+        if pos <= write_position {
+            return Ok(true);
+        }
+        // Continuing would download any data that's already available again
+        let skipped = self
+            .shared
+            .downloaded
+            .read()
+            .gaps(write_position..pos)
+            .next();
+        Ok(skipped == Some(write_position..pos))
+    }
+
+    async fn restart_prefetch<S: SourceStream>(
+        &mut self,
+        stream: &mut S,
+        pos: u64,
+    ) -> io::Result<()> {
+        self.end_prefetch()?;
+        // Cancel the prefetch request so it doesn't compete with the new one for bandwidth
+        stream.close();
+        self.seek(stream, pos, None).await?;
+        self.prefetch_start = self.writer.stream_position()?;
+        self.prefetch_start_time = self.settings.get_clock().now();
+        self.shared.prefetch_complete.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn end_prefetch(&mut self) -> io::Result<()> {
+        // Prefetched data is normally marked as downloaded once the prefetch target is reached,
+        // so we need to mark it here if prefetch was interrupted
+        let position = self.writer.stream_position()?;
+        if position > self.prefetch_start {
+            self.mark_downloaded(self.prefetch_start..position)?;
+        }
+        self.shared.prefetch_complete.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
     async fn download_finish<S: SourceStream>(
         &mut self,
         stream: &mut S,
-        content_length: Option<u64>,
     ) -> io::Result<DownloadFinishResult> {
+        self.flush()?;
+        let content_length = *self.shared.content_length.read();
         if let Some(content_length) = content_length {
             let gap = self.get_download_gap(content_length);
-            if let Some(gap) = gap {
-                debug!(
-                    missing = format!("{gap:?}"),
-                    "downloading missing stream chunk"
+            if gap.is_some() && !self.settings.fill_gaps {
+                return Ok(DownloadFinishResult::GapsRemaining);
+            }
+            if gap.is_some() && self.range_request_limit_reached() {
+                warn!("range request limit reached, skipping the remaining missing chunks");
+            } else if let Some(gap) = gap {
+                let gap = self.coalesce_gap(gap, content_length);
+                let gaps = self.following_gaps(
+                    gap.clone(),
+                    content_length,
+                    stream.max_ranges_per_request(),
                 );
-                self.seek(stream, gap.start, Some(gap.end)).await?;
+                if gaps.len() > 1 {
+                    debug!(
+                        missing = format!("{gaps:?}"),
+                        "downloading missing stream chunks"
+                    );
+                    self.seek_ranges(stream, &gaps).await?;
+                } else {
+                    debug!(
+                        missing = format!("{gap:?}"),
+                        "downloading missing stream chunk"
+                    );
+                    self.seek(stream, gap.start, Some(gap.end)).await?;
+                }
                 return Ok(DownloadFinishResult::ChunkMissing);
             }
         }
-        self.writer.flush()?;
+        self.report_final_length()?;
         self.complete_download();
         Ok(DownloadFinishResult::Complete)
     }
 
+    async fn download_requested_gap<S: SourceStream>(
+        &mut self,
+        stream: &mut S,
+    ) -> io::Result<bool> {
+        let requested = self.shared.requested_position.load(Ordering::SeqCst);
+        let Some(content_length) = *self.shared.content_length.read() else {
+            return Ok(false);
+        };
+        if requested < 0 {
+            return Ok(false);
+        }
+        let read_position = self.shared.read_position.load(Ordering::SeqCst);
+        let gap = self
+            .shared
+            .downloaded
+            .read()
+            .gaps(read_position..content_length)
+            .next()
+            .filter(|gap| (gap.start as i64) < requested);
+        if gap.is_some() && self.range_request_limit_reached() {
+            // Let the reader know that the data will never arrive
+            warn!("range request limit reached, unable to download requested chunk");
+            self.complete_download();
+        } else if let Some(gap) = gap {
+            let gap = self.coalesce_gap(gap, content_length);
+            debug!(
+                missing = format!("{gap:?}"),
+                "downloading missing stream chunk requested by the reader"
+            );
+            self.seek(stream, gap.start, Some(gap.end)).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
     fn handle_response_chunk(&mut self, bytes: Bytes) -> io::Result<()> {
         let position = self.writer.stream_position()?;
-        self.writer.write_all(&bytes)?;
-        self.writer.flush()?;
+        self.write_chunk(bytes)?;
         let new_position = self.writer.stream_position()?;
+        self.shared
+            .write_position
+            .store(new_position, Ordering::SeqCst);
+        self.check_content_length(position, new_position)?;
         trace!(
             previous_position = position,
             new_position,
@@ -295,33 +1140,271 @@ impl<H: StorageWriter> Source<H> {
         // RangeSet will panic if we try to insert a slice with 0 length. This could
         // happen if the current chunk is empty.
         if new_position > position {
-            self.downloaded.write().insert(position..new_position);
+            let unflushed_start = *self.unflushed_start.get_or_insert(position);
+            if new_position - unflushed_start >= self.settings.flush_interval
+                || self.shared.requested_position.load(Ordering::SeqCst) > -1
+            {
+                self.flush()?;
+            }
         }
-        let requested = self.requested_position.load(Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Writes any data that's still buffered by the decoder once the stream ends
+    fn finish_decoding(&mut self) -> io::Result<()> {
+        #[cfg(feature = "compression")]
+        if let Some(decoder) = self.decoder.take() {
+            let bytes = decoder.finish()?;
+            if !bytes.is_empty() {
+                self.handle_response_chunk(bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Decodes the chunk if needed, applies the chunk transform and writes the result to storage.
+    // Returns the number of bytes written.
+    fn write_chunk(&mut self, bytes: Bytes) -> io::Result<u64> {
+        #[cfg(feature = "compression")]
+        let bytes = match &mut self.decoder {
+            Some(decoder) => decoder.decode(&bytes)?,
+            None => bytes,
+        };
+        let bytes = match self.settings.chunk_transform.clone() {
+            Some(transform) => {
+                let len = bytes.len();
+                let bytes = transform.call(self.writer.stream_position()?, bytes)?;
+                if bytes.len() != len && !self.length_transformed {
+                    warn!(
+                        original_length = len,
+                        transformed_length = bytes.len(),
+                        "chunk transform changed the length of a chunk, treating the content \
+                         length as unknown"
+                    );
+                    self.length_transformed = true;
+                    *self.shared.content_length.write() = None;
+                    self.shared.seekable.store(false, Ordering::SeqCst);
+                }
+                bytes
+            }
+            None => bytes,
+        };
+        self.writer.write_all(&bytes)?;
+        self.shared
+            .bytes_on_disk
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Ok(bytes.len() as u64)
+    }
+
+    fn check_content_length(&mut self, chunk_start: u64, position: u64) -> io::Result<()> {
+        let mut content_length = self.shared.content_length.write();
+        let Some(length) = *content_length else {
+            return Ok(());
+        };
+        if position <= length {
+            return Ok(());
+        }
+        match self.settings.content_length_exceeded {
+            ContentLengthExceeded::TreatAsUnknown => {
+                warn!(
+                    content_length = length,
+                    position, "stream exceeded the content length, treating it as unknown"
+                );
+                *content_length = None;
+                Ok(())
+            }
+            ContentLengthExceeded::Error => {
+                error!(
+                    content_length = length,
+                    position, "stream exceeded the content length"
+                );
+                drop(content_length);
+                // Keep the data that was received up to the content length
+                let start = if self.shared.prefetch_complete.load(Ordering::SeqCst) {
+                    self.unflushed_start.take().unwrap_or(chunk_start)
+                } else {
+                    self.prefetch_start
+                };
+                self.writer.flush()?;
+                if length > start {
+                    self.mark_downloaded(start..length)?;
+                }
+                // The error needs to be visible by the time the reader is notified
+                let error = self.source_handle().set_download_error(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream exceeded the reported content length",
+                ));
+                // Make sure the reader doesn't wait on data that will never arrive
+                self.complete_download();
+                Err(error)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let position = self.writer.stream_position()?;
+        if let Some(unflushed_start) = self.unflushed_start.take() {
+            if position > unflushed_start {
+                trace!(start = unflushed_start, end = position, "flushed data");
+                return self.mark_downloaded(unflushed_start..position);
+            }
+        }
+        self.writer.flush()?;
+        self.notify_reader(position);
+        Ok(())
+    }
+
+    // Wakes up the reader if the position it's waiting on is now available
+    fn notify_reader(&self, position: u64) {
+        let requested = self.shared.requested_position.load(Ordering::SeqCst);
         if requested > -1 {
             debug!(
                 requested_position = requested,
-                current_position = new_position,
+                current_position = position,
                 "received requested position"
             );
-            if new_position as i64 >= requested {
-                debug!("requested position reached, notifying");
-                self.requested_position.store(-1, Ordering::SeqCst);
-                let (mutex, cvar) = &*self.position_reached;
-                (mutex.lock()).position_reached = true;
-                cvar.notify_all();
+            // If there are gaps in the downloaded data, the writer may be past the requested
+            // position in a later part of the stream, so make sure the range that's currently
+            // being written starts at or before the reader's position
+            let read_position = self.shared.read_position.load(Ordering::SeqCst);
+            let reached = self
+                .shared
+                .downloaded
+                .read()
+                .get(read_position)
+                .is_some_and(|range| range.end as i64 >= requested);
+            if reached {
+                // The reader may have withdrawn the request if it stopped waiting
+                if self
+                    .shared
+                    .requested_position
+                    .compare_exchange(requested, -1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    debug!("requested position reached, notifying");
+                    self.shared
+                        .notify_waiters(|waiter| waiter.position_reached = true);
+                }
             }
         }
-        Ok(())
     }
 
-    fn should_seek(&mut self, pos: u64) -> io::Result<bool> {
-        let downloaded = self.downloaded.read();
-        Ok(if let Some(range) = downloaded.get(&pos) {
-            !range.contains(&self.writer.stream_position()?)
-        } else {
-            true
-        })
+    fn paused_by_user(&self) -> bool {
+        // Keep downloading while the reader is waiting so it doesn't get stuck
+        self.shared.paused.load(Ordering::SeqCst)
+            && self.shared.requested_position.load(Ordering::SeqCst) == -1
+    }
+
+    fn range_request_limit_reached(&self) -> bool {
+        self.settings
+            .range_request_limit_reached(self.shared.range_requests.load(Ordering::SeqCst))
+    }
+
+    fn read_ahead_exceeded(&mut self) -> io::Result<bool> {
+        let Some(max_read_ahead) = self.settings.max_read_ahead else {
+            return Ok(false);
+        };
+        if self.shared.requested_position.load(Ordering::SeqCst) > -1 {
+            // Never pause while the reader is waiting on data that hasn't been downloaded yet
+            return Ok(false);
+        }
+        let write_position = self.writer.stream_position()?;
+        let read_ahead =
+            write_position.saturating_sub(self.shared.read_position.load(Ordering::SeqCst));
+        if read_ahead >= max_read_ahead {
+            trace!(read_ahead, max_read_ahead, "read-ahead limit reached");
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn budget_exceeded(&self) -> bool {
+        let Some(budget) = &self.shared.budget else {
+            return false;
+        };
+        // Report usage before checking so the limit accounts for everything downloaded so far
+        budget.set_usage(
+            self.shared
+                .downloaded
+                .read()
+                .ranges()
+                .map(|r| r.end - r.start)
+                .sum(),
+        );
+        if self.shared.requested_position.load(Ordering::SeqCst) > -1 {
+            return false;
+        }
+        let exceeded = budget.should_pause();
+        if exceeded {
+            trace!("disk budget exceeded");
+        }
+        exceeded
+    }
+
+    fn should_seek(&mut self, pos: u64, stream_active: bool) -> io::Result<bool> {
+        let write_position = self.writer.stream_position()?;
+        let downloaded = self.shared.downloaded.read();
+        if let Some(range) = downloaded.get(pos) {
+            return Ok(!range.contains(&write_position));
+        }
+        // If the position is a short distance ahead of the current request, it's faster to keep
+        // downloading than to start a new request
+        let coalesce = stream_active
+            && pos >= write_position
+            && pos - write_position < self.settings.range_coalesce_threshold
+            && self.range_end.map_or(true, |end| pos < end);
+        if coalesce {
+            debug!(
+                write_position,
+                "seek position is close to the current position, continuing the current request"
+            );
+        }
+        Ok(!coalesce)
+    }
+
+    // Collects up to `max_ranges` gaps starting with `gap` so they can be downloaded with one
+    // request
+    fn following_gaps(
+        &self,
+        gap: Range<u64>,
+        content_length: u64,
+        max_ranges: usize,
+    ) -> Vec<Range<u64>> {
+        let mut gaps = vec![gap];
+        while let Some(last) = gaps.last().filter(|_| gaps.len() < max_ranges) {
+            let next_gap = self
+                .shared
+                .downloaded
+                .read()
+                .gaps(last.end..content_length)
+                .next();
+            let Some(next_gap) = next_gap else {
+                break;
+            };
+            gaps.push(self.coalesce_gap(next_gap, content_length));
+        }
+        gaps
+    }
+
+    // Extends the gap to include any following gaps that are close enough to be downloaded with
+    // the same request
+    fn coalesce_gap(&self, gap: Range<u64>, content_length: u64) -> Range<u64> {
+        let threshold = self.settings.range_coalesce_threshold;
+        let mut coalesced = gap.clone();
+        for next_gap in self.shared.downloaded.read().gaps(gap.end..content_length) {
+            if next_gap.start - coalesced.end >= threshold {
+                break;
+            }
+            coalesced.end = next_gap.end;
+        }
+        if coalesced != gap {
+            debug!(
+                gap = format!("{gap:?}"),
+                coalesced = format!("{coalesced:?}"),
+                "coalesced missing stream chunks"
+            );
+        }
+        coalesced
     }
 
     async fn seek<S: SourceStream>(
@@ -330,31 +1413,237 @@ impl<H: StorageWriter> Source<H> {
         start: u64,
         end: Option<u64>,
     ) -> io::Result<()> {
+        self.shared.range_requests.fetch_add(1, Ordering::SeqCst);
+        if self.settings.serialize_requests {
+            debug!("closing the current response before sending a new request");
+            stream.close();
+        }
         stream.seek_range(start, end).await?;
-        self.writer.seek(SeekFrom::Start(start))?;
+        self.range_end = end;
+        self.seek_writer(stream, start)
+    }
+
+    async fn seek_ranges<S: SourceStream>(
+        &mut self,
+        stream: &mut S,
+        ranges: &[Range<u64>],
+    ) -> io::Result<()> {
+        self.shared.range_requests.fetch_add(1, Ordering::SeqCst);
+        if self.settings.serialize_requests {
+            debug!("closing the current response before sending a new request");
+            stream.close();
+        }
+        stream.seek_ranges(ranges).await?;
+        // The response may contain more ranges, so only the end of the first one is known to be
+        // part of the current request
+        self.range_end = ranges.first().map(|range| range.end);
+        self.seek_writer(stream, ranges.first().map_or(0, |range| range.start))
+    }
+
+    fn seek_writer<S: SourceStream>(&mut self, stream: &S, start: u64) -> io::Result<()> {
+        self.update_source_info(stream.info());
+        if stream.supports_seek() {
+            self.writer.seek(SeekFrom::Start(start))?;
+        } else {
+            // The server ignored the range request and sent the whole resource, so we need to
+            // start writing from the beginning again
+            warn!("source does not support seeking, restarting download from the beginning");
+            self.shared.seekable.store(false, Ordering::SeqCst);
+            self.writer.seek(SeekFrom::Start(0))?;
+        }
+        self.shared
+            .write_position
+            .store(self.writer.stream_position()?, Ordering::SeqCst);
         Ok(())
     }
 
+    // Moves the writer to the start of the next part of a response that contains several ranges
+    fn move_writer(&mut self, position: u64) -> io::Result<()> {
+        if self.writer.stream_position()? == position {
+            return Ok(());
+        }
+        debug!(position, "response skipped to another range");
+        self.flush()?;
+        self.writer.seek(SeekFrom::Start(position))?;
+        self.shared.write_position.store(position, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Waits until the connection limit allows another connection to the stream's host
+    async fn acquire_connection<S: SourceStream>(
+        &mut self,
+        stream: &mut S,
+        cancellation_token: &CancellationToken,
+    ) -> io::Result<Option<ConnectionPermit>> {
+        let Some(limit) = self.settings.connection_limit.clone() else {
+            return Ok(None);
+        };
+        let Some(url) = stream.info().url else {
+            return Ok(None);
+        };
+        let Some(host) = connection_limit::host(&url) else {
+            return Ok(None);
+        };
+        if let Some(permit) = limit.try_acquire(host) {
+            return Ok(Some(permit));
+        }
+
+        debug!(host, "waiting for a connection to become available");
+        // The response doesn't need to stay open while waiting if it can be requested again
+        let restart = stream.supports_restart();
+        if restart {
+            stream.close();
+        }
+        let permit = tokio::select! {
+            permit = limit.acquire(host) => permit,
+            _ = cancellation_token.cancelled() => return Ok(None),
+        };
+        if restart {
+            let position = self.writer.stream_position()?;
+            self.seek(stream, position, None).await?;
+        }
+        Ok(Some(permit))
+    }
+
+    // Returns whether a new request was sent
+    async fn reconnect<S: SourceStream>(&mut self, stream: &mut S) -> io::Result<bool> {
+        self.flush()?;
+        let position = self.writer.stream_position()?;
+        if self
+            .shared
+            .content_length
+            .read()
+            .is_some_and(|content_length| position >= content_length)
+        {
+            debug!("stream already reached the end, not reconnecting");
+            return Ok(false);
+        }
+        if !stream.supports_restart() {
+            debug!("stream can't be restarted, not reconnecting");
+            return Ok(false);
+        }
+        if self.length_transformed {
+            debug!("positions no longer match the source, not reconnecting");
+            return Ok(false);
+        }
+        if self.range_request_limit_reached() {
+            warn!("range request limit reached, not reconnecting");
+            return Ok(false);
+        }
+        debug!(position, "reconnecting");
+        // The current response may be stuck on a connection that's no longer usable
+        stream.close();
+        // After a response with several ranges has moved past the first one, the end of the
+        // current range isn't known
+        let end = self.range_end.filter(|end| *end > position);
+        self.seek(stream, position, end).await?;
+        Ok(true)
+    }
+
     fn get_download_gap(&self, content_length: u64) -> Option<Range<u64>> {
-        let downloaded = self.downloaded.read();
-        let range = 0..content_length;
-        let mut gaps = downloaded.gaps(&range);
-        gaps.next()
+        let downloaded = self.shared.downloaded.read();
+        // Data ahead of the reader is needed first, so fill those gaps before going back to any
+        // that were skipped earlier
+        let read_position = self
+            .shared
+            .read_position
+            .load(Ordering::SeqCst)
+            .min(content_length);
+        let gap = downloaded.gaps(read_position..content_length).next();
+        gap.or_else(|| downloaded.gaps(0..content_length).next())
+    }
+
+    // The stream may have moved to a different source, such as a mirror, while seeking
+    fn update_source_info(&self, source_info: SourceInfo) {
+        let changed = *self.shared.source_info.borrow() != source_info;
+        if changed {
+            debug!(url = ?source_info.url, "source changed");
+            self.shared.source_info.send_replace(source_info);
+        }
+    }
+
+    fn report_content_length(&self, content_length: Option<u64>) {
+        if let Some(on_content_length) = &self.settings.on_content_length {
+            debug!(content_length, "reporting content length");
+            on_content_length.call(content_length);
+        }
+    }
+
+    // Streams without a known content length only find out their size once they reach the end
+    fn report_final_length(&mut self) -> io::Result<()> {
+        if self.shared.content_length.read().is_none() {
+            let length = self.writer.stream_position()?;
+            self.report_content_length(Some(length));
+        }
+        Ok(())
+    }
+
+    fn record_speed_sample(&self, len: u64) {
+        let now = self.shared.clock.now();
+        let mut samples = self.shared.speed_samples.lock();
+        let total = samples.back().map_or(0, |(_, total)| *total) + len;
+        samples.push_back((now, total));
+        // Keep the newest sample from before the window so the speed covers the whole window
+        while samples.len() > MAX_SPEED_SAMPLES
+            || samples
+                .get(1)
+                .is_some_and(|(time, _)| now.saturating_duration_since(*time) >= SPEED_WINDOW)
+        {
+            samples.pop_front();
+        }
     }
 
     fn complete_download(&self) {
-        let (mutex, cvar) = &*self.position_reached;
-        (mutex.lock()).stream_done = true;
-        cvar.notify_all();
+        self.shared
+            .notify_waiters(|waiter| waiter.stream_done = true);
+        self.shared.progress.send_modify(|_| {});
+    }
+
+    // Makes newly downloaded data available. The data is always flushed to storage and added to
+    // the downloaded ranges before the reader is woken up, and progress subscribers are only
+    // notified after that, so anything observing the progress can read the data it was notified
+    // about.
+    fn mark_downloaded(&mut self, range: Range<u64>) -> io::Result<()> {
+        let end = range.end;
+        self.add_downloaded(range)?;
+        self.notify_downloaded(end);
+        Ok(())
+    }
+
+    fn add_downloaded(&mut self, range: Range<u64>) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.settings.fsync_on_flush {
+            // The data needs to be durable before anything can rely on it being downloaded
+            trace!(start = range.start, end = range.end, "syncing data");
+            self.writer.sync_data()?;
+        }
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.shared.downloaded.write().insert(range);
+        if let Some(on_first_byte) = &self.settings.on_first_byte {
+            on_first_byte.call();
+        }
+        Ok(())
+    }
+
+    fn notify_downloaded(&self, position: u64) {
+        self.notify_reader(position);
+        self.shared.progress.send_modify(|_| {});
     }
 
     pub(crate) fn source_handle(&self) -> SourceHandle {
         SourceHandle {
-            downloaded: self.downloaded.clone(),
-            requested_position: self.requested_position.clone(),
-            position_reached: self.position_reached.clone(),
-            seek_tx: self.seek_tx.clone(),
-            content_length: self.content_length,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<H: StorageWriter> Drop for Source<H> {
+    fn drop(&mut self) {
+        // Let other downloads sharing the budget continue now that this one is done
+        if let Some(budget) = &self.shared.budget {
+            budget.set_fetching(false);
         }
     }
 }
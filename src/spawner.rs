@@ -0,0 +1,114 @@
+//! Controls where the download task runs.
+//!
+//! By default, the download task is spawned on the current Tokio runtime using [tokio::spawn], so
+//! [StreamDownload](crate::StreamDownload) must be created from within a runtime. Use
+//! [Spawner::runtime] to run it on a different runtime instead, or [Spawner::manual] to receive the
+//! task and drive it yourself.
+//!
+//! Reads block the calling thread until the requested data has been downloaded, so the download
+//! task must be able to make progress while a read is waiting. Reads must never happen on a thread
+//! that's responsible for driving the download task. This is especially important when using a
+//! current-thread runtime or a manually driven task. Do the reads on a separate thread, such as one
+//! created with [spawn_blocking](tokio::task::spawn_blocking), instead.
+
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Determines where the download task runs. Set with
+/// [Settings::spawner](crate::Settings::spawner).
+#[derive(Debug, Clone, Default)]
+pub struct Spawner(SpawnerKind);
+
+#[derive(Debug, Clone, Default)]
+enum SpawnerKind {
+    #[default]
+    Current,
+    Runtime(Arc<Handle>),
+    Manual(mpsc::UnboundedSender<BoxFuture<'static, ()>>),
+}
+
+impl Spawner {
+    /// Spawns the download task on the runtime that the [StreamDownload](crate::StreamDownload)
+    /// was created from. This is the default.
+    pub fn current() -> Self {
+        Self(SpawnerKind::Current)
+    }
+
+    /// Spawns the download task on the runtime referenced by `handle`.
+    pub fn runtime(handle: Handle) -> Self {
+        Self(SpawnerKind::Runtime(Arc::new(handle)))
+    }
+
+    /// Sends each download task to the returned receiver instead of spawning it. The task won't
+    /// make any progress until it's polled, so reads will block until it's being driven.
+    ///
+    /// A new task is sent whenever a download starts, including when the source is replaced. If a
+    /// task is dropped before it finishes, [join](crate::StreamDownload::join) returns an error.
+    pub fn manual() -> (Self, mpsc::UnboundedReceiver<BoxFuture<'static, ()>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(SpawnerKind::Manual(tx)), rx)
+    }
+
+    pub(crate) fn spawn<F>(&self, task: F) -> DownloadTask
+    where
+        F: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        match &self.0 {
+            SpawnerKind::Current => DownloadTask::Spawned(tokio::spawn(task)),
+            SpawnerKind::Runtime(handle) => DownloadTask::Spawned(handle.spawn(task)),
+            SpawnerKind::Manual(tx) => {
+                let (result_tx, result_rx) = oneshot::channel();
+                // If the receiver was dropped, the task is dropped as well, which is reported
+                // when joining
+                tx.send(Box::pin(async move {
+                    result_tx.send(task.await).ok();
+                }))
+                .ok();
+                DownloadTask::Manual(result_rx)
+            }
+        }
+    }
+}
+
+impl PartialEq for Spawner {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (SpawnerKind::Current, SpawnerKind::Current) => true,
+            (SpawnerKind::Runtime(a), SpawnerKind::Runtime(b)) => Arc::ptr_eq(a, b),
+            (SpawnerKind::Manual(a), SpawnerKind::Manual(b)) => a.same_channel(b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Spawner {}
+
+// Handle to the running download task
+#[derive(Debug)]
+pub(crate) enum DownloadTask {
+    Spawned(JoinHandle<io::Result<()>>),
+    Manual(oneshot::Receiver<io::Result<()>>),
+}
+
+impl DownloadTask {
+    pub(crate) async fn wait(&mut self) -> io::Result<()> {
+        match self {
+            Self::Spawned(handle) => match handle.await {
+                Ok(result) => result,
+                Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+            },
+            Self::Manual(rx) => rx.await.unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "download task was dropped before it finished",
+                ))
+            }),
+        }
+    }
+}
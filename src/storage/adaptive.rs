@@ -5,7 +5,7 @@
 //! This module is useful when you want to need to support both infinite and finite streams without
 //! explicitly checking.
 
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, IoSliceMut, Read, Seek, SeekFrom, Write};
 use std::num::NonZeroUsize;
 
 use super::bounded::{BoundedStorageProvider, BoundedStorageReader, BoundedStorageWriter};
@@ -69,6 +69,13 @@ where
             Self::Unbounded(inner) => inner.read(buf),
         }
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Bounded(inner) => inner.read_vectored(bufs),
+            Self::Unbounded(inner) => inner.read_vectored(bufs),
+        }
+    }
 }
 
 impl<T> Seek for AdaptiveStorageReader<T>
@@ -94,6 +101,13 @@ where
             Self::Unbounded(inner) => Ok(AdaptiveStorageWriter::Unbounded(inner.writer()?)),
         }
     }
+
+    fn sync_data(writer: &mut Self::Writer) -> io::Result<()> {
+        match writer {
+            AdaptiveStorageWriter::Bounded(inner) => BoundedStorageReader::<T>::sync_data(inner),
+            AdaptiveStorageWriter::Unbounded(inner) => T::sync_data(inner),
+        }
+    }
 }
 
 /// Write handle created by an [AdaptiveStorageReader].
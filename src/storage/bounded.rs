@@ -115,6 +115,10 @@ where
             shared_info: self.shared_info.clone(),
         })
     }
+
+    fn sync_data(writer: &mut Self::Writer) -> io::Result<()> {
+        T::sync_data(&mut writer.inner)
+    }
 }
 
 impl<T> Read for BoundedStorageReader<T>
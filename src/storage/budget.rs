@@ -0,0 +1,203 @@
+//! A shared limit on the amount of data stored by multiple downloads.
+//!
+//! Each [StreamDownload](crate::StreamDownload) created with the same [DiskBudget] (see
+//! [Settings::disk_budget](crate::Settings::disk_budget)) reports how much data it has stored.
+//! Once the total reaches the limit, only the download that was read from most recently keeps
+//! fetching, and the others pause until they're read from again or until downloads are dropped
+//! and free up space.
+//!
+//! Like [Settings::max_read_ahead](crate::Settings::max_read_ahead), the limit doesn't apply while
+//! a reader is waiting on data that hasn't been downloaded yet, so it's possible to exceed it.
+//! Read times are taken from each download's [Settings::clock](crate::Settings::clock), so all
+//! downloads sharing a budget should use the same clock.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::error::Error;
+//! use std::result::Result;
+//!
+//! use stream_download::storage::budget::DiskBudget;
+//! use stream_download::storage::temp::TempStorageProvider;
+//! use stream_download::{Settings, StreamDownload};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn Error>> {
+//!     // Shared between all downloads
+//!     let budget = DiskBudget::new(512 * 1024 * 1024);
+//!     let reader = StreamDownload::new_http(
+//!         "https://some-cool-url.com/some-file.mp3".parse()?,
+//!         TempStorageProvider::default(),
+//!         Settings::default().disk_budget(Some(budget.clone())),
+//!     )
+//!     .await?;
+//!     println!("{} bytes in use", budget.usage());
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use tracing::debug;
+
+use crate::clock::Clock;
+
+/// Limit on the total number of bytes stored by all downloads that share it.
+/// Cloning the budget returns a handle to the same shared limit.
+#[derive(Clone)]
+pub struct DiskBudget {
+    limit: u64,
+    inner: Arc<BudgetInner>,
+}
+
+#[derive(Default)]
+struct BudgetInner {
+    downloads: Mutex<BudgetState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    next_id: u64,
+    usage: HashMap<u64, DownloadUsage>,
+}
+
+struct DownloadUsage {
+    bytes: u64,
+    last_read: Instant,
+    fetching: bool,
+}
+
+impl DiskBudget {
+    /// Creates a new [DiskBudget] that allows up to `limit` bytes to be stored.
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            inner: Default::default(),
+        }
+    }
+
+    /// The maximum number of bytes that can be stored before downloads are paused.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// The total number of bytes currently stored by all registered downloads.
+    pub fn usage(&self) -> u64 {
+        self.inner
+            .downloads
+            .lock()
+            .usage
+            .values()
+            .map(|usage| usage.bytes)
+            .sum()
+    }
+
+    /// The number of downloads currently registered with the budget.
+    pub fn downloads(&self) -> usize {
+        self.inner.downloads.lock().usage.len()
+    }
+
+    pub(crate) fn register(&self, clock: Arc<dyn Clock>) -> BudgetRegistration {
+        let mut state = self.inner.downloads.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.usage.insert(
+            id,
+            DownloadUsage {
+                bytes: 0,
+                last_read: clock.now(),
+                fetching: true,
+            },
+        );
+        debug!(id, "registered download with disk budget");
+        BudgetRegistration {
+            budget: self.clone(),
+            id,
+            clock,
+        }
+    }
+}
+
+impl fmt::Debug for DiskBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskBudget")
+            .field("limit", &self.limit)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+impl PartialEq for DiskBudget {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for DiskBudget {}
+
+// Tracks the usage of a single download. The download is removed from the budget once this is
+// dropped.
+#[derive(Debug)]
+pub(crate) struct BudgetRegistration {
+    budget: DiskBudget,
+    id: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl BudgetRegistration {
+    pub(crate) fn set_usage(&self, bytes: u64) {
+        if let Some(usage) = self.budget.inner.downloads.lock().usage.get_mut(&self.id) {
+            usage.bytes = bytes;
+        }
+    }
+
+    pub(crate) fn mark_read(&self) {
+        if let Some(usage) = self.budget.inner.downloads.lock().usage.get_mut(&self.id) {
+            usage.last_read = self.clock.now();
+        }
+    }
+
+    pub(crate) fn set_fetching(&self, fetching: bool) {
+        if let Some(usage) = self.budget.inner.downloads.lock().usage.get_mut(&self.id) {
+            usage.fetching = fetching;
+        }
+        self.budget.inner.notify.notify_waiters();
+    }
+
+    // Once the budget is used up, only the most recently read download is allowed to continue
+    pub(crate) fn should_pause(&self) -> bool {
+        let state = self.budget.inner.downloads.lock();
+        let total: u64 = state.usage.values().map(|usage| usage.bytes).sum();
+        if total < self.budget.limit {
+            return false;
+        }
+        let Some(last_read) = state.usage.get(&self.id).map(|usage| usage.last_read) else {
+            return false;
+        };
+        state
+            .usage
+            .iter()
+            .any(|(id, usage)| *id != self.id && usage.fetching && usage.last_read > last_read)
+    }
+
+    // Resolves when another download stops fetching or is removed from the budget. Updates are
+    // only received once the future is polled or enabled.
+    pub(crate) fn changed(&self) -> Notified<'_> {
+        self.budget.inner.notify.notified()
+    }
+}
+
+impl Drop for BudgetRegistration {
+    fn drop(&mut self) {
+        self.budget.inner.downloads.lock().usage.remove(&self.id);
+        debug!(id = self.id, "removed download from disk budget");
+        self.budget.inner.notify.notify_waiters();
+    }
+}
@@ -0,0 +1,305 @@
+//! Storage wrappers that compress the downloaded data before writing it to another storage layer.
+//!
+//! This is useful for caching large, compressible resources such as text or logs on disk at the
+//! cost of extra CPU time. The stream is split into fixed-size blocks that are compressed
+//! independently with DEFLATE, and an in-memory index maps each block to its location in the
+//! underlying storage. Seeking to a position only requires decompressing the block that contains
+//! it.
+//!
+//! The block that's currently being downloaded is held in memory until it's complete or the
+//! download moves to a different position. If the download moves before the block is complete,
+//! the partial block is compressed on its own and the rest of the block is stored separately once
+//! it's downloaded. Compressed blocks are always appended to the underlying storage, so data that
+//! is downloaded again after seeking takes up additional space.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use parking_lot::Mutex;
+use rangemap::RangeMap;
+use tracing::trace;
+
+use super::{StorageProvider, StorageReader, StorageWriter};
+
+/// Creates a [CompressedStorageReader] that compresses blocks of `block_size` bytes.
+#[derive(Clone, Debug)]
+pub struct CompressedStorageProvider<T>
+where
+    T: StorageProvider,
+{
+    inner: T,
+    block_size: usize,
+    level: u32,
+}
+
+impl<T> CompressedStorageProvider<T>
+where
+    T: StorageProvider,
+{
+    /// Creates a new [CompressedStorageProvider] that stores compressed blocks of `block_size`
+    /// bytes in the storage created by `inner`. Larger blocks usually compress better, but more
+    /// data needs to be decompressed after each seek.
+    pub fn new(inner: T, block_size: NonZeroUsize) -> Self {
+        Self {
+            inner,
+            block_size: block_size.get(),
+            level: Compression::default().level(),
+        }
+    }
+
+    /// Sets the compression level from 0 (no compression) to 9 (best compression).
+    /// Values above 9 are treated as 9. Defaults to 6.
+    pub fn level(self, level: u32) -> Self {
+        Self {
+            level: level.min(9),
+            ..self
+        }
+    }
+}
+
+impl<T> StorageProvider for CompressedStorageProvider<T>
+where
+    T: StorageProvider,
+{
+    type Reader = CompressedStorageReader<T::Reader>;
+
+    fn create_reader(&self, _content_length: Option<u64>) -> io::Result<Self::Reader> {
+        // The compressed size isn't known ahead of time
+        Ok(CompressedStorageReader {
+            inner: self.inner.create_reader(None)?,
+            index: Arc::new(Mutex::new(Index::default())),
+            block_size: self.block_size,
+            level: self.level,
+            pos: 0,
+            cache: None,
+        })
+    }
+}
+
+// Location of a compressed block in the underlying storage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Block {
+    // Position in the stream of the first byte in the block
+    start: u64,
+    offset: u64,
+    len: usize,
+}
+
+// Contiguous data that hasn't been compressed yet
+#[derive(Debug)]
+struct PendingBlock {
+    start: u64,
+    buf: Vec<u8>,
+}
+
+impl PendingBlock {
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    // Maps positions in the stream to the block holding the most recently written data for them
+    blocks: RangeMap<u64, Block>,
+    pending: Option<PendingBlock>,
+    // End of the compressed data in the underlying storage
+    compressed_end: u64,
+    // End of the data written so far
+    len: u64,
+}
+
+impl Index {
+    fn compress_pending<W: Write + Seek>(&mut self, writer: &mut W, level: u32) -> io::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        if pending.buf.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+        encoder.write_all(&pending.buf)?;
+        let compressed = encoder.finish()?;
+        trace!(
+            start = pending.start,
+            len = pending.buf.len(),
+            compressed_len = compressed.len(),
+            "compressed block"
+        );
+
+        writer.seek(SeekFrom::Start(self.compressed_end))?;
+        writer.write_all(&compressed)?;
+        self.blocks.insert(
+            pending.start..pending.end(),
+            Block {
+                start: pending.start,
+                offset: self.compressed_end,
+                len: compressed.len(),
+            },
+        );
+        self.compressed_end += compressed.len() as u64;
+        Ok(())
+    }
+}
+
+fn seek_position(current: u64, end: u64, pos: SeekFrom) -> io::Result<u64> {
+    let new_pos = match pos {
+        SeekFrom::Start(pos) => Some(pos),
+        SeekFrom::Current(from_current) => current.checked_add_signed(from_current),
+        SeekFrom::End(from_end) => end.checked_add_signed(from_end),
+    };
+    new_pos.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+fn decompress<R: Read + Seek>(reader: &mut R, block: Block) -> io::Result<Vec<u8>> {
+    let mut compressed = vec![0; block.len];
+    reader.seek(SeekFrom::Start(block.offset))?;
+    reader.read_exact(&mut compressed)?;
+    let mut buf = Vec::new();
+    DeflateDecoder::new(compressed.as_slice()).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reader created by a [CompressedStorageProvider]. Decompresses the block containing the current
+/// position and reads from it.
+#[derive(Debug)]
+pub struct CompressedStorageReader<T: StorageReader> {
+    inner: T,
+    index: Arc<Mutex<Index>>,
+    block_size: usize,
+    level: u32,
+    pos: u64,
+    // Most recently decompressed block so sequential reads don't decompress it again
+    cache: Option<(Block, Vec<u8>)>,
+}
+
+impl<T: StorageReader> Read for CompressedStorageReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let index = self.index.lock();
+        let mut read_len = buf.len();
+        if let Some(pending) = &index.pending {
+            if (pending.start..pending.end()).contains(&self.pos) {
+                let offset = (self.pos - pending.start) as usize;
+                let read_len = read_len.min(pending.buf.len() - offset);
+                buf[..read_len].copy_from_slice(&pending.buf[offset..offset + read_len]);
+                self.pos += read_len as u64;
+                return Ok(read_len);
+            }
+            // Data from the pending block is newer than anything that's been compressed
+            if pending.start > self.pos {
+                read_len = read_len.min((pending.start - self.pos) as usize);
+            }
+        }
+        let Some((range, block)) = index.blocks.get_key_value(&self.pos) else {
+            return Ok(0);
+        };
+        // Later parts of the block may have been replaced by newer data
+        let read_len = read_len.min((range.end - self.pos) as usize);
+        let block = *block;
+        drop(index);
+
+        let data = match &self.cache {
+            Some((cached, data)) if *cached == block => data,
+            _ => {
+                trace!(start = block.start, "decompressing block");
+                let data = decompress(&mut self.inner, block)?;
+                &self.cache.insert((block, data)).1
+            }
+        };
+        let offset = (self.pos - block.start) as usize;
+        buf[..read_len].copy_from_slice(&data[offset..offset + read_len]);
+        self.pos += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl<T: StorageReader> Seek for CompressedStorageReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = self.index.lock().len;
+        self.pos = seek_position(self.pos, end, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl<T: StorageReader> StorageReader for CompressedStorageReader<T> {
+    type Writer = CompressedStorageWriter<T::Writer>;
+
+    fn writer(&self) -> io::Result<Self::Writer> {
+        Ok(CompressedStorageWriter {
+            inner: self.inner.writer()?,
+            index: self.index.clone(),
+            block_size: self.block_size as u64,
+            level: self.level,
+            pos: 0,
+        })
+    }
+
+    // The pending block only exists in memory, so only the data that was already compressed can
+    // be synced
+    fn sync_data(writer: &mut Self::Writer) -> io::Result<()> {
+        T::sync_data(&mut writer.inner)
+    }
+}
+
+/// Write handle created by a [CompressedStorageReader].
+#[derive(Debug)]
+pub struct CompressedStorageWriter<T: StorageWriter> {
+    inner: T,
+    index: Arc<Mutex<Index>>,
+    block_size: u64,
+    level: u32,
+    pos: u64,
+}
+
+impl<T: StorageWriter> Write for CompressedStorageWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut index = self.index.lock();
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            let pending = match &mut index.pending {
+                Some(pending) if pending.end() == self.pos => pending,
+                _ => {
+                    // The pending block must stay contiguous, so compress it before starting a
+                    // new one
+                    index.compress_pending(&mut self.inner, self.level)?;
+                    index.pending.insert(PendingBlock {
+                        start: self.pos,
+                        buf: Vec::new(),
+                    })
+                }
+            };
+            // Blocks never cross a block boundary, so each position is always in the same block
+            let boundary = (self.pos / self.block_size + 1) * self.block_size;
+            let len = remaining.len().min((boundary - self.pos) as usize);
+            pending.buf.extend_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+            self.pos += len as u64;
+            if self.pos == boundary {
+                index.compress_pending(&mut self.inner, self.level)?;
+            }
+        }
+        index.len = index.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: StorageWriter> Seek for CompressedStorageWriter<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = self.index.lock().len;
+        self.pos = seek_position(self.pos, end, pos)?;
+        Ok(self.pos)
+    }
+}
@@ -1,7 +1,7 @@
 //! Storage implementations for reading and writing to an in-memory buffer. If the content length is
 //! known, the buffer size will be initialized to the content length, but the buffer will expand
 //! beyond that if required.
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, IoSliceMut, Read, Seek, SeekFrom, Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
@@ -53,6 +53,21 @@ impl Read for MemoryStorage {
         self.pos += read_len;
         Ok(read_len)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let inner = self.inner.read();
+
+        let mut available_len = (inner.len() - self.pos).min(self.written.load(Ordering::SeqCst));
+        let mut total_len = 0;
+        for buf in bufs {
+            let read_len = available_len.min(buf.len());
+            buf[..read_len].copy_from_slice(&inner[self.pos..self.pos + read_len]);
+            self.pos += read_len;
+            available_len -= read_len;
+            total_len += read_len;
+        }
+        Ok(total_len)
+    }
 }
 
 impl Seek for MemoryStorage {
@@ -1,12 +1,18 @@
 //! Configurable implementations for the buffer's storage layer.
 //! Pre-configured implementations are available for memory and temporary file-based storage.
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::storage_error;
 
 pub mod adaptive;
 pub mod bounded;
+pub mod budget;
+#[cfg(feature = "compression")]
+pub mod compressed;
 pub mod memory;
 #[cfg(feature = "temp-storage")]
 pub mod temp;
+pub mod tiered;
 
 /// Creates a [StorageReader] based on the content length returned from the
 /// [SourceStream](crate::source::SourceStream).
@@ -25,9 +31,55 @@ pub trait StorageReader: Read + Seek + Send {
 
     /// Returns a handle that can write to the underlying storage.
     fn writer(&self) -> io::Result<Self::Writer>;
+
+    /// Makes sure all data written through `writer` so far has reached stable storage. This is
+    /// only called if [Settings::fsync_on_flush](crate::Settings::fsync_on_flush) is enabled.
+    /// The default implementation only flushes the writer, which is enough for storage that
+    /// doesn't outlive the process.
+    fn sync_data(writer: &mut Self::Writer) -> io::Result<()>
+    where
+        Self: Sized,
+    {
+        writer.flush()
+    }
 }
 
 /// Handle for writing to the underlying storage layer.
 pub trait StorageWriter: Write + Seek + Send + 'static {}
 
 impl<T> StorageWriter for T where T: Write + Seek + Send + 'static {}
+
+// Reports errors from the underlying storage being removed as [StorageLost](crate::StorageLost)
+pub(crate) struct LostStorageWriter<W> {
+    inner: W,
+    sync_data: fn(&mut W) -> io::Result<()>,
+}
+
+impl<W: StorageWriter> LostStorageWriter<W> {
+    pub(crate) fn new<R: StorageReader<Writer = W>>(inner: W) -> Self {
+        Self {
+            inner,
+            sync_data: R::sync_data,
+        }
+    }
+
+    pub(crate) fn sync_data(&mut self) -> io::Result<()> {
+        (self.sync_data)(&mut self.inner).map_err(storage_error)
+    }
+}
+
+impl<W: Write> Write for LostStorageWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).map_err(storage_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().map_err(storage_error)
+    }
+}
+
+impl<W: Seek> Seek for LostStorageWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos).map_err(storage_error)
+    }
+}
@@ -1,8 +1,9 @@
 //! Storage implementations for reading and writing to a temporary file. If the content length is
-//! known, the buffer size will be initialized to the content length, but the buffer will expand
-//! beyond that if required.
+//! known, the file will be pre-allocated to the content length, but it will expand beyond that if
+//! required. On most file systems, the pre-allocated file will be created as a sparse file so the
+//! unwritten regions don't take up any disk space.
 use std::fs::File;
-use std::io::{self, BufReader, Read, Seek};
+use std::io::{self, IoSliceMut, Read, Seek};
 use std::path::PathBuf;
 
 use tempfile::NamedTempFile;
@@ -34,7 +35,7 @@ impl TempStorageProvider {
 impl StorageProvider for TempStorageProvider {
     type Reader = TempStorageReader;
 
-    fn create_reader(&self, _content_length: Option<u64>) -> io::Result<Self::Reader> {
+    fn create_reader(&self, content_length: Option<u64>) -> io::Result<Self::Reader> {
         let tempfile = if let Some(dir) = &self.storage_dir {
             NamedTempFile::new_in(dir)
         } else {
@@ -42,18 +43,30 @@ impl StorageProvider for TempStorageProvider {
         }
         .wrap_err("error creating temp file")?;
 
+        if let Some(content_length) = content_length {
+            // Pre-allocating the file prevents it from being extended repeatedly when data is
+            // written out of order
+            tempfile
+                .as_file()
+                .set_len(content_length)
+                .wrap_err("error pre-allocating temp file")?;
+        }
+
         let handle = tempfile.reopen().wrap_err("error reopening temp file")?;
         Ok(TempStorageReader {
-            reader: BufReader::new(tempfile),
+            reader: tempfile,
             handle,
         })
     }
 }
 
 /// Reader created by a [TempStorageProvider]. Reads from a temporary file.
+///
+/// Reads are not buffered since a buffer could end up holding parts of the pre-allocated file
+/// that haven't been downloaded yet.
 #[derive(Debug)]
 pub struct TempStorageReader {
-    reader: BufReader<NamedTempFile>,
+    reader: NamedTempFile,
     handle: File,
 }
 
@@ -61,6 +74,10 @@ impl Read for TempStorageReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.reader.read(buf)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.reader.read_vectored(bufs)
+    }
 }
 
 impl Seek for TempStorageReader {
@@ -77,4 +94,8 @@ impl StorageReader for TempStorageReader {
             .try_clone()
             .wrap_err("error cloning temporary file")
     }
+
+    fn sync_data(writer: &mut Self::Writer) -> io::Result<()> {
+        writer.sync_data()
+    }
 }
@@ -0,0 +1,212 @@
+//! Storage wrappers that keep the most recently written data in memory and spill older data to
+//! another storage layer.
+//!
+//! This is useful for reducing read latency when the reader stays close to the download position,
+//! while still allowing random access to the rest of the stream. Data is only written to the
+//! underlying storage once it leaves the in-memory window, so ranges that are still in memory are
+//! never written to disk. If the download moves to a different part of the stream, the entire
+//! window is spilled before the new range is written.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use bytes::{Buf, BytesMut};
+use parking_lot::Mutex;
+use tracing::trace;
+
+use super::{StorageProvider, StorageReader, StorageWriter};
+
+/// Creates a [TieredStorageReader] that keeps up to `size` bytes in memory.
+#[derive(Clone, Debug)]
+pub struct TieredStorageProvider<T>
+where
+    T: StorageProvider,
+{
+    inner: T,
+    size: usize,
+}
+
+impl<T> TieredStorageProvider<T>
+where
+    T: StorageProvider,
+{
+    /// Creates a new [TieredStorageProvider]. Once more than `size` bytes have been written, the
+    /// oldest data is moved to the storage created by `inner`.
+    pub fn new(inner: T, size: NonZeroUsize) -> Self {
+        Self {
+            inner,
+            size: size.get(),
+        }
+    }
+}
+
+impl<T> StorageProvider for TieredStorageProvider<T>
+where
+    T: StorageProvider,
+{
+    type Reader = TieredStorageReader<T::Reader>;
+
+    fn create_reader(&self, content_length: Option<u64>) -> io::Result<Self::Reader> {
+        Ok(TieredStorageReader {
+            inner: self.inner.create_reader(content_length)?,
+            window: Arc::new(Mutex::new(Window {
+                start: 0,
+                buf: BytesMut::with_capacity(self.size),
+            })),
+            size: self.size,
+            pos: 0,
+        })
+    }
+}
+
+// Contiguous section of the stream that's held in memory
+#[derive(Debug)]
+struct Window {
+    start: u64,
+    buf: BytesMut,
+}
+
+impl Window {
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    fn spill<W: Write + Seek>(&mut self, writer: &mut W, len: usize) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        trace!(start = self.start, len, "spilling data from memory");
+        writer.seek(SeekFrom::Start(self.start))?;
+        writer.write_all(&self.buf[..len])?;
+        self.buf.advance(len);
+        self.start += len as u64;
+        Ok(())
+    }
+}
+
+fn seek_position(current: u64, end: u64, pos: SeekFrom) -> io::Result<u64> {
+    let new_pos = match pos {
+        SeekFrom::Start(pos) => Some(pos),
+        SeekFrom::Current(from_current) => current.checked_add_signed(from_current),
+        SeekFrom::End(from_end) => end.checked_add_signed(from_end),
+    };
+    new_pos.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// Reader created by a [TieredStorageProvider]. Reads from memory if the data is still in the
+/// in-memory window and from the underlying storage otherwise.
+#[derive(Debug)]
+pub struct TieredStorageReader<T: StorageReader> {
+    inner: T,
+    window: Arc<Mutex<Window>>,
+    size: usize,
+    pos: u64,
+}
+
+impl<T: StorageReader> Read for TieredStorageReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let window = self.window.lock();
+        if (window.start..window.end()).contains(&self.pos) {
+            let offset = (self.pos - window.start) as usize;
+            let read_len = buf.len().min(window.buf.len() - offset);
+            buf[..read_len].copy_from_slice(&window.buf[offset..offset + read_len]);
+            self.pos += read_len as u64;
+            return Ok(read_len);
+        }
+        // Don't read past the start of the window since that data hasn't been spilled yet
+        let read_len = if self.pos < window.start {
+            buf.len().min((window.start - self.pos) as usize)
+        } else {
+            buf.len()
+        };
+        drop(window);
+
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        let read_len = self.inner.read(&mut buf[..read_len])?;
+        self.pos += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl<T: StorageReader> Seek for TieredStorageReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = if let SeekFrom::End(_) = pos {
+            let window_end = self.window.lock().end();
+            self.inner.seek(SeekFrom::End(0))?.max(window_end)
+        } else {
+            0
+        };
+        self.pos = seek_position(self.pos, end, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl<T: StorageReader> StorageReader for TieredStorageReader<T> {
+    type Writer = TieredStorageWriter<T::Writer>;
+
+    fn writer(&self) -> io::Result<Self::Writer> {
+        Ok(TieredStorageWriter {
+            inner: self.inner.writer()?,
+            window: self.window.clone(),
+            size: self.size,
+            pos: 0,
+        })
+    }
+
+    // Data that's still in the window only exists in memory, so only the data that was moved to
+    // the underlying storage can be synced
+    fn sync_data(writer: &mut Self::Writer) -> io::Result<()> {
+        T::sync_data(&mut writer.inner)
+    }
+}
+
+/// Write handle created by a [TieredStorageReader].
+#[derive(Debug)]
+pub struct TieredStorageWriter<T: StorageWriter> {
+    inner: T,
+    window: Arc<Mutex<Window>>,
+    size: usize,
+    pos: u64,
+}
+
+impl<T: StorageWriter> Write for TieredStorageWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut window = self.window.lock();
+        if self.pos != window.end() {
+            // The window must stay contiguous, so move everything to the underlying storage
+            // before starting a new one
+            let len = window.buf.len();
+            window.spill(&mut self.inner, len)?;
+            window.start = self.pos;
+        }
+        window.buf.extend_from_slice(buf);
+        self.pos += buf.len() as u64;
+
+        let excess = window.buf.len().saturating_sub(self.size);
+        window.spill(&mut self.inner, excess)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: StorageWriter> Seek for TieredStorageWriter<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = if let SeekFrom::End(_) = pos {
+            let window_end = self.window.lock().end();
+            self.inner.seek(SeekFrom::End(0))?.max(window_end)
+        } else {
+            0
+        };
+        self.pos = seek_position(self.pos, end, pos)?;
+        Ok(self.pos)
+    }
+}
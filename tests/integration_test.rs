@@ -1,24 +1,60 @@
-use std::io::{Read, Seek, SeekFrom};
-use std::num::NonZeroUsize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::ops::Range;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use std::time::Duration;
-use std::{fs, io};
+use std::time::{Duration, Instant};
+use std::{fs, future, io};
 
 mod setup;
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{Stream, StreamExt};
+use rangemap::RangeSet;
 use rstest::rstest;
-use setup::{SERVER_ADDR, SERVER_RT};
-use stream_download::source::SourceStream;
+use setup::{ACCEPT_ENCODINGS, PEER_ADDRS, SERVER_ADDR, SERVER_RT, USER_AGENTS};
+#[cfg(feature = "ftp")]
+use setup::{FTP_ADDR, FTP_COMMANDS};
+use stream_download::availability::{AvailabilityMap, AvailabilityMapFactory, PieceMap};
+use stream_download::backoff::{BackoffStrategy, Exponential, Fibonacci, Fixed, Jitter};
+#[cfg(feature = "test-util")]
+use stream_download::clock::TestClock;
+use stream_download::connection_limit::ConnectionLimit;
+#[cfg(feature = "data-url")]
+use stream_download::data_url::DataUrlStream;
+#[cfg(feature = "aes")]
+use stream_download::decrypt::{Aes128Cbc, Aes128Ctr};
+#[cfg(feature = "ftp")]
+use stream_download::ftp::{FtpStream, FtpUrl};
+#[cfg(feature = "hash")]
+use stream_download::hash::{PrefixHash, StreamHasher};
+#[cfg(feature = "test-util")]
+use stream_download::replay::{replay, Op, Replay};
+use stream_download::shared::SharedStreamDownload;
+use stream_download::source::{SourceInfo, SourceStream};
+use stream_download::spawner::Spawner;
 use stream_download::storage::adaptive::AdaptiveStorageProvider;
 use stream_download::storage::bounded::BoundedStorageProvider;
-use stream_download::storage::memory::MemoryStorageProvider;
+use stream_download::storage::budget::DiskBudget;
+#[cfg(feature = "compression")]
+use stream_download::storage::compressed::CompressedStorageProvider;
+use stream_download::storage::memory::{MemoryStorage, MemoryStorageProvider};
 use stream_download::storage::temp::TempStorageProvider;
-use stream_download::storage::StorageProvider;
-use stream_download::{http, Settings, StreamDownload};
+use stream_download::storage::tiered::TieredStorageProvider;
+use stream_download::storage::{StorageProvider, StorageReader};
+#[cfg(feature = "compression")]
+use stream_download::Decompression;
+use stream_download::{
+    channel, http, AdaptivePrefetch, ChunkTransform, ContentLengthCallback, ContentLengthExceeded,
+    ContentLengthResolver, DeadlineExceeded, DownloadError, FirstByteCallback,
+    MaxDownloadSizeExceeded, MetricsHandle, PrefetchSeek, Settings, StorageLost, StreamDownload,
+    TooManyRangeRequests,
+};
 use tokio::sync::{mpsc, oneshot};
 use tokio::task::spawn_blocking;
 
@@ -26,6 +62,7 @@ struct TestClient {
     inner: reqwest::Client,
     tx: mpsc::Sender<(Command, oneshot::Sender<Duration>)>,
     has_content_length: bool,
+    supports_range: bool,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -110,6 +147,14 @@ impl TestClient {
             inner: reqwest::Client::new(),
             tx,
             has_content_length,
+            supports_range: true,
+        }
+    }
+
+    fn without_range_support(self) -> Self {
+        Self {
+            supports_range: false,
+            ..self
         }
     }
 }
@@ -149,8 +194,30 @@ impl http::Client for TestClient {
         self.tx.send((Command::GetRange, tx)).await.unwrap();
         tokio::time::sleep(rx.await.unwrap()).await;
 
+        let inner = if self.supports_range {
+            self.inner.get_range(url, start, end).await?
+        } else {
+            http::Client::get(&self.inner, url).await?
+        };
+
+        Ok(TestResponse {
+            inner,
+            tx: self.tx.clone(),
+            has_content_length: self.has_content_length,
+        })
+    }
+
+    async fn get_with_headers(
+        &self,
+        url: &Self::Url,
+        headers: &[(String, String)],
+    ) -> Result<Self::Response, Self::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send((Command::GetRange, tx)).await.unwrap();
+        tokio::time::sleep(rx.await.unwrap()).await;
+
         Ok(TestResponse {
-            inner: self.inner.get_range(url, start, end).await?,
+            inner: self.inner.get_with_headers(url, headers).await?,
             tx: self.tx.clone(),
             has_content_length: self.has_content_length,
         })
@@ -174,7 +241,12 @@ impl http::ClientResponse for TestResponse {
     }
 
     fn headers(&self) -> Self::Headers {
-        http::ClientResponse::headers(&self.inner)
+        let mut headers = http::ClientResponse::headers(&self.inner);
+        if !self.has_content_length {
+            // Otherwise the length would be requested with a range request instead
+            headers.remove(reqwest::header::ACCEPT_RANGES);
+        }
+        headers
     }
 
     fn is_success(&self) -> bool {
@@ -185,6 +257,14 @@ impl http::ClientResponse for TestResponse {
         self.inner.status_error()
     }
 
+    fn trailers(&self) -> http::TrailersFuture<Self::Headers> {
+        let mut trailers = reqwest::header::HeaderMap::new();
+        if let Some(content_length) = self.inner.content_length() {
+            trailers.insert(reqwest::header::CONTENT_LENGTH, content_length.into());
+        }
+        Box::pin(future::ready(Some(trailers)))
+    }
+
     fn stream(self) -> Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Unpin + Send + Sync> {
         Box::new(TestStream {
             tx: self.tx.clone(),
@@ -195,10 +275,38 @@ impl http::ClientResponse for TestResponse {
     }
 }
 
+fn user_agents(query: &str) -> Vec<Option<String>> {
+    USER_AGENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(uri, _)| uri.ends_with(query))
+        .map(|(_, user_agent)| user_agent.clone())
+        .collect()
+}
+
+fn accept_encodings(query: &str) -> Vec<Option<String>> {
+    ACCEPT_ENCODINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(uri, _)| uri.ends_with(query))
+        .map(|(_, encoding)| encoding.clone())
+        .collect()
+}
+
 fn get_file_buf() -> Vec<u8> {
     fs::read("./assets/music.mp3").unwrap()
 }
 
+// Reads that reach the end of the content don't wait for the stream to finish, so tests that expect
+// the whole stream to be consumed need to wait for the download explicitly
+fn wait_for_download<P: StorageProvider>(reader: &StreamDownload<P>) {
+    while !reader.debug_state().download_complete() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
 fn compare(a: impl Into<Vec<u8>>, b: impl Into<Vec<u8>>) {
     let a = a.into();
     let b = b.into();
@@ -380,6 +488,7 @@ fn slow_download(
             let mut buf = Vec::new();
             reader.read_to_end(&mut buf).unwrap();
             compare(get_file_buf(), buf);
+            wait_for_download(&reader);
         })
         .await
         .unwrap();
@@ -414,7 +523,7 @@ fn bounded(
                 }
                 responder.send(Duration::from_millis(0)).ok();
             };
-            (rx, prefetch_size as usize)
+            (rx, prefetch_size)
         });
 
         let mut reader = StreamDownload::from_stream(
@@ -504,6 +613,7 @@ fn adaptive(
                 }
                 buf.extend(&temp_buf[..read_len]);
             }
+            wait_for_download(&reader);
             buf
         })
         .await
@@ -621,6 +731,7 @@ fn seek_basic(
             let file_buf = get_file_buf();
             compare(&file_buf[0..4096], initial_buf);
             compare(file_buf, buf);
+            wait_for_download(&reader);
         });
 
         handle.await.unwrap();
@@ -662,7 +773,29 @@ fn seek_all(
                 responder.send(Duration::from_millis(50)).unwrap();
             }
 
-            assert!(range_requests > 0);
+            let len = get_file_buf().len() as u64;
+            let seek_position1 = if seek_from1 == "end" {
+                len - seek_from_val1
+            } else {
+                seek_from_val1
+            };
+            // The first seek reads to the end, so the second one is relative to the end as well
+            let seek_position2 = if seek_from2 == "start" {
+                seek_from_val2
+            } else {
+                len - seek_from_val2
+            };
+            // Seeking to the end of the stream doesn't need to download anything, so the first
+            // seek that needs data decides whether a range request is sent. Seeks within the
+            // prefetch window are served by continuing prefetch, which also downloads the data
+            // before the seek position, so the other seek doesn't need a range request either.
+            let first_download = if seek_position1 == len {
+                seek_position2
+            } else {
+                seek_position1
+            };
+            let needs_range_request = first_download < len && first_download >= prefetch_bytes;
+            assert!(range_requests > 0 || !needs_range_request);
             assert!(stream_ends > 0);
         });
 
@@ -685,7 +818,9 @@ fn seek_all(
             if seek_from1 == "start" {
                 reader.seek(SeekFrom::Start(seek_from_val1)).unwrap();
             } else if seek_from1 == "end" {
-                reader.seek(SeekFrom::End(seek_from_val1 as i64)).unwrap();
+                reader
+                    .seek(SeekFrom::End(-(seek_from_val1 as i64)))
+                    .unwrap();
             } else if seek_from1 == "current" {
                 reader
                     .seek(SeekFrom::Current(seek_from_val1 as i64))
@@ -698,7 +833,9 @@ fn seek_all(
             if seek_from2 == "start" {
                 reader.seek(SeekFrom::Start(seek_from_val2)).unwrap();
             } else if seek_from2 == "end" {
-                reader.seek(SeekFrom::End(seek_from_val2 as i64)).unwrap();
+                reader
+                    .seek(SeekFrom::End(-(seek_from_val2 as i64)))
+                    .unwrap();
             } else if seek_from2 == "current" {
                 reader
                     .seek(SeekFrom::Current(-(seek_from_val2 as i64)))
@@ -721,6 +858,7 @@ fn seek_all(
             } else if seek_from2 == "end" || seek_from2 == "current" {
                 compare(&file_buf[file_buf.len() - seek_from_val2 as usize..], buf2);
             }
+            wait_for_download(&reader);
         })
         .await
         .unwrap();
@@ -762,3 +900,7070 @@ fn cancel_download(#[case] prefetch_bytes: u64) {
         .unwrap();
     });
 }
+
+#[rstest]
+fn seek_without_range_support(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::from_millis(0)).unwrap();
+
+            let mut range_requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests += 1;
+                }
+                responder.send(Duration::from_millis(50)).ok();
+            }
+            range_requests
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true).without_range_support(),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let seek_pos = file_buf.len() - 4096;
+            // The server only reveals that it ignores range requests once one is sent
+            assert!(reader.can_seek());
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+            assert!(!reader.can_seek());
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(1, handle.await.unwrap());
+    });
+}
+
+#[rstest]
+fn seek_buffered(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::from_millis(0)).unwrap();
+
+            let mut range_requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests += 1;
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+            range_requests
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf.clone(), buf);
+
+            let mid = file_buf.len() / 2;
+            reader.seek(SeekFrom::Start(mid as u64)).unwrap();
+            let mut buf = [0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[mid..mid + 4096], buf);
+
+            reader.seek(SeekFrom::Current(-8192)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[mid - 4096..mid], buf);
+
+            assert_eq!(
+                file_buf.len() as u64,
+                reader.seek(SeekFrom::End(0)).unwrap()
+            );
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(0, handle.await.unwrap());
+    });
+}
+
+#[rstest]
+fn prefetch_complete(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (start_tx, start_rx) = oneshot::channel::<()>();
+
+        let handle = tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::from_millis(0)).unwrap();
+
+            // Hold back the content until the reader has been created
+            start_rx.await.unwrap();
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(prefetch_bytes == 0, reader.prefetch_complete());
+        start_tx.send(()).unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            assert!(reader.prefetch_complete());
+        })
+        .await
+        .unwrap();
+
+        handle.await.unwrap();
+    });
+}
+
+#[rstest]
+fn flush_interval_download(
+    #[values(0, 64*1024, 1024*1024)] flush_interval: u64,
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .flush_interval(flush_interval),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut initial_buf = [0; 4096];
+            reader.read_exact(&mut initial_buf).unwrap();
+            compare(&file_buf[..4096], initial_buf);
+
+            reader.seek(SeekFrom::Start(128 * 1024)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[128 * 1024..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn cancel_download_buffered(
+    #[values(0, 1, 64*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(50)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = [0; 1];
+            reader.read_exact(&mut buf).unwrap();
+            reader.cancel_download();
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+
+            let file_buf = get_file_buf();
+            assert!(buf.len() < file_buf.len() - 1);
+            compare(&file_buf[1..buf.len() + 1], buf);
+
+            // reads past the downloaded data should keep returning EOF
+            let mut buf = [0; 1];
+            assert_eq!(0, reader.read(&mut buf).unwrap());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn mirrors(#[values(0, 256*1024)] prefetch_bytes: u64) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let stream = http::HttpStream::new_with_mirrors(
+            reqwest::Client::new(),
+            [
+                format!("http://{}/missing.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let file_buf = get_file_buf();
+        assert_eq!(file_buf.len() as u64, stream.content_length().unwrap());
+
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            TempStorageProvider::default(),
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            reader.seek(SeekFrom::Start(4096)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[4096..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn mirrors_all_failed() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let result = http::HttpStream::new_with_mirrors(
+            reqwest::Client::new(),
+            [
+                format!("http://{}/missing1.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+                format!("http://{}/missing2.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            ],
+        )
+        .await;
+
+        assert!(result.is_err());
+    });
+}
+
+#[rstest]
+fn stall_stats() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(10)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            TempStorageProvider::default(),
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            assert_eq!(0, reader.stats().stall_count());
+            assert_eq!(Duration::ZERO, reader.stats().total_stall_duration());
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+
+            let stats = reader.stats();
+            assert!(stats.stall_count() > 0);
+            assert!(stats.total_stall_duration() > Duration::ZERO);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn byte_counters(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            wait_for_download(&reader);
+
+            let stats = reader.stats();
+            let file_len = get_file_buf().len() as u64;
+            assert_eq!(file_len, stats.bytes_received());
+            assert_eq!(file_len, stats.bytes_on_disk());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn debug_state(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_len = get_file_buf().len() as u64;
+            let state = reader.debug_state();
+            assert_eq!(0, state.read_position());
+            assert_eq!(Some(file_len), state.content_length());
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+
+            let state = reader.debug_state();
+            assert_eq!(file_len, state.read_position());
+            assert_eq!(file_len, state.write_position());
+            assert_eq!(1, state.downloaded().len(), "{:?}", state.downloaded());
+            assert_eq!(0..file_len, state.downloaded()[0]);
+            assert!(state.download_complete());
+            assert_eq!(None, state.download_error());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+#[case(Some(12345))]
+#[case(None)]
+fn temp_preallocate(#[case] content_length: Option<u64>) {
+    let dir = std::env::temp_dir().join(format!(
+        "stream-download-preallocate-{}-{}",
+        std::process::id(),
+        content_length.unwrap_or_default()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+
+    let _reader = TempStorageProvider::new_in(&dir)
+        .create_reader(content_length)
+        .unwrap();
+    let files = fs::read_dir(&dir)
+        .unwrap()
+        .map(|f| f.unwrap().metadata().unwrap().len())
+        .collect::<Vec<_>>();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(vec![content_length.unwrap_or_default()], files);
+}
+
+#[rstest]
+fn content_length_override(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let file_buf = get_file_buf();
+        let stream = http::HttpStream::new(
+            TestClient::new(tx, false),
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(stream.content_length().is_none());
+
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .content_length_override(Some(file_buf.len() as u64)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            reader.seek(SeekFrom::End(-1024)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[file_buf.len() - 1024..], buf);
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn content_length_resolver(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let file_buf = get_file_buf();
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+        let stream = http::HttpStream::new(TestClient::new(tx, false), url.parse().unwrap())
+            .await
+            .unwrap();
+        assert!(stream.content_length().is_none());
+
+        let resolved_url = Arc::new(Mutex::new(None));
+        let resolved_url_ = resolved_url.clone();
+        let file_len = file_buf.len() as u64;
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default().content_length_resolver(Some(ContentLengthResolver::new(
+                move |info| {
+                    *resolved_url_.lock().unwrap() = info.url.clone();
+                    future::ready(Some(file_len))
+                },
+            ))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(url), *resolved_url.lock().unwrap());
+        assert_eq!(Some(file_len), reader.metrics_handle().content_length());
+
+        spawn_blocking(move || {
+            reader.seek(SeekFrom::End(-1024)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[file_buf.len() - 1024..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn content_length_resolver_precedence() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_len = get_file_buf().len() as u64;
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+
+        // The override is used without calling the resolver
+        let stream = http::HttpStream::new(reqwest::Client::new(), url.parse().unwrap())
+            .await
+            .unwrap();
+        let reader = StreamDownload::from_stream(
+            stream,
+            MemoryStorageProvider::default(),
+            Settings::default()
+                .content_length_override(Some(file_len))
+                .content_length_resolver(Some(ContentLengthResolver::new(|_| async {
+                    panic!("resolver should not be called")
+                }))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(file_len), reader.metrics_handle().content_length());
+
+        // The reported length is used if the resolver doesn't return one
+        let stream = http::HttpStream::new(reqwest::Client::new(), url.parse().unwrap())
+            .await
+            .unwrap();
+        let reader = StreamDownload::from_stream(
+            stream,
+            MemoryStorageProvider::default(),
+            Settings::default()
+                .content_length_resolver(Some(ContentLengthResolver::new(|_| future::ready(None)))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(file_len), reader.metrics_handle().content_length());
+    });
+}
+
+#[rstest]
+fn max_read_ahead(
+    #[values(0, 32*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let downloaded = Arc::new(AtomicUsize::new(0));
+        let downloaded_ = downloaded.clone();
+
+        tokio::spawn(async move {
+            while let Some((command, responder)) = rx.recv().await {
+                if let Command::NextChunk(size) = command {
+                    downloaded_.store(size, Ordering::SeqCst);
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let max_read_ahead = 64 * 1024;
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .max_read_ahead(Some(max_read_ahead)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = [0; 1];
+            reader.read_exact(&mut buf).unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+            // the downloader may overshoot the limit by one chunk at most
+            assert!(downloaded.load(Ordering::SeqCst) < 2 * max_read_ahead as usize);
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&get_file_buf()[1..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn trailers() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let mut stream = http::HttpStream::new(
+            TestClient::new(tx, true),
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let mut buf = Vec::new();
+        while let Some(bytes) = stream.next().await {
+            assert!(stream.trailers().is_none());
+            buf.extend(bytes.unwrap());
+        }
+        compare(get_file_buf(), buf.clone());
+        assert_eq!(
+            buf.len().to_string(),
+            stream
+                .trailers()
+                .unwrap()
+                .get("Content-Length")
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+    });
+}
+
+// Sends a `Content-Length` trailer with a fixed value regardless of the response body
+struct TrailerClient {
+    inner: reqwest::Client,
+    trailer_length: u64,
+}
+
+struct TrailerResponse {
+    inner: reqwest::Response,
+    trailer_length: u64,
+}
+
+#[async_trait]
+impl http::Client for TrailerClient {
+    type Url = reqwest::Url;
+    type Response = TrailerResponse;
+    type Error = reqwest::Error;
+    type Headers = reqwest::header::HeaderMap;
+
+    fn create() -> Self {
+        unimplemented!()
+    }
+
+    async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error> {
+        Ok(TrailerResponse {
+            inner: self.inner.get(url.clone()).send().await?,
+            trailer_length: self.trailer_length,
+        })
+    }
+
+    async fn get_range(
+        &self,
+        _url: &Self::Url,
+        _start: u64,
+        _end: Option<u64>,
+    ) -> Result<Self::Response, Self::Error> {
+        unimplemented!()
+    }
+}
+
+impl http::ClientResponse for TrailerResponse {
+    type Error = reqwest::Error;
+    type Headers = reqwest::header::HeaderMap;
+
+    fn content_length(&self) -> Option<u64> {
+        self.inner.content_length()
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.inner.content_type()
+    }
+
+    fn headers(&self) -> Self::Headers {
+        http::ClientResponse::headers(&self.inner)
+    }
+
+    fn is_success(&self) -> bool {
+        self.inner.is_success()
+    }
+
+    fn status_error(self) -> Result<(), Self::Error> {
+        self.inner.status_error()
+    }
+
+    fn trailers(&self) -> http::TrailersFuture<Self::Headers> {
+        let mut trailers = reqwest::header::HeaderMap::new();
+        trailers.insert(reqwest::header::CONTENT_LENGTH, self.trailer_length.into());
+        Box::pin(future::ready(Some(trailers)))
+    }
+
+    fn stream(self) -> Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Unpin + Send + Sync> {
+        Box::new(self.inner.bytes_stream())
+    }
+}
+
+#[rstest]
+fn mismatched_trailers(
+    #[values(false, true)] validate_trailers: bool,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_len = get_file_buf().len() as u64;
+        let stream = http::HttpStream::new(
+            TrailerClient {
+                inner: reqwest::Client::new(),
+                trailer_length: file_len - 1,
+            },
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default().validate_trailers(validate_trailers),
+        )
+        .await
+        .unwrap();
+
+        let reader = spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            reader
+        })
+        .await
+        .unwrap();
+        let result = reader.join().await;
+        if validate_trailers {
+            let err = result.unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert!(err.to_string().contains("trailers"), "{err}");
+        } else {
+            result.unwrap();
+        }
+    });
+}
+
+#[rstest]
+fn conditional_request() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let stream = http::HttpStream::new(reqwest::Client::new(), url.clone())
+            .await
+            .unwrap();
+        let validators = stream.cache_validators();
+        assert!(!validators.is_empty());
+
+        let result =
+            http::HttpStream::new_conditional(reqwest::Client::new(), url.clone(), &validators)
+                .await
+                .unwrap();
+        assert!(matches!(result, http::Revalidation::NotModified));
+
+        let result = http::HttpStream::new_conditional(
+            reqwest::Client::new(),
+            url,
+            &http::CacheValidators::default(),
+        )
+        .await
+        .unwrap();
+        let http::Revalidation::Modified(stream) = result else {
+            panic!("expected modified response");
+        };
+        let buf = stream.map(|bytes| bytes.unwrap().to_vec()).concat().await;
+        compare(get_file_buf(), buf);
+    });
+}
+
+#[rstest]
+fn custom_range_header(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let range_calls = Arc::new(AtomicUsize::new(0));
+        let range_calls_ = range_calls.clone();
+        let stream = http::HttpStream::new(
+            TestClient::new(tx, true),
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .range_header(Arc::new(move |start, end| {
+            range_calls_.fetch_add(1, Ordering::SeqCst);
+            vec![(
+                "Range".to_string(),
+                format!(
+                    "bytes={start}-{}",
+                    end.map(|e| e.to_string()).unwrap_or_default()
+                ),
+            )]
+        }));
+
+        let mut reader = StreamDownload::from_stream(stream, storage, Settings::default())
+            .await
+            .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+        })
+        .await
+        .unwrap();
+
+        assert!(range_calls.load(Ordering::SeqCst) > 0);
+    });
+}
+
+#[rstest]
+fn custom_range_unit(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let stream = http::HttpStream::new(
+            reqwest::Client::new(),
+            format!("http://{}/music.mp3?customunit", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .range_unit("items");
+
+        // Limit the read-ahead so seeking always needs a range request
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(64 * 1024)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+            // The server ignores ranges in bytes, which would have disabled seeking
+            assert!(reader.can_seek());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn mismatched_range_unit(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!(
+                "http://{}/music.mp3?mismatchedunit",
+                SERVER_ADDR.get().unwrap()
+            )
+            .parse()
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(64 * 1024)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            // The range response can't be placed in the stream, so it isn't treated as the start
+            // of the resource either
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert!(buf.is_empty());
+            assert!(reader
+                .debug_state()
+                .download_error()
+                .unwrap()
+                .contains("Content-Range"));
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn sparse_metadata_read(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let mut range_requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests += 1;
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+            range_requests
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .fill_gaps(false),
+        )
+        .await
+        .unwrap();
+
+        let reader = spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut header = [0; 4096];
+            reader.read_exact(&mut header).unwrap();
+            compare(&file_buf[..4096], header);
+
+            reader.seek(SeekFrom::End(-128)).unwrap();
+            let mut tail = [0; 128];
+            reader.read_exact(&mut tail).unwrap();
+            compare(&file_buf[file_buf.len() - 128..], tail);
+
+            // Give the downloader time to fill in the gap if it were going to
+            std::thread::sleep(Duration::from_millis(100));
+            let state = reader.debug_state();
+            assert_eq!(2, state.downloaded().len());
+            assert!(!state.download_complete());
+            reader
+        })
+        .await
+        .unwrap();
+        drop(reader);
+
+        assert_eq!(1, handle.await.unwrap());
+    });
+}
+
+#[rstest]
+fn sparse_read_fills_gap_on_demand(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .fill_gaps(false),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut header = [0; 4096];
+            reader.read_exact(&mut header).unwrap();
+            reader.seek(SeekFrom::End(-128)).unwrap();
+            let mut tail = [0; 128];
+            reader.read_exact(&mut tail).unwrap();
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn read_at_end(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (start_tx, start_rx) = oneshot::channel::<()>();
+
+        tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::from_millis(0)).unwrap();
+
+            // Hold back the content so any read that needs to wait on the download would block
+            start_rx.await.unwrap();
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let file_len = file_buf.len() as u64;
+            let mut buf = [0; 4096];
+
+            assert_eq!(file_len, reader.seek(SeekFrom::End(0)).unwrap());
+            assert_eq!(0, reader.read(&mut buf).unwrap());
+            assert_eq!(
+                file_len + 1,
+                reader.seek(SeekFrom::Start(file_len + 1)).unwrap()
+            );
+            assert_eq!(0, reader.read(&mut buf).unwrap());
+
+            start_tx.send(()).unwrap();
+            reader.seek(SeekFrom::End(-1)).unwrap();
+            assert_eq!(1, reader.read(&mut buf).unwrap());
+            assert_eq!(file_buf[file_buf.len() - 1], buf[0]);
+            assert_eq!(0, reader.read(&mut buf).unwrap());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+#[case("data:text/plain;base64,SGVsbG8sIFdvcmxkIQ==", "text/plain")]
+#[case("data:,Hello%2C%20World%21", "text/plain;charset=US-ASCII")]
+#[case("data:text/plain;base64,SGVsbG8s%20IFdvcmxk\nIQ==", "text/plain")]
+fn data_url(#[case] url: &str, #[case] media_type: &str) {
+    let stream = DataUrlStream::new(url).unwrap();
+    assert_eq!(media_type, stream.media_type());
+    assert_eq!(Some(13), stream.content_length());
+
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = String::new();
+            reader.read_to_string(&mut buf).unwrap();
+            assert_eq!("Hello, World!", buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+#[case("text/plain;base64,SGVsbG8=")]
+#[case("data:text/plain;base64")]
+#[case("data:text/plain;base64,SGVsbG8")]
+fn data_url_invalid(#[case] url: &str) {
+    assert_eq!(
+        io::ErrorKind::InvalidInput,
+        DataUrlStream::new(url).unwrap_err().kind()
+    );
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+fn data_url_seek(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    let file_buf = get_file_buf();
+    let url = file_buf
+        .iter()
+        .fold("data:audio/mpeg,".to_string(), |url, b| {
+            url + &format!("%{b:02X}")
+        });
+
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new::<DataUrlStream>(
+            url,
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "ftp")]
+#[rstest]
+fn ftp_seek(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values("user", "norest")] user: &str,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    let url = format!(
+        "ftp://{user}:password@{}/music.mp3",
+        FTP_ADDR.get().unwrap()
+    );
+
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new::<FtpStream>(
+            url,
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            assert_eq!(
+                Some(file_buf.len() as u64),
+                reader.debug_state().content_length()
+            );
+
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "ftp")]
+#[rstest]
+#[case("user", true)]
+#[case("norest", false)]
+fn ftp_seek_range(#[case] user: &str, #[case] supports_rest: bool) {
+    let url = format!(
+        "ftp://{user}:password@{}/music.mp3",
+        FTP_ADDR.get().unwrap()
+    );
+    let file_buf = get_file_buf();
+    let start = file_buf.len() - 4096;
+    let end = file_buf.len() - 1024;
+
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut stream = FtpStream::create(url).await.unwrap();
+        stream
+            .seek_range(start as u64, Some(end as u64))
+            .await
+            .unwrap();
+        assert_eq!(supports_rest, stream.supports_seek());
+        if supports_rest {
+            assert!(FTP_COMMANDS
+                .lock()
+                .unwrap()
+                .contains(&format!("REST {start}")));
+        }
+
+        let mut buf = Vec::new();
+        while let Some(bytes) = stream.next().await {
+            buf.extend_from_slice(&bytes.unwrap());
+        }
+        if supports_rest {
+            compare(&file_buf[start..end], buf);
+        } else {
+            // The whole file is sent when the server can't restart the transfer
+            compare(file_buf, buf);
+        }
+    });
+}
+
+#[cfg(feature = "ftp")]
+#[rstest]
+fn ftp_missing_file() {
+    let url = format!("ftp://{}/missing.mp3", FTP_ADDR.get().unwrap());
+    SERVER_RT.get().unwrap().block_on(async move {
+        FtpStream::create(url).await.unwrap_err();
+    });
+}
+
+#[cfg(feature = "ftp")]
+#[rstest]
+#[case("http://localhost/music.mp3")]
+#[case("ftp://localhost")]
+#[case("ftp://localhost/")]
+#[case("ftp://localhost:port/music.mp3")]
+#[case("ftp://user@/music.mp3")]
+fn ftp_url_invalid(#[case] url: &str) {
+    assert_eq!(
+        io::ErrorKind::InvalidInput,
+        FtpUrl::parse(url).unwrap_err().kind()
+    );
+}
+
+#[rstest]
+fn seek_debounce(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let mut range_requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests += 1;
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+            range_requests
+        });
+
+        let debounce = Duration::from_millis(100);
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .fill_gaps(false)
+                .seek_debounce(debounce),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let seek_pos = file_buf.len() - 4096;
+            let seek_start = std::time::Instant::now();
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            assert!(seek_start.elapsed() >= debounce);
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(1, handle.await.unwrap());
+    });
+}
+
+#[cfg(feature = "test-util")]
+#[rstest]
+fn seek_debounce_test_clock(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let range_requests = Arc::new(AtomicUsize::new(0));
+        let range_requests_ = range_requests.clone();
+
+        tokio::spawn(async move {
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests_.fetch_add(1, Ordering::SeqCst);
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let clock = TestClock::new();
+        let debounce = Duration::from_secs(60 * 60);
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .fill_gaps(false)
+                .seek_debounce(debounce)
+                .clock(clock.clone()),
+        )
+        .await
+        .unwrap();
+
+        let file_buf = get_file_buf();
+        let seek_pos = file_buf.len() - 4096;
+        let reader_handle = spawn_blocking(move || {
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+        });
+
+        // The seek isn't handled until the clock reaches the end of the debounce window
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(0, range_requests.load(Ordering::SeqCst));
+        clock.advance(debounce / 2);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(0, range_requests.load(Ordering::SeqCst));
+        clock.advance(debounce / 2);
+
+        reader_handle.await.unwrap();
+        assert_eq!(1, range_requests.load(Ordering::SeqCst));
+    });
+}
+
+#[rstest]
+fn pause_resume(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (start_tx, start_rx) = oneshot::channel::<()>();
+
+        tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::from_millis(0)).unwrap();
+
+            // Hold back the content until the download has been paused
+            start_rx.await.unwrap();
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        reader.pause();
+        start_tx.send(()).unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            // Reads still make progress while paused
+            let mut initial_buf = [0; 4096];
+            reader.read_exact(&mut initial_buf).unwrap();
+            compare(&file_buf[..4096], initial_buf);
+
+            std::thread::sleep(Duration::from_millis(50));
+            let write_position = reader.debug_state().write_position();
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(write_position, reader.debug_state().write_position());
+            assert!(!reader.debug_state().download_complete());
+
+            reader.resume();
+            wait_for_download(&reader);
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[4096..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+struct OffsetClient {
+    inner: reqwest::Client,
+    start: u64,
+}
+
+#[async_trait]
+impl http::Client for OffsetClient {
+    type Url = reqwest::Url;
+    type Response = reqwest::Response;
+    type Error = reqwest::Error;
+    type Headers = reqwest::header::HeaderMap;
+
+    fn create() -> Self {
+        unimplemented!()
+    }
+
+    async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error> {
+        // Simulate a server that only returns part of the resource for the initial request
+        http::Client::get_range(&self.inner, url, self.start, None).await
+    }
+
+    async fn get_range(
+        &self,
+        url: &Self::Url,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Self::Response, Self::Error> {
+        http::Client::get_range(&self.inner, url, start, end).await
+    }
+}
+
+#[rstest]
+fn initial_range_response(
+    #[values(0, 1, 4096)] start: u64,
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let stream = http::HttpStream::new(
+            OffsetClient {
+                inner: reqwest::Client::new(),
+                start,
+            },
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        let file_buf = get_file_buf();
+        assert_eq!(Some(file_buf.len() as u64), stream.content_length());
+        assert_eq!(start, stream.initial_position());
+
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+fn switch_source(#[values(0, 256*1024)] prefetch_bytes: u64, #[values(0, 4096)] read_len: usize) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            TempStorageProvider::default(),
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        let file_buf = get_file_buf();
+        let mut reader = spawn_blocking(move || {
+            let mut buf = vec![0; read_len];
+            reader.read_exact(&mut buf).unwrap();
+            reader
+        })
+        .await
+        .unwrap();
+
+        let new_buf: Vec<u8> = file_buf.iter().rev().copied().collect();
+        let url = new_buf
+            .iter()
+            .fold("data:audio/mpeg,".to_string(), |url, b| {
+                url + &format!("%{b:02X}")
+            });
+        reader
+            .switch_source::<DataUrlStream>(url, TempStorageProvider::default())
+            .await
+            .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&new_buf[read_len..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+fn switch_source_retain_buffer(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let mut reader = StreamDownload::new_http(
+            url.clone(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        let mut reader = spawn_blocking(move || {
+            let mut buf = vec![0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+            reader
+        })
+        .await
+        .unwrap();
+
+        let err = reader
+            .switch_source_retain_buffer::<DataUrlStream>("data:,different".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        reader
+            .switch_source_retain_buffer::<http::HttpStream<reqwest::Client>>(url)
+            .await
+            .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[4096..], buf);
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+struct GzipClient {
+    inner: reqwest::Client,
+}
+
+#[async_trait]
+impl http::Client for GzipClient {
+    type Url = reqwest::Url;
+    type Response = reqwest::Response;
+    type Error = reqwest::Error;
+    type Headers = reqwest::header::HeaderMap;
+
+    fn create() -> Self {
+        unimplemented!()
+    }
+
+    async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error> {
+        self.inner
+            .get(url.clone())
+            .header(reqwest::header::ACCEPT_ENCODING, "gzip")
+            .send()
+            .await
+    }
+
+    async fn get_range(
+        &self,
+        url: &Self::Url,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Self::Response, Self::Error> {
+        http::Client::get_range(&self.inner, url, start, end).await
+    }
+}
+
+#[rstest]
+fn content_encoding(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let stream = http::HttpStream::new(
+            GzipClient {
+                inner: reqwest::Client::new(),
+            },
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some("gzip"), stream.header("Content-Encoding"));
+        assert_eq!(None, stream.content_length());
+        assert!(!stream.supports_seek());
+
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            // The client doesn't decode the body, so we should get back the compressed file
+            let file_buf = fs::read("./assets/music.mp3.gz").unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn range_coalesce_seek(
+    #[values(0, 64*1024)] range_coalesce_threshold: u64,
+    #[values(48*1024, 256*1024)] seek_pos: usize,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let mut range_requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests += 1;
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+            range_requests
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .range_coalesce_threshold(range_coalesce_threshold),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = vec![0; 1024];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[..1024], buf);
+
+            // The read-ahead limit prevents the download from reaching this position yet
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+        })
+        .await
+        .unwrap();
+
+        let range_requests = handle.await.unwrap();
+        // Only short forward seeks continue the current request
+        if range_coalesce_threshold == 0 || seek_pos as u64 >= range_coalesce_threshold {
+            assert!(range_requests > 0);
+        } else {
+            assert_eq!(0, range_requests);
+        }
+    });
+}
+
+#[rstest]
+fn range_coalesce_gaps(
+    #[values(0, 64*1024)] range_coalesce_threshold: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let mut range_requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests += 1;
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+            range_requests
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .range_coalesce_threshold(range_coalesce_threshold),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            // Skip over two sections of the stream that are separated by a small downloaded range
+            for seek_pos in [150 * 1024, 280 * 1024] {
+                reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+                let mut buf = vec![0; 1024];
+                reader.read_exact(&mut buf).unwrap();
+                compare(&file_buf[seek_pos..seek_pos + 1024], buf);
+            }
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[280 * 1024 + 1024..], buf);
+
+            wait_for_download(&reader);
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+
+        // Two seeks plus one request for each gap that's filled in
+        let expected = if range_coalesce_threshold == 0 { 4 } else { 3 };
+        assert_eq!(expected, handle.await.unwrap());
+    });
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+fn source_info() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+        let reader = StreamDownload::new_http(
+            url.parse().unwrap(),
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+        let info = reader.source_info();
+        assert_eq!(Some(url), info.url);
+        assert_eq!(Some("audio/mpeg"), info.content_type.as_deref());
+        assert!(info.supports_seek);
+        assert_eq!(Some(get_file_buf().len() as u64), info.content_length);
+
+        let reader = StreamDownload::new::<DataUrlStream>(
+            "data:text/plain,hello".to_string(),
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+        let info = reader.source_info();
+        assert_eq!(None, info.url);
+        assert_eq!(Some("text/plain"), info.content_type.as_deref());
+        assert_eq!(Some(5), info.content_length);
+    });
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+fn can_seek() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+        assert!(reader.can_seek());
+        assert!(reader.metrics_handle().can_seek());
+
+        let reader = StreamDownload::new::<DataUrlStream>(
+            "data:text/plain,hello".to_string(),
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+        assert!(reader.can_seek());
+
+        let (_tx, rx) = mpsc::channel(32);
+        let reader = StreamDownload::new::<channel::ChannelStream>(
+            rx,
+            MemoryStorageProvider::default(),
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        assert!(!reader.can_seek());
+    });
+}
+
+#[rstest]
+fn probe() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+        let info = http::HttpStream::probe(reqwest::Client::new(), url.parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(Some(url.clone()), info.url);
+        assert_eq!(Some("audio/mpeg"), info.content_type.as_deref());
+        assert_eq!(Some(get_file_buf().len() as u64), info.content_length);
+        assert!(info.supports_seek);
+        assert_eq!(
+            info,
+            StreamDownload::probe(url.parse().unwrap()).await.unwrap()
+        );
+
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let commands = tokio::spawn(async move {
+            let mut commands = Vec::new();
+            while let Some((command, responder)) = rx.recv().await {
+                commands.push(command);
+                responder.send(Duration::from_millis(0)).ok();
+            }
+            commands
+        });
+        let info = http::HttpStream::probe(
+            TestClient::new(tx, true).without_range_support(),
+            url.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(get_file_buf().len() as u64), info.content_length);
+        assert!(!info.supports_seek);
+        // The body is never read
+        assert_eq!(vec![Command::GetRange], commands.await.unwrap());
+
+        let err = http::HttpStream::probe(
+            reqwest::Client::new(),
+            format!("http://{}/missing.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    });
+}
+
+#[rstest]
+fn max_range_requests(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let mut range_requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::GetRange {
+                    range_requests += 1;
+                }
+                responder.send(Duration::from_millis(0)).ok();
+            }
+            range_requests
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .max_range_requests(Some(1)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let seek_pos = 150 * 1024;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+
+            let err = reader.seek(SeekFrom::Start(280 * 1024)).unwrap_err();
+            assert!(err.get_ref().unwrap().is::<TooManyRangeRequests>());
+            assert_eq!(seek_pos as u64, reader.stream_position().unwrap());
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+            // The skipped part of the stream can't be filled in
+            wait_for_download(&reader);
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(1, handle.await.unwrap());
+    });
+}
+
+#[rstest]
+fn read_tail_first(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let mut range_requests = 0;
+            let mut held: Vec<oneshot::Sender<Duration>> = Vec::new();
+            while let Some((command, responder)) = rx.recv().await {
+                match command {
+                    Command::GetRange => {
+                        range_requests += 1;
+                        responder.send(Duration::from_millis(0)).ok();
+                        // Release the initial request once the tail has been requested
+                        for responder in held.drain(..) {
+                            responder.send(Duration::from_millis(0)).ok();
+                        }
+                    }
+                    // Hold back the initial request so none of the prefix is downloaded
+                    Command::NextChunk(_) if range_requests == 0 => held.push(responder),
+                    _ => {
+                        responder.send(Duration::from_millis(0)).ok();
+                    }
+                }
+            }
+            range_requests
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .fill_gaps(false),
+        )
+        .await
+        .unwrap();
+
+        let reader = spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let len = file_buf.len() as u64;
+
+            // The last 22 bytes are where a zip file's end of central directory record lives
+            reader.seek(SeekFrom::End(-22)).unwrap();
+            let mut tail = [0; 22];
+            reader.read_exact(&mut tail).unwrap();
+            compare(&file_buf[file_buf.len() - 22..], tail);
+            assert_eq!(0, reader.read(&mut [0; 1]).unwrap());
+
+            // A chunk from the initial request may have arrived before the seek, but the rest
+            // of the prefix shouldn't be downloaded
+            let state = reader.debug_state();
+            assert!(state.downloaded().len() <= 2);
+            assert_eq!(Some(&(len - 22..len)), state.downloaded().last());
+
+            // The prefix is only downloaded once it's read
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+            reader
+        })
+        .await
+        .unwrap();
+        drop(reader);
+
+        assert_eq!(2, handle.await.unwrap());
+    });
+}
+
+#[rstest]
+fn snapshot_to(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let mut sparse_reader = StreamDownload::new_http(
+            url.clone(),
+            storage.clone(),
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .fill_gaps(false),
+        )
+        .await
+        .unwrap();
+        let mut reader = StreamDownload::new_http(url, storage, Settings::default())
+            .await
+            .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("snapshot.mp3");
+
+            let mut buf = vec![0; 4096];
+            sparse_reader.read_exact(&mut buf).unwrap();
+            let seek_pos = 150 * 1024;
+            sparse_reader.seek(SeekFrom::Start(seek_pos)).unwrap();
+            sparse_reader.read_exact(&mut buf).unwrap();
+            sparse_reader.cancel_download();
+
+            // Only the contiguous prefix is exported
+            let prefix_len = sparse_reader.debug_state().downloaded()[0].end;
+            let copied = sparse_reader.snapshot_to(&path).unwrap();
+            assert_eq!(prefix_len, copied);
+            compare(&file_buf[..copied as usize], fs::read(&path).unwrap());
+            assert_eq!(seek_pos + 4096, sparse_reader.stream_position().unwrap());
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            let copied = reader.snapshot_to(&path).unwrap();
+            assert_eq!(file_buf.len() as u64, copied);
+            compare(file_buf, fs::read(&path).unwrap());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn finalize(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let mut reader = StreamDownload::new_http(
+            url.clone(),
+            storage.clone(),
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(64 * 1024)),
+        )
+        .await
+        .unwrap();
+
+        let file_buf = get_file_buf();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("partial.mp3");
+        let reader = spawn_blocking(move || {
+            let mut buf = vec![0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+            reader
+        })
+        .await
+        .unwrap();
+        let downloaded = reader.debug_state().downloaded()[0].end;
+        let written = reader.finalize(&path).await.unwrap();
+        assert!(written >= downloaded);
+        assert!(written < file_buf.len() as u64);
+        compare(&file_buf[..written as usize], fs::read(&path).unwrap());
+
+        // The file can be used to resume the download
+        let mut reader = StreamDownload::from_stream_resumed(
+            http::HttpStream::new(reqwest::Client::new(), url)
+                .await
+                .unwrap(),
+            storage,
+            Settings::default(),
+            written,
+            fs::File::open(&path).unwrap(),
+        )
+        .await
+        .unwrap();
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+struct OverDeliveringStream {
+    data: Bytes,
+    position: usize,
+    reported_length: u64,
+}
+
+impl Stream for OverDeliveringStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.position >= self.data.len() {
+            return Poll::Ready(None);
+        }
+        let end = (self.position + 4096).min(self.data.len());
+        let chunk = self.data.slice(self.position..end);
+        self.position = end;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[async_trait]
+impl SourceStream for OverDeliveringStream {
+    type Url = u64;
+    type StreamError = io::Error;
+
+    async fn create(reported_length: Self::Url) -> io::Result<Self> {
+        Ok(Self {
+            data: get_file_buf().into(),
+            position: 0,
+            reported_length,
+        })
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.reported_length)
+    }
+
+    async fn seek_range(&mut self, start: u64, _end: Option<u64>) -> io::Result<()> {
+        self.position = start as usize;
+        Ok(())
+    }
+}
+
+#[rstest]
+fn content_length_exceeded(
+    #[values(ContentLengthExceeded::TreatAsUnknown, ContentLengthExceeded::Error)]
+    behavior: ContentLengthExceeded,
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let reported_length = file_buf.len() as u64 - 10000;
+        let mut reader = StreamDownload::new::<OverDeliveringStream>(
+            reported_length,
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .content_length_exceeded(behavior),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            wait_for_download(&reader);
+            let state = reader.debug_state();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+
+            match behavior {
+                ContentLengthExceeded::TreatAsUnknown => {
+                    assert_eq!(None, state.content_length());
+                    assert_eq!(None, state.download_error());
+                    compare(file_buf, buf);
+                }
+                ContentLengthExceeded::Error => {
+                    assert_eq!(Some(reported_length), state.content_length());
+                    assert!(state.download_error().is_some());
+                    compare(&file_buf[..reported_length as usize], buf);
+                }
+            }
+        })
+        .await
+        .unwrap();
+    });
+}
+
+// Breaks the connection at each of the given positions until the stream is restarted
+struct FailingStream {
+    data: Bytes,
+    position: usize,
+    fail_at: Vec<usize>,
+    broken: bool,
+}
+
+impl Stream for FailingStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let position = self.position;
+        if !self.broken && self.fail_at.first().is_some_and(|pos| *pos <= position) {
+            self.fail_at.remove(0);
+            self.broken = true;
+        }
+        if self.broken {
+            return Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset",
+            ))));
+        }
+        if self.position >= self.data.len() {
+            return Poll::Ready(None);
+        }
+        let end = (self.position + 4096).min(self.data.len());
+        let chunk = self.data.slice(self.position..end);
+        self.position = end;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[async_trait]
+impl SourceStream for FailingStream {
+    type Url = Vec<usize>;
+    type StreamError = io::Error;
+
+    async fn create(fail_at: Self::Url) -> io::Result<Self> {
+        Ok(Self {
+            data: get_file_buf().into(),
+            position: 0,
+            fail_at,
+            broken: false,
+        })
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.data.len() as u64)
+    }
+
+    async fn seek_range(&mut self, start: u64, _end: Option<u64>) -> io::Result<()> {
+        self.position = start as usize;
+        self.broken = false;
+        Ok(())
+    }
+}
+
+#[rstest]
+fn backoff_retry(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new::<FailingStream>(
+            vec![100_000, 100_000, 500_000],
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .backoff(Fixed::new(Duration::from_millis(1), 2)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            wait_for_download(&reader);
+            assert_eq!(None, reader.debug_state().download_error());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn backoff_retries_exhausted(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let reader = StreamDownload::new::<FailingStream>(
+            vec![100_000; 3],
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .backoff(Jitter::new(Exponential::new(
+                    Duration::from_millis(1),
+                    Duration::from_millis(10),
+                    2,
+                ))),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            wait_for_download(&reader);
+            assert!(reader.debug_state().download_error().is_some());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[test]
+fn backoff_delays() {
+    let ms = Duration::from_millis;
+    let delays = |backoff: &dyn BackoffStrategy| {
+        (1..)
+            .map_while(|attempt| backoff.next_delay(attempt))
+            .map(|delay| delay.as_millis())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        vec![10, 20, 40, 50, 50],
+        delays(&Exponential::new(ms(10), ms(50), 5))
+    );
+    assert_eq!(vec![10, 10], delays(&Fixed::new(ms(10), 2)));
+    assert_eq!(
+        vec![10, 10, 20, 30, 50, 70],
+        delays(&Fibonacci::new(ms(10), ms(70), 6))
+    );
+
+    let jitter = Jitter::new(Fixed::new(ms(100), 100));
+    for attempt in 1..=100 {
+        let delay = jitter.next_delay(attempt).unwrap();
+        assert!(delay >= ms(50) && delay <= ms(100));
+    }
+    assert_eq!(None, jitter.next_delay(101));
+}
+
+struct RecordingClient {
+    inner: TestClient,
+    range_starts: Arc<Mutex<Vec<u64>>>,
+}
+
+#[async_trait]
+impl http::Client for RecordingClient {
+    type Url = reqwest::Url;
+    type Response = TestResponse;
+    type Error = reqwest::Error;
+    type Headers = reqwest::header::HeaderMap;
+
+    fn create() -> Self {
+        unimplemented!()
+    }
+
+    async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error> {
+        self.inner.get(url).await
+    }
+
+    async fn get_range(
+        &self,
+        url: &Self::Url,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Self::Response, Self::Error> {
+        self.range_starts.lock().unwrap().push(start);
+        self.inner.get_range(url, start, end).await
+    }
+}
+
+#[rstest]
+fn resume_download(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let resume_from = 100_000;
+        let range_starts = Arc::new(Mutex::new(Vec::new()));
+        let existing = file_buf[..resume_from as usize].to_vec();
+        let mut reader = StreamDownload::from_stream_resumed(
+            http::HttpStream::new(
+                RecordingClient {
+                    inner: TestClient::new(tx, true),
+                    range_starts: range_starts.clone(),
+                },
+                url.parse().unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage.clone(),
+            Settings::default().prefetch_bytes(prefetch_bytes),
+            resume_from,
+            existing.as_slice(),
+        )
+        .await
+        .unwrap();
+
+        let file_buf_ = file_buf.clone();
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf_, buf);
+        })
+        .await
+        .unwrap();
+        assert_eq!(vec![resume_from], *range_starts.lock().unwrap());
+
+        let stream = http::HttpStream::new(reqwest::Client::new(), url.parse().unwrap())
+            .await
+            .unwrap();
+        let err = StreamDownload::from_stream_resumed(
+            stream,
+            storage.clone(),
+            Settings::default(),
+            file_buf.len() as u64 + 1,
+            io::repeat(0),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let stream = http::HttpStream::new(reqwest::Client::new(), url.parse().unwrap())
+            .await
+            .unwrap();
+        let err = StreamDownload::from_stream_resumed(
+            stream,
+            storage,
+            Settings::default(),
+            1024,
+            [0; 512].as_slice(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    });
+}
+
+#[rstest]
+fn fill_gaps_ahead_of_reader(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (start_tx, start_rx) = oneshot::channel::<()>();
+
+        tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::from_millis(0)).unwrap();
+
+            // Hold back the content until the download has been paused
+            start_rx.await.unwrap();
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let range_starts = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                RecordingClient {
+                    inner: TestClient::new(tx, true),
+                    range_starts: range_starts.clone(),
+                },
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        reader.pause();
+        start_tx.send(()).unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let first_seek = 150 * 1024;
+            let second_seek = 270 * 1024;
+            // Only the requested data is downloaded while paused, leaving gaps on both sides of
+            // the first seek position
+            for seek_pos in [first_seek, second_seek] {
+                reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+                let mut buf = [0; 1024];
+                reader.read_exact(&mut buf).unwrap();
+                compare(&file_buf[seek_pos..seek_pos + 1024], buf);
+            }
+
+            reader
+                .seek(SeekFrom::Start(first_seek as u64 + 512))
+                .unwrap();
+            reader.resume();
+            wait_for_download(&reader);
+
+            let range_starts = range_starts.lock().unwrap().clone();
+            assert_eq!([first_seek as u64, second_seek as u64], range_starts[..2]);
+            // The gap the reader will hit next is filled before the one behind it
+            assert!((first_seek as u64..second_seek as u64).contains(&range_starts[2]));
+            assert!(range_starts[3] < first_seek as u64);
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[first_seek + 512..], buf);
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn chunks(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(0, 4096)] read_len: usize,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = vec![0; read_len];
+            reader.read_exact(&mut buf).unwrap();
+
+            // Chunks pick up from the current position
+            let chunks = reader.chunks().collect::<io::Result<Vec<_>>>().unwrap();
+            assert!(chunks
+                .iter()
+                .all(|chunk| !chunk.is_empty() && chunk.len() <= 64 * 1024));
+            buf.extend(chunks.into_iter().flatten());
+            compare(file_buf, buf);
+            assert_eq!(0, reader.chunks().count());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn chunks_after_download(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            wait_for_download(&reader);
+
+            // The whole stream is available, but chunks are still limited in size
+            let chunks = reader.chunks().collect::<io::Result<Vec<_>>>().unwrap();
+            assert!(chunks.len() > 1);
+            assert!(chunks.iter().all(|chunk| chunk.len() <= 64 * 1024));
+            assert_eq!(64 * 1024, chunks[0].len());
+            let buf = chunks.into_iter().flatten().collect::<Vec<_>>();
+            compare(get_file_buf(), buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn post_request(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let stream = http::HttpStream::new_post(
+            reqwest::Client::new(),
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            "query",
+        )
+        .await
+        .unwrap();
+        let file_buf = get_file_buf();
+        assert_eq!(Some(file_buf.len() as u64), stream.content_length());
+
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            // Range requests reuse the body, otherwise the server would reject them
+            let seek_pos = 150 * 1024;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+            assert!(reader.debug_state().download_error().is_none());
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn post_request_without_body() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let result = http::HttpStream::new_post(
+            reqwest::Client::new(),
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            "",
+        )
+        .await;
+        assert!(result.is_err());
+    });
+}
+
+#[rstest]
+fn disk_budget(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let budget = DiskBudget::new(64 * 1024);
+        let (start_tx, _) = tokio::sync::broadcast::channel::<()>(1);
+        let mut readers = Vec::new();
+        for _ in 0..2 {
+            let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+            let mut start_rx = start_tx.subscribe();
+            tokio::spawn(async move {
+                let (command, responder) = rx.recv().await.unwrap();
+                assert_eq!(Command::GetUrl, command);
+                responder.send(Duration::from_millis(0)).unwrap();
+
+                // Hold back the content until both downloads are registered with the budget
+                start_rx.recv().await.unwrap();
+                while let Some((_, responder)) = rx.recv().await {
+                    responder.send(Duration::from_millis(0)).ok();
+                }
+            });
+
+            readers.push(
+                StreamDownload::from_stream(
+                    http::HttpStream::new(
+                        TestClient::new(tx, true),
+                        format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                            .parse()
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap(),
+                    storage.clone(),
+                    Settings::default()
+                        .prefetch_bytes(0)
+                        .max_read_ahead(Some(128 * 1024))
+                        .disk_budget(Some(budget.clone())),
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        assert_eq!(2, budget.downloads());
+        start_tx.send(()).unwrap();
+
+        let budget_ = budget.clone();
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut second = readers.pop().unwrap();
+            let mut first = readers.pop().unwrap();
+            let mut buf = [0; 1];
+            first.read_exact(&mut buf).unwrap();
+            second.read_exact(&mut buf).unwrap();
+
+            // The second download was read from more recently, so it keeps going until it
+            // reaches the read-ahead limit while the first one waits for space
+            while second.debug_state().write_position() < 128 * 1024 {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            let write_position = first.debug_state().write_position();
+            assert!(write_position < 128 * 1024);
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(write_position, first.debug_state().write_position());
+            assert!(budget_.usage() >= budget_.limit());
+
+            // Reading ignores the budget
+            let mut buf = Vec::new();
+            first.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[1..], buf);
+            drop(first);
+            assert_eq!(1, budget_.downloads());
+
+            let mut buf = Vec::new();
+            second.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[1..], buf);
+        })
+        .await
+        .unwrap();
+        assert_eq!(0, budget.downloads());
+        assert_eq!(0, budget.usage());
+    });
+}
+
+#[cfg(feature = "test-util")]
+#[rstest]
+fn disk_budget_test_clock(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let clock = TestClock::new();
+        let budget = DiskBudget::new(64 * 1024);
+        let (start_tx, _) = tokio::sync::broadcast::channel::<()>(1);
+        let mut readers = Vec::new();
+        for _ in 0..2 {
+            let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+            let mut start_rx = start_tx.subscribe();
+            tokio::spawn(async move {
+                let (command, responder) = rx.recv().await.unwrap();
+                assert_eq!(Command::GetUrl, command);
+                responder.send(Duration::from_millis(0)).unwrap();
+
+                // Hold back the content until both downloads are registered with the budget
+                start_rx.recv().await.unwrap();
+                while let Some((_, responder)) = rx.recv().await {
+                    responder.send(Duration::from_millis(0)).ok();
+                }
+            });
+
+            readers.push(
+                StreamDownload::from_stream(
+                    http::HttpStream::new(
+                        TestClient::new(tx, true),
+                        format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                            .parse()
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap(),
+                    storage.clone(),
+                    Settings::default()
+                        .prefetch_bytes(0)
+                        .max_read_ahead(Some(128 * 1024))
+                        .disk_budget(Some(budget.clone()))
+                        .clock(clock.clone()),
+                )
+                .await
+                .unwrap(),
+            );
+        }
+
+        let budget_ = budget.clone();
+        spawn_blocking(move || {
+            let mut second = readers.pop().unwrap();
+            let mut first = readers.pop().unwrap();
+            start_tx.send(()).unwrap();
+            let mut buf = [0; 1];
+            second.read_exact(&mut buf).unwrap();
+            first.read_exact(&mut buf).unwrap();
+
+            // The first download was read from last, but the clock hasn't moved, so neither was
+            // read from more recently and both keep going once the budget is used up
+            for reader in [&first, &second] {
+                while reader.debug_state().write_position() < 128 * 1024 {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+            assert!(budget_.usage() > budget_.limit());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn connection_limit(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let limit = ConnectionLimit::new(1);
+        let url: reqwest::Url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let settings = Settings::default()
+            .prefetch_bytes(0)
+            .connection_limit(Some(limit.clone()));
+        // The first download pauses once it reaches the read-ahead limit, but it keeps its
+        // connection
+        let first = StreamDownload::new_http(
+            url.clone(),
+            storage.clone(),
+            settings.clone().max_read_ahead(Some(64 * 1024)),
+        )
+        .await
+        .unwrap();
+        let mut second = StreamDownload::new_http(url, storage, settings)
+            .await
+            .unwrap();
+
+        let limit_ = limit.clone();
+        spawn_blocking(move || {
+            while first.debug_state().write_position() < 64 * 1024 {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(0, second.debug_state().write_position());
+            assert_eq!(
+                HashMap::from([(SERVER_ADDR.get().unwrap().to_string(), 1)]),
+                limit_.in_flight()
+            );
+
+            // The response was closed while waiting, so it's requested again once the first
+            // download releases its connection
+            drop(first);
+            let mut buf = Vec::new();
+            second.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            wait_for_download(&second);
+        })
+        .await
+        .unwrap();
+        assert!(limit.in_flight().is_empty());
+    });
+}
+
+#[rstest]
+fn seek_out_of_bounds(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let file_len = file_buf.len() as u64;
+            let mut buf = [0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+
+            for seek_from in [
+                SeekFrom::End(-(file_len as i64 + 1)),
+                SeekFrom::End(i64::MIN),
+                SeekFrom::Current(-4097),
+                SeekFrom::Current(i64::MIN),
+            ] {
+                let err = reader.seek(seek_from).unwrap_err();
+                assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+                // The position is unchanged
+                assert_eq!(4096, reader.stream_position().unwrap());
+            }
+
+            assert_eq!(file_len, reader.seek(SeekFrom::End(0)).unwrap());
+            assert_eq!(0, reader.read(&mut buf).unwrap());
+
+            // Seeking past the end is allowed, like std::io::Cursor
+            assert_eq!(file_len + 1, reader.seek(SeekFrom::End(1)).unwrap());
+            assert_eq!(0, reader.read(&mut buf).unwrap());
+
+            assert_eq!(0, reader.seek(SeekFrom::End(-(file_len as i64))).unwrap());
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+struct ChannelStream {
+    rx: mpsc::UnboundedReceiver<Bytes>,
+    content_length: u64,
+}
+
+impl Stream for ChannelStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|chunk| chunk.map(Ok))
+    }
+}
+
+#[async_trait]
+impl SourceStream for ChannelStream {
+    type Url = ();
+    type StreamError = io::Error;
+
+    async fn create(_: Self::Url) -> io::Result<Self> {
+        unimplemented!()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.content_length)
+    }
+
+    async fn seek_range(&mut self, _start: u64, _end: Option<u64>) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "seeking is not supported",
+        ))
+    }
+}
+
+#[rstest]
+fn try_read(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut reader = StreamDownload::from_stream(
+            ChannelStream {
+                rx,
+                content_length: file_buf.len() as u64,
+            },
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = [0; 4096];
+            let timeout = || Instant::now() + Duration::from_millis(50);
+            let err = reader.try_read(&mut buf, timeout()).unwrap_err();
+            assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+            assert_eq!(0, reader.debug_state().read_position());
+
+            // Only the data that's available is returned
+            tx.send(Bytes::copy_from_slice(&file_buf[..1024])).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            assert_eq!(1024, reader.try_read(&mut buf, deadline).unwrap());
+            compare(&file_buf[..1024], &buf[..1024]);
+            let err = reader.try_read(&mut buf, timeout()).unwrap_err();
+            assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+            assert_eq!(1024, reader.debug_state().read_position());
+
+            // Abandoned requests don't affect later reads
+            for chunk in file_buf[1024..].chunks(4096) {
+                tx.send(Bytes::copy_from_slice(chunk)).unwrap();
+            }
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[1024..], buf);
+            assert_eq!(0, reader.try_read(&mut [0; 4096], timeout()).unwrap());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn reader_notifications(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let chunk_count = 16;
+        let data = Bytes::copy_from_slice(&file_buf[..chunk_count * 4096]);
+
+        // Without a blocked reader, none of the updates wake anything up
+        let (tx, rx) = mpsc::unbounded_channel();
+        let reader = StreamDownload::from_stream(
+            ChannelStream {
+                rx,
+                content_length: data.len() as u64,
+            },
+            storage.clone(),
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        for chunk in data.chunks(4096) {
+            tx.send(Bytes::copy_from_slice(chunk)).unwrap();
+        }
+        drop(tx);
+        let reader = spawn_blocking(move || {
+            wait_for_download(&reader);
+            reader
+        })
+        .await
+        .unwrap();
+        assert_eq!(0, reader.debug_state().reader_notifications());
+
+        // A blocked reader is woken up once for each chunk it waits on
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut reader = StreamDownload::from_stream(
+            ChannelStream {
+                rx,
+                content_length: data.len() as u64,
+            },
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        let metrics = reader.metrics_handle();
+        let expected = data.clone();
+        let read_task = spawn_blocking(move || {
+            let mut buf = vec![0; 4096];
+            for chunk in expected.chunks(4096) {
+                reader.read_exact(&mut buf).unwrap();
+                compare(chunk, buf.as_slice());
+            }
+            reader
+        });
+        for (i, chunk) in data.chunks(4096).enumerate() {
+            // Give the reader time to block after requesting the chunk
+            let position = ((i + 1) * 4096) as u64;
+            while metrics.debug_state().requested_position() != Some(position) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tx.send(Bytes::copy_from_slice(chunk)).unwrap();
+        }
+        let reader = read_task.await.unwrap();
+        let notifications = reader.debug_state().reader_notifications();
+        assert!(
+            (1..=chunk_count as u64).contains(&notifications),
+            "{notifications} notifications"
+        );
+    });
+}
+
+#[rstest]
+fn would_block_at(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let length = file_buf.len() as u64;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let reader = StreamDownload::from_stream(
+            ChannelStream {
+                rx,
+                content_length: length,
+            },
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        assert!(reader.would_block_at(0));
+        tx.send(Bytes::copy_from_slice(&file_buf[..1024])).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reader.would_block_at(0) {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!reader.would_block_at(1023));
+        assert!(reader.would_block_at(1024));
+        // Reads at the end of the stream return immediately
+        assert!(!reader.would_block_at(length));
+        assert!(!reader.would_block_at(length + 1));
+        // Checking doesn't request any data
+        assert_eq!(None, reader.debug_state().requested_position());
+
+        reader.cancel_download();
+        while !reader.debug_state().download_complete() {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!reader.would_block_at(1024));
+    });
+}
+
+#[rstest]
+fn ensure_buffered(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let length = file_buf.len() as u64;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut reader = StreamDownload::from_stream(
+            ChannelStream {
+                rx,
+                content_length: length,
+            },
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let sender = {
+                let file_buf = file_buf.clone();
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    for chunk in file_buf[..8192].chunks(1024) {
+                        std::thread::sleep(Duration::from_millis(10));
+                        tx.send(Bytes::copy_from_slice(chunk)).unwrap();
+                    }
+                })
+            };
+            reader.ensure_buffered(8192).unwrap();
+            assert!(!reader.would_block_at(8191));
+            // The reader doesn't move
+            assert_eq!(0, reader.debug_state().read_position());
+            sender.join().unwrap();
+
+            // Positions that are already available return immediately
+            reader.ensure_buffered(4096).unwrap();
+
+            let mut buf = vec![0; 8192];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[..8192], buf);
+
+            // The stream ends before reaching the requested position
+            tx.send(Bytes::copy_from_slice(&file_buf[8192..16384]))
+                .unwrap();
+            drop(tx);
+            assert!(reader.ensure_buffered(length).is_err());
+            assert!(!reader.would_block_at(16383));
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn seek_from_end_before_download(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let length = get_file_buf().len() as u64;
+        // No data is ever sent, so anything that waits on the download would hang
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let mut reader = StreamDownload::from_stream(
+            ChannelStream {
+                rx,
+                content_length: length,
+            },
+            storage.clone(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+        let mut unknown_length_reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, false),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            assert_eq!(length, reader.seek(SeekFrom::End(0)).unwrap());
+            assert_eq!(0, reader.read(&mut [0; 1024]).unwrap());
+
+            let err = unknown_length_reader.seek(SeekFrom::End(0)).unwrap_err();
+            assert_eq!(io::ErrorKind::Unsupported, err.kind());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[test]
+fn download_to_async() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+        let stream = http::HttpStream::new(reqwest::Client::new(), url.parse().unwrap())
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        let written = StreamDownload::download_to_async(stream, &mut buf, Settings::default())
+            .await
+            .unwrap();
+        assert_eq!(file_buf.len() as u64, written);
+        compare(file_buf.clone(), buf);
+
+        // Writer errors are returned
+        let stream = http::HttpStream::new(reqwest::Client::new(), url.parse().unwrap())
+            .await
+            .unwrap();
+        let mut small_buf = [0; 1024];
+        let err = StreamDownload::download_to_async(
+            stream,
+            &mut io::Cursor::new(&mut small_buf[..]),
+            Settings::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::WriteZero, err.kind());
+
+        // Streams that end early return an error after writing everything they sent
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Bytes::copy_from_slice(&file_buf[..1024])).unwrap();
+        drop(tx);
+        let stream = ChannelStream {
+            rx,
+            content_length: file_buf.len() as u64,
+        };
+        let mut buf = Vec::new();
+        let err = StreamDownload::download_to_async(stream, &mut buf, Settings::default())
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        compare(&file_buf[..1024], buf);
+
+        // Cancelling keeps the data that was already written
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Bytes::copy_from_slice(&file_buf[..1024])).unwrap();
+        let stream = ChannelStream {
+            rx,
+            content_length: file_buf.len() as u64,
+        };
+        let mut buf = Vec::new();
+        tokio::time::timeout(
+            Duration::from_millis(50),
+            StreamDownload::download_to_async(stream, &mut buf, Settings::default()),
+        )
+        .await
+        .unwrap_err();
+        compare(&file_buf[..1024], buf);
+        drop(tx);
+    });
+}
+
+#[test]
+fn download_to_async_retry() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let content_lengths = Arc::new(Mutex::new(Vec::new()));
+        let first_byte = Arc::new(AtomicBool::new(false));
+        let settings = Settings::default()
+            .backoff(Fixed::new(Duration::from_millis(1), 2))
+            .on_content_length(Some(ContentLengthCallback::new({
+                let content_lengths = content_lengths.clone();
+                move |content_length| content_lengths.lock().unwrap().push(content_length)
+            })))
+            .on_first_byte(Some(FirstByteCallback::new({
+                let first_byte = first_byte.clone();
+                move || first_byte.store(true, Ordering::SeqCst)
+            })));
+
+        let stream = FailingStream::create(vec![100_000, 100_000, 500_000])
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        let written = StreamDownload::download_to_async(stream, &mut buf, settings.clone())
+            .await
+            .unwrap();
+        assert_eq!(file_buf.len() as u64, written);
+        compare(file_buf.clone(), buf);
+        assert_eq!(
+            vec![Some(file_buf.len() as u64)],
+            *content_lengths.lock().unwrap()
+        );
+        assert!(first_byte.load(Ordering::SeqCst));
+
+        // The stream error is returned with its original kind once the retries run out
+        let stream = FailingStream::create(vec![100_000; 3]).await.unwrap();
+        let err = StreamDownload::download_to_async(stream, &mut Vec::new(), settings)
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+
+        // Without a backoff strategy, the first error is returned
+        let stream = FailingStream::create(vec![100 * 1024]).await.unwrap();
+        let mut buf = Vec::new();
+        let err = StreamDownload::download_to_async(stream, &mut buf, Settings::default())
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+        compare(&file_buf[..100 * 1024], buf);
+    });
+}
+
+struct PanickingStream;
+
+impl Stream for PanickingStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        panic!("stream panicked");
+    }
+}
+
+#[async_trait]
+impl SourceStream for PanickingStream {
+    type Url = ();
+    type StreamError = io::Error;
+
+    async fn create(_: Self::Url) -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        None
+    }
+
+    async fn seek_range(&mut self, _start: u64, _end: Option<u64>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[rstest]
+fn join(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage.clone(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        let reader = spawn_blocking(move || {
+            let mut reader = reader;
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            reader
+        })
+        .await
+        .unwrap();
+        reader.join().await.unwrap();
+
+        // Cancelling isn't an error
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage.clone(),
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        reader.cancel_download();
+        reader.join().await.unwrap();
+
+        let reader = StreamDownload::new::<PanickingStream>((), storage, Settings::default())
+            .await
+            .unwrap();
+        let err = reader.join().await.unwrap_err();
+        let join_error = err
+            .into_inner()
+            .unwrap()
+            .downcast::<tokio::task::JoinError>()
+            .unwrap();
+        assert!(join_error.is_panic());
+    });
+}
+
+#[rstest]
+fn join_download_error(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let reader = StreamDownload::new::<OverDeliveringStream>(
+            1024,
+            storage,
+            Settings::default().content_length_exceeded(ContentLengthExceeded::Error),
+        )
+        .await
+        .unwrap();
+        let err = reader.join().await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    });
+}
+
+#[rstest]
+fn socket_options(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let socket_options = http::SocketOptions::default()
+            .tcp_nodelay(true)
+            .tcp_keepalive(Duration::from_secs(30));
+        assert_eq!(Some(true), socket_options.get_tcp_nodelay());
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            socket_options.get_tcp_keepalive()
+        );
+
+        let options = http::ClientOptions::default().socket_options(socket_options);
+        assert_eq!(socket_options, options.get_socket_options());
+
+        let stream = http::HttpStream::<reqwest::Client>::new_with_options(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            options,
+        )
+        .await
+        .unwrap();
+
+        let mut reader = StreamDownload::from_stream(stream, storage, Settings::default())
+            .await
+            .unwrap();
+
+        spawn_blocking(move || {
+            reader.seek(SeekFrom::Start(1024)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&get_file_buf()[1024..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn buffer_options(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let options = http::BufferOptions::default()
+            .http2_stream_window_size(64 * 1024)
+            .http2_connection_window_size(128 * 1024)
+            .http2_max_frame_size(16 * 1024);
+        assert_eq!(Some(64 * 1024), options.get_http2_stream_window_size());
+        assert_eq!(Some(128 * 1024), options.get_http2_connection_window_size());
+        assert_eq!(Some(16 * 1024), options.get_http2_max_frame_size());
+
+        let stream = http::HttpStream::<reqwest::Client>::new_with_options(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            http::ClientOptions::default().buffer_options(options),
+        )
+        .await
+        .unwrap();
+
+        let mut reader = StreamDownload::from_stream(stream, storage, Settings::default())
+            .await
+            .unwrap();
+
+        spawn_blocking(move || {
+            reader.seek(SeekFrom::Start(1024)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&get_file_buf()[1024..], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[test]
+fn piece_map_availability() {
+    let mut map = PieceMap::new(NonZeroU64::new(100).unwrap(), 250);
+    map.insert(120..180);
+    assert_eq!(None, map.get(150));
+    assert_eq!(100, map.fetch_start(150));
+    assert_eq!(200, map.fetch_start(210));
+    assert_eq!(vec![0..120, 180..250], map.gaps(0..250).collect::<Vec<_>>());
+
+    map.insert(100..120);
+    map.insert(180..200);
+    assert!(map.is_piece_complete(1));
+    assert_eq!(Some(100..200), map.get(150));
+
+    map.insert(200..250);
+    assert!(map.is_piece_complete(2));
+    assert_eq!(Some(100..250), map.get(249));
+    assert_eq!(vec![100..250], map.ranges().collect::<Vec<_>>());
+}
+
+#[rstest]
+fn piece_map(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let piece_size = 64 * 1024;
+        let factory = AvailabilityMapFactory::new(move |content_length| {
+            Box::new(PieceMap::new(
+                NonZeroU64::new(piece_size).unwrap(),
+                content_length.unwrap(),
+            ))
+        });
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .availability_map(Some(factory)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let start = 3 * piece_size + 1000;
+            reader.seek(SeekFrom::Start(start)).unwrap();
+            let mut buf = [0; 1024];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[start as usize..start as usize + 1024], buf);
+
+            let state = reader.debug_state();
+            for range in state.downloaded() {
+                assert_eq!(0, range.start % piece_size);
+                assert!(range.end % piece_size == 0 || range.end == file_buf.len() as u64);
+            }
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[test]
+fn user_agent() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url = |name: &str| {
+            format!(
+                "http://{}/music.mp3?user-agent-{name}",
+                SERVER_ADDR.get().unwrap()
+            )
+            .parse()
+            .unwrap()
+        };
+
+        let mut stream = http::HttpStream::<reqwest::Client>::create(url("default"))
+            .await
+            .unwrap();
+        stream.seek_range(1024, None).await.unwrap();
+
+        let options = http::ClientOptions::default().user_agent("custom-agent/1.0");
+        assert_eq!(Some("custom-agent/1.0"), options.get_user_agent());
+        let mut stream =
+            http::HttpStream::<reqwest::Client>::new_with_options(url("custom"), options)
+                .await
+                .unwrap();
+        stream.seek_range(1024, None).await.unwrap();
+
+        // Headers supplied for range requests take precedence
+        let mut stream = http::HttpStream::<reqwest::Client>::create(url("explicit"))
+            .await
+            .unwrap()
+            .range_header(Arc::new(|start, end| {
+                vec![
+                    (
+                        "Range".to_string(),
+                        format!(
+                            "bytes={start}-{}",
+                            end.map(|e| e.to_string()).unwrap_or_default()
+                        ),
+                    ),
+                    ("User-Agent".to_string(), "explicit-agent/1.0".to_string()),
+                ]
+            }));
+        stream.seek_range(1024, None).await.unwrap();
+
+        let default = Some(http::DEFAULT_USER_AGENT.to_string());
+        let custom = Some("custom-agent/1.0".to_string());
+        let explicit = Some("explicit-agent/1.0".to_string());
+        assert_eq!(vec![default.clone(); 2], user_agents("user-agent-default"));
+        assert_eq!(vec![custom; 2], user_agents("user-agent-custom"));
+        // The initial request isn't a range request, so it still uses the client's user agent
+        assert_eq!(vec![default, explicit], user_agents("user-agent-explicit"));
+    });
+}
+
+#[test]
+fn prewarm() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3?prewarm", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        // Use a separate connection pool so other tests can't take the warmed connection
+        let client = reqwest::Client::new();
+        http::Client::prewarm(&client, &url).await.unwrap();
+        let mut stream = http::HttpStream::new(client, url.clone()).await.unwrap();
+        let mut buf = Vec::new();
+        while let Some(bytes) = stream.next().await {
+            buf.extend_from_slice(&bytes.unwrap());
+        }
+        compare(get_file_buf(), buf);
+
+        let peers: Vec<_> = PEER_ADDRS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(uri, _)| uri.ends_with("?prewarm"))
+            .map(|(_, peer)| *peer)
+            .collect();
+        assert_eq!(2, peers.len());
+        assert_eq!(peers[0], peers[1]);
+
+        http::HttpStream::<reqwest::Client>::prewarm(&url)
+            .await
+            .unwrap();
+        let url = "http://127.0.0.1:1/music.mp3".parse().unwrap();
+        assert!(http::HttpStream::<reqwest::Client>::prewarm(&url)
+            .await
+            .is_err());
+    });
+}
+
+#[test]
+fn accept_encoding_identity() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let query = "accept-encoding-identity";
+        let url = format!("http://{}/music.mp3?{query}", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let mut stream = http::HttpStream::<reqwest::Client>::create(url)
+            .await
+            .unwrap();
+        assert_eq!(Some(get_file_buf().len() as u64), stream.content_length());
+        stream.seek_range(1024, None).await.unwrap();
+
+        assert_eq!(
+            vec![Some("identity".to_string()); 2],
+            accept_encodings(query)
+        );
+    });
+}
+
+#[cfg(feature = "compression")]
+#[rstest]
+fn manual_decompression(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let stream = http::HttpStream::<reqwest::Client>::new_with_options(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            http::ClientOptions::default().accept_encoding("gzip"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some("gzip"), stream.content_encoding());
+
+        let mut reader = StreamDownload::from_stream(
+            stream,
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .decompression(Decompression::Manual),
+        )
+        .await
+        .unwrap();
+        assert_eq!(None, reader.debug_state().content_length());
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf.clone(), buf);
+
+            // Data that's already decoded can still be read again
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+// Serves the data as it is while reporting the supplied content encoding
+#[cfg(feature = "compression")]
+struct EncodedStream {
+    data: Bytes,
+    content_encoding: &'static str,
+    position: usize,
+}
+
+#[cfg(feature = "compression")]
+impl Stream for EncodedStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.position >= self.data.len() {
+            return Poll::Ready(None);
+        }
+        let end = (self.position + 4096).min(self.data.len());
+        let chunk = self.data.slice(self.position..end);
+        self.position = end;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[cfg(feature = "compression")]
+#[async_trait]
+impl SourceStream for EncodedStream {
+    type Url = (Vec<u8>, &'static str);
+    type StreamError = io::Error;
+
+    async fn create((data, content_encoding): Self::Url) -> io::Result<Self> {
+        Ok(Self {
+            data: data.into(),
+            content_encoding,
+            position: 0,
+        })
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.data.len() as u64)
+    }
+
+    async fn seek_range(&mut self, _start: u64, _end: Option<u64>) -> io::Result<()> {
+        unimplemented!()
+    }
+
+    fn content_encoding(&self) -> Option<&str> {
+        Some(self.content_encoding)
+    }
+}
+
+#[cfg(feature = "compression")]
+fn encode(content_encoding: &str, data: &[u8]) -> Vec<u8> {
+    match content_encoding {
+        "gzip" => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        "deflate" => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        _ => data.to_vec(),
+    }
+}
+
+#[cfg(feature = "compression")]
+#[rstest]
+fn decompression_encodings(
+    #[values("gzip", "deflate", "br")] content_encoding: &'static str,
+    #[values(Decompression::Raw, Decompression::Manual)] decompression: Decompression,
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+) {
+    let file_buf = get_file_buf();
+    let encoded = encode(content_encoding, &file_buf);
+    // Unsupported encodings are stored as they are received
+    let decoded = decompression == Decompression::Manual && content_encoding != "br";
+
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new::<EncodedStream>(
+            (encoded.clone(), content_encoding),
+            MemoryStorageProvider::default(),
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .decompression(decompression),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (!decoded).then_some(encoded.len() as u64),
+            reader.debug_state().content_length()
+        );
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            if decoded {
+                compare(file_buf, buf);
+            } else {
+                compare(encoded, buf);
+            }
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "compression")]
+#[rstest]
+fn decompression_truncated(#[values(0, 256*1024)] prefetch_bytes: u64) {
+    let file_buf = get_file_buf();
+    let mut encoded = encode("gzip", &file_buf);
+    // Drop the trailer that contains the checksum and the length
+    encoded.truncate(encoded.len() - 8);
+
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new::<EncodedStream>(
+            (encoded, "gzip"),
+            MemoryStorageProvider::default(),
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .decompression(Decompression::Manual),
+        )
+        .await
+        .unwrap();
+
+        let reader = spawn_blocking(move || {
+            // All of the data can be decoded, but the stream can't be verified
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+            reader
+        })
+        .await
+        .unwrap();
+        let err = reader.join().await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    });
+}
+
+#[rstest]
+fn on_content_length(
+    #[values(true, false)] has_content_length: bool,
+    #[values(0, 1024*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let lengths = Arc::new(Mutex::new(Vec::new()));
+        let lengths_ = lengths.clone();
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, has_content_length),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .on_content_length(Some(ContentLengthCallback::new(move |length| {
+                    lengths_.lock().unwrap().push(length);
+                }))),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+
+        let file_len = get_file_buf().len() as u64;
+        if has_content_length {
+            assert_eq!(vec![Some(file_len)], *lengths.lock().unwrap());
+        } else {
+            assert_eq!(vec![None, Some(file_len)], *lengths.lock().unwrap());
+        }
+    });
+}
+
+#[rstest]
+fn on_first_byte(
+    #[values(0, 1024*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_ = calls.clone();
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .on_first_byte(Some(FirstByteCallback::new(move || {
+                    calls_.fetch_add(1, Ordering::SeqCst);
+                }))),
+        )
+        .await
+        .unwrap();
+
+        let calls_ = calls.clone();
+        spawn_blocking(move || {
+            let mut buf = [0; 1];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(1, calls_.load(Ordering::SeqCst));
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            wait_for_download(&reader);
+        })
+        .await
+        .unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    });
+}
+
+#[cfg(feature = "data-url")]
+#[rstest]
+fn on_first_byte_without_data(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_ = calls.clone();
+        let settings = Settings::default().on_first_byte(Some(FirstByteCallback::new(move || {
+            calls_.fetch_add(1, Ordering::SeqCst);
+        })));
+
+        let empty = StreamDownload::new::<DataUrlStream>(
+            "data:text/plain,".to_string(),
+            storage.clone(),
+            settings.clone(),
+        )
+        .await
+        .unwrap();
+        // Errors only stop the download once there are no retries left
+        let failed = StreamDownload::new::<FailingStream>(
+            vec![0],
+            storage,
+            settings.backoff(Fixed::new(Duration::ZERO, 0)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            wait_for_download(&empty);
+            wait_for_download(&failed);
+            assert!(failed.debug_state().download_error().is_some());
+        })
+        .await
+        .unwrap();
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+    });
+}
+
+struct CloseRecordingStream {
+    inner: http::HttpStream<TestClient>,
+    events: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl Stream for CloseRecordingStream {
+    type Item = Result<Bytes, reqwest::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+#[async_trait]
+impl SourceStream for CloseRecordingStream {
+    type Url = reqwest::Url;
+    type StreamError = reqwest::Error;
+
+    async fn create(_url: Self::Url) -> io::Result<Self> {
+        unimplemented!()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.inner.content_length()
+    }
+
+    async fn seek_range(&mut self, start: u64, end: Option<u64>) -> io::Result<()> {
+        self.events.lock().unwrap().push("seek");
+        self.inner.seek_range(start, end).await
+    }
+
+    fn close(&mut self) {
+        self.events.lock().unwrap().push("close");
+        self.inner.close();
+    }
+}
+
+#[rstest]
+fn serialize_requests(
+    #[values(true, false)] serialize: bool,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = StreamDownload::from_stream(
+            CloseRecordingStream {
+                inner: http::HttpStream::new(TestClient::new(tx, true), url.clone())
+                    .await
+                    .unwrap(),
+                events: events.clone(),
+            },
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .serialize_requests(serialize),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+        })
+        .await
+        .unwrap();
+
+        {
+            let events = events.lock().unwrap();
+            assert!(!events.is_empty());
+            if serialize {
+                for pair in events.chunks(2) {
+                    assert_eq!(["close", "seek"], pair);
+                }
+            } else {
+                assert!(events.iter().all(|event| *event == "seek"));
+            }
+        }
+
+        // Nothing is returned from a closed response until the next request is sent
+        let mut stream = http::HttpStream::new(reqwest::Client::new(), url)
+            .await
+            .unwrap();
+        stream.close();
+        assert!(stream.next().await.is_none());
+        stream.seek_range(0, Some(1023)).await.unwrap();
+        let mut buf = Vec::new();
+        while let Some(bytes) = stream.next().await {
+            buf.extend_from_slice(&bytes.unwrap());
+        }
+        compare(&get_file_buf()[..1024], buf);
+    });
+}
+
+#[rstest]
+fn read_vectored(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(1)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = Vec::new();
+            let (mut first, mut second, mut third) = ([0; 100], [0; 5000], [0; 20000]);
+            loop {
+                let mut bufs = [
+                    IoSliceMut::new(&mut first),
+                    IoSliceMut::new(&mut second),
+                    IoSliceMut::new(&mut third),
+                ];
+                let mut len = reader.read_vectored(&mut bufs).unwrap();
+                if len == 0 {
+                    break;
+                }
+                // Every buffer is filled unless the end of the stream is reached
+                assert!(len == 25100 || buf.len() + len == file_buf.len());
+                for part in [&first[..], &second[..], &third[..]] {
+                    let part_len = part.len().min(len);
+                    buf.extend_from_slice(&part[..part_len]);
+                    len -= part_len;
+                }
+            }
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn buf_read(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(0)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+
+            // Filling the buffer doesn't move the position until the data is consumed
+            let first = reader.fill_buf().unwrap().to_vec();
+            assert!(!first.is_empty());
+            compare(&file_buf[..first.len()], first.as_slice());
+            assert_eq!(0, reader.stream_position().unwrap());
+            reader.consume(1);
+            assert_eq!(1, reader.stream_position().unwrap());
+            compare(&file_buf[1..first.len()], reader.fill_buf().unwrap());
+
+            let mut buf = Vec::new();
+            while reader.read_until(b'\n', &mut buf).unwrap() > 0 {}
+            compare(&file_buf[1..], buf);
+            assert!(reader.fill_buf().unwrap().is_empty());
+
+            // Seeking discards the buffered data
+            let seek_pos = 1024;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let buf = reader.fill_buf().unwrap();
+            compare(&file_buf[seek_pos..seek_pos + buf.len()], buf);
+            let mut line = Vec::new();
+            reader.read_until(b'\n', &mut line).unwrap();
+            compare(&file_buf[seek_pos..seek_pos + line.len()], line.as_slice());
+            assert_eq!(
+                (seek_pos + line.len()) as u64,
+                reader.stream_position().unwrap()
+            );
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn buf_read_partial(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut reader = StreamDownload::from_stream(
+            ChannelStream {
+                rx,
+                content_length: file_buf.len() as u64,
+            },
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            // Whatever is available is returned without waiting for a full buffer
+            tx.send(Bytes::copy_from_slice(&file_buf[..100])).unwrap();
+            compare(&file_buf[..100], reader.fill_buf().unwrap());
+            reader.consume(100);
+
+            // The buffer is still limited to 8 KiB when more data is available
+            tx.send(Bytes::copy_from_slice(&file_buf[100..20_000]))
+                .unwrap();
+            let mut buf = Vec::new();
+            while buf.len() < 20_000 - 100 {
+                let available = reader.fill_buf().unwrap();
+                assert!(available.len() <= 8 * 1024);
+                buf.extend_from_slice(available);
+                let len = available.len();
+                reader.consume(len);
+            }
+            compare(&file_buf[100..20_000], buf);
+
+            // The stream ending early leaves nothing more to return
+            drop(tx);
+            assert!(reader.fill_buf().unwrap().is_empty());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+struct RangeFailingClient {
+    inner: reqwest::Client,
+    failing_url: reqwest::Url,
+    start: u64,
+}
+
+#[async_trait]
+impl http::Client for RangeFailingClient {
+    type Url = reqwest::Url;
+    type Response = reqwest::Response;
+    type Error = reqwest::Error;
+    type Headers = reqwest::header::HeaderMap;
+
+    fn create() -> Self {
+        unimplemented!()
+    }
+
+    async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error> {
+        if self.start > 0 {
+            http::Client::get_range(&self.inner, url, self.start, None).await
+        } else {
+            http::Client::get(&self.inner, url).await
+        }
+    }
+
+    async fn get_range(
+        &self,
+        url: &Self::Url,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Self::Response, Self::Error> {
+        // Simulate a server that stops responding after the initial request
+        let url = if *url == self.failing_url {
+            url.join("missing.mp3").unwrap()
+        } else {
+            url.clone()
+        };
+        http::Client::get_range(&self.inner, &url, start, end).await
+    }
+}
+
+// Holds back the initial response until a range is requested, which is then served from the
+// second URL, like a server whose mirror takes over after a failed range request
+struct MirrorStream {
+    data: Bytes,
+    position: usize,
+    url: String,
+    mirror: String,
+    held: bool,
+}
+
+impl Stream for MirrorStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // The download task is woken up by the seek request instead
+        if self.held {
+            return Poll::Pending;
+        }
+        if self.position >= self.data.len() {
+            return Poll::Ready(None);
+        }
+        let end = (self.position + 4096).min(self.data.len());
+        let chunk = self.data.slice(self.position..end);
+        self.position = end;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[async_trait]
+impl SourceStream for MirrorStream {
+    type Url = (String, String);
+    type StreamError = io::Error;
+
+    async fn create((url, mirror): Self::Url) -> io::Result<Self> {
+        Ok(Self {
+            data: get_file_buf().into(),
+            position: 0,
+            url,
+            mirror,
+            held: true,
+        })
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.data.len() as u64)
+    }
+
+    async fn seek_range(&mut self, start: u64, _end: Option<u64>) -> io::Result<()> {
+        self.url = self.mirror.clone();
+        self.position = start as usize;
+        self.held = false;
+        Ok(())
+    }
+
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            url: Some(self.url.clone()),
+            supports_seek: true,
+            content_length: self.content_length(),
+            ..Default::default()
+        }
+    }
+}
+
+#[test]
+fn source_changes() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new::<MirrorStream>(
+            ("primary".to_string(), "mirror".to_string()),
+            TempStorageProvider::default(),
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+
+        let mut changes = reader.source_changes();
+        assert_eq!(Some("primary".to_string()), changes.borrow().url);
+        assert!(!changes.has_changed().unwrap());
+
+        let file_buf = get_file_buf();
+        let mut reader = spawn_blocking(move || {
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+            reader
+        })
+        .await
+        .unwrap();
+
+        // The initial response never sent any data, so the read could only be served by the
+        // range request, which moved the download to the mirror before writing anything
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(Some("mirror".to_string()), changes.borrow_and_update().url);
+        assert_eq!(Some("mirror".to_string()), reader.source_info().url);
+
+        // Subscribers keep receiving updates after the source is replaced
+        reader
+            .switch_source::<MirrorStream>(
+                ("replacement".to_string(), "mirror".to_string()),
+                TempStorageProvider::default(),
+            )
+            .await
+            .unwrap();
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(reader.source_info(), *changes.borrow());
+        assert_eq!(Some("replacement".to_string()), reader.source_info().url);
+    });
+}
+
+#[cfg(feature = "hash")]
+#[derive(Clone)]
+struct SipHasher(std::collections::hash_map::DefaultHasher);
+
+#[cfg(feature = "hash")]
+impl StreamHasher for SipHasher {
+    fn update(&mut self, data: &[u8]) {
+        std::hash::Hasher::write(&mut self.0, data);
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        std::hash::Hasher::finish(&self.0).to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(feature = "hash")]
+#[rstest]
+fn update_hash(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .max_read_ahead(Some(4096))
+                .fill_gaps(false),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let expected = |len: u64| {
+                let mut hasher = SipHasher(Default::default());
+                hasher.update(&file_buf[..len as usize]);
+                hasher.digest()
+            };
+            let mut hash = PrefixHash::new(SipHasher(Default::default()));
+
+            let mut buf = vec![0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+            let hashed_len = reader.update_hash(&mut hash).unwrap();
+            assert!(hashed_len >= 4096);
+            assert_eq!(hashed_len, hash.hashed_len());
+            assert_eq!(expected(hashed_len), hash.digest());
+            assert_eq!(4096, reader.stream_position().unwrap());
+
+            // Data past a gap isn't covered until the gap is filled
+            let seek_pos = file_buf.len() - 4096;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            let gap_start = reader.debug_state().downloaded()[0].end;
+            assert_eq!(gap_start, reader.update_hash(&mut hash).unwrap());
+            assert_eq!(expected(gap_start), hash.digest());
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert_eq!(
+                file_buf.len() as u64,
+                reader.update_hash(&mut hash).unwrap()
+            );
+            assert_eq!(expected(file_buf.len() as u64), hash.digest());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn total_timeout(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(20)).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .total_timeout(Some(Duration::from_millis(100))),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            // Reads stop at whatever was downloaded before the deadline
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert!(buf.len() < file_buf.len());
+            compare(&file_buf[..buf.len()], buf);
+            assert_eq!(
+                Some(DeadlineExceeded.to_string()),
+                reader.debug_state().download_error().map(ToOwned::to_owned)
+            );
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn prefetch_one_byte(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (read_tx, read_rx) = oneshot::channel::<()>();
+
+        let handle = tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::ZERO).unwrap();
+
+            // Hold back the rest of the stream once the first non-empty chunk has been sent
+            // until the first read has finished
+            loop {
+                let (command, responder) = rx.recv().await.unwrap();
+                match command {
+                    Command::NextChunk(0) => {
+                        responder.send(Duration::ZERO).unwrap();
+                    }
+                    Command::NextChunk(_) => {
+                        read_rx.await.unwrap();
+                        responder.send(Duration::ZERO).unwrap();
+                        break;
+                    }
+                    // Sent for empty chunks
+                    Command::EndStream => {}
+                    Command::GetUrl | Command::GetRange => panic!("unexpected request"),
+                }
+            }
+
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::EndStream {
+                    return;
+                }
+                assert!(matches!(command, Command::NextChunk(_)));
+                responder.send(Duration::ZERO).unwrap();
+            }
+            panic!("Stream not finished");
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(1),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = [0; 1];
+            assert_eq!(1, reader.read(&mut buf).unwrap());
+            assert_eq!(file_buf[0], buf[0]);
+            assert!(reader.prefetch_complete());
+            read_tx.send(()).unwrap();
+
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+            compare(&file_buf[1..], rest);
+            wait_for_download(&reader);
+        })
+        .await
+        .unwrap();
+
+        handle.await.unwrap();
+    });
+}
+
+#[rstest]
+fn metrics_handle(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        let metrics = reader.metrics_handle();
+        let file_len = get_file_buf().len() as u64;
+        assert_eq!(Some(file_len), metrics.content_length());
+
+        let monitor = std::thread::spawn({
+            let metrics = metrics.clone();
+            move || {
+                while !metrics.download_complete() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                metrics
+            }
+        });
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+
+            let metrics = monitor.join().unwrap();
+            assert_eq!(file_len, metrics.read_position());
+            assert_eq!(file_len, metrics.write_position());
+            assert_eq!(vec![0..file_len], metrics.downloaded());
+            assert!(metrics.prefetch_complete());
+            assert_eq!(None, metrics.download_error());
+            assert_eq!(reader.stats(), metrics.stats());
+            assert_eq!(reader.debug_state(), metrics.debug_state());
+        })
+        .await
+        .unwrap();
+
+        // The handle doesn't keep the download running after the reader is dropped
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+        let metrics = reader.metrics_handle();
+        drop(reader);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !metrics.download_complete() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "local-server")]
+#[rstest]
+fn serve_local(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        let (url, server) = reader.serve_local().await.unwrap();
+        let url = url.to_string();
+        let file_buf = get_file_buf();
+        let file_len = file_buf.len();
+        let client = reqwest::Client::new();
+
+        let get = |range: Option<&'static str>| {
+            let mut request = client.get(&url);
+            if let Some(range) = range {
+                request = request.header(reqwest::header::RANGE, range);
+            }
+            request.send()
+        };
+
+        let response = get(None).await.unwrap();
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+        assert_eq!("bytes", response.headers()[reqwest::header::ACCEPT_RANGES]);
+        compare(file_buf.clone(), response.bytes().await.unwrap());
+
+        let response = get(Some("bytes=1000-199999")).await.unwrap();
+        assert_eq!(reqwest::StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(
+            format!("bytes 1000-199999/{file_len}"),
+            response.headers()[reqwest::header::CONTENT_RANGE]
+        );
+        compare(&file_buf[1000..200000], response.bytes().await.unwrap());
+
+        let response = get(Some("bytes=-100")).await.unwrap();
+        assert_eq!(reqwest::StatusCode::PARTIAL_CONTENT, response.status());
+        compare(&file_buf[file_len - 100..], response.bytes().await.unwrap());
+
+        let response = get(Some("bytes=250000-")).await.unwrap();
+        assert_eq!(reqwest::StatusCode::PARTIAL_CONTENT, response.status());
+        compare(&file_buf[250000..], response.bytes().await.unwrap());
+
+        let response = get(Some("bytes=400000-")).await.unwrap();
+        assert_eq!(
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE,
+            response.status()
+        );
+        assert_eq!(
+            format!("bytes */{file_len}"),
+            response.headers()[reqwest::header::CONTENT_RANGE]
+        );
+
+        let response = client.head(&url).send().await.unwrap();
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+        assert_eq!(
+            file_len.to_string(),
+            response.headers()[reqwest::header::CONTENT_LENGTH]
+        );
+
+        server.shutdown().await.unwrap();
+        assert!(client.get(&url).send().await.is_err());
+    });
+}
+
+// The test file is 300179 bytes long
+#[cfg(feature = "local-server")]
+#[rstest]
+#[case("bytes=0-0", 206, 0..1)]
+#[case("bytes=300178-", 206, 300178..300179)]
+#[case("bytes=300178-300178", 206, 300178..300179)]
+#[case("bytes=300100-400000", 206, 300100..300179)]
+#[case("bytes=-1", 206, 300178..300179)]
+#[case("bytes=-400000", 206, 0..300179)]
+#[case("bytes=300179-", 416, 0..0)]
+#[case("bytes=-0", 416, 0..0)]
+#[case("bytes=5-2", 200, 0..300179)]
+#[case("bytes=0-1,5-6", 200, 0..300179)]
+#[case("bytes=a-b", 200, 0..300179)]
+#[case("items=0-1", 200, 0..300179)]
+fn serve_local_ranges(
+    #[case] range: &str,
+    #[case] status: u16,
+    #[case] expected: std::ops::Range<usize>,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        let (url, server) = reader.serve_local().await.unwrap();
+        let file_buf = get_file_buf();
+        let file_len = file_buf.len();
+        let response = reqwest::Client::new()
+            .get(url.to_string())
+            .header(reqwest::header::RANGE, range)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(status, response.status().as_u16());
+        if status == 206 {
+            assert_eq!(
+                format!("bytes {}-{}/{file_len}", expected.start, expected.end - 1),
+                response.headers()[reqwest::header::CONTENT_RANGE]
+            );
+        }
+        compare(&file_buf[expected], response.bytes().await.unwrap());
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[cfg(feature = "local-server")]
+#[rstest]
+fn serve_local_if_range() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            MemoryStorageProvider::default(),
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+
+        let (url, server) = reader.serve_local().await.unwrap();
+        let url = url.to_string();
+        let file_buf = get_file_buf();
+        let client = reqwest::Client::new();
+        let get = |if_range: String| {
+            client
+                .get(&url)
+                .header(reqwest::header::RANGE, "bytes=1000-1999")
+                .header(reqwest::header::IF_RANGE, if_range)
+                .send()
+        };
+
+        let response = client.head(&url).send().await.unwrap();
+        let etag = response.headers()[reqwest::header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(etag.starts_with('"'));
+
+        let response = get(etag.clone()).await.unwrap();
+        assert_eq!(reqwest::StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!(etag, response.headers()[reqwest::header::ETAG]);
+        compare(&file_buf[1000..2000], response.bytes().await.unwrap());
+
+        // Anything else returns the whole stream
+        for if_range in [
+            "\"other\"".to_string(),
+            format!("W/{etag}"),
+            "Wed, 21 Oct 2015 07:28:00 GMT".to_string(),
+        ] {
+            let response = get(if_range).await.unwrap();
+            assert_eq!(reqwest::StatusCode::OK, response.status());
+            assert!(response
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .is_none());
+            compare(file_buf.clone(), response.bytes().await.unwrap());
+        }
+        server.shutdown().await.unwrap();
+    });
+}
+
+#[rstest]
+fn spawner(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let runtime_handle = runtime.handle().clone();
+    // Drive the dedicated runtime from a separate thread
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let runtime_thread = std::thread::spawn(move || runtime.block_on(stop_rx));
+
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+
+        let reader = StreamDownload::new_http(
+            url.parse().unwrap(),
+            storage.clone(),
+            Settings::default().spawner(Spawner::runtime(runtime_handle)),
+        )
+        .await
+        .unwrap();
+        let reader = spawn_blocking(move || {
+            let mut reader = reader;
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            reader
+        })
+        .await
+        .unwrap();
+        reader.join().await.unwrap();
+
+        let (spawner, mut tasks) = Spawner::manual();
+        let reader = StreamDownload::new_http(
+            url.parse().unwrap(),
+            storage.clone(),
+            Settings::default().spawner(spawner),
+        )
+        .await
+        .unwrap();
+        // Nothing is downloaded until the task is driven
+        let task = tasks.try_recv().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!reader.prefetch_complete());
+        assert!(reader.debug_state().downloaded().is_empty());
+
+        let driver = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(task);
+        });
+        let reader = spawn_blocking(move || {
+            let mut reader = reader;
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            reader
+        })
+        .await
+        .unwrap();
+        reader.join().await.unwrap();
+        driver.join().unwrap();
+
+        // Dropping the task without finishing it is reported when joining
+        let (spawner, mut tasks) = Spawner::manual();
+        let reader = StreamDownload::new_http(
+            url.parse().unwrap(),
+            storage,
+            Settings::default().spawner(spawner),
+        )
+        .await
+        .unwrap();
+        drop(tasks.try_recv().unwrap());
+        assert!(reader.join().await.is_err());
+    });
+
+    stop_tx.send(()).unwrap();
+    runtime_thread.join().unwrap().unwrap();
+}
+
+#[rstest]
+fn reconnect(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        let handle = tokio::spawn(async move {
+            let (command, responder) = rx.recv().await.unwrap();
+            assert_eq!(Command::GetUrl, command);
+            responder.send(Duration::ZERO).unwrap();
+
+            // Stall the connection after the first chunk until the reader reconnects
+            let mut stalled = Vec::new();
+            loop {
+                let (command, responder) = rx.recv().await.unwrap();
+                match command {
+                    Command::NextChunk(0) if stalled.is_empty() => {
+                        responder.send(Duration::ZERO).unwrap();
+                    }
+                    Command::NextChunk(_) => stalled.push(responder),
+                    Command::GetRange => {
+                        responder.send(Duration::ZERO).unwrap();
+                        break;
+                    }
+                    Command::EndStream => {}
+                    Command::GetUrl => panic!("unexpected request"),
+                }
+            }
+            // The stalled response was closed, so this doesn't deliver any more data
+            for responder in stalled {
+                responder.send(Duration::ZERO).ok();
+            }
+
+            while let Some((command, responder)) = rx.recv().await {
+                if command == Command::EndStream {
+                    return;
+                }
+                assert!(matches!(command, Command::NextChunk(_)));
+                responder.send(Duration::ZERO).unwrap();
+            }
+            panic!("Stream not finished");
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(1),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let mut buf = [0; 1];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(file_buf[0], buf[0]);
+
+            reader.reconnect();
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+            compare(&file_buf[1..], rest);
+            assert_eq!(1, reader.debug_state().downloaded().len());
+            wait_for_download(&reader);
+        })
+        .await
+        .unwrap();
+
+        handle.await.unwrap();
+    });
+}
+
+// Stops delivering data at the given position without closing until the stream is restarted
+struct StallingStream {
+    data: Bytes,
+    position: usize,
+    stall_at: Option<usize>,
+}
+
+impl Stream for StallingStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let position = self.position;
+        if self.stall_at.is_some_and(|stall_at| position >= stall_at) {
+            return Poll::Pending;
+        }
+        if self.position >= self.data.len() {
+            return Poll::Ready(None);
+        }
+        let end = (self.position + 4096).min(self.data.len());
+        let chunk = self.data.slice(self.position..end);
+        self.position = end;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+#[async_trait]
+impl SourceStream for StallingStream {
+    type Url = usize;
+    type StreamError = io::Error;
+
+    async fn create(stall_at: Self::Url) -> io::Result<Self> {
+        Ok(Self {
+            data: get_file_buf().into(),
+            position: 0,
+            stall_at: Some(stall_at),
+        })
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Some(self.data.len() as u64)
+    }
+
+    async fn seek_range(&mut self, start: u64, _end: Option<u64>) -> io::Result<()> {
+        self.position = start as usize;
+        self.stall_at = None;
+        Ok(())
+    }
+}
+
+#[rstest]
+fn stall_timeout(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new::<StallingStream>(
+            100_000,
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .stall_timeout(Some(Duration::from_millis(50))),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            // The reader waits on the stalled connection until the watchdog restarts it
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+            wait_for_download(&reader);
+            assert_eq!(1, reader.stats().stall_recoveries());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn resume_check_length(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let file_len = file_buf.len() as u64;
+        let url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap());
+        let resume_from = 100_000;
+        let existing = file_buf[..resume_from as usize].to_vec();
+        let new_stream = || async {
+            http::HttpStream::new(reqwest::Client::new(), url.parse().unwrap())
+                .await
+                .unwrap()
+        };
+
+        // The stored length is stale
+        let err = StreamDownload::from_stream_resumed(
+            new_stream().await,
+            storage.clone(),
+            Settings::default().content_length_override(Some(file_len - 1000)),
+            resume_from,
+            existing.as_slice(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // The stored length is trusted without checking
+        StreamDownload::from_stream_resumed(
+            new_stream().await,
+            storage.clone(),
+            Settings::default()
+                .content_length_override(Some(file_len - 1000))
+                .check_resume_length(false),
+            resume_from,
+            existing.as_slice(),
+        )
+        .await
+        .unwrap();
+
+        let mut reader = StreamDownload::from_stream_resumed(
+            new_stream().await,
+            storage,
+            Settings::default().content_length_override(Some(file_len)),
+            resume_from,
+            existing.as_slice(),
+        )
+        .await
+        .unwrap();
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn channel_stream(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let half = file_buf.len() / 2;
+        let (tx, rx) = mpsc::channel(32);
+        let (resume_tx, resume_rx) = oneshot::channel();
+
+        let producer = tokio::spawn({
+            let file_buf = file_buf.clone();
+            async move {
+                for chunk in file_buf[..half].chunks(4096) {
+                    tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+                }
+                resume_rx.await.unwrap();
+                for chunk in file_buf[half..].chunks(4096) {
+                    tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+                }
+            }
+        });
+
+        let mut reader = StreamDownload::new::<channel::ChannelStream>(
+            rx,
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        assert_eq!(None, reader.source_info().content_length);
+        assert!(!reader.source_info().supports_seek);
+
+        spawn_blocking(move || {
+            let mut buf = vec![0; half];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[..half], buf);
+
+            // Data that hasn't been produced yet can't be requested
+            let err = reader
+                .seek(SeekFrom::Start(half as u64 + 4096))
+                .unwrap_err();
+            assert_eq!(io::ErrorKind::Unsupported, err.kind());
+
+            // Seeking within the received data works
+            reader.seek(SeekFrom::Start(100)).unwrap();
+            let mut buf = vec![0; half - 100];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[100..half], buf);
+
+            resume_tx.send(()).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[half..], buf);
+        })
+        .await
+        .unwrap();
+        producer.await.unwrap();
+    });
+}
+
+#[rstest]
+fn shared_stream_download(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default(),
+        )
+        .await
+        .unwrap();
+        let reader = SharedStreamDownload::new(reader);
+
+        let chunk_size = 4096;
+        let tasks: Vec<_> = (0..4)
+            .map(|i| {
+                let reader = reader.clone();
+                let file_buf = file_buf.clone();
+                spawn_blocking(move || {
+                    // Each task reads every fourth chunk, so the reads are interleaved
+                    let mut position = i * chunk_size;
+                    while position < file_buf.len() {
+                        let end = (position + chunk_size).min(file_buf.len());
+                        let mut buf = vec![0; end - position];
+                        reader.read_exact_at(position as u64, &mut buf).unwrap();
+                        compare(&file_buf[position..end], buf);
+                        position += 4 * chunk_size;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let file_len = file_buf.len() as u64;
+        assert_eq!(0, reader.read_at(file_len, &mut [0; 16]).unwrap());
+        assert_eq!(Some(file_len), reader.metrics_handle().content_length());
+
+        let clone = reader.clone();
+        let Err(reader) = reader.try_into_inner() else {
+            panic!("the reader is still shared");
+        };
+        drop(clone);
+        assert!(reader.try_into_inner().is_ok());
+    });
+}
+
+#[rstest]
+fn wait_for_position(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, rx) = mpsc::channel(32);
+        let reader = StreamDownload::new::<channel::ChannelStream>(
+            rx,
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        let metrics = reader.metrics_handle();
+
+        let wait = tokio::spawn({
+            let metrics = metrics.clone();
+            async move { metrics.wait_for_position(1024).await }
+        });
+        tx.send(Bytes::from(vec![0; 1024])).await.unwrap();
+        // The byte at the position hasn't been downloaded yet
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!wait.is_finished());
+
+        tx.send(Bytes::from(vec![0; 1])).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        // Data that's already downloaded resolves immediately
+        metrics.wait_for_position(0).await.unwrap();
+
+        drop(tx);
+        let err = tokio::time::timeout(Duration::from_secs(5), metrics.wait_for_position(2048))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        drop(reader);
+    });
+}
+
+#[rstest]
+fn adaptive_prefetch(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let settings = Settings::default()
+            .prefetch_bytes(8 * 1024)
+            .adaptive_prefetch(Some(AdaptivePrefetch::new(
+                64 * 1024,
+                Duration::from_millis(100),
+            )));
+        let chunk = || Bytes::from(vec![0; 4 * 1024]);
+        let prefetch_complete = |metrics: MetricsHandle| {
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while !metrics.prefetch_complete() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+
+        // Fast connections prefetch more than the minimum
+        let (tx, rx) = mpsc::channel(32);
+        let reader =
+            StreamDownload::new::<channel::ChannelStream>(rx, storage.clone(), settings.clone())
+                .await
+                .unwrap();
+        let metrics = reader.metrics_handle();
+        for _ in 0..2 {
+            tx.send(chunk()).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!metrics.prefetch_complete());
+        for _ in 0..14 {
+            tx.send(chunk()).await.unwrap();
+        }
+        prefetch_complete(metrics.clone()).await.unwrap();
+        assert!(metrics.stats().prefetch_throughput().is_some());
+
+        // Slow connections only prefetch the minimum
+        let (tx, rx) = mpsc::channel(32);
+        let reader = StreamDownload::new::<channel::ChannelStream>(rx, storage, settings)
+            .await
+            .unwrap();
+        let metrics = reader.metrics_handle();
+        tx.send(chunk()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        tx.send(chunk()).await.unwrap();
+        prefetch_complete(metrics.clone()).await.unwrap();
+        assert!(metrics.stats().prefetch_throughput().unwrap() < 64 * 1024);
+        drop(reader);
+    });
+}
+
+#[rstest]
+fn download_error_source(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let stream = http::HttpStream::new(
+            RangeFailingClient {
+                inner: reqwest::Client::new(),
+                failing_url: url.clone(),
+                start: 4096,
+            },
+            url,
+        )
+        .await
+        .unwrap();
+        let mut reader =
+            StreamDownload::from_stream(stream, storage, Settings::default().prefetch_bytes(0))
+                .await
+                .unwrap();
+
+        let reader = spawn_blocking(move || {
+            // The initial response skips the start of the stream and the range request for it
+            // fails, which stops the download
+            let err = reader.chunks().next().unwrap().unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+            let download_error = err.get_ref().unwrap().downcast_ref::<DownloadError>();
+            assert!(download_error.is_some());
+            // The HTTP client's error is available from the source chain
+            let source = err.source().unwrap().downcast_ref::<reqwest::Error>();
+            assert_eq!(
+                Some(reqwest::StatusCode::NOT_FOUND),
+                source.unwrap().status()
+            );
+            reader
+        })
+        .await
+        .unwrap();
+
+        let err = reader.join().await.unwrap_err();
+        assert!(err.source().unwrap().is::<reqwest::Error>());
+    });
+}
+
+// Storage that fails the same way as a file that was deleted by the OS once it's marked as removed
+#[derive(Clone)]
+struct RemovableStorageProvider<P> {
+    inner: P,
+    removed: Arc<AtomicBool>,
+}
+
+struct RemovableStorage<T> {
+    inner: T,
+    removed: Arc<AtomicBool>,
+}
+
+impl<T> RemovableStorage<T> {
+    fn check_removed(&self) -> io::Result<()> {
+        if self.removed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No such file or directory",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<P: StorageProvider> StorageProvider for RemovableStorageProvider<P> {
+    type Reader = RemovableStorage<P::Reader>;
+
+    fn create_reader(&self, content_length: Option<u64>) -> io::Result<Self::Reader> {
+        Ok(RemovableStorage {
+            inner: self.inner.create_reader(content_length)?,
+            removed: self.removed.clone(),
+        })
+    }
+}
+
+impl<R: StorageReader> StorageReader for RemovableStorage<R> {
+    type Writer = RemovableStorage<R::Writer>;
+
+    fn writer(&self) -> io::Result<Self::Writer> {
+        Ok(RemovableStorage {
+            inner: self.inner.writer()?,
+            removed: self.removed.clone(),
+        })
+    }
+}
+
+impl<T: Read> Read for RemovableStorage<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_removed()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for RemovableStorage<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_removed()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_removed()?;
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for RemovableStorage<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+// Memory storage that records how much of the written data was synced
+#[derive(Clone, Default)]
+struct SyncRecordingStorageProvider {
+    synced: Arc<AtomicU64>,
+    syncs: Arc<AtomicUsize>,
+}
+
+struct SyncRecordingStorage {
+    inner: MemoryStorage,
+    written: u64,
+    synced: Arc<AtomicU64>,
+    syncs: Arc<AtomicUsize>,
+}
+
+impl StorageProvider for SyncRecordingStorageProvider {
+    type Reader = SyncRecordingStorage;
+
+    fn create_reader(&self, content_length: Option<u64>) -> io::Result<Self::Reader> {
+        Ok(SyncRecordingStorage {
+            inner: MemoryStorageProvider::default().create_reader(content_length)?,
+            written: 0,
+            synced: self.synced.clone(),
+            syncs: self.syncs.clone(),
+        })
+    }
+}
+
+impl StorageReader for SyncRecordingStorage {
+    type Writer = Self;
+
+    fn writer(&self) -> io::Result<Self::Writer> {
+        Ok(Self {
+            inner: self.inner.writer()?,
+            written: 0,
+            synced: self.synced.clone(),
+            syncs: self.syncs.clone(),
+        })
+    }
+
+    fn sync_data(writer: &mut Self::Writer) -> io::Result<()> {
+        writer.synced.fetch_max(writer.written, Ordering::SeqCst);
+        writer.syncs.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Read for SyncRecordingStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for SyncRecordingStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written = self.written.max(self.inner.stream_position()?);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for SyncRecordingStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[rstest]
+fn fsync_on_flush(#[values(0, 256*1024)] prefetch_bytes: u64, #[values(false, true)] fsync: bool) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        tokio::spawn(async move {
+            while let Some((_, responder)) = rx.recv().await {
+                responder.send(Duration::from_millis(1)).ok();
+            }
+        });
+
+        let storage = SyncRecordingStorageProvider::default();
+        let synced = storage.synced.clone();
+        let syncs = storage.syncs.clone();
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .fsync_on_flush(fsync),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let metrics = reader.metrics_handle();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 4096];
+            loop {
+                // Data is only marked as downloaded once it's been synced
+                let downloaded = metrics.downloaded();
+                let synced = synced.load(Ordering::SeqCst);
+                if fsync {
+                    assert!(downloaded.iter().all(|range| range.end <= synced));
+                }
+                let len = reader.read(&mut chunk).unwrap();
+                if len == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..len]);
+            }
+            compare(get_file_buf(), buf);
+            assert_eq!(fsync, syncs.load(Ordering::SeqCst) > 0);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+// Checks that nothing is marked as downloaded before it's been synced
+#[derive(Debug)]
+struct SyncCheckedMap {
+    inner: Box<dyn AvailabilityMap>,
+    synced: Arc<AtomicU64>,
+    inserts: Arc<AtomicUsize>,
+}
+
+impl AvailabilityMap for SyncCheckedMap {
+    fn insert(&mut self, range: Range<u64>) {
+        let synced = self.synced.load(Ordering::SeqCst);
+        assert!(
+            range.end <= synced,
+            "{range:?} was marked as downloaded, but only {synced} bytes were synced"
+        );
+        self.inserts.fetch_add(1, Ordering::SeqCst);
+        self.inner.insert(range);
+    }
+
+    fn get(&self, position: u64) -> Option<Range<u64>> {
+        self.inner.get(position)
+    }
+
+    fn gaps(&self, range: Range<u64>) -> Box<dyn Iterator<Item = Range<u64>> + '_> {
+        self.inner.gaps(range)
+    }
+
+    fn ranges(&self) -> Box<dyn Iterator<Item = Range<u64>> + '_> {
+        self.inner.ranges()
+    }
+}
+
+#[rstest]
+fn fsync_durable_marker(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(0, 64*1024)] flush_interval: u64,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let storage = SyncRecordingStorageProvider::default();
+        let synced = storage.synced.clone();
+        let inserts = Arc::new(AtomicUsize::new(0));
+        let inserts_ = inserts.clone();
+        let factory = AvailabilityMapFactory::new(move |_| {
+            Box::new(SyncCheckedMap {
+                inner: Box::<RangeSet<u64>>::default(),
+                synced: synced.clone(),
+                inserts: inserts_.clone(),
+            })
+        });
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .flush_interval(flush_interval)
+                .fsync_on_flush(true)
+                .availability_map(Some(factory)),
+        )
+        .await
+        .unwrap();
+
+        let reader = spawn_blocking(move || {
+            // Seek past the prefetch window so the downloaded ranges aren't contiguous
+            let file_buf = get_file_buf();
+            let seek_pos = file_buf.len() / 2;
+            reader.seek(SeekFrom::Start(seek_pos as u64)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[seek_pos..], buf);
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+            reader
+        })
+        .await
+        .unwrap();
+        // The map panics inside the download task if the check fails
+        reader.join().await.unwrap();
+        assert!(inserts.load(Ordering::SeqCst) > 0);
+    });
+}
+
+#[rstest]
+fn storage_lost(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let removed = Arc::new(AtomicBool::new(false));
+        let storage = RemovableStorageProvider {
+            inner: storage,
+            removed: removed.clone(),
+        };
+        let (tx, rx) = mpsc::channel(32);
+        let mut reader = StreamDownload::new::<channel::ChannelStream>(
+            rx,
+            storage,
+            Settings::default().prefetch_bytes(0),
+        )
+        .await
+        .unwrap();
+        let metrics = reader.metrics_handle();
+
+        tx.send(Bytes::from(vec![1; 4096])).await.unwrap();
+        metrics.wait_for_position(4095).await.unwrap();
+
+        removed.store(true, Ordering::SeqCst);
+        // Writing the next chunk fails and stops the download
+        tx.send(Bytes::from(vec![2; 4096])).await.unwrap();
+
+        let reader = spawn_blocking(move || {
+            let err = reader.read(&mut [0; 1024]).unwrap_err();
+            assert_eq!(io::ErrorKind::NotFound, err.kind());
+            assert!(err.get_ref().unwrap().is::<StorageLost>());
+            reader
+        })
+        .await
+        .unwrap();
+
+        let err = reader.join().await.unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        let download_error = err.get_ref().unwrap().downcast_ref::<DownloadError>();
+        assert!(download_error
+            .unwrap()
+            .inner()
+            .get_ref()
+            .unwrap()
+            .is::<StorageLost>());
+    });
+}
+
+#[rstest]
+#[case(PrefetchSeek::ContinueWithinWindow, 16 * 1024, false)]
+#[case(PrefetchSeek::ContinueWithinWindow, 64 * 1024 - 1, false)]
+// Seeks at or past the end of the prefetch window restart prefetch at the seek position
+#[case(PrefetchSeek::ContinueWithinWindow, 64 * 1024, true)]
+#[case(PrefetchSeek::ContinueWithinWindow, 200 * 1024, true)]
+#[case(PrefetchSeek::Restart, 16 * 1024, true)]
+fn prefetch_seek(
+    #[case] policy: PrefetchSeek,
+    #[case] seek_position: u64,
+    #[case] restarts: bool,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (seek_tx, seek_rx) = oneshot::channel::<()>();
+
+        // Hold back the first chunk until the reader has seeked so the seek arrives during
+        // prefetch
+        tokio::spawn(async move {
+            let mut seek_rx = Some(seek_rx);
+            while let Some((command, responder)) = rx.recv().await {
+                if matches!(command, Command::NextChunk(len) if len > 0) {
+                    if let Some(seek_rx) = seek_rx.take() {
+                        seek_rx.await.ok();
+                    }
+                }
+                responder.send(Duration::ZERO).ok();
+            }
+        });
+
+        let range_starts = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                RecordingClient {
+                    inner: TestClient::new(tx, true),
+                    range_starts: range_starts.clone(),
+                },
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(64 * 1024)
+                .prefetch_seek(policy),
+        )
+        .await
+        .unwrap();
+
+        let metrics = reader.metrics_handle();
+        tokio::spawn(async move {
+            while metrics.debug_state().requested_position().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // Give the download task time to receive the seek
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            seek_tx.send(()).ok();
+        });
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let start = seek_position as usize;
+            reader.seek(SeekFrom::Start(seek_position)).unwrap();
+            let mut buf = vec![0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[start..start + 4096], buf);
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+
+        let range_starts = range_starts.lock().unwrap();
+        if restarts {
+            assert_eq!(Some(&seek_position), range_starts.first());
+        } else {
+            assert!(range_starts.is_empty(), "{range_starts:?}");
+        }
+    });
+}
+
+// Records the Range header of every request
+struct RangeHeaderClient {
+    inner: TestClient,
+    ranges: Arc<Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl http::Client for RangeHeaderClient {
+    type Url = reqwest::Url;
+    type Response = TestResponse;
+    type Error = reqwest::Error;
+    type Headers = reqwest::header::HeaderMap;
+
+    fn create() -> Self {
+        unimplemented!()
+    }
+
+    async fn get(&self, url: &Self::Url) -> Result<Self::Response, Self::Error> {
+        self.inner.get(url).await
+    }
+
+    async fn get_range(
+        &self,
+        url: &Self::Url,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Self::Response, Self::Error> {
+        self.ranges.lock().unwrap().push(format!(
+            "bytes={start}-{}",
+            end.map(|e| e.to_string()).unwrap_or_default()
+        ));
+        self.inner.get_range(url, start, end).await
+    }
+
+    async fn get_with_headers(
+        &self,
+        url: &Self::Url,
+        headers: &[(String, String)],
+    ) -> Result<Self::Response, Self::Error> {
+        self.ranges.lock().unwrap().extend(
+            headers
+                .iter()
+                .filter(|(name, _)| name == "Range")
+                .map(|(_, value)| value.clone()),
+        );
+        self.inner.get_with_headers(url, headers).await
+    }
+}
+
+#[rstest]
+fn multiple_ranges(
+    #[values(true, false)] server_support: bool,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+
+        // Stop the first two responses once they've returned enough data for the reader so the
+        // sections after them are still missing when the stream reaches the end
+        tokio::spawn(async move {
+            let mut requests = 0;
+            while let Some((command, responder)) = rx.recv().await {
+                let delay = match command {
+                    Command::GetUrl | Command::GetRange => {
+                        requests += 1;
+                        Duration::ZERO
+                    }
+                    Command::NextChunk(len) if requests <= 2 && len >= 4096 => {
+                        Duration::from_secs(3600)
+                    }
+                    _ => Duration::ZERO,
+                };
+                responder.send(delay).ok();
+            }
+        });
+
+        let query = if server_support { "?multirange" } else { "" };
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let stream = http::HttpStream::new(
+            RangeHeaderClient {
+                inner: TestClient::new(tx, true),
+                ranges: ranges.clone(),
+            },
+            format!("http://{}/music.mp3{query}", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .max_ranges_per_request(4);
+        let mut reader =
+            StreamDownload::from_stream(stream, storage, Settings::default().prefetch_bytes(0))
+                .await
+                .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            for position in [0, 100_000] {
+                reader.seek(SeekFrom::Start(position)).unwrap();
+                let mut buf = vec![0; 4096];
+                reader.read_exact(&mut buf).unwrap();
+                compare(&file_buf[position as usize..position as usize + 4096], buf);
+            }
+            reader.seek(SeekFrom::Start(200_000)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[200_000..], buf);
+            wait_for_download(&reader);
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+
+        let ranges = ranges.lock().unwrap();
+        let multiple: Vec<_> = ranges.iter().filter(|range| range.contains(',')).collect();
+        assert_eq!(1, multiple.len(), "{ranges:?}");
+        // Without server support, the missing sections are requested one at a time instead
+        assert_eq!(
+            server_support,
+            ranges.last() == Some(multiple[0]),
+            "{ranges:?}"
+        );
+    });
+}
+
+#[rstest]
+fn set_prefetch_bytes(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (held_tx, held_rx) = oneshot::channel::<()>();
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let (finish_tx, finish_rx) = oneshot::channel::<()>();
+
+        // Hold back the download once some data has been prefetched until the setting is changed,
+        // then let exactly one more chunk through
+        tokio::spawn(async move {
+            let mut held_tx = Some(held_tx);
+            let mut release_rx = Some(release_rx);
+            let mut finish_rx = Some(finish_rx);
+            while let Some((command, responder)) = rx.recv().await {
+                if matches!(command, Command::NextChunk(len) if len >= 32 * 1024) {
+                    if let Some(release_rx) = release_rx.take() {
+                        held_tx.take().unwrap().send(()).ok();
+                        release_rx.await.ok();
+                    } else if let Some(finish_rx) = finish_rx.take() {
+                        finish_rx.await.ok();
+                    }
+                }
+                responder.send(Duration::ZERO).ok();
+            }
+        });
+
+        let mut reader = StreamDownload::from_stream(
+            http::HttpStream::new(
+                TestClient::new(tx, true),
+                format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap(),
+            storage,
+            // Larger than the file, so prefetch would only finish at the end of the stream
+            Settings::default().prefetch_bytes(1024 * 1024),
+        )
+        .await
+        .unwrap();
+
+        held_rx.await.unwrap();
+        assert!(!reader.prefetch_complete());
+        reader.set_prefetch_bytes(16 * 1024);
+        release_tx.send(()).unwrap();
+
+        let metrics = reader.metrics_handle();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !metrics.prefetch_complete() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!metrics.download_complete());
+        finish_tx.send(()).unwrap();
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(get_file_buf(), buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn content_length_from_range_request(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let url: reqwest::Url = format!("http://{}/music.mp3?nolength", SERVER_ADDR.get().unwrap())
+            .parse()
+            .unwrap();
+        let client = reqwest::Client::new();
+        let head = client.head(url.clone()).send().await.unwrap();
+        assert_eq!(reqwest::StatusCode::METHOD_NOT_ALLOWED, head.status());
+        let response = client.get(url.clone()).send().await.unwrap();
+        assert!(response.content_length().is_none());
+        drop(response);
+
+        let stream = http::HttpStream::new(client, url).await.unwrap();
+        let file_buf = get_file_buf();
+        assert_eq!(Some(file_buf.len() as u64), stream.content_length());
+
+        let mut reader = StreamDownload::from_stream(stream, storage, Settings::default())
+            .await
+            .unwrap();
+        spawn_blocking(move || {
+            reader.seek(SeekFrom::End(-1024)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&file_buf[file_buf.len() - 1024..], buf);
+
+            reader.rewind().unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn progress_is_readable(
+    #[values(0, 64 * 1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        let file_len = get_file_buf().len() as u64;
+        let metrics = reader.metrics_handle();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let progress = tokio::spawn(async move {
+            for position in (0..file_len).step_by(4096) {
+                metrics.wait_for_position(position).await.unwrap();
+                tx.send(position).unwrap();
+            }
+        });
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            // Every position reported by the progress notifications has to be readable right away
+            for position in rx {
+                reader.seek(SeekFrom::Start(position)).unwrap();
+                let mut buf = vec![0; 4096];
+                let len = reader.try_read(&mut buf, Instant::now()).unwrap();
+                assert!(len > 0);
+                let start = position as usize;
+                compare(&file_buf[start..start + len], &buf[..len]);
+            }
+        })
+        .await
+        .unwrap();
+        progress.await.unwrap();
+    });
+}
+
+#[rstest]
+fn into_memory() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let file_buf = get_file_buf();
+        let mut cursor = StreamDownload::into_memory::<http::HttpStream<reqwest::Client>>(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            Settings::default(),
+            file_buf.len() as u64,
+        )
+        .await
+        .unwrap();
+        compare(file_buf.as_slice(), cursor.get_ref().as_slice());
+
+        cursor.seek(SeekFrom::Start(4096)).unwrap();
+        let mut buf = [0; 1024];
+        cursor.read_exact(&mut buf).unwrap();
+        compare(&file_buf[4096..4096 + 1024], buf);
+    });
+}
+
+#[rstest]
+fn into_memory_too_large() {
+    SERVER_RT.get().unwrap().block_on(async move {
+        // The content length is known, so this fails before downloading anything
+        let err = StreamDownload::into_memory::<http::HttpStream<reqwest::Client>>(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            Settings::default(),
+            get_file_buf().len() as u64 - 1,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.get_ref().unwrap().is::<MaxDownloadSizeExceeded>());
+    });
+}
+
+#[rstest]
+#[case(3 * 4096, true)]
+#[case(3 * 4096 - 1, false)]
+fn into_memory_unknown_length(#[case] max_download_size: u64, #[case] fits: bool) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, rx) = mpsc::channel(32);
+        for i in 0..3 {
+            tx.send(Bytes::from(vec![i; 4096])).await.unwrap();
+        }
+        drop(tx);
+
+        let result = StreamDownload::into_memory::<channel::ChannelStream>(
+            rx,
+            Settings::default(),
+            max_download_size,
+        )
+        .await;
+        if fits {
+            let buf = result.unwrap().into_inner();
+            assert_eq!(3 * 4096, buf.len());
+            assert_eq!(vec![2; 4096], buf[2 * 4096..]);
+        } else {
+            let err = result.unwrap_err();
+            assert!(err.get_ref().unwrap().is::<MaxDownloadSizeExceeded>());
+        }
+    });
+}
+
+#[cfg(feature = "test-util")]
+#[rstest]
+fn download_speed(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let clock = TestClock::new();
+        let (tx, rx) = mpsc::channel(32);
+        let reader = StreamDownload::new::<channel::ChannelStream>(
+            rx,
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .content_length_override(Some(4 * 4096))
+                .clock(clock.clone()),
+        )
+        .await
+        .unwrap();
+        let metrics = reader.metrics_handle();
+
+        tx.send(Bytes::from(vec![0; 4096])).await.unwrap();
+        metrics.wait_for_position(4095).await.unwrap();
+        // No time has passed since the download started
+        assert_eq!(None, reader.download_speed());
+        assert_eq!(None, reader.eta());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Some(4096.0), reader.download_speed());
+        assert_eq!(Some(Duration::from_secs(3)), reader.eta());
+
+        tx.send(Bytes::from(vec![0; 4096])).await.unwrap();
+        metrics.wait_for_position(8191).await.unwrap();
+        assert_eq!(Some(8192.0), metrics.download_speed());
+        assert_eq!(Some(Duration::from_secs(1)), metrics.eta());
+
+        // The speed drops while no data is received
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(Some(2048.0), reader.download_speed());
+        assert_eq!(Some(Duration::from_secs(4)), reader.eta());
+
+        for _ in 0..2 {
+            tx.send(Bytes::from(vec![0; 4096])).await.unwrap();
+        }
+        drop(tx);
+        while !metrics.download_complete() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(None, reader.download_speed());
+        assert_eq!(Some(Duration::ZERO), reader.eta());
+    });
+}
+
+#[rstest]
+fn prefetch_seek_closes_request(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Duration>)>(32);
+        let (seek_tx, seek_rx) = oneshot::channel::<()>();
+
+        // Hold back the first chunk until the reader has seeked so the seek arrives during
+        // prefetch
+        tokio::spawn(async move {
+            let mut seek_rx = Some(seek_rx);
+            while let Some((command, responder)) = rx.recv().await {
+                if matches!(command, Command::NextChunk(len) if len > 0) {
+                    if let Some(seek_rx) = seek_rx.take() {
+                        seek_rx.await.ok();
+                    }
+                }
+                responder.send(Duration::ZERO).ok();
+            }
+        });
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = StreamDownload::from_stream(
+            CloseRecordingStream {
+                inner: http::HttpStream::new(
+                    TestClient::new(tx, true),
+                    format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                        .parse()
+                        .unwrap(),
+                )
+                .await
+                .unwrap(),
+                events: events.clone(),
+            },
+            storage,
+            // Gaps aren't filled so any data before the seek position came from the original
+            // request
+            Settings::default()
+                .prefetch_bytes(64 * 1024)
+                .fill_gaps(false),
+        )
+        .await
+        .unwrap();
+
+        let metrics = reader.metrics_handle();
+        let seek_metrics = metrics.clone();
+        tokio::spawn(async move {
+            while seek_metrics.debug_state().requested_position().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // Give the download task time to receive the seek
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            seek_tx.send(()).ok();
+        });
+
+        let seek_position = 200 * 1024;
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let start = seek_position as usize;
+            reader.seek(SeekFrom::Start(seek_position)).unwrap();
+            let mut buf = vec![0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[start..start + 4096], buf);
+        })
+        .await
+        .unwrap();
+
+        // The original request was closed before the new one was sent and stopped before reaching
+        // the end of the prefetch window
+        assert_eq!(["close", "seek"], events.lock().unwrap()[..2]);
+        let downloaded = metrics.downloaded();
+        assert!(
+            downloaded
+                .iter()
+                .all(|range| range.start >= seek_position || range.end < 64 * 1024),
+            "{downloaded:?}"
+        );
+    });
+}
+
+#[rstest]
+fn chunk_transform(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        // Depends on the position so misaligned chunks are detected
+        let transform = ChunkTransform::new(|position, chunk| {
+            Ok(chunk
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ (position + i as u64) as u8)
+                .collect())
+        });
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .chunk_transform(Some(transform)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let expected: Vec<_> = get_file_buf()
+                .into_iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ i as u8)
+                .collect();
+            reader.seek(SeekFrom::Start(200000)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&expected[200000..], buf);
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(expected, buf);
+            assert_eq!(
+                Some(get_file_buf().len() as u64),
+                reader.debug_state().content_length()
+            );
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn chunk_transform_length(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let transform = ChunkTransform::new(|_, chunk| {
+            Ok(chunk.iter().flat_map(|byte| [*byte, *byte]).collect())
+        });
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .chunk_transform(Some(transform)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let expected: Vec<_> = get_file_buf()
+                .into_iter()
+                .flat_map(|byte| [byte, byte])
+                .collect();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(expected.clone(), buf);
+            wait_for_download(&reader);
+            assert_eq!(None, reader.debug_state().content_length());
+            let stats = reader.stats();
+            assert_eq!(get_file_buf().len() as u64, stats.bytes_received());
+            assert_eq!(expected.len() as u64, stats.bytes_on_disk());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn chunk_transform_error(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let transform = ChunkTransform::new(|position, chunk| {
+            if position + chunk.len() as u64 > 100000 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid chunk"));
+            }
+            Ok(chunk)
+        });
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .chunk_transform(Some(transform)),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            // Only the data before the failed chunk is available
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            assert!(buf.len() <= 100000);
+            compare(&get_file_buf()[..buf.len()], buf);
+            assert!(reader
+                .debug_state()
+                .download_error()
+                .unwrap()
+                .contains("invalid chunk"));
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn read_backward(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(1, 4096, 100000)] window: usize,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            let end = reader.seek(SeekFrom::End(0)).unwrap() as usize;
+            let mut buf = vec![0; window];
+            let mut windows = Vec::new();
+            loop {
+                let len = reader.read_backward(&mut buf).unwrap();
+                if len == 0 {
+                    break;
+                }
+                windows.push(buf[..len].to_vec());
+                if windows.len() == 1 {
+                    // The first window ends at the end of the stream
+                    compare(&file_buf[end - len..], &buf[..len]);
+                }
+            }
+            assert_eq!(0, reader.stream_position().unwrap());
+            compare(
+                file_buf,
+                windows.into_iter().rev().flatten().collect::<Vec<_>>(),
+            );
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[rstest]
+fn read_backward_unknown_length(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let (tx, rx) = mpsc::channel(32);
+        let mut reader =
+            StreamDownload::new::<channel::ChannelStream>(rx, storage, Settings::default())
+                .await
+                .unwrap();
+        tx.send(Bytes::from(vec![1; 4096])).await.unwrap();
+        drop(tx);
+
+        spawn_blocking(move || {
+            let mut buf = vec![0; 4096];
+            reader.read_exact(&mut buf).unwrap();
+            let err = reader.read_backward(&mut buf).unwrap_err();
+            assert_eq!(io::ErrorKind::Unsupported, err.kind());
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "aes")]
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+// Test vectors from NIST SP 800-38A
+#[cfg(feature = "aes")]
+const AES_KEY: &str = "2b7e151628aed2a6abf7158809cf4f3c";
+#[cfg(feature = "aes")]
+const AES_PLAINTEXT: &str = "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+                             30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710";
+
+#[cfg(feature = "aes")]
+#[rstest]
+fn aes_decrypt(
+    #[values("ctr", "cbc")] mode: &'static str,
+    #[values(1, 7, 16, 40)] chunk_size: usize,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let key = hex(AES_KEY).try_into().unwrap();
+        let (transform, ciphertext) = if mode == "ctr" {
+            (
+                ChunkTransform::from(Aes128Ctr::new(
+                    key,
+                    hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap(),
+                )),
+                hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff\
+                     5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee"),
+            )
+        } else {
+            (
+                ChunkTransform::from(Aes128Cbc::new(
+                    key,
+                    hex("000102030405060708090a0b0c0d0e0f").try_into().unwrap(),
+                )),
+                hex("7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2\
+                     73bed6b8e3c1743b7116e69e222295163ff1caa1681fac09120eca307586e1a7"),
+            )
+        };
+        let (tx, rx) = mpsc::channel(32);
+        let mut reader = StreamDownload::new::<channel::ChannelStream>(
+            rx,
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .chunk_transform(Some(transform)),
+        )
+        .await
+        .unwrap();
+
+        for chunk in ciphertext.chunks(chunk_size) {
+            tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        drop(tx);
+
+        spawn_blocking(move || {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(hex(AES_PLAINTEXT), buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "aes")]
+#[rstest]
+fn aes_ctr_seek(
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let cipher = Aes128Ctr::new([1; 16], [0xff; 16]);
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(prefetch_bytes)
+                .chunk_transform(Some(cipher.clone().into())),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            // The keystream is its own inverse, so applying it to the plaintext gives the same
+            // result as decrypting it
+            let mut expected = get_file_buf();
+            cipher.apply_keystream(0, &mut expected).unwrap();
+            reader.seek(SeekFrom::Start(200001)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(&expected[200001..], buf);
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(expected, buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "aes")]
+#[rstest]
+fn aes_cbc_seek(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            storage,
+            Settings::default()
+                .prefetch_bytes(0)
+                .chunk_transform(Some(Aes128Cbc::new([1; 16], [0; 16]).into())),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            // The seek is rejected if a partial block already made the stream unseekable,
+            // otherwise the transform fails once the data at the new position arrives
+            if let Err(e) = reader.seek(SeekFrom::Start(200000)) {
+                assert_eq!(io::ErrorKind::Unsupported, e.kind());
+                return;
+            }
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            wait_for_download(&reader);
+            assert!(reader
+                .debug_state()
+                .download_error()
+                .unwrap()
+                .contains("in order"));
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "compression")]
+#[rstest]
+fn compressed(
+    #[values(16, 4096, 64*1024)] block_size: usize,
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            CompressedStorageProvider::new(storage, NonZeroUsize::new(block_size).unwrap()),
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            reader.seek(SeekFrom::Start(200_000)).unwrap();
+            let mut buf = [0; 10_000];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[200_000..210_000], buf);
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf.clone(), buf);
+
+            reader.seek(SeekFrom::Start(1000)).unwrap();
+            let mut buf = [0; 1024];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[1000..2024], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    let provider = CompressedStorageProvider::new(
+        TempStorageProvider::new_in(dir.path()),
+        NonZeroUsize::new(4096).unwrap(),
+    )
+    .level(9);
+    let mut reader = provider.create_reader(None).unwrap();
+    let mut writer = reader.writer().unwrap();
+
+    let data = "line of compressible log output\n"
+        .repeat(32 * 1024)
+        .into_bytes();
+    // Write the second half first so the block in the middle is stored in two parts
+    let mid = data.len() / 2 + 100;
+    writer.seek(SeekFrom::Start(mid as u64)).unwrap();
+    for chunk in data[mid..].chunks(1000) {
+        writer.write_all(chunk).unwrap();
+    }
+    writer.seek(SeekFrom::Start(0)).unwrap();
+    for chunk in data[..mid].chunks(1000) {
+        writer.write_all(chunk).unwrap();
+    }
+    // Overwriting data that was already compressed takes precedence over the old data
+    writer.seek(SeekFrom::Start(5000)).unwrap();
+    writer.write_all(&[0; 10]).unwrap();
+    writer.flush().unwrap();
+
+    let mut expected = data.clone();
+    expected[5000..5010].fill(0);
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).unwrap();
+    compare(expected.clone(), buf);
+
+    reader.seek(SeekFrom::Start(4990)).unwrap();
+    let mut buf = [0; 30];
+    reader.read_exact(&mut buf).unwrap();
+    compare(&expected[4990..5020], buf);
+    assert_eq!(data.len() as u64, reader.seek(SeekFrom::End(0)).unwrap());
+
+    let disk_size: u64 = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(disk_size < data.len() as u64 / 10);
+}
+
+#[cfg(feature = "test-util")]
+#[rstest]
+fn replay_ops(
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+    #[values(1, 1000, 64 * 1024)] chunk_size: usize,
+    #[values(0, 256 * 1024)] prefetch_bytes: u64,
+) {
+    let reference = (0..=255).cycle().take(100_000).collect::<Vec<u8>>();
+    Replay::new(reference)
+        .storage(storage)
+        .settings(Settings::default().prefetch_bytes(prefetch_bytes))
+        .chunk_size(NonZeroUsize::new(chunk_size).unwrap())
+        .run(&[
+            Op::Read(0),
+            Op::Read(1024),
+            Op::Seek(SeekFrom::Start(90_000)),
+            Op::Read(4096),
+            Op::Seek(SeekFrom::Current(-50_000)),
+            Op::Read(1),
+            Op::Seek(SeekFrom::End(-10)),
+            Op::Read(4096),
+            Op::Read(4096),
+            // Seeking before the start fails without moving the position
+            Op::Seek(SeekFrom::Current(-200_000)),
+            Op::Seek(SeekFrom::End(-200_000)),
+            Op::Seek(SeekFrom::Start(150_000)),
+            Op::Read(4096),
+            Op::Seek(SeekFrom::Start(0)),
+            Op::Read(200_000),
+        ]);
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn replay_empty() {
+    replay(
+        &[
+            Op::Read(1024),
+            Op::Seek(SeekFrom::End(0)),
+            Op::Seek(SeekFrom::End(-1)),
+            Op::Read(1024),
+        ],
+        &[],
+    );
+}
+
+#[rstest]
+fn tiered(
+    #[values(1, 4096, 64*1024, 4*1024*1024)] window_size: usize,
+    #[values(0, 256*1024)] prefetch_bytes: u64,
+    #[values(TempStorageProvider::default(), MemoryStorageProvider::default())]
+    storage: impl StorageProvider + 'static,
+) {
+    SERVER_RT.get().unwrap().block_on(async move {
+        let mut reader = StreamDownload::new_http(
+            format!("http://{}/music.mp3", SERVER_ADDR.get().unwrap())
+                .parse()
+                .unwrap(),
+            TieredStorageProvider::new(storage, NonZeroUsize::new(window_size).unwrap()),
+            Settings::default().prefetch_bytes(prefetch_bytes),
+        )
+        .await
+        .unwrap();
+
+        spawn_blocking(move || {
+            let file_buf = get_file_buf();
+            reader.seek(SeekFrom::Start(200_000)).unwrap();
+            let mut buf = [0; 10_000];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[200_000..210_000], buf);
+
+            reader.seek(SeekFrom::Start(0)).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).unwrap();
+            compare(file_buf.clone(), buf);
+
+            reader.seek(SeekFrom::Start(1000)).unwrap();
+            let mut buf = [0; 1024];
+            reader.read_exact(&mut buf).unwrap();
+            compare(&file_buf[1000..2024], buf);
+        })
+        .await
+        .unwrap();
+    });
+}
@@ -1,9 +1,13 @@
 use std::io::Read;
+#[cfg(feature = "test-util")]
+use std::io::SeekFrom;
 use std::num::NonZeroUsize;
 use std::{fs, io};
 
 use proptest::prelude::*;
 use setup::{SERVER_ADDR, SERVER_RT};
+#[cfg(feature = "test-util")]
+use stream_download::replay::{Op, Replay};
 use stream_download::storage::bounded::BoundedStorageProvider;
 use stream_download::storage::memory::MemoryStorageProvider;
 use stream_download::{Settings, StreamDownload};
@@ -78,6 +82,31 @@ proptest! {
     }
 }
 
+#[cfg(feature = "test-util")]
+fn op(len: i64) -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (0..len as usize * 2).prop_map(Op::Read),
+        (0..len as u64 * 2).prop_map(|pos| Op::Seek(SeekFrom::Start(pos))),
+        (-len * 2..len * 2).prop_map(|pos| Op::Seek(SeekFrom::Current(pos))),
+        (-len * 2..len).prop_map(|pos| Op::Seek(SeekFrom::End(pos))),
+    ]
+}
+
+#[cfg(feature = "test-util")]
+proptest! {
+    #[test]
+    fn replay_proptest(
+        len in 1..64*1024i64,
+        chunk_size in 1..16*1024usize,
+        ops in prop::collection::vec(op(64*1024), 1..32),
+    ) {
+        let reference = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        Replay::new(reference)
+            .chunk_size(NonZeroUsize::new(chunk_size).unwrap())
+            .run(&ops);
+    }
+}
+
 fn get_file_buf() -> Vec<u8> {
     fs::read("./assets/music.mp3").unwrap()
 }
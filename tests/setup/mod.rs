@@ -1,13 +1,35 @@
+use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::sync::OnceLock;
+use std::sync::{Mutex, OnceLock};
+use std::{fs, io};
 
 use ctor::ctor;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, RANGE, USER_AGENT,
+};
+use hyper::http::request::Parts;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
 use tokio::runtime::Runtime;
+use tower::Service;
 use tower_http::services::ServeDir;
 use tracing_subscriber::EnvFilter;
 
 pub static SERVER_RT: OnceLock<Runtime> = OnceLock::new();
 pub static SERVER_ADDR: OnceLock<SocketAddr> = OnceLock::new();
+// The URI and User-Agent header of every request received by the server
+pub static USER_AGENTS: Mutex<Vec<(String, Option<String>)>> = Mutex::new(Vec::new());
+// The URI and Accept-Encoding header of every request received by the server
+pub static ACCEPT_ENCODINGS: Mutex<Vec<(String, Option<String>)>> = Mutex::new(Vec::new());
+// The URI and client address of every request received by the server
+pub static PEER_ADDRS: Mutex<Vec<(String, SocketAddr)>> = Mutex::new(Vec::new());
+#[cfg(feature = "ftp")]
+pub static FTP_ADDR: OnceLock<SocketAddr> = OnceLock::new();
+// Every command received by the FTP server
+#[cfg(feature = "ftp")]
+pub static FTP_COMMANDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
 #[ctor]
 fn setup() {
@@ -15,16 +37,283 @@ fn setup() {
 
     let rt = SERVER_RT.get_or_init(|| Runtime::new().unwrap());
     let _guard = rt.enter();
-    let service = ServeDir::new("./assets");
+    let serve_dir = ServeDir::new("./assets").precompressed_gzip();
+    let service = service_fn(move |request: Request<Body>| {
+        let mut serve_dir = serve_dir.clone();
+        async move {
+            let (mut parts, body) = request.into_parts();
+            USER_AGENTS.lock().unwrap().push((
+                parts.uri.to_string(),
+                parts
+                    .headers
+                    .get(USER_AGENT)
+                    .and_then(|value| value.to_str().ok())
+                    .map(ToOwned::to_owned),
+            ));
+            ACCEPT_ENCODINGS.lock().unwrap().push((
+                parts.uri.to_string(),
+                parts
+                    .headers
+                    .get(ACCEPT_ENCODING)
+                    .and_then(|value| value.to_str().ok())
+                    .map(ToOwned::to_owned),
+            ));
+            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+            // Serve POST requests the same way as GET as long as they include a body. Anything
+            // else is rejected as an unsupported method.
+            if parts.method == Method::POST && !body.is_empty() {
+                parts.method = Method::GET;
+            }
+            if let Some(response) =
+                multirange_response(&parts).or_else(|| no_length_response(&parts))
+            {
+                return Ok(response.map(|body| {
+                    body.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                        .boxed_unsync()
+                }));
+            }
+            let custom_unit = translate_range_unit(&mut parts);
+            serve_dir
+                .call(Request::from_parts(parts, Body::empty()))
+                .await
+                .map(|mut response| {
+                    if custom_unit {
+                        restore_range_unit(&mut response);
+                    }
+                    response.map(|body| body.boxed_unsync())
+                })
+        }
+    });
 
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let peer = conn.remote_addr();
+        let mut service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                PEER_ADDRS
+                    .lock()
+                    .unwrap()
+                    .push((request.uri().to_string(), peer));
+                service.call(request)
+            }))
+        }
+    });
     let server = hyper::Server::try_bind(&"127.0.0.1:0".parse().unwrap())
         .unwrap()
-        .serve(tower::make::Shared::new(service));
+        .serve(make_service);
     SERVER_ADDR.get_or_init(|| server.local_addr());
 
     rt.spawn(async move {
         server.await.unwrap();
     });
+
+    #[cfg(feature = "ftp")]
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        FTP_ADDR.get_or_init(|| listener.local_addr().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        rt.spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(ftp_session(socket));
+            }
+        });
+    }
+}
+
+// A minimal FTP server that supports just enough commands to retrieve files from the assets
+// directory. Logging in as `norest` makes the server reject REST commands.
+#[cfg(feature = "ftp")]
+async fn ftp_session(socket: tokio::net::TcpStream) -> io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b"220 ready\r\n").await?;
+
+    let mut supports_rest = true;
+    let mut offset = 0;
+    let mut passive = None;
+    while let Some(line) = lines.next_line().await? {
+        FTP_COMMANDS.lock().unwrap().push(line.clone());
+        let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+        let response = match command {
+            "USER" => {
+                supports_rest = arg != "norest";
+                "331 password required".to_owned()
+            }
+            "PASS" => "230 logged in".to_owned(),
+            "TYPE" => "200 type set".to_owned(),
+            "SIZE" => match fs::metadata(format!("./assets/{arg}")) {
+                Ok(metadata) => format!("213 {}", metadata.len()),
+                Err(_) => "550 file not found".to_owned(),
+            },
+            "REST" if supports_rest => {
+                offset = arg.parse().unwrap_or(0);
+                "350 restarting".to_owned()
+            }
+            "PASV" => {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+                let port = listener.local_addr()?.port();
+                passive = Some(listener);
+                format!(
+                    "227 entering passive mode (127,0,0,1,{},{})",
+                    port >> 8,
+                    port & 0xff
+                )
+            }
+            "RETR" => match (fs::read(format!("./assets/{arg}")), passive.take()) {
+                (Ok(file), Some(listener)) => {
+                    writer.write_all(b"150 opening data connection\r\n").await?;
+                    let (mut data, _) = listener.accept().await?;
+                    let start = offset.min(file.len());
+                    offset = 0;
+                    // The client closes the connection early when it seeks
+                    if data.write_all(&file[start..]).await.is_err() {
+                        continue;
+                    }
+                    data.shutdown().await?;
+                    "226 transfer complete".to_owned()
+                }
+                (Err(_), _) => "550 file not found".to_owned(),
+                (_, None) => "425 use PASV first".to_owned(),
+            },
+            "QUIT" => {
+                writer.write_all(b"221 bye\r\n").await?;
+                return Ok(());
+            }
+            _ => "502 command not implemented".to_owned(),
+        };
+        writer
+            .write_all(format!("{response}\r\n").as_bytes())
+            .await?;
+    }
+    Ok(())
+}
+
+// Simulates a server that doesn't report the length of the full resource and rejects HEAD
+// requests, but still supports range requests. This is only done for URLs with a `nolength` query.
+fn no_length_response(parts: &Parts) -> Option<Response<Body>> {
+    if parts.uri.query() != Some("nolength") {
+        return None;
+    }
+    if parts.method == Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .ok();
+    }
+    if parts.headers.contains_key(RANGE) {
+        return None;
+    }
+    let file = fs::read(format!("./assets{}", parts.uri.path())).ok()?;
+    // Streaming the body makes hyper use chunked encoding instead of sending a Content-Length
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for chunk in file.chunks(16 * 1024) {
+            if sender
+                .send_data(hyper::body::Bytes::copy_from_slice(chunk))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    Response::builder()
+        .header(CONTENT_TYPE, "audio/mpeg")
+        .header(ACCEPT_RANGES, "bytes")
+        .body(body)
+        .ok()
+}
+
+const CUSTOM_RANGE_UNIT: &str = "items";
+
+// Simulates a server that uses a custom range unit by translating it to bytes before the request
+// is served. Requests using the bytes unit have their range ignored. This is only done for URLs
+// with a `customunit` query. URLs with a `mismatchedunit` query serve ranges requested in bytes,
+// but report them in the custom unit.
+fn translate_range_unit(parts: &mut Parts) -> bool {
+    match parts.uri.query() {
+        Some("customunit") => {}
+        Some("mismatchedunit") => return true,
+        _ => return false,
+    }
+    let range = parts
+        .headers
+        .remove(RANGE)
+        .and_then(|range| range.to_str().ok().map(ToOwned::to_owned));
+    if let Some(range) = range.and_then(|range| {
+        range
+            .strip_prefix(&format!("{CUSTOM_RANGE_UNIT}="))
+            .map(|range| format!("bytes={range}"))
+    }) {
+        parts
+            .headers
+            .insert(RANGE, HeaderValue::from_str(&range).unwrap());
+    }
+    true
+}
+
+fn restore_range_unit<B>(response: &mut Response<B>) {
+    let content_range = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes"))
+        .map(|range| format!("{CUSTOM_RANGE_UNIT}{range}"));
+    if let Some(content_range) = content_range {
+        response.headers_mut().insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).unwrap(),
+        );
+    }
+}
+
+const BOUNDARY: &str = "3d6b6a416f9b5";
+
+// ServeDir rejects requests for multiple ranges, so they're served here instead as
+// multipart/byteranges. This is only done for URLs with a `multirange` query so the fallback can
+// still be tested with the same files.
+fn multirange_response(parts: &Parts) -> Option<Response<Body>> {
+    if parts.uri.query() != Some("multirange") {
+        return None;
+    }
+    let ranges = parts
+        .headers
+        .get(RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes=")?;
+    if !ranges.contains(',') {
+        return None;
+    }
+    let file = fs::read(format!("./assets{}", parts.uri.path())).ok()?;
+    let len = file.len();
+    let mut body = Vec::new();
+    for range in ranges.split(',') {
+        let (start, end) = range.trim().split_once('-')?;
+        let start = start.parse::<usize>().ok()?;
+        let end = end.parse::<usize>().map_or(len - 1, |end| end.min(len - 1));
+        body.extend_from_slice(
+            format!(
+                "--{BOUNDARY}\r\nContent-Type: audio/mpeg\r\nContent-Range: bytes \
+                 {start}-{end}/{len}\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&file[start..=end]);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={BOUNDARY}"),
+        )
+        .body(Body::from(body))
+        .ok()
 }
 
 fn setup_logger() {